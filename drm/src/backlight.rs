@@ -0,0 +1,123 @@
+//! Panel backlights exposed through `/sys/class/backlight`.
+
+use anyhow::Context;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Sets the backlight at `path` (e.g. `/sys/class/backlight/backlight`) to full brightness.
+pub fn power_on(path: &Path) -> anyhow::Result<()> {
+    set_brightness(path, max_brightness(path)?)?;
+    set_power(path, true)
+}
+
+/// Switches the backlight at `path` on or off, keeping its brightness.
+pub fn set_power(path: &Path, on: bool) -> anyhow::Result<()> {
+    // FB_BLANK_UNBLANK and FB_BLANK_POWERDOWN.
+    let power = if on { "0" } else { "4" };
+    fs::write(path.join("bl_power"), power).context("write bl_power")
+}
+
+/// The current brightness of the backlight at `path`.
+pub fn brightness(path: &Path) -> anyhow::Result<u32> {
+    read_u32(&path.join("brightness"))
+}
+
+/// The largest brightness the backlight at `path` takes.
+pub fn max_brightness(path: &Path) -> anyhow::Result<u32> {
+    read_u32(&path.join("max_brightness"))
+}
+
+/// Sets the backlight at `path` to `brightness`, clamped to its maximum.
+pub fn set_brightness(path: &Path, brightness: u32) -> anyhow::Result<()> {
+    let brightness = brightness.min(max_brightness(path)?);
+    fs::write(path.join("brightness"), brightness.to_string()).context("write brightness")
+}
+
+/// Follows the brightness the host sets through the `GUD_PROPERTY_BACKLIGHT_BRIGHTNESS`
+/// property, in the range 0-100, on a backlight with a range of its own. Changes fade in over
+/// the ramp's duration instead of jumping, if there is one.
+pub struct Dimmer {
+    path: PathBuf,
+    max: u32,
+    ramp: Duration,
+    // The host's last brightness, in percent.
+    target: Option<u8>,
+    // The brightness written last, and the fade under way: where it started, where it's going
+    // and when.
+    level: u32,
+    fade: Option<(u32, u32, Instant)>,
+}
+
+impl Dimmer {
+    pub fn new(path: PathBuf, ramp: Duration) -> anyhow::Result<Self> {
+        Ok(Self {
+            max: max_brightness(&path)?,
+            level: brightness(&path)?,
+            path,
+            ramp,
+            target: None,
+            fade: None,
+        })
+    }
+
+    /// The backlight's brightness in percent, to advertise to the host.
+    pub fn percent(&self) -> u8 {
+        match self.max {
+            0 => 100,
+            max => ((u64::from(self.level) * 100 + u64::from(max) / 2) / u64::from(max)) as u8,
+        }
+    }
+
+    /// Goes to the host's `percent`: right away without a ramp, otherwise as it's
+    /// [`step`](Self::step)ped. Setting where it's going already does nothing.
+    pub fn set(&mut self, percent: u8) -> anyhow::Result<()> {
+        if self.target == Some(percent) {
+            return Ok(());
+        }
+        self.target = Some(percent);
+        let to = ((u64::from(percent.min(100)) * u64::from(self.max) + 50) / 100) as u32;
+        match self.ramp.is_zero() {
+            true => self.write(to)?,
+            false => self.fade = Some((self.level, to, Instant::now())),
+        }
+        Ok(())
+    }
+
+    /// Whether a fade is under way, which has to be stepped until it's done.
+    pub fn is_fading(&self) -> bool {
+        self.fade.is_some()
+    }
+
+    /// Writes the brightness due now, if it's fading.
+    pub fn step(&mut self) -> anyhow::Result<()> {
+        let Some((from, to, started)) = self.fade else {
+            return Ok(());
+        };
+        let elapsed = started.elapsed();
+        if elapsed >= self.ramp {
+            self.fade = None;
+            return self.write(to);
+        }
+        let progress = elapsed.as_secs_f64() / self.ramp.as_secs_f64();
+        let level = f64::from(from) + (f64::from(to) - f64::from(from)) * progress;
+        self.write(level.round() as u32)
+    }
+
+    fn write(&mut self, level: u32) -> anyhow::Result<()> {
+        if level != self.level {
+            fs::write(self.path.join("brightness"), level.to_string())
+                .context("write brightness")?;
+            self.level = level;
+        }
+        Ok(())
+    }
+}
+
+fn read_u32(path: &Path) -> anyhow::Result<u32> {
+    let value = fs::read_to_string(path).with_context(|| format!("read {}", path.display()))?;
+    value
+        .trim()
+        .parse()
+        .with_context(|| format!("parse {}", path.display()))
+}
@@ -0,0 +1,321 @@
+use anyhow::Context;
+use clap::{Parser, ValueEnum};
+use drm::buffer::DrmFourcc;
+use drm::control::{connector, crtc, Device, Mode};
+use gud_gadget::protocol::PixelFormat;
+use serde::Deserialize;
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use crate::Card;
+
+/// Expose a DRM display as a Generic USB Display gadget.
+#[derive(Debug, Parser)]
+pub struct Args {
+    /// DRM device node to scan out to.
+    #[arg(default_value = "/dev/dri/card0")]
+    pub card: PathBuf,
+    /// TOML file with the USB identity and display settings.
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+    /// Print the card's connectors, CRTCs and modes, then exit.
+    #[arg(long)]
+    pub list: bool,
+    /// Connector to drive, by name (e.g. `HDMI-A-1`) or id. Repeat it to drive several, each is a
+    /// display of its own on the host. Defaults to every connected one.
+    #[arg(long)]
+    pub connector: Vec<String>,
+    /// CRTC to drive the connector with, by id. The n-th `--crtc` goes with the n-th connector.
+    /// Defaults to the first free one the connector supports.
+    #[arg(long)]
+    pub crtc: Vec<u32>,
+    /// Panel mode to scan out, as `WIDTHxHEIGHT` or `WIDTHxHEIGHT@REFRESH`. The n-th `--mode` goes
+    /// with the n-th connector. Defaults to the connector's preferred mode.
+    #[arg(long)]
+    pub mode: Vec<ModeSpec>,
+    /// Scanout pixel format, `rgb565` by default. Frames from the host are converted to it.
+    #[arg(long, value_enum)]
+    pub format: Option<Format>,
+    /// Shown while no host is driving the display, a `#RRGGBB` color or a PNG. Defaults to black.
+    #[arg(long)]
+    pub splash: Option<String>,
+    /// Switch the panels and backlight off while no host is connected, instead of showing the
+    /// splash, to save power. The last frame is back when the host returns.
+    #[arg(long)]
+    pub blank_on_disconnect: bool,
+    /// Shown once `gud-drm` is told to stop, like `--splash`, after the host's been told the
+    /// display's disconnected. The last frame stays up if unset.
+    #[arg(long)]
+    pub disconnected: Option<String>,
+    /// Seconds to wait when stopping for the host to poll the connectors and see they're
+    /// disconnected, before unbinding the gadget. 12 by default: the connectors are offered with
+    /// status polling, which the host does every 10.
+    #[arg(long)]
+    pub shutdown_timeout: Option<u64>,
+    /// Render with the GPU (needs the `gpu` feature).
+    #[arg(long)]
+    pub gpu: bool,
+    /// Rotate the output clockwise by this many degrees, for panels mounted sideways or upside
+    /// down. The host is offered the modes the way up they're seen.
+    #[arg(long)]
+    pub rotate: Option<u32>,
+    /// Mirror the output horizontally, before rotating it.
+    #[arg(long)]
+    pub flip: bool,
+    /// Correct the panel's gamma by raising each color channel to this power: above 1 darkens
+    /// washed out midtones, below 1 brightens them.
+    #[arg(long)]
+    pub gamma: Option<f32>,
+    /// The panel's `/sys/class/backlight` device. It's switched on at startup, and the host's
+    /// brightness slider controls it.
+    #[arg(long)]
+    pub backlight: Option<PathBuf>,
+    /// Fade the backlight to the brightness the host sets over this many milliseconds, instead
+    /// of jumping to it.
+    #[arg(long, requires = "backlight")]
+    pub backlight_ramp: Option<u64>,
+    /// Hold back each frame until the previous one is on screen, so the host renders at the
+    /// panel's refresh rate instead of racing ahead.
+    #[arg(long)]
+    pub vsync: bool,
+    /// Present at most this many frames a second, merging the damage of frames that arrive in
+    /// between, for panels slower than the host sends frames.
+    #[arg(long)]
+    pub max_fps: Option<u32>,
+    /// The panel's touchscreen evdev device, whose touches are sent to the host through a HID
+    /// function (needs the `touch` feature).
+    #[arg(long)]
+    pub touch: Option<PathBuf>,
+    /// Serve a D-Bus interface on the system bus to show and control the display (needs the
+    /// `dbus` feature).
+    #[arg(long)]
+    pub dbus: bool,
+    /// Let the host burst this many packets after the first on a SuperSpeed link, up to 15.
+    /// Needs a USB3 UDC such as DWC3 to make a difference.
+    #[arg(long)]
+    pub max_burst: Option<u8>,
+    /// Bytes to read from the USB endpoint at once, 64 KiB at high speed and 256 KiB at
+    /// SuperSpeed by default.
+    #[arg(long)]
+    pub chunk_size: Option<usize>,
+    /// Wait for a UDC to appear instead of failing when there's none yet, e.g. at boot before its
+    /// driver has probed, and bind the gadget again when it goes away and comes back.
+    #[arg(long)]
+    pub wait_udc: bool,
+    /// Write frame statistics to this file every few seconds, in the Prometheus text format for
+    /// node_exporter's textfile collector.
+    #[arg(long)]
+    pub metrics_file: Option<PathBuf>,
+    /// Keep the state the host last committed in this file, so it's picked up again after a
+    /// restart.
+    #[arg(long)]
+    pub state_file: Option<PathBuf>,
+    /// Log a CRC-32 of every frame received, and the header of any that arrived short or
+    /// corrupted. For bringing up a new UDC.
+    #[arg(long)]
+    pub validate: bool,
+    /// Outline each rect the host redraws.
+    #[arg(long)]
+    pub show_damage: bool,
+    /// Hold back every read from the USB endpoint by this many milliseconds, to test how the host
+    /// copes with a slow device.
+    #[arg(long)]
+    pub read_delay: Option<u64>,
+    /// Receive frames at no more than this many bytes per second, to test how the host copes
+    /// with a slow link.
+    #[arg(long)]
+    pub max_bandwidth: Option<u64>,
+    /// Record every control transfer with the host and the header of every frame to this file,
+    /// to replay the session without the host (see `gud_gadget::trace`).
+    #[arg(long)]
+    pub trace: Option<PathBuf>,
+    /// Record a CRC-32 of every frame in the `--trace`.
+    #[arg(long, requires = "trace")]
+    pub trace_checksums: bool,
+}
+
+#[derive(Debug, Clone, Copy, ValueEnum, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    Rgb565,
+    Xrgb8888,
+}
+
+impl Format {
+    pub fn fourcc(self) -> DrmFourcc {
+        match self {
+            Format::Rgb565 => DrmFourcc::Rgb565,
+            Format::Xrgb8888 => DrmFourcc::Xrgb8888,
+        }
+    }
+
+    pub fn bpp(self) -> u32 {
+        self.pixel_format().bits_per_pixel() as u32
+    }
+
+    pub fn pixel_format(self) -> PixelFormat {
+        match self {
+            Format::Rgb565 => PixelFormat::Rgb565,
+            Format::Xrgb8888 => PixelFormat::Xrgb8888,
+        }
+    }
+}
+
+/// A `--mode` selection.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub struct ModeSpec {
+    pub width: u16,
+    pub height: u16,
+    pub refresh: Option<u32>,
+}
+
+impl ModeSpec {
+    pub fn matches(&self, mode: &Mode) -> bool {
+        mode.size() == (self.width, self.height)
+            && self
+                .refresh
+                .is_none_or(|refresh| mode.vrefresh() == refresh)
+    }
+}
+
+impl FromStr for ModeSpec {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (size, refresh) = match s.split_once('@') {
+            Some((size, refresh)) => (size, Some(refresh.parse().context("invalid refresh")?)),
+            None => (s, None),
+        };
+        let (width, height) = size.split_once('x').context("expected WIDTHxHEIGHT")?;
+        Ok(ModeSpec {
+            width: width.parse().context("invalid width")?,
+            height: height.parse().context("invalid height")?,
+            refresh,
+        })
+    }
+}
+
+impl TryFrom<String> for ModeSpec {
+    type Error = anyhow::Error;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+/// The kernel's name for a connector, e.g. `HDMI-A-1`.
+pub fn connector_name(info: &connector::Info) -> String {
+    format!("{}-{}", info.interface().as_str(), info.interface_id())
+}
+
+/// Picks the connectors named by `--connector`, or all the connected ones.
+pub fn select_connectors(card: &Card, names: &[String]) -> anyhow::Result<Vec<connector::Info>> {
+    let resources = card.resource_handles().context("load DRM resources")?;
+    let connectors = resources
+        .connectors()
+        .iter()
+        .map(|handle| card.get_connector(*handle, false))
+        .collect::<Result<Vec<_>, _>>()
+        .context("get DRM connector")?;
+    if names.is_empty() {
+        let connected: Vec<_> = connectors
+            .into_iter()
+            .filter(|info| info.state() == connector::State::Connected)
+            .collect();
+        anyhow::ensure!(!connected.is_empty(), "no connected connectors found");
+        return Ok(connected);
+    }
+    names
+        .iter()
+        .map(|name| {
+            connectors
+                .iter()
+                .find(|info| {
+                    connector_name(info) == *name || u32::from(info.handle()).to_string() == *name
+                })
+                .cloned()
+                .with_context(|| format!("no connector {name}"))
+        })
+        .collect()
+}
+
+/// Picks the CRTC with id `id`, or the first one that can drive `connector` and isn't `taken`.
+pub fn select_crtc(
+    card: &Card,
+    connector: &connector::Info,
+    id: Option<u32>,
+    taken: &[crtc::Handle],
+) -> anyhow::Result<crtc::Handle> {
+    let resources = card.resource_handles().context("load DRM resources")?;
+    if let Some(id) = id {
+        return resources
+            .crtcs()
+            .iter()
+            .copied()
+            .find(|crtc| u32::from(*crtc) == id)
+            .with_context(|| format!("no CRTC {id}"));
+    }
+    connector
+        .encoders()
+        .iter()
+        .flat_map(|encoder| card.get_encoder(*encoder))
+        .flat_map(|encoder| resources.filter_crtcs(encoder.possible_crtcs()))
+        .find(|crtc| !taken.contains(crtc))
+        .with_context(|| format!("no free CRTC for {}", connector_name(connector)))
+}
+
+/// Picks the mode matching `spec`, or the connector's first (preferred) mode.
+pub fn select_mode(connector: &connector::Info, spec: Option<ModeSpec>) -> anyhow::Result<Mode> {
+    let mut modes = connector.modes().iter();
+    match spec {
+        Some(spec) => modes
+            .find(|mode| spec.matches(mode))
+            .copied()
+            .with_context(|| format!("connector has no mode {spec:?}")),
+        None => modes.next().copied().context("connector has no modes"),
+    }
+}
+
+/// Prints the card's DRM resources for `--list`.
+pub fn list(card: &Card) -> anyhow::Result<()> {
+    let resources = card.resource_handles().context("load DRM resources")?;
+    for handle in resources.connectors() {
+        let info = card.get_connector(*handle, false)?;
+        println!(
+            "connector {} {} ({:?})",
+            u32::from(*handle),
+            connector_name(&info),
+            info.state()
+        );
+        for mode in info.modes() {
+            let (width, height) = mode.size();
+            println!("  mode {}x{}@{}", width, height, mode.vrefresh());
+        }
+        let crtcs: Vec<u32> = info
+            .encoders()
+            .iter()
+            .flat_map(|encoder| card.get_encoder(*encoder))
+            .flat_map(|encoder| resources.filter_crtcs(encoder.possible_crtcs()))
+            .map(u32::from)
+            .collect();
+        println!("  crtcs {:?}", crtcs);
+    }
+    for handle in resources.crtcs() {
+        let info = card.get_crtc(*handle)?;
+        match info.mode() {
+            Some(mode) => {
+                let (width, height) = mode.size();
+                println!(
+                    "crtc {} {}x{}@{}",
+                    u32::from(*handle),
+                    width,
+                    height,
+                    mode.vrefresh()
+                );
+            }
+            None => println!("crtc {} (inactive)", u32::from(*handle)),
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,283 @@
+//! The `--config` file, so deployments can change behavior without recompiling.
+//!
+//! ```toml
+//! [usb]
+//! vendor_id = 0x1d50
+//! product_id = 0x614d
+//! manufacturer = "The Internet"
+//! product = "Generic USB Display"
+//! serial = "0001"
+//! # max_burst = 15 # for SuperSpeed UDCs
+//! # chunk_size = 131072
+//! # wait_udc = true
+//!
+//! [display]
+//! connector = ["DSI-1", "HDMI-A-1"] # or just one, connector = "DSI-1"
+//! mode = ["720x1440@60", "1920x1080"]
+//! format = "rgb565"
+//! modes = ["720x1440", "360x720"]
+//! formats = ["xrgb8888", "rgb565"]
+//! # connector_type = "panel" # derived from each DRM connector if unset
+//! compression = true
+//! # rotate = 90
+//! # flip = true
+//! # gamma = 1.2
+//! vsync = true
+//! # max_fps = 30
+//! backlight = "/sys/class/backlight/backlight"
+//! # backlight_ramp = 250
+//! splash = "/usr/share/gud-gadget/splash.png"
+//! # blank_on_disconnect = true
+//! # disconnected = "#202020"
+//! # shutdown_timeout = 12
+//! # touch = "/dev/input/event1" # needs the touch feature
+//! # dbus = true # needs the dbus feature
+//! # metrics_file = "/var/lib/node_exporter/textfile/gud.prom"
+//! # state_file = "/var/lib/gud-gadget/state"
+//! ```
+//!
+//! Command line flags take precedence over the file.
+
+use anyhow::Context;
+use gud_gadget::protocol::*;
+use serde::de::Error as _;
+use serde::{Deserialize, Deserializer};
+use std::path::{Path, PathBuf};
+
+use crate::cli::{Format, ModeSpec};
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub usb: Usb,
+    pub display: Display,
+}
+
+/// How the gadget identifies itself, and talks, on the bus.
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Usb {
+    pub vendor_id: u16,
+    pub product_id: u16,
+    pub manufacturer: String,
+    pub product: String,
+    pub serial: String,
+    /// See `--max-burst`.
+    pub max_burst: Option<u8>,
+    /// See `--chunk-size`.
+    pub chunk_size: Option<usize>,
+    /// See `--wait-udc`.
+    pub wait_udc: bool,
+}
+
+impl Default for Usb {
+    fn default() -> Self {
+        Self {
+            vendor_id: gud_gadget::OPENMOKO_VENDOR_ID,
+            product_id: gud_gadget::OPENMOKO_GUD_PRODUCT_ID,
+            manufacturer: "The Internet".to_string(),
+            product: "Generic USB Display".to_string(),
+            serial: String::new(),
+            max_burst: None,
+            chunk_size: None,
+            wait_udc: false,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Display {
+    /// See `--connector`.
+    #[serde(deserialize_with = "one_or_many")]
+    pub connector: Vec<String>,
+    /// See `--crtc`.
+    #[serde(deserialize_with = "one_or_many")]
+    pub crtc: Vec<u32>,
+    /// See `--mode`.
+    #[serde(deserialize_with = "one_or_many")]
+    pub mode: Vec<ModeSpec>,
+    /// See `--format`.
+    pub format: Option<Format>,
+    /// Connector modes offered to the host, all of them if unset.
+    pub modes: Option<Vec<ModeSpec>>,
+    /// GUD pixel formats offered to the host, the ones the output handles best if unset.
+    #[serde(deserialize_with = "pixel_formats")]
+    pub formats: Option<Vec<PixelFormat>>,
+    /// The connector type reported for every connector, instead of the DRM connector's.
+    pub connector_type: Option<ConnectorType>,
+    /// Whether to advertise LZ4 compression.
+    pub compression: bool,
+    /// See `--rotate`.
+    pub rotate: Option<u32>,
+    /// See `--flip`.
+    pub flip: bool,
+    /// See `--gamma`.
+    pub gamma: Option<f32>,
+    /// See `--vsync`.
+    pub vsync: bool,
+    /// See `--max-fps`.
+    pub max_fps: Option<u32>,
+    /// See `--backlight`.
+    pub backlight: Option<PathBuf>,
+    /// See `--backlight-ramp`.
+    pub backlight_ramp: Option<u64>,
+    /// See `--splash`.
+    pub splash: Option<String>,
+    /// See `--blank-on-disconnect`.
+    pub blank_on_disconnect: bool,
+    /// See `--disconnected`.
+    pub disconnected: Option<String>,
+    /// See `--shutdown-timeout`.
+    pub shutdown_timeout: Option<u64>,
+    /// See `--touch`.
+    pub touch: Option<PathBuf>,
+    /// See `--dbus`.
+    pub dbus: bool,
+    /// See `--metrics-file`.
+    pub metrics_file: Option<PathBuf>,
+    /// See `--state-file`.
+    pub state_file: Option<PathBuf>,
+}
+
+impl Default for Display {
+    fn default() -> Self {
+        Self {
+            connector: Vec::new(),
+            crtc: Vec::new(),
+            mode: Vec::new(),
+            format: None,
+            modes: None,
+            formats: None,
+            connector_type: None,
+            compression: true,
+            rotate: None,
+            flip: false,
+            gamma: None,
+            vsync: false,
+            max_fps: None,
+            backlight: None,
+            backlight_ramp: None,
+            splash: None,
+            blank_on_disconnect: false,
+            disconnected: None,
+            shutdown_timeout: None,
+            touch: None,
+            dbus: false,
+            metrics_file: None,
+            state_file: None,
+        }
+    }
+}
+
+impl Display {
+    /// Whether `mode` is offered to the host.
+    pub fn advertises(&self, mode: &drm::control::Mode) -> bool {
+        self.modes
+            .as_ref()
+            .is_none_or(|specs| specs.iter().any(|spec| spec.matches(mode)))
+    }
+
+    /// The `GUD_COMPRESSION_*` flags to advertise.
+    pub fn compression(&self) -> u8 {
+        match self.compression {
+            true => GUD_COMPRESSION_LZ4,
+            false => 0,
+        }
+    }
+}
+
+/// The kind of connector reported to the host.
+#[derive(Debug, Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ConnectorType {
+    Panel,
+    Vga,
+    Composite,
+    Svideo,
+    Component,
+    Dvi,
+    Displayport,
+    Hdmi,
+}
+
+impl ConnectorType {
+    /// The closest match for a DRM connector, built-in displays are panels.
+    pub fn of(interface: drm::control::connector::Interface) -> Self {
+        use drm::control::connector::Interface;
+        match interface {
+            Interface::VGA => ConnectorType::Vga,
+            Interface::Composite | Interface::TV => ConnectorType::Composite,
+            Interface::SVideo => ConnectorType::Svideo,
+            Interface::Component => ConnectorType::Component,
+            Interface::DVII | Interface::DVID | Interface::DVIA => ConnectorType::Dvi,
+            Interface::DisplayPort => ConnectorType::Displayport,
+            Interface::HDMIA | Interface::HDMIB => ConnectorType::Hdmi,
+            _ => ConnectorType::Panel,
+        }
+    }
+}
+
+impl From<ConnectorType> for u8 {
+    fn from(connector_type: ConnectorType) -> u8 {
+        match connector_type {
+            ConnectorType::Panel => GUD_CONNECTOR_TYPE_PANEL,
+            ConnectorType::Vga => GUD_CONNECTOR_TYPE_VGA,
+            ConnectorType::Composite => GUD_CONNECTOR_TYPE_COMPOSITE,
+            ConnectorType::Svideo => GUD_CONNECTOR_TYPE_SVIDEO,
+            ConnectorType::Component => GUD_CONNECTOR_TYPE_COMPONENT,
+            ConnectorType::Dvi => GUD_CONNECTOR_TYPE_DVI,
+            ConnectorType::Displayport => GUD_CONNECTOR_TYPE_DISPLAYPORT,
+            ConnectorType::Hdmi => GUD_CONNECTOR_TYPE_HDMI,
+        }
+    }
+}
+
+impl Config {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| format!("read config {}", path.display()))?;
+        toml::from_str(&contents).with_context(|| format!("parse config {}", path.display()))
+    }
+}
+
+// Accepts a single value as well as a list.
+fn one_or_many<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+    deserializer: D,
+) -> Result<Vec<T>, D::Error> {
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany<T> {
+        One(T),
+        Many(Vec<T>),
+    }
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
+}
+
+fn pixel_formats<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Option<Vec<PixelFormat>>, D::Error> {
+    let Some(names) = Option::<Vec<String>>::deserialize(deserializer)? else {
+        return Ok(None);
+    };
+    names
+        .iter()
+        .map(|name| {
+            Ok(match name.as_str() {
+                "r1" => PixelFormat::R1,
+                "r8" => PixelFormat::R8,
+                "xrgb1111" => PixelFormat::Xrgb1111,
+                "rgb332" => PixelFormat::Rgb332,
+                "rgb565" => PixelFormat::Rgb565,
+                "rgb888" => PixelFormat::Rgb888,
+                "xrgb8888" => PixelFormat::Xrgb8888,
+                "argb8888" => PixelFormat::Argb8888,
+                name => return Err(D::Error::custom(format!("unknown pixel format {name}"))),
+            })
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
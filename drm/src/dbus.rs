@@ -0,0 +1,189 @@
+//! A D-Bus service on the system bus, so a phone's UI (e.g. a Phosh quick setting) can show and
+//! control the USB display.
+//!
+//! The `io.github.samcday.GudGadget1` interface at `/io/github/samcday/GudGadget` has these
+//! properties:
+//!
+//! - `Enabled` (writable): whether the gadget is bound to the UDC, i.e. whether hosts see a display.
+//! - `Connected`: whether a host is driving the display, i.e. has committed a mode.
+//! - `Mode`: the host's committed mode as `WIDTHxHEIGHT`, empty if there's none.
+//! - `Fps`: frames received per second, averaged over a second. Changes aren't signalled.
+//! - `Brightness` (writable) and `MaxBrightness`: the configured backlight's, 0 without one.
+//!
+//! Owning the name on the system bus needs a D-Bus policy that allows it.
+
+use anyhow::Context;
+use gud_gadget::wait::Waker;
+use gud_gadget::{DisplayMode, Stats};
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tracing::warn;
+use zbus::blocking::connection;
+use zbus::fdo;
+
+use crate::backlight;
+
+pub const NAME: &str = "io.github.samcday.GudGadget";
+const PATH: &str = "/io/github/samcday/GudGadget";
+
+#[derive(Debug)]
+struct Status {
+    enabled: bool,
+    connected: bool,
+    mode: String,
+    fps: f64,
+}
+
+struct Display {
+    status: Arc<Mutex<Status>>,
+    backlight: Option<PathBuf>,
+    // Has the event loop pick up changes right away.
+    waker: Waker,
+}
+
+fn io_error(err: anyhow::Error) -> fdo::Error {
+    fdo::Error::IOError(format!("{:#}", err))
+}
+
+#[zbus::interface(name = "io.github.samcday.GudGadget1")]
+impl Display {
+    #[zbus(property)]
+    fn enabled(&self) -> bool {
+        self.status.lock().unwrap().enabled
+    }
+
+    #[zbus(property)]
+    fn set_enabled(&mut self, enabled: bool) {
+        self.status.lock().unwrap().enabled = enabled;
+        self.waker.wake();
+    }
+
+    #[zbus(property)]
+    fn connected(&self) -> bool {
+        self.status.lock().unwrap().connected
+    }
+
+    #[zbus(property)]
+    fn mode(&self) -> String {
+        self.status.lock().unwrap().mode.clone()
+    }
+
+    #[zbus(property(emits_changed_signal = "false"))]
+    fn fps(&self) -> f64 {
+        self.status.lock().unwrap().fps
+    }
+
+    #[zbus(property)]
+    fn brightness(&self) -> fdo::Result<u32> {
+        match &self.backlight {
+            Some(path) => backlight::brightness(path).map_err(io_error),
+            None => Ok(0),
+        }
+    }
+
+    #[zbus(property)]
+    fn set_brightness(&mut self, brightness: u32) -> fdo::Result<()> {
+        let path = self
+            .backlight
+            .as_ref()
+            .ok_or_else(|| fdo::Error::NotSupported("no backlight configured".to_string()))?;
+        backlight::set_brightness(path, brightness).map_err(io_error)
+    }
+
+    #[zbus(property)]
+    fn max_brightness(&self) -> fdo::Result<u32> {
+        match &self.backlight {
+            Some(path) => backlight::max_brightness(path).map_err(io_error),
+            None => Ok(0),
+        }
+    }
+}
+
+/// The running service. The event loop keeps its properties up to date.
+pub struct Service {
+    conn: zbus::blocking::Connection,
+    status: Arc<Mutex<Status>>,
+    // The frame count at `counted`, for the frame rate.
+    frames: u64,
+    counted: Instant,
+}
+
+impl Service {
+    /// Claims [`NAME`] on the system bus and serves the interface, from a thread of zbus' own.
+    /// `waker` is woken when `Enabled` is set.
+    pub fn start(backlight: Option<PathBuf>, waker: Waker) -> anyhow::Result<Self> {
+        let status = Arc::new(Mutex::new(Status {
+            enabled: true,
+            connected: false,
+            mode: String::new(),
+            fps: 0.0,
+        }));
+        let display = Display {
+            status: status.clone(),
+            backlight,
+            waker,
+        };
+        let conn = connection::Builder::system()
+            .and_then(|builder| builder.name(NAME))
+            .and_then(|builder| builder.serve_at(PATH, display))
+            .and_then(|builder| builder.build())
+            .with_context(|| format!("serve {} on the system bus", NAME))?;
+        Ok(Self {
+            conn,
+            status,
+            frames: 0,
+            counted: Instant::now(),
+        })
+    }
+
+    /// Whether the gadget should be bound, as last set through `Enabled`.
+    pub fn enabled(&self) -> bool {
+        self.status.lock().unwrap().enabled
+    }
+
+    /// Updates `Connected` and `Mode` from the host's committed mode, if any.
+    pub fn set_mode(&self, mode: Option<&DisplayMode>) {
+        let connected = mode.is_some();
+        let mode = mode
+            .map(|mode| format!("{}x{}", mode.hdisplay, mode.vdisplay))
+            .unwrap_or_default();
+        let (connected_changed, mode_changed) = {
+            let mut status = self.status.lock().unwrap();
+            (
+                std::mem::replace(&mut status.connected, connected) != connected,
+                std::mem::replace(&mut status.mode, mode.clone()) != mode,
+            )
+        };
+        if connected_changed {
+            self.signal(|display, ctxt| zbus::block_on(display.connected_changed(ctxt)));
+        }
+        if mode_changed {
+            self.signal(|display, ctxt| zbus::block_on(display.mode_changed(ctxt)));
+        }
+    }
+
+    /// Updates `Fps` from the endpoint's `stats` once a second. Call it on every pass of the
+    /// event loop.
+    pub fn tick(&mut self, stats: Stats) {
+        let elapsed = self.counted.elapsed();
+        if elapsed >= Duration::from_secs(1) {
+            let frames = stats.frames - self.frames;
+            self.status.lock().unwrap().fps = frames as f64 / elapsed.as_secs_f64();
+            self.frames = stats.frames;
+            self.counted = Instant::now();
+        }
+    }
+
+    // Emits a PropertiesChanged signal with `emit`.
+    fn signal(&self, emit: impl FnOnce(&Display, &zbus::SignalContext<'_>) -> zbus::Result<()>) {
+        let result = self
+            .conn
+            .object_server()
+            .interface::<_, Display>(PATH)
+            .and_then(|iface| emit(&iface.get(), iface.signal_context()));
+        if let Err(err) = result {
+            warn!("D-Bus signal failed: {:#}", err);
+        }
+    }
+}
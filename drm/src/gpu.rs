@@ -0,0 +1,412 @@
+//! Presents frames through GBM/EGL, letting the GPU do the format conversion, scaling and
+//! rotation instead of the CPU.
+
+use anyhow::{bail, Context};
+use drm::buffer::{Buffer, DrmFourcc};
+use drm::control::{connector, crtc, framebuffer, Device, Mode, PageFlipFlags, RawResourceHandle};
+use gbm::{AsRaw, BufferObject, BufferObjectFlags};
+use glow::HasContext;
+use gud_gadget::blit::Transform;
+use gud_gadget::protocol::*;
+use gud_gadget::Frame;
+use khronos_egl as egl;
+use tracing::debug;
+
+use crate::scanout::Flips;
+use crate::Card;
+
+// From EGL_KHR_platform_gbm.
+const EGL_PLATFORM_GBM_KHR: egl::Enum = 0x31D7;
+
+const VERTEX_SHADER: &str = r#"
+attribute vec2 position;
+attribute vec2 texcoord;
+varying vec2 v_texcoord;
+
+void main() {
+    v_texcoord = texcoord;
+    gl_Position = vec4(position, 0.0, 1.0);
+}
+"#;
+
+const FRAGMENT_SHADER: &str = r#"
+precision mediump float;
+uniform sampler2D tex;
+uniform bool swap_rb;
+varying vec2 v_texcoord;
+
+void main() {
+    vec4 color = texture2D(tex, v_texcoord);
+    gl_FragColor = vec4(swap_rb ? color.bgr : color.rgb, 1.0);
+}
+"#;
+
+// A locked GBM buffer as seen by DRM.
+struct BoBuffer {
+    size: (u32, u32),
+    pitch: u32,
+    handle: drm::buffer::Handle,
+}
+
+impl Buffer for BoBuffer {
+    fn size(&self) -> (u32, u32) {
+        self.size
+    }
+
+    fn format(&self) -> DrmFourcc {
+        DrmFourcc::Xrgb8888
+    }
+
+    fn pitch(&self) -> u32 {
+        self.pitch
+    }
+
+    fn handle(&self) -> drm::buffer::Handle {
+        self.handle
+    }
+}
+
+pub struct Renderer {
+    gbm: gbm::Device<Card>,
+    flips: Flips,
+    gbm_surface: gbm::Surface<framebuffer::Handle>,
+    egl: egl::DynamicInstance<egl::EGL1_5>,
+    display: egl::Display,
+    surface: egl::Surface,
+    gl: glow::Context,
+    texture: glow::Texture,
+    swap_rb: Option<glow::UniformLocation>,
+    // Size and format of the texture, matching the host's committed state.
+    texture_state: Option<(u32, u32, u8)>,
+    // Whether the texture's been updated since the last frame was presented.
+    uploaded: bool,
+    crtc: crtc::Handle,
+    connector: connector::Handle,
+    mode: Mode,
+    // The buffer being scanned out, kept locked until the next flip completes.
+    scanout: Option<BufferObject<framebuffer::Handle>>,
+}
+
+impl Renderer {
+    /// Sets up rendering to `crtc` in the given mode, turning or flipping frames with
+    /// `transform`.
+    pub fn new(
+        card: Card,
+        flips: Flips,
+        crtc: crtc::Handle,
+        connector: connector::Handle,
+        mode: Mode,
+        transform: Transform,
+    ) -> anyhow::Result<Self> {
+        let (width, height) = mode.size();
+        let gbm = gbm::Device::new(card).context("create GBM device")?;
+        let gbm_surface = gbm
+            .create_surface::<framebuffer::Handle>(
+                width.into(),
+                height.into(),
+                gbm::Format::Xrgb8888,
+                BufferObjectFlags::SCANOUT | BufferObjectFlags::RENDERING,
+            )
+            .context("create GBM surface")?;
+
+        let egl = unsafe { egl::DynamicInstance::<egl::EGL1_5>::load_required() }
+            .context("load libEGL")?;
+        let display = unsafe {
+            egl.get_platform_display(
+                EGL_PLATFORM_GBM_KHR,
+                gbm.as_raw() as *mut _,
+                &[egl::ATTRIB_NONE],
+            )
+        }
+        .context("get EGL display")?;
+        egl.initialize(display).context("initialize EGL")?;
+        egl.bind_api(egl::OPENGL_ES_API).context("bind GLES API")?;
+
+        let mut configs = Vec::with_capacity(64);
+        egl.choose_config(
+            display,
+            &[
+                egl::SURFACE_TYPE,
+                egl::WINDOW_BIT,
+                egl::RENDERABLE_TYPE,
+                egl::OPENGL_ES2_BIT,
+                egl::RED_SIZE,
+                8,
+                egl::GREEN_SIZE,
+                8,
+                egl::BLUE_SIZE,
+                8,
+                egl::NONE,
+            ],
+            &mut configs,
+        )
+        .context("choose EGL config")?;
+        // The config has to match the GBM surface's format.
+        let config = configs
+            .into_iter()
+            .find(|config| {
+                egl.get_config_attrib(display, *config, egl::NATIVE_VISUAL_ID)
+                    .is_ok_and(|id| id as u32 == DrmFourcc::Xrgb8888 as u32)
+            })
+            .context("no XRGB8888 EGL config")?;
+
+        let context = egl
+            .create_context(
+                display,
+                config,
+                None,
+                &[egl::CONTEXT_CLIENT_VERSION, 2, egl::NONE],
+            )
+            .context("create EGL context")?;
+        let surface = unsafe {
+            egl.create_platform_window_surface(
+                display,
+                config,
+                gbm_surface.as_raw() as *mut _,
+                &[egl::ATTRIB_NONE],
+            )
+        }
+        .context("create EGL surface")?;
+        egl.make_current(display, Some(surface), Some(surface), Some(context))
+            .context("make EGL context current")?;
+
+        let gl = unsafe {
+            glow::Context::from_loader_function(|name| {
+                egl.get_proc_address(name)
+                    .map_or(std::ptr::null(), |f| f as *const _)
+            })
+        };
+
+        let (texture, swap_rb) = unsafe { Self::init_gl(&gl, transform) }?;
+        unsafe { gl.viewport(0, 0, width.into(), height.into()) };
+
+        Ok(Self {
+            gbm,
+            flips,
+            gbm_surface,
+            egl,
+            display,
+            surface,
+            gl,
+            texture,
+            swap_rb,
+            texture_state: None,
+            uploaded: false,
+            crtc,
+            connector,
+            mode,
+            scanout: None,
+        })
+    }
+
+    unsafe fn init_gl(
+        gl: &glow::Context,
+        transform: Transform,
+    ) -> anyhow::Result<(glow::Texture, Option<glow::UniformLocation>)> {
+        let program = gl.create_program().map_err(anyhow::Error::msg)?;
+        for (ty, source) in [
+            (glow::VERTEX_SHADER, VERTEX_SHADER),
+            (glow::FRAGMENT_SHADER, FRAGMENT_SHADER),
+        ] {
+            let shader = gl.create_shader(ty).map_err(anyhow::Error::msg)?;
+            gl.shader_source(shader, source);
+            gl.compile_shader(shader);
+            if !gl.get_shader_compile_status(shader) {
+                bail!("compile shader: {}", gl.get_shader_info_log(shader));
+            }
+            gl.attach_shader(program, shader);
+        }
+        gl.bind_attrib_location(program, 0, "position");
+        gl.bind_attrib_location(program, 1, "texcoord");
+        gl.link_program(program);
+        if !gl.get_program_link_status(program) {
+            bail!("link program: {}", gl.get_program_info_log(program));
+        }
+        gl.use_program(Some(program));
+
+        // Texture coordinates of the corners, counterclockwise from the bottom left. Rotating
+        // the frame clockwise shifts them around by a corner per quarter turn, flipping it first
+        // mirrors them.
+        let corners = [[0.0, 1.0], [1.0, 1.0], [1.0, 0.0], [0.0, 0.0]];
+        let texcoord = |corner: usize| {
+            let [u, v] = corners[(corner + transform.quarter_turns as usize) % 4];
+            match transform.flip {
+                true => [1.0 - u, v],
+                false => [u, v],
+            }
+        };
+        // A triangle strip covering the viewport: bottom left, bottom right, top left, top right.
+        let vertices: Vec<f32> = [
+            (-1.0, -1.0, 0),
+            (1.0, -1.0, 1),
+            (-1.0, 1.0, 3),
+            (1.0, 1.0, 2),
+        ]
+        .into_iter()
+        .flat_map(|(x, y, corner)| {
+            let [u, v] = texcoord(corner);
+            [x, y, u, v]
+        })
+        .collect();
+        let vertex_bytes: Vec<u8> = vertices.iter().flat_map(|f| f.to_ne_bytes()).collect();
+
+        let buffer = gl.create_buffer().map_err(anyhow::Error::msg)?;
+        gl.bind_buffer(glow::ARRAY_BUFFER, Some(buffer));
+        gl.buffer_data_u8_slice(glow::ARRAY_BUFFER, &vertex_bytes, glow::STATIC_DRAW);
+        gl.vertex_attrib_pointer_f32(0, 2, glow::FLOAT, false, 16, 0);
+        gl.enable_vertex_attrib_array(0);
+        gl.vertex_attrib_pointer_f32(1, 2, glow::FLOAT, false, 16, 8);
+        gl.enable_vertex_attrib_array(1);
+
+        let texture = gl.create_texture().map_err(anyhow::Error::msg)?;
+        gl.bind_texture(glow::TEXTURE_2D, Some(texture));
+        // Scaling to the panel is done by the texture sampler.
+        for (param, value) in [
+            (glow::TEXTURE_MIN_FILTER, glow::LINEAR),
+            (glow::TEXTURE_MAG_FILTER, glow::LINEAR),
+            (glow::TEXTURE_WRAP_S, glow::CLAMP_TO_EDGE),
+            (glow::TEXTURE_WRAP_T, glow::CLAMP_TO_EDGE),
+        ] {
+            gl.tex_parameter_i32(glow::TEXTURE_2D, param, value as i32);
+        }
+        gl.pixel_store_i32(glow::UNPACK_ALIGNMENT, 1);
+
+        Ok((texture, gl.get_uniform_location(program, "swap_rb")))
+    }
+
+    /// Uploads the frame's damage rect and presents the result, waiting for the flip.
+    pub fn draw(&mut self, frame: &Frame, mode: &DisplayMode) -> anyhow::Result<()> {
+        self.upload(frame, mode)?;
+        self.present()
+    }
+
+    /// Uploads the frame's damage rect, leaving drawing it to [`present`](Self::present).
+    pub fn upload(&mut self, frame: &Frame, mode: &DisplayMode) -> anyhow::Result<()> {
+        // GL format and type of the host's pixel format, and whether red and blue are swapped
+        // relative to it. GL reads little-endian RGB888/XRGB8888 bytes as BGR(X).
+        let (format, ty, swap_rb) = match frame.format {
+            GUD_PIXEL_FORMAT_XRGB8888 | GUD_PIXEL_FORMAT_ARGB8888 => {
+                (glow::RGBA, glow::UNSIGNED_BYTE, true)
+            }
+            GUD_PIXEL_FORMAT_RGB888 => (glow::RGB, glow::UNSIGNED_BYTE, true),
+            GUD_PIXEL_FORMAT_RGB565 => (glow::RGB, glow::UNSIGNED_SHORT_5_6_5, false),
+            format => bail!("unsupported pixel format {:#x}", format),
+        };
+        let info = &frame.info;
+
+        unsafe {
+            let state = (mode.hdisplay as u32, mode.vdisplay as u32, frame.format);
+            if self.texture_state != Some(state) {
+                debug!("allocating {}x{} texture", state.0, state.1);
+                self.gl.tex_image_2d(
+                    glow::TEXTURE_2D,
+                    0,
+                    format as i32,
+                    state.0 as i32,
+                    state.1 as i32,
+                    0,
+                    format,
+                    ty,
+                    None,
+                );
+                self.gl.uniform_1_i32(self.swap_rb.as_ref(), swap_rb as i32);
+                self.texture_state = Some(state);
+            }
+
+            self.gl.bind_texture(glow::TEXTURE_2D, Some(self.texture));
+            self.gl.tex_sub_image_2d(
+                glow::TEXTURE_2D,
+                0,
+                info.x as i32,
+                info.y as i32,
+                info.width as i32,
+                info.height as i32,
+                format,
+                ty,
+                glow::PixelUnpackData::Slice(&frame.data),
+            );
+        }
+        self.uploaded = true;
+        Ok(())
+    }
+
+    /// Draws and flips to the texture if it's been uploaded to since the last flip, waiting for
+    /// the flip.
+    pub fn present(&mut self) -> anyhow::Result<()> {
+        if !std::mem::take(&mut self.uploaded) {
+            return Ok(());
+        }
+        unsafe { self.gl.draw_arrays(glow::TRIANGLE_STRIP, 0, 4) };
+        self.egl
+            .swap_buffers(self.display, self.surface)
+            .context("swap buffers")?;
+
+        self.flip()
+    }
+
+    pub fn crtc(&self) -> crtc::Handle {
+        self.crtc
+    }
+
+    /// Switches the display on or off with the connector's DPMS property.
+    pub fn set_active(&mut self, active: bool) -> anyhow::Result<()> {
+        let props = self
+            .gbm
+            .get_properties(self.connector)
+            .context("get connector properties")?
+            .as_hashmap(&*self.gbm)
+            .context("get connector property info")?;
+        let dpms = props
+            .get("DPMS")
+            .context("connector has no DPMS property")?;
+        // DRM_MODE_DPMS_ON and DRM_MODE_DPMS_OFF.
+        let value = if active { 0 } else { 3 };
+        self.gbm
+            .set_property(self.connector, dpms.handle(), value)
+            .context("set DPMS")
+    }
+
+    fn flip(&mut self) -> anyhow::Result<()> {
+        let mut bo =
+            unsafe { self.gbm_surface.lock_front_buffer() }.context("lock front buffer")?;
+        let fb = match bo.userdata()? {
+            Some(fb) => *fb,
+            None => {
+                let handle = RawResourceHandle::new(unsafe { bo.handle()?.u32_ })
+                    .context("null buffer handle")?;
+                let buffer = BoBuffer {
+                    size: (bo.width()?, bo.height()?),
+                    pitch: bo.stride()?,
+                    handle: handle.into(),
+                };
+                let fb = self
+                    .gbm
+                    .add_framebuffer(&buffer, 24, 32)
+                    .context("add framebuffer")?;
+                bo.set_userdata(fb)?;
+                fb
+            }
+        };
+
+        if self.scanout.is_none() {
+            self.gbm
+                .set_crtc(
+                    self.crtc,
+                    Some(fb),
+                    (0, 0),
+                    &[self.connector],
+                    Some(self.mode),
+                )
+                .context("set CRTC")?;
+        } else {
+            self.gbm
+                .page_flip(self.crtc, fb, PageFlipFlags::EVENT, None)
+                .context("page flip")?;
+            self.flips.submitted(self.crtc);
+            // Wait for the flip so the previous buffer can be released back to the surface.
+            self.flips.wait(&self.gbm, self.crtc)?;
+        }
+        self.scanout = Some(bo);
+        Ok(())
+    }
+}
@@ -0,0 +1,100 @@
+//! DRM hotplug notifications, from the kernel's uevent netlink socket.
+
+use drm::control::{connector, Device};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+
+use crate::Card;
+
+// The multicast group the kernel sends uevents to (udev rebroadcasts on others).
+const KERNEL_UEVENT_GROUP: u32 = 1;
+
+pub struct Uevents(OwnedFd);
+
+impl Uevents {
+    pub fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = KERNEL_UEVENT_GROUP;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(fd))
+    }
+
+    /// Drains pending uevents without blocking, returning whether any of them was a DRM hotplug.
+    pub fn hotplug(&self) -> io::Result<bool> {
+        let mut buf = [0u8; 8192];
+        let mut hotplug = false;
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    self.0.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                return match err.kind() {
+                    io::ErrorKind::WouldBlock => Ok(hotplug),
+                    _ => Err(err),
+                };
+            }
+            hotplug |= is_drm_hotplug(&buf[..len as usize]);
+        }
+    }
+}
+
+impl AsRawFd for Uevents {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// Whether a uevent (a header followed by NUL separated `KEY=value` pairs) is a DRM hotplug.
+fn is_drm_hotplug(uevent: &[u8]) -> bool {
+    let (mut drm, mut hotplug) = (false, false);
+    for field in uevent.split(|&b| b == 0) {
+        match field {
+            b"SUBSYSTEM=drm" => drm = true,
+            b"HOTPLUG=1" => hotplug = true,
+            _ => {}
+        }
+    }
+    drm && hotplug
+}
+
+/// Reads the EDID of the display attached to `connector`, empty if there's none.
+pub fn edid(card: &Card, connector: connector::Handle) -> io::Result<Vec<u8>> {
+    let props = card.get_properties(connector)?;
+    for (&prop, &value) in props.iter() {
+        if card.get_property(prop)?.name().to_bytes() == b"EDID" {
+            return match value {
+                0 => Ok(Vec::new()),
+                blob => card.get_property_blob(blob),
+            };
+        }
+    }
+    Ok(Vec::new())
+}
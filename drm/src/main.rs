@@ -1,13 +1,43 @@
-use drm::buffer::Buffer;
-use drm::control::Device;
-use gud_gadget::{DisplayMode, Event};
-use std::env::args;
+use anyhow::Context;
+use clap::Parser;
+use drm::control::{Device, Mode};
+use gud_gadget::blit::{blit_transformed, Filter, Lut, Scale, Swizzle, Transform};
+use gud_gadget::protocol::*;
+use gud_gadget::trace::Tracer;
+use gud_gadget::udc::{self, UdcEvent, UdcMonitor};
+use gud_gadget::wait::Waiter;
+use gud_gadget::{ConnectorHandle, DisplayMode, Event, Frame, Function, ModeSet};
+use std::os::fd::AsRawFd;
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
 use tracing_subscriber::{fmt, prelude::*, EnvFilter};
 use usb_gadget::function::custom::{Custom, Interface};
-use usb_gadget::{default_udc, Class, Config, Gadget, Strings};
+use usb_gadget::{default_udc, Class, Config, Gadget, Id, Strings};
+
+mod backlight;
+mod cli;
+mod config;
+#[cfg(feature = "dbus")]
+mod dbus;
+#[cfg(feature = "gpu")]
+mod gpu;
+mod hotplug;
+mod metrics;
+mod scanout;
+mod splash;
+#[cfg(feature = "touch")]
+mod touch;
+
+use backlight::Dimmer;
+use scanout::{Flips, Scanout, VsyncPacer};
+use splash::Splash;
+
+// How often a backlight fade is stepped, about once a frame.
+const FADE_STEP: Duration = Duration::from_millis(16);
+// Seconds to wait on shutdown for the host to poll the connectors, which it does every 10.
+const DEFAULT_SHUTDOWN_TIMEOUT: u64 = 12;
 
 #[derive(Debug)]
 /// A simple wrapper for a device node.
@@ -28,7 +58,7 @@ impl Device for Card {}
 
 /// Simple helper methods for opening a `Card`.
 impl Card {
-    pub fn open(path: &str) -> Self {
+    pub fn open(path: impl AsRef<std::path::Path>) -> Self {
         let mut options = std::fs::OpenOptions::new();
         options.read(true);
         options.write(true);
@@ -38,65 +68,234 @@ impl Card {
     pub fn open_global() -> Self {
         Self::open("/dev/dri/card0")
     }
+
+    pub fn try_clone(&self) -> std::io::Result<Self> {
+        Ok(Card(self.0.try_clone()?))
+    }
 }
 
-fn main() -> anyhow::Result<()> {
-    tracing_subscriber::registry()
-        .with(fmt::layer())
-        .with(EnvFilter::from_default_env())
-        .init();
-
-    let card_path = args()
-        .skip(1)
-        .next()
-        .expect("specify full path to /dev/dri/cardN as program argument");
-    let card = Card::open(&card_path);
-    let udc = default_udc().expect("no UDC found");
-
-    let resources = card.resource_handles().expect("load drm resources failed");
-
-    let connector = resources
-        .connectors()
-        .iter()
-        .map(|h| {
-            card.get_connector(*h, false)
-                .expect("get drm connector failed")
-        })
-        .find(|c| c.state() == drm::control::connector::State::Connected)
-        .expect("no connected connectors found");
-
-    let crtc = resources
-        .crtcs()
-        .iter()
-        .flat_map(|crtc| card.get_crtc(*crtc))
-        .next()
-        .expect("no crtc found");
-
-    let mut min_width = u32::MAX;
-    let mut min_height = u32::MAX;
-    let mut max_width = 0;
-    let mut max_height = 0;
-    for mode in connector.modes() {
-        let (width, height) = mode.size();
-        let width = width as u32;
-        let height = height as u32;
-        if width < min_width {
-            min_width = width
+/// Where received frames end up.
+enum Output {
+    /// Blitted by the CPU into double-buffered dumb buffers.
+    Dumb(Scanout),
+    #[cfg(feature = "gpu")]
+    Gpu(Box<gpu::Renderer>),
+}
+
+impl Output {
+    /// Draws a whole frame in `mode`, transformed and scaled to the panel.
+    fn show(
+        &mut self,
+        frame: &Frame,
+        mode: &DisplayMode,
+        fb_format: PixelFormat,
+        scale: Scale,
+        transform: Transform,
+    ) -> anyhow::Result<()> {
+        match self {
+            Output::Dumb(scanout) => scanout.draw(0..scale.height as usize, |fb, pitch| {
+                let format = PixelFormat::from_u8(frame.format).context("unknown format")?;
+                let swizzle = Swizzle::NONE;
+                Ok(blit_transformed(
+                    &frame.info,
+                    mode,
+                    format,
+                    &frame.data,
+                    fb,
+                    pitch,
+                    fb_format,
+                    swizzle,
+                    scale,
+                    transform,
+                )?)
+            }),
+            #[cfg(feature = "gpu")]
+            Output::Gpu(renderer) => renderer.draw(frame, mode),
         }
-        if width > max_width {
-            max_width = width
+    }
+
+    /// Flips to the frames drawn since the last flip, if any.
+    fn present(&mut self) -> anyhow::Result<()> {
+        match self {
+            Output::Dumb(scanout) => scanout.present(),
+            #[cfg(feature = "gpu")]
+            Output::Gpu(renderer) => renderer.present(),
         }
-        if height < min_height {
-            min_height = height
+    }
+
+    fn crtc(&self) -> drm::control::crtc::Handle {
+        match self {
+            Output::Dumb(scanout) => scanout.crtc(),
+            #[cfg(feature = "gpu")]
+            Output::Gpu(renderer) => renderer.crtc(),
         }
-        if height > max_height {
-            max_height = height
+    }
+
+    fn set_active(&mut self, active: bool) -> anyhow::Result<()> {
+        match self {
+            Output::Dumb(scanout) => scanout.set_active(active),
+            #[cfg(feature = "gpu")]
+            Output::Gpu(renderer) => renderer.set_active(active),
         }
     }
+}
+
+/// A DRM connector driven as one of the function's GUD connectors.
+struct Head {
+    connector: drm::control::connector::Handle,
+    // Connector modes offered to the host, updated on hotplug.
+    modes: Vec<Mode>,
+    // The mode that's scanned out, whichever of `modes` the host picks is scaled to it.
+    mode: Mode,
+    scale: Scale,
+    output: Output,
+    status: ConnectorHandle,
+}
+
+/// The smallest and largest width and height among the heads' modes as the host sees them,
+/// counting a head's scanout mode if it has none.
+fn mode_bounds(heads: &[Head], transform: Transform) -> (u32, u32, u32, u32) {
+    let sizes = || {
+        heads.iter().flat_map(|head| {
+            head.modes
+                .iter()
+                .chain(head.modes.is_empty().then_some(&head.mode))
+                .map(|mode| {
+                    let (width, height) = mode.size();
+                    transform.apply_size(width.into(), height.into())
+                })
+        })
+    };
+    let widths = || sizes().map(|(width, _)| width);
+    let heights = || sizes().map(|(_, height)| height);
+    (
+        widths().min().unwrap(),
+        heights().min().unwrap(),
+        widths().max().unwrap(),
+        heights().max().unwrap(),
+    )
+}
+
+/// A list from the command line, or the config's if there's none. They replace rather than add
+/// to each other.
+fn or_config<'a, T>(args: &'a [T], config: &'a [T]) -> &'a [T] {
+    match args.is_empty() {
+        true => config,
+        false => args,
+    }
+}
+
+/// A connector mode as offered to the host, turned the way the panel is seen.
+fn display_mode(mode: &Mode, transform: Transform) -> DisplayMode {
+    transform.apply_mode(&DisplayMode::from(*mode))
+}
+
+fn connector_status(state: drm::control::connector::State) -> ConnectorStatus {
+    match state {
+        drm::control::connector::State::Connected => ConnectorStatus::Connected,
+        drm::control::connector::State::Disconnected => ConnectorStatus::Disconnected,
+        drm::control::connector::State::Unknown => ConnectorStatus::Unknown,
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    let registry = tracing_subscriber::registry()
+        .with(fmt::layer())
+        .with(EnvFilter::from_default_env());
+    // Per-frame spans are at trace level, e.g. RUST_LOG=gud_gadget=trace shows them in Tracy.
+    #[cfg(feature = "tracy")]
+    let registry = registry.with(tracing_tracy::TracyLayer::default());
+    registry.init();
+
+    let args = cli::Args::parse();
+    let config = match &args.config {
+        Some(path) => config::Config::load(path)?,
+        None => config::Config::default(),
+    };
+    let display = &config.display;
+    let format = args
+        .format
+        .or(display.format)
+        .unwrap_or(cli::Format::Rgb565);
+    let rotate = args.rotate.or(display.rotate).unwrap_or(0);
+    let transform = Transform {
+        flip: args.flip || display.flip,
+        ..Transform::rotate(rotate).with_context(|| format!("unsupported rotation {}", rotate))?
+    };
+    if args.gpu && !cfg!(feature = "gpu") {
+        anyhow::bail!("--gpu needs the gpu feature");
+    }
+    let serve_dbus = args.dbus || display.dbus;
+    if serve_dbus && !cfg!(feature = "dbus") {
+        anyhow::bail!("--dbus needs the dbus feature");
+    }
+    let touchscreen = args.touch.as_ref().or(display.touch.as_ref());
+    if touchscreen.is_some() && !cfg!(feature = "touch") {
+        anyhow::bail!("--touch needs the touch feature");
+    }
+    let card = Card::open(&args.card);
+    if args.list {
+        return cli::list(&card);
+    }
+
+    let connectors = cli::select_connectors(&card, or_config(&args.connector, &display.connector))?;
+    let crtcs = or_config(&args.crtc, &display.crtc);
+    let mode_specs = or_config(&args.mode, &display.mode);
+    let backlight = args.backlight.as_ref().or(display.backlight.as_ref());
+    if let Some(path) = backlight {
+        if let Err(err) = backlight::power_on(path) {
+            warn!("backlight {} failed: {:#}", path.display(), err);
+        }
+    }
+    let ramp = Duration::from_millis(args.backlight_ramp.or(display.backlight_ramp).unwrap_or(0));
+    let mut dimmer = backlight.and_then(|path| {
+        Dimmer::new(path.clone(), ramp)
+            .inspect_err(|err| warn!("backlight {} failed: {:#}", path.display(), err))
+            .ok()
+    });
+    let wait_udc = args.wait_udc || config.usb.wait_udc;
+    let udc = match wait_udc {
+        true => udc::wait_for_udc(None)?,
+        false => default_udc().expect("no UDC found"),
+    };
+
+    let advertised = |connector: &drm::control::connector::Info| -> Vec<Mode> {
+        connector
+            .modes()
+            .iter()
+            .copied()
+            .filter(|mode| display.advertises(mode))
+            .collect()
+    };
 
     usb_gadget::remove_all().expect("UDC init failed");
 
-    let (mut gud_data, gud_data_ep) = gud_gadget::PixelDataEndpoint::new();
+    let (mut gud_data, gud_data_ep) = match args.max_burst.or(config.usb.max_burst) {
+        Some(max_burst) => gud_gadget::PixelDataEndpoint::with_max_burst(max_burst),
+        None => gud_gadget::PixelDataEndpoint::new(),
+    };
+    gud_data.set_chunk_size(args.chunk_size.or(config.usb.chunk_size));
+    // XRGB8888 gives the host better quality and compression, it's converted to the panel's format.
+    gud_data.set_convert_to(Some(format.pixel_format()));
+    gud_data.set_transform(transform);
+    gud_data.set_lut(args.gamma.or(display.gamma).map(Lut::gamma));
+    gud_data.set_max_fps(args.max_fps.or(display.max_fps));
+    gud_data.set_debug(gud_gadget::debug::DebugOptions {
+        validate: args.validate,
+        outline: args.show_damage,
+    });
+    gud_data.set_throttle(gud_gadget::debug::Throttle {
+        delay: Duration::from_millis(args.read_delay.unwrap_or(0)),
+        bandwidth: args.max_bandwidth,
+    });
+    let tracer = match &args.trace {
+        Some(path) => Some(
+            Tracer::create(path, args.trace_checksums)
+                .with_context(|| format!("create trace {}", path.display()))?,
+        ),
+        None => None,
+    };
+    gud_data.set_tracer(tracer.clone());
     let (mut gud, gud_handle) = Custom::builder()
         .with_interface(
             Interface::new(Class::vendor_specific(Class::VENDOR_SPECIFIC, 0), "GUD")
@@ -104,106 +303,506 @@ fn main() -> anyhow::Result<()> {
         )
         .build();
 
-    let _reg = Gadget::new(
+    let gadget_config = Config::new("gud").with_function(gud_handle);
+    // Touches go back to the host through a HID function in the same configuration.
+    #[cfg(feature = "touch")]
+    let (gadget_config, touch_hid) = match touchscreen {
+        Some(_) => {
+            let (hid, handle) = gud_gadget::touch::hid_builder().build();
+            (gadget_config.with_function(handle), Some(hid))
+        }
+        None => (gadget_config, None),
+    };
+
+    let usb = &config.usb;
+    let mut reg = Gadget::new(
         Class::interface_specific(),
-        gud_gadget::OPENMOKO_GUD_ID,
-        Strings::new("The Internet", "Generic USB Display", ""),
+        Id::new(usb.vendor_id, usb.product_id),
+        Strings::new(&usb.manufacturer, &usb.product, &usb.serial),
     )
-    .with_config(Config::new("gud").with_function(gud_handle))
+    .with_config(gadget_config)
     .bind(&udc)
     .expect("UDC binding failed");
 
+    #[cfg(feature = "touch")]
+    if let (Some(path), Some(hid)) = (touchscreen, touch_hid) {
+        touch::forward(hid, path, transform)?;
+    }
+
     let running = Arc::new(AtomicBool::new(true));
+    // The loop sleeps until the host sends a request, so Ctrl-C has to wake it.
+    let mut waiter = Waiter::new().context("create waiter")?;
+    waiter.watch(gud.fd().context("get ep0 fd")?);
 
     let r = running.clone();
+    let waker = waiter.waker();
     ctrlc::set_handler(move || {
         r.store(false, Ordering::SeqCst);
+        waker.wake();
     })
     .expect("cleanup handler registration failed");
 
-    let mode = connector.modes().first().unwrap();
+    let mut function = Function::new();
+    function.set_compression(display.compression());
+    function.set_tracer(tracer);
 
-    println!("picked mode {:?}", mode);
+    // Each DRM connector is a GUD connector of its own, in the same order.
+    let flips = Flips::default();
+    let mut heads: Vec<Head> = Vec::with_capacity(connectors.len());
+    for (i, connector) in connectors.iter().enumerate() {
+        let taken: Vec<_> = heads.iter().map(|head| head.output.crtc()).collect();
+        let crtc = cli::select_crtc(&card, connector, crtcs.get(i).copied(), &taken)?;
+        let mode = cli::select_mode(connector, mode_specs.get(i).copied())?;
+        println!(
+            "picked connector {} mode {:?}",
+            cli::connector_name(connector),
+            mode
+        );
 
-    let (width, height) = mode.size();
-    let mut db = card
-        // .create_dumb_buffer((width.into(), height.into()), drm::buffer::DrmFourcc::Xrgb8888, 32)
-        .create_dumb_buffer(
-            (width.into(), height.into()),
-            drm::buffer::DrmFourcc::Rgb565,
-            16,
-        )
-        .expect("Could not create dumb buffer");
-
-    let fb = card
-        .add_framebuffer(&db, 16, 16)
-        .expect("Could not create FB");
-    card.set_crtc(
-        crtc.handle(),
-        Some(fb),
-        (0, 0),
-        &[connector.handle()],
-        Some(*mode),
-    )
-    .expect("Could not set CRTC");
+        let (width, height) = mode.size();
+        let scale = Scale {
+            width: width.into(),
+            height: height.into(),
+            filter: Filter::Bilinear,
+        };
+        let output = match args.gpu {
+            false => Output::Dumb(Scanout::new(
+                card.try_clone()?,
+                flips.clone(),
+                crtc,
+                connector.handle(),
+                mode,
+                format.fourcc(),
+                format.bpp(),
+            )?),
+            #[cfg(feature = "gpu")]
+            true => Output::Gpu(Box::new(gpu::Renderer::new(
+                card.try_clone()?,
+                flips.clone(),
+                crtc,
+                connector.handle(),
+                mode,
+                transform,
+            )?)),
+            #[cfg(not(feature = "gpu"))]
+            true => unreachable!(),
+        };
 
-    let pitch = db.pitch();
+        let index = match i {
+            0 => 0,
+            _ => function.add_connector(),
+        };
+        let connector_type = display
+            .connector_type
+            .unwrap_or_else(|| config::ConnectorType::of(connector.interface()));
+        function.set_connector_type(index, connector_type.into());
+        // The connector's state is mirrored below, have the host poll it to pick up hotplugs.
+        // It drops interlaced and doublescan modes unless the connector says it takes them.
+        let modes = advertised(connector);
+        let mut flags = GUD_CONNECTOR_FLAGS_POLL_STATUS;
+        for mode in &modes {
+            let mode_flags = mode.flags();
+            if mode_flags.contains(drm::control::ModeFlags::INTERLACE) {
+                flags |= GUD_CONNECTOR_FLAGS_INTERLACE;
+            }
+            if mode_flags.contains(drm::control::ModeFlags::DBLSCAN) {
+                flags |= GUD_CONNECTOR_FLAGS_DOUBLESCAN;
+            }
+        }
+        function.set_connector_flags(index, flags);
+        let status = function.connector(index).unwrap();
+        status.set_status(connector_status(connector.state()));
+        match hotplug::edid(&card, connector.handle()) {
+            Ok(edid) => status.set_edid(edid),
+            Err(err) => warn!("read EDID failed: {:#}", err),
+        }
 
-    let mut mapping = card
-        .map_dumb_buffer(&mut db)
-        .expect("map_dumb_buffer failed");
+        heads.push(Head {
+            connector: connector.handle(),
+            modes,
+            mode,
+            scale,
+            output,
+            status,
+        });
+    }
 
-    while running.load(Ordering::Relaxed) {
-        let event = gud
-            .event_timeout(Duration::from_millis(100))
-            .expect("read GUD event");
-        if event.is_none() {
-            continue;
+    // The host's brightness slider is for the backlight of the first connector, the panel.
+    if let Some(dimmer) = &dimmer {
+        function.set_connector_properties(
+            0,
+            Properties::new().with(Property::backlight_brightness(dimmer.percent())),
+        );
+    }
+
+    if args.vsync || display.vsync {
+        let crtcs = heads.iter().map(|head| head.output.crtc()).collect();
+        let pacer = VsyncPacer::new(card.try_clone()?, flips.clone(), crtcs);
+        function.set_frame_pacer(Some(Box::new(pacer)));
+    }
+
+    let splash = match args.splash.as_deref().or(display.splash.as_deref()) {
+        Some(splash) => Splash::parse(splash)?,
+        None => Splash::default(),
+    };
+    let disconnected = args
+        .disconnected
+        .as_deref()
+        .or(display.disconnected.as_deref())
+        .map(Splash::parse)
+        .transpose()?;
+    let show = |head: &mut Head, splash: &Splash| {
+        let (frame, mode) = splash.frame();
+        if let Err(err) =
+            head.output
+                .show(&frame, &mode, format.pixel_format(), head.scale, transform)
+        {
+            warn!("showing splash failed: {:#}", err);
         }
-        let event = event.unwrap();
+    };
+    let show_splash = |head: &mut Head| show(head, &splash);
+    heads.iter_mut().for_each(show_splash);
+    // Blanks the panels while the host is suspended, or gone with --blank-on-disconnect, to save
+    // power.
+    let set_power = |heads: &mut [Head], on: bool| {
+        for head in heads {
+            if let Err(err) = head.output.set_active(on) {
+                warn!("switching display on={} failed: {:#}", on, err);
+            }
+        }
+        if let Some(path) = backlight {
+            if let Err(err) = backlight::set_power(path, on) {
+                warn!("backlight {} failed: {:#}", path.display(), err);
+            }
+        }
+    };
+    let blank_on_disconnect = args.blank_on_disconnect || display.blank_on_disconnect;
+    if blank_on_disconnect {
+        // No host's connected yet.
+        set_power(&mut heads, false);
+    }
+
+    let uevents = hotplug::Uevents::open()
+        .inspect_err(|err| warn!("can't listen for hotplug, polling instead: {:#}", err))
+        .ok();
+    let mut connector_checked = Instant::now();
+    // Set when the host asks for the connectors to be probed right away.
+    let mut force_detect = false;
+    // The head the host last sent a frame to.
+    let mut driven = None;
 
-        if let Ok(Some(gud_event)) = gud_gadget::event(event) {
-            match gud_event {
-                Event::GetDescriptor(req) => {
-                    req.send_descriptor(min_width, min_height, max_width, max_height)
-                        .expect("failed to send descriptor");
+    let state_file = args.state_file.as_ref().or(display.state_file.as_ref());
+    if let Some(path) = state_file {
+        if let Err(err) = function.load_state(path) {
+            warn!("restoring state failed: {:#}", err);
+        }
+    }
+    // Pick up where the host left off, it's likely to come back to the same head.
+    if let Some(state) = function.last_state() {
+        let connector = usize::from(state.connector);
+        if let Some(head) = heads.get(connector) {
+            debug!(
+                "restored {}x{} on connector {}",
+                state.mode.hdisplay, state.mode.vdisplay, connector
+            );
+            gud_data.set_scale(Some(head.scale));
+            driven = Some(connector);
+        }
+    }
+    let mut saved_state = function.last_state().cloned();
+
+    // The gadget's bound and set up, the host can use it from here on.
+    #[cfg(feature = "systemd")]
+    let mut notifier = gud_gadget::systemd::Notifier::new();
+    #[cfg(feature = "systemd")]
+    notifier.ready();
+
+    let mut exporter = args
+        .metrics_file
+        .clone()
+        .or_else(|| display.metrics_file.clone())
+        .map(metrics::Exporter::new);
+
+    #[cfg(feature = "dbus")]
+    let mut service = match serve_dbus {
+        true => Some(dbus::Service::start(backlight.cloned(), waiter.waker())?),
+        false => None,
+    };
+    // Unbinding the gadget is how the display is switched off over D-Bus.
+    #[cfg(feature = "dbus")]
+    let mut bound = true;
+    #[cfg(not(feature = "dbus"))]
+    let bound = true;
+    // The UDC may go away and come back, e.g. when its driver's reloaded.
+    let udc_monitor = match wait_udc {
+        true => UdcMonitor::open()
+            .inspect_err(|err| warn!("can't listen for UDCs, won't rebind: {:#}", err))
+            .ok(),
+        false => None,
+    };
+    let fds = [
+        uevents.as_ref().map(AsRawFd::as_raw_fd),
+        udc_monitor.as_ref().map(AsRawFd::as_raw_fd),
+    ];
+    fds.into_iter().flatten().for_each(|fd| waiter.watch(fd));
+    // Polling the connectors, the metrics and the frame rate shown over D-Bus need waking up for
+    // every second, and the watchdog as often as it asks. Otherwise the loop sleeps until
+    // there's a request or uevent.
+    #[cfg(feature = "dbus")]
+    let ticking = service.is_some();
+    #[cfg(not(feature = "dbus"))]
+    let ticking = false;
+    let periodic =
+        (uevents.is_none() || exporter.is_some() || ticking).then_some(Duration::from_secs(1));
+    #[cfg(feature = "systemd")]
+    let timeout = periodic.into_iter().chain(notifier.interval()).min();
+    #[cfg(not(feature = "systemd"))]
+    let timeout = periodic;
+
+    while running.load(Ordering::Relaxed) {
+        if let Some(path) = state_file.filter(|_| function.last_state() != saved_state.as_ref()) {
+            saved_state = function.last_state().cloned();
+            if let Err(err) = function.save_state(path) {
+                warn!("saving state failed: {:#}", err);
+            }
+        }
+        #[cfg(feature = "systemd")]
+        notifier.tick();
+        if let Some(monitor) = &udc_monitor {
+            let events = monitor.events().unwrap_or_else(|err| {
+                warn!("read uevents failed: {:#}", err);
+                Vec::new()
+            });
+            for event in events {
+                match event {
+                    UdcEvent::Removed(name) if name == udc.name() => {
+                        warn!("UDC {} went away", name.to_string_lossy());
+                    }
+                    UdcEvent::Added(name) if name == udc.name() && bound => {
+                        match udc::rebind(&mut reg, &name) {
+                            Ok(rebound) => debug!("UDC back, rebound={}", rebound),
+                            Err(err) => warn!("rebinding the gadget failed: {:#}", err),
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if let Some(exporter) = &mut exporter {
+            exporter.tick(gud_data.stats());
+        }
+        #[cfg(feature = "dbus")]
+        if let Some(service) = &mut service {
+            service.tick(gud_data.stats());
+            service.set_mode(function.state().map(|state| &state.mode));
+            if service.enabled() != bound {
+                bound = service.enabled();
+                debug!("binding gadget={}", bound);
+                let udc = bound.then_some(&udc);
+                if let Err(err) = reg.bind(udc) {
+                    warn!("binding the gadget failed: {:#}", err);
+                }
+            }
+        }
+        // Mirror the connectors' state, it's reported when the host polls.
+        let hotplug = force_detect
+            || match &uevents {
+                Some(uevents) => uevents.hotplug().unwrap_or_else(|err| {
+                    warn!("read uevents failed: {:#}", err);
+                    false
+                }),
+                None => connector_checked.elapsed() >= Duration::from_secs(1),
+            };
+        if hotplug {
+            connector_checked = Instant::now();
+            for head in &mut heads {
+                match card.get_connector(head.connector, uevents.is_some() || force_detect) {
+                    Ok(info) => {
+                        debug!(
+                            "connector {} {:?} with {} modes",
+                            cli::connector_name(&info),
+                            info.state(),
+                            info.modes().len()
+                        );
+                        head.status.set_status(connector_status(info.state()));
+                        if advertised(&info) != head.modes {
+                            head.modes = advertised(&info);
+                            head.status.mark_changed();
+                        }
+                    }
+                    Err(err) => warn!("get connector state failed: {:#}", err),
                 }
-                Event::GetPixelFormats(req) => {
-                    req.send_pixel_formats(&[gud_gadget::GUD_PIXEL_FORMAT_RGB565]).unwrap()
+                match hotplug::edid(&card, head.connector) {
+                    Ok(edid) => head.status.set_edid(edid),
+                    Err(err) => warn!("read EDID failed: {:#}", err),
                 }
-                Event::GetDisplayModes(req) => {
-                    let modes = card
-                        .get_modes(connector.handle())
-                        .unwrap()
+            }
+            force_detect = false;
+        }
+
+        if let Some(dimmer) = &mut dimmer {
+            let brightness = function
+                .state()
+                .filter(|state| state.connector == 0)
+                .and_then(|state| {
+                    state
+                        .properties
                         .iter()
-                        .map(|mode| {
-                            let (hdisplay, vdisplay) = mode.size();
-                            let (hsync_start, hsync_end, htotal) = mode.hsync();
-                            let (vsync_start, vsync_end, vtotal) = mode.vsync();
-                            DisplayMode {
-                                clock: mode.clock(),
-                                hdisplay,
-                                htotal,
-                                hsync_end,
-                                hsync_start,
-                                vtotal,
-                                vdisplay,
-                                vsync_end,
-                                vsync_start,
-                                flags: 0,
-                            }
-                        })
-                        .collect::<Vec<DisplayMode>>();
-                    req.send_modes(&modes).expect("failed to send modes");
+                        .find(|property| property.prop == GUD_PROPERTY_BACKLIGHT_BRIGHTNESS)
+                });
+            let result = match brightness {
+                Some(property) => dimmer.set(property.val.min(100) as u8),
+                None => Ok(()),
+            };
+            if let Err(err) = result.and_then(|_| dimmer.step()) {
+                warn!("setting brightness failed: {:#}", err);
+            }
+        }
+        if gud_data.present().is_some() {
+            if let Some(head) = driven.and_then(|connector| heads.get_mut(connector)) {
+                if let Err(err) = head.output.present() {
+                    warn!("presenting frame failed: {:#}", err);
                 }
-                Event::Buffer(info) => {
-                    gud_data
-                        .recv_buffer(info, mapping.as_mut(), pitch as usize, 2)
-                        .expect("recv_buffer failed");
+            }
+        }
+        let timeout = match dimmer.as_ref().is_some_and(Dimmer::is_fading) {
+            true => timeout.into_iter().chain([FADE_STEP]).min(),
+            false => timeout,
+        };
+        // Woken up when merged frames are due to be presented.
+        let deadline = gud_data.present_deadline();
+        let timeout = timeout
+            .into_iter()
+            .chain(deadline.map(|deadline| deadline.saturating_duration_since(Instant::now())))
+            .min();
+        waiter.wait(timeout).context("wait for events")?;
+        let Some(event) = gud.try_event().expect("read GUD event") else {
+            continue;
+        };
+
+        let gud_event = match function.event(event) {
+            Ok(Some(gud_event)) => gud_event,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("GUD request failed: {:#}", err);
+                continue;
+            }
+        };
+
+        match gud_event {
+            Event::GetDescriptor(req) => {
+                let (min_width, min_height, max_width, max_height) = mode_bounds(&heads, transform);
+                req.send_descriptor(min_width, min_height, max_width, max_height)
+                    .expect("failed to send descriptor");
+            }
+            Event::GetPixelFormats(req) => {
+                // All heads have the same kind of output.
+                let formats: Vec<u8> = match (&display.formats, &heads[0].output) {
+                    (Some(formats), _) => formats.iter().map(|format| (*format).into()).collect(),
+                    (None, Output::Dumb(_)) => {
+                        vec![GUD_PIXEL_FORMAT_XRGB8888, GUD_PIXEL_FORMAT_RGB565]
+                    }
+                    #[cfg(feature = "gpu")]
+                    (None, Output::Gpu(_)) => vec![
+                        GUD_PIXEL_FORMAT_XRGB8888,
+                        GUD_PIXEL_FORMAT_RGB888,
+                        GUD_PIXEL_FORMAT_RGB565,
+                    ],
+                };
+                req.send_pixel_formats(&formats).unwrap()
+            }
+            Event::GetDisplayModes(req) => {
+                // The function has exactly as many connectors as there are heads.
+                let modes = heads[req.connector()]
+                    .modes
+                    .iter()
+                    .map(|mode| display_mode(mode, transform))
+                    .collect::<ModeSet>();
+                // Sorted best first, so what the host has no room for matters least.
+                req.send_modes_truncated(modes.modes())
+                    .expect("failed to send modes");
+            }
+            Event::Buffer(info) => {
+                // The dispatcher only emits buffers once a state is committed.
+                let state = function.state().expect("no committed state");
+                let connector = usize::from(state.connector);
+                // The head the host switched away from goes back to the splash.
+                if let Some(previous) = driven.replace(connector).filter(|&i| i != connector) {
+                    show_splash(&mut heads[previous]);
+                }
+                let head = &mut heads[connector];
+                gud_data.set_scale(Some(head.scale));
+                let result = match &mut head.output {
+                    // Flipped to once the limiter lets the frame be presented.
+                    Output::Dumb(scanout) => scanout.render(
+                        scanout::damaged_rows(
+                            &info,
+                            &state.mode,
+                            head.scale.height as usize,
+                            transform,
+                        ),
+                        |fb, pitch| gud_data.recv_buffer_converted(info, state, fb, pitch),
+                    ),
+                    #[cfg(feature = "gpu")]
+                    Output::Gpu(renderer) => gud_data
+                        .recv_frame(info, state.format)
+                        .and_then(|frame| renderer.upload(&frame, &state.mode)),
+                };
+                if let Err(err) = result {
+                    warn!("recv_buffer failed: {:#}", err);
+                    // Whatever was written is presented, the limiter only knows of whole frames.
+                    if let Err(err) = head.output.present() {
+                        warn!("presenting frame failed: {:#}", err);
+                    }
+                }
+            }
+            Event::DisplayEnable(true) => {}
+            Event::ForceDetect { .. } => force_detect = true,
+            Event::DisplayEnable(false) => heads.iter_mut().for_each(show_splash),
+            Event::Connect | Event::Resume => set_power(&mut heads, true),
+            Event::Suspend => set_power(&mut heads, false),
+            Event::Reset | Event::Disconnect => {
+                if let Err(err) = gud_data.reset() {
+                    warn!("resetting data endpoint failed: {:#}", err);
+                }
+                match blank_on_disconnect {
+                    // The framebuffers keep the last frame, it's back once the host returns.
+                    true => set_power(&mut heads, false),
+                    // The splash stays up until the host sends a frame again.
+                    false => {
+                        driven = None;
+                        heads.iter_mut().for_each(show_splash);
+                    }
                 }
             }
         }
     }
 
+    // Park the display, rather than leave the host with a frozen one and the UDC half set up.
+    #[cfg(feature = "systemd")]
+    notifier.stopping();
+    if let Err(err) = gud_data.reset() {
+        warn!("resetting data endpoint failed: {:#}", err);
+    }
+    if let Some(disconnected) = &disconnected {
+        heads.iter_mut().for_each(|head| show(head, disconnected));
+    }
+    match bound {
+        true => {
+            let timeout = args
+                .shutdown_timeout
+                .or(display.shutdown_timeout)
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+            println!(
+                "waiting up to {}s for the host to drop the display",
+                timeout
+            );
+            let timeout = Duration::from_secs(timeout);
+            if let Err(err) = function.stop(&mut gud, &mut reg, timeout) {
+                warn!("stopping the gadget failed: {:#}", err);
+            }
+        }
+        false => function.shutdown(),
+    }
     Ok(())
 }
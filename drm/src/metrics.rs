@@ -0,0 +1,73 @@
+//! Writes the endpoint's [`Stats`] to a file in the Prometheus text format, for node_exporter's
+//! textfile collector to pick up. Fleets of kiosks get their displays' frame rate, bandwidth and
+//! errors monitored without the daemon serving HTTP itself.
+
+use anyhow::Context;
+use gud_gadget::Stats;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tracing::warn;
+
+const INTERVAL: Duration = Duration::from_secs(5);
+
+pub struct Exporter {
+    path: PathBuf,
+    // The sample the rates were last computed from.
+    last: Stats,
+    written: Instant,
+}
+
+impl Exporter {
+    pub fn new(path: PathBuf) -> Self {
+        Self {
+            path,
+            last: Stats::default(),
+            written: Instant::now(),
+        }
+    }
+
+    /// Rewrites the file every few seconds. Call it on every pass of the event loop.
+    pub fn tick(&mut self, stats: Stats) {
+        let elapsed = self.written.elapsed();
+        if elapsed < INTERVAL {
+            return;
+        }
+        if let Err(err) = self.write(stats, elapsed) {
+            warn!(
+                "writing metrics to {} failed: {:#}",
+                self.path.display(),
+                err
+            );
+        }
+        self.last = stats;
+        self.written = Instant::now();
+    }
+
+    // Writes next to the file and renames it over, so the collector never reads half of it.
+    fn write(&self, stats: Stats, elapsed: Duration) -> anyhow::Result<()> {
+        let tmp = self.path.with_extension("prom.tmp");
+        let mut out = BufWriter::new(File::create(&tmp).context("create")?);
+        stats.write_prometheus(&mut out)?;
+        let secs = elapsed.as_secs_f64();
+        for (name, help, value) in [
+            (
+                "gud_frames_per_second",
+                "Frames received per second.",
+                (stats.frames - self.last.frames) as f64 / secs,
+            ),
+            (
+                "gud_received_bytes_per_second",
+                "Bytes read from the USB endpoint per second.",
+                (stats.bytes - self.last.bytes) as f64 / secs,
+            ),
+        ] {
+            writeln!(out, "# HELP {} {}", name, help)?;
+            writeln!(out, "# TYPE {} gauge", name)?;
+            writeln!(out, "{} {}", name, value)?;
+        }
+        out.into_inner()?.sync_all()?;
+        std::fs::rename(&tmp, &self.path).context("rename")
+    }
+}
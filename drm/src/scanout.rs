@@ -0,0 +1,355 @@
+//! Double-buffered scanout of dumb buffers, presented with atomic page flips.
+
+use anyhow::Context;
+use drm::buffer::{Buffer, DrmFourcc};
+use drm::control::dumbbuffer::DumbBuffer;
+use drm::control::{
+    atomic, connector, crtc, framebuffer, plane, property, AtomicCommitFlags, Device, Event, Mode,
+    PlaneType,
+};
+use drm::{ClientCapability, Device as _};
+use gud_gadget::blit::Transform;
+use gud_gadget::{DisplayMode, FramePacer, SetBuffer};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::ops::Range;
+use std::rc::Rc;
+use tracing::{debug, trace_span};
+
+use crate::Card;
+
+/// Page flips pending on a card that's shared by several outputs. Waiting for one CRTC's flip
+/// collects the events of the others too.
+#[derive(Debug, Clone, Default)]
+pub struct Flips(Rc<RefCell<HashSet<crtc::Handle>>>);
+
+impl Flips {
+    /// Notes that a flip with an event was submitted on `crtc`.
+    pub fn submitted(&self, crtc: crtc::Handle) {
+        self.0.borrow_mut().insert(crtc);
+    }
+
+    /// Blocks until the pending flip on `crtc`, if there is one, completes.
+    pub fn wait(&self, card: &Card, crtc: crtc::Handle) -> anyhow::Result<()> {
+        let _wait = trace_span!("wait_flip", ?crtc).entered();
+        while self.0.borrow().contains(&crtc) {
+            for event in card.receive_events().context("receive DRM events")? {
+                if let Event::PageFlip(event) = event {
+                    debug!(
+                        "page flip {} on {:?} after {:?}",
+                        event.frame, event.crtc, event.duration
+                    );
+                    self.0.borrow_mut().remove(&event.crtc);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Holds back the host's frames until the previous one on the driven head has flipped, so it
+/// renders at the panel's refresh rate. The heads' CRTCs are indexed by GUD connector.
+#[derive(Debug)]
+pub struct VsyncPacer {
+    card: Card,
+    flips: Flips,
+    crtcs: Vec<crtc::Handle>,
+}
+
+impl VsyncPacer {
+    pub fn new(card: Card, flips: Flips, crtcs: Vec<crtc::Handle>) -> Self {
+        Self { card, flips, crtcs }
+    }
+}
+
+impl FramePacer for VsyncPacer {
+    fn wait(&mut self, connector: usize) -> anyhow::Result<()> {
+        match self.crtcs.get(connector) {
+            Some(&crtc) => self.flips.wait(&self.card, crtc),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct Scanout {
+    card: Card,
+    flips: Flips,
+    buffers: [DumbBuffer; 2],
+    framebuffers: [framebuffer::Handle; 2],
+    // Index of the buffer that's drawn into next.
+    back: usize,
+    height: usize,
+    crtc: crtc::Handle,
+    active: property::Handle,
+    plane: plane::Handle,
+    fb_id: property::Handle,
+    // Rows damaged by the last frame, which the back buffer is missing.
+    stale: Range<usize>,
+    // Rows drawn into the back buffer that haven't been flipped to yet.
+    pending: Option<Range<usize>>,
+}
+
+impl Scanout {
+    /// Allocates two dumb buffers of the given format and modesets `crtc` to scan out the first.
+    pub fn new(
+        card: Card,
+        flips: Flips,
+        crtc: crtc::Handle,
+        connector: connector::Handle,
+        mode: Mode,
+        format: DrmFourcc,
+        bpp: u32,
+    ) -> anyhow::Result<Self> {
+        card.set_client_capability(ClientCapability::UniversalPlanes, true)
+            .context("enable universal planes")?;
+        card.set_client_capability(ClientCapability::Atomic, true)
+            .context("enable atomic modesetting")?;
+
+        let (width, height) = mode.size();
+        let mut buffers = Vec::with_capacity(2);
+        let mut framebuffers = Vec::with_capacity(2);
+        for _ in 0..2 {
+            let buffer = card
+                .create_dumb_buffer((width.into(), height.into()), format, bpp)
+                .context("create dumb buffer")?;
+            framebuffers.push(
+                card.add_framebuffer(&buffer, bpp, bpp)
+                    .context("add framebuffer")?,
+            );
+            buffers.push(buffer);
+        }
+        let buffers: [DumbBuffer; 2] = buffers.try_into().unwrap();
+        let framebuffers: [framebuffer::Handle; 2] = framebuffers.try_into().unwrap();
+
+        let plane = primary_plane(&card, crtc)?;
+        let connector_props = properties(&card, connector)?;
+        let crtc_props = properties(&card, crtc)?;
+        let plane_props = properties(&card, plane)?;
+
+        let mut req = atomic::AtomicModeReq::new();
+        req.add_property(
+            connector,
+            connector_props["CRTC_ID"],
+            property::Value::CRTC(Some(crtc)),
+        );
+        let mode_blob = card
+            .create_property_blob(&mode)
+            .context("create mode blob")?;
+        req.add_property(crtc, crtc_props["MODE_ID"], mode_blob);
+        req.add_property(crtc, crtc_props["ACTIVE"], property::Value::Boolean(true));
+        req.add_property(
+            plane,
+            plane_props["FB_ID"],
+            property::Value::Framebuffer(Some(framebuffers[0])),
+        );
+        req.add_property(
+            plane,
+            plane_props["CRTC_ID"],
+            property::Value::CRTC(Some(crtc)),
+        );
+        for (prop, value) in [
+            ("SRC_X", 0),
+            ("SRC_Y", 0),
+            ("SRC_W", (width as u64) << 16),
+            ("SRC_H", (height as u64) << 16),
+            ("CRTC_W", width as u64),
+            ("CRTC_H", height as u64),
+        ] {
+            req.add_property(
+                plane,
+                plane_props[prop],
+                property::Value::UnsignedRange(value),
+            );
+        }
+        for prop in ["CRTC_X", "CRTC_Y"] {
+            req.add_property(plane, plane_props[prop], property::Value::SignedRange(0));
+        }
+        card.atomic_commit(AtomicCommitFlags::ALLOW_MODESET, req)
+            .context("atomic modeset")?;
+
+        Ok(Self {
+            card,
+            flips,
+            buffers,
+            framebuffers,
+            back: 1,
+            height: height.into(),
+            crtc,
+            active: crtc_props["ACTIVE"],
+            plane,
+            fb_id: plane_props["FB_ID"],
+            stale: 0..0,
+            pending: None,
+        })
+    }
+
+    /// Draws a frame into the back buffer with `blit` and flips to it.
+    ///
+    /// `blit` is passed the back buffer and its pitch. It only has to write the rows in `damage`,
+    /// the rest of the buffer is brought up to date with the front buffer first. This waits for
+    /// the previous flip, so frames are paced by vblank.
+    pub fn draw(
+        &mut self,
+        damage: Range<usize>,
+        blit: impl FnOnce(&mut [u8], usize) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let result = self.render(damage, blit);
+        // Whatever was written is presented, even if receiving the frame failed part way.
+        self.present()?;
+        result
+    }
+
+    /// Like [`draw`](Self::draw), but leaves flipping to [`present`](Self::present), so several
+    /// frames can be drawn into the back buffer and shown at once.
+    pub fn render(
+        &mut self,
+        damage: Range<usize>,
+        blit: impl FnOnce(&mut [u8], usize) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        let damage = damage.start.min(self.height)..damage.end.min(self.height);
+        let pitch = self.buffers[0].pitch() as usize;
+        if let Some(pending) = &mut self.pending {
+            // The back buffer is already up to date with the front buffer.
+            let map = self.card.map_dumb_buffer(&mut self.buffers[self.back]);
+            let mut back = map.context("map back buffer")?;
+            *pending = pending.start.min(damage.start)..pending.end.max(damage.end);
+            return blit(back.as_mut(), pitch);
+        }
+        self.wait_flip()?;
+
+        let [first, second] = &mut self.buffers;
+        let (front, back) = match self.back {
+            0 => (second, first),
+            _ => (first, second),
+        };
+        let mut back = self.card.map_dumb_buffer(back).context("map back buffer")?;
+        if !self.stale.is_empty() {
+            let front = self
+                .card
+                .map_dumb_buffer(front)
+                .context("map front buffer")?;
+            let len = back.len();
+            let stale = (self.stale.start * pitch).min(len)..(self.stale.end * pitch).min(len);
+            back[stale.clone()].copy_from_slice(&front[stale]);
+        }
+        let result = blit(back.as_mut(), pitch);
+        self.pending = Some(damage);
+        result
+    }
+
+    /// Flips to what's been drawn since the last flip, if anything.
+    pub fn present(&mut self) -> anyhow::Result<()> {
+        let Some(pending) = self.pending.take() else {
+            return Ok(());
+        };
+        self.flip()?;
+        self.stale = pending;
+        Ok(())
+    }
+
+    pub fn crtc(&self) -> crtc::Handle {
+        self.crtc
+    }
+
+    /// Switches the CRTC on or off. The last frame is shown again when it's switched back on.
+    pub fn set_active(&mut self, active: bool) -> anyhow::Result<()> {
+        self.wait_flip()?;
+        let mut req = atomic::AtomicModeReq::new();
+        req.add_property(self.crtc, self.active, property::Value::Boolean(active));
+        self.card
+            .atomic_commit(AtomicCommitFlags::ALLOW_MODESET, req)
+            .context("atomic CRTC activation")
+    }
+
+    fn flip(&mut self) -> anyhow::Result<()> {
+        let mut req = atomic::AtomicModeReq::new();
+        req.add_property(
+            self.plane,
+            self.fb_id,
+            property::Value::Framebuffer(Some(self.framebuffers[self.back])),
+        );
+        self.card
+            .atomic_commit(
+                AtomicCommitFlags::PAGE_FLIP_EVENT | AtomicCommitFlags::NONBLOCK,
+                req,
+            )
+            .context("atomic page flip")?;
+        self.flips.submitted(self.crtc);
+        self.back ^= 1;
+        Ok(())
+    }
+
+    fn wait_flip(&mut self) -> anyhow::Result<()> {
+        self.flips.wait(&self.card, self.crtc)
+    }
+}
+
+/// The framebuffer rows a damage rect in the host's `mode` ends up in after transforming it and
+/// scaling to `height` rows, with a row of slack either side for filtering.
+pub fn damaged_rows(
+    info: &SetBuffer,
+    mode: &DisplayMode,
+    height: usize,
+    transform: Transform,
+) -> Range<usize> {
+    // Turned sideways, the rect's columns become rows.
+    let (start, len, mode_height) = match transform.swaps_axes() {
+        true => (info.x, info.width, mode.hdisplay),
+        false => (info.y, info.height, mode.vdisplay),
+    };
+    let (start, len) = (start as usize, len as usize);
+    let mode_height = (mode_height as usize).max(1);
+    let rows = (start * height / mode_height).saturating_sub(1)
+        ..((start + len) * height).div_ceil(mode_height) + 1;
+    // Whether they're also upside down: the first pixel of a 2x2 frame lands on the bottom row.
+    match transform.apply(0, 0, 2, 2).1 {
+        0 => rows,
+        _ => height.saturating_sub(rows.end)..height.saturating_sub(rows.start),
+    }
+}
+
+fn primary_plane(card: &Card, crtc: crtc::Handle) -> anyhow::Result<plane::Handle> {
+    let resources = card.resource_handles().context("get DRM resources")?;
+    let planes: Vec<plane::Handle> = card
+        .plane_handles()
+        .context("list planes")?
+        .iter()
+        .copied()
+        .filter(|plane| {
+            card.get_plane(*plane).is_ok_and(|info| {
+                resources
+                    .filter_crtcs(info.possible_crtcs())
+                    .contains(&crtc)
+            })
+        })
+        .collect();
+    let primary = planes.iter().copied().find(|plane| {
+        let Ok(props) = card.get_properties(*plane) else {
+            return false;
+        };
+        let primary = props.iter().any(|(&id, &value)| {
+            card.get_property(id)
+                .is_ok_and(|info| info.name().to_str() == Ok("type"))
+                && value == (PlaneType::Primary as u32).into()
+        });
+        primary
+    });
+    primary
+        .or(planes.first().copied())
+        .context("no plane for CRTC")
+}
+
+fn properties(
+    card: &Card,
+    handle: impl drm::control::ResourceHandle,
+) -> anyhow::Result<HashMap<String, property::Handle>> {
+    let props = card
+        .get_properties(handle)
+        .context("get properties")?
+        .as_hashmap(card)
+        .context("get property info")?;
+    Ok(props
+        .into_iter()
+        .map(|(name, info)| (name, info.handle()))
+        .collect())
+}
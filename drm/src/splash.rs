@@ -0,0 +1,103 @@
+//! What's shown on the panel while no host is driving it.
+
+use anyhow::Context;
+use bytes::Bytes;
+use gud_gadget::protocol::GUD_PIXEL_FORMAT_XRGB8888;
+use gud_gadget::{DisplayMode, Frame, SetBuffer};
+use std::fs::File;
+use std::path::Path;
+
+/// A standby image, stretched to fill the panel.
+#[derive(Debug, Clone)]
+pub struct Splash {
+    width: u16,
+    height: u16,
+    // XRGB8888 pixels.
+    pixels: Bytes,
+}
+
+impl Default for Splash {
+    /// Solid black.
+    fn default() -> Self {
+        Self::color(0)
+    }
+}
+
+impl Splash {
+    /// Parses a `--splash` argument, either a `#RRGGBB` color or the path to a PNG.
+    pub fn parse(splash: &str) -> anyhow::Result<Self> {
+        match splash.strip_prefix('#') {
+            Some(hex) if hex.len() == 6 => Ok(Self::color(
+                u32::from_str_radix(hex, 16).context("invalid splash color")?,
+            )),
+            _ => Self::load(Path::new(splash)),
+        }
+    }
+
+    /// A solid `0xRRGGBB` color.
+    pub fn color(rgb: u32) -> Self {
+        Self {
+            width: 1,
+            height: 1,
+            pixels: Bytes::copy_from_slice(&rgb.to_le_bytes()),
+        }
+    }
+
+    /// Decodes a PNG. Transparent pixels are blended onto black.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let file = File::open(path).with_context(|| format!("open splash {}", path.display()))?;
+        let mut decoder = png::Decoder::new(file);
+        decoder.set_transformations(png::Transformations::normalize_to_color8());
+        let mut reader = decoder.read_info().context("decode splash")?;
+        let mut buf = vec![0; reader.output_buffer_size()];
+        let info = reader.next_frame(&mut buf).context("decode splash")?;
+        let width = u16::try_from(info.width).context("splash too wide")?;
+        let height = u16::try_from(info.height).context("splash too tall")?;
+
+        let channels = info.color_type.samples();
+        let mut pixels = Vec::with_capacity(width as usize * height as usize * 4);
+        for line in buf.chunks_exact(info.line_size).take(height.into()) {
+            for pixel in line.chunks_exact(channels).take(width.into()) {
+                let (rgb, alpha) = match *pixel {
+                    [l] => ([l, l, l], 255),
+                    [l, a] => ([l, l, l], a),
+                    [r, g, b] => ([r, g, b], 255),
+                    [r, g, b, a] => ([r, g, b], a),
+                    _ => unreachable!("normalized to 8 bit gray or RGB"),
+                };
+                let [r, g, b] = rgb.map(|c| (c as u16 * alpha as u16 / 255) as u8);
+                pixels.extend_from_slice(&[b, g, r, 0xff]);
+            }
+        }
+
+        Ok(Self {
+            width,
+            height,
+            pixels: pixels.into(),
+        })
+    }
+
+    /// The splash as a full frame, along with the mode it's to be scaled from.
+    pub fn frame(&self) -> (Frame, DisplayMode) {
+        let (width, height) = (self.width as u32, self.height as u32);
+        let frame = Frame {
+            info: SetBuffer {
+                x: 0,
+                y: 0,
+                width,
+                height,
+                length: width * height * 4,
+                compression: 0,
+                compressed_length: 0,
+            },
+            format: GUD_PIXEL_FORMAT_XRGB8888,
+            data: self.pixels.clone(),
+        };
+        let mode = DisplayMode {
+            hdisplay: self.width,
+            vdisplay: self.height,
+            ..Default::default()
+        };
+        (frame, mode)
+    }
+}
@@ -0,0 +1,47 @@
+//! Forwards touches on the panel to the host through the HID touch function.
+
+use anyhow::Context;
+use gud_gadget::blit::Transform;
+use gud_gadget::touch::{Contact, TouchReporter, Touchscreen, LOGICAL_MAX};
+use std::path::Path;
+use tracing::warn;
+use usb_gadget::function::hid::Hid;
+
+/// Reports contacts from the evdev device at `path` on a thread of its own, until reading it
+/// fails. They're turned back from the panel's `transform`, to line up with what the host drew.
+pub fn forward(hid: Hid, path: &Path, transform: Transform) -> anyhow::Result<()> {
+    let mut touchscreen =
+        Touchscreen::open(path).with_context(|| format!("open touchscreen {}", path.display()))?;
+    let mut reporter = TouchReporter::open(&hid).context("open HID touch device")?;
+    let inverse = transform.inverse();
+    std::thread::spawn(move || {
+        // The function's device stays around as long as this does.
+        let _hid = hid;
+        loop {
+            let contacts = match touchscreen.next_contacts() {
+                Ok(contacts) => contacts,
+                Err(err) => {
+                    warn!("read touchscreen failed: {:#}", err);
+                    return;
+                }
+            };
+            let contacts: Vec<Contact> = contacts
+                .into_iter()
+                .map(|contact| {
+                    // Positions are normalized, so the panel is a square either way up.
+                    let size = u32::from(LOGICAL_MAX) + 1;
+                    let (x, y) = inverse.apply(contact.x.into(), contact.y.into(), size, size);
+                    Contact {
+                        x: x as u16,
+                        y: y as u16,
+                        ..contact
+                    }
+                })
+                .collect();
+            if let Err(err) = reporter.send(&contacts) {
+                warn!("send touch report failed: {:#}", err);
+            }
+        }
+    });
+    Ok(())
+}
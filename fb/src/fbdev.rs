@@ -0,0 +1,253 @@
+//! A legacy `/dev/fbN` framebuffer, for boards whose display drivers don't do KMS.
+
+use anyhow::{bail, Context};
+use gud_gadget::blit::Swizzle;
+use gud_gadget::protocol::{ModeFlags, PixelFormat};
+use gud_gadget::DisplayMode;
+use memmap2::{MmapMut, MmapOptions};
+use std::fs::{File, OpenOptions};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+
+// From linux/fb.h.
+const FBIOGET_VSCREENINFO: libc::c_ulong = 0x4600;
+const FBIOGET_FSCREENINFO: libc::c_ulong = 0x4602;
+const FBIOBLANK: libc::c_ulong = 0x4611;
+const FB_BLANK_UNBLANK: libc::c_int = 0;
+const FB_BLANK_POWERDOWN: libc::c_int = 4;
+const FB_SYNC_HOR_HIGH_ACT: u32 = 1;
+const FB_SYNC_VERT_HIGH_ACT: u32 = 2;
+const FB_VMODE_INTERLACED: u32 = 1;
+const FB_VMODE_DOUBLE: u32 = 2;
+const FB_VMODE_MASK: u32 = 255;
+
+/// `struct fb_bitfield`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct FbBitfield {
+    offset: u32,
+    length: u32,
+    msb_right: u32,
+}
+
+/// `struct fb_var_screeninfo`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct FbVarScreeninfo {
+    xres: u32,
+    yres: u32,
+    xres_virtual: u32,
+    yres_virtual: u32,
+    xoffset: u32,
+    yoffset: u32,
+    bits_per_pixel: u32,
+    grayscale: u32,
+    red: FbBitfield,
+    green: FbBitfield,
+    blue: FbBitfield,
+    transp: FbBitfield,
+    nonstd: u32,
+    activate: u32,
+    height: u32,
+    width: u32,
+    accel_flags: u32,
+    pixclock: u32,
+    left_margin: u32,
+    right_margin: u32,
+    upper_margin: u32,
+    lower_margin: u32,
+    hsync_len: u32,
+    vsync_len: u32,
+    sync: u32,
+    vmode: u32,
+    rotate: u32,
+    colorspace: u32,
+    reserved: [u32; 4],
+}
+
+/// `struct fb_fix_screeninfo`
+#[repr(C)]
+#[derive(Debug, Default, Clone, Copy)]
+struct FbFixScreeninfo {
+    id: [u8; 16],
+    smem_start: libc::c_ulong,
+    smem_len: u32,
+    type_: u32,
+    type_aux: u32,
+    visual: u32,
+    xpanstep: u16,
+    ypanstep: u16,
+    ywrapstep: u16,
+    line_length: u32,
+    mmio_start: libc::c_ulong,
+    mmio_len: u32,
+    accel: u32,
+    capabilities: u16,
+    reserved: [u16; 2],
+}
+
+pub struct Framebuffer {
+    file: File,
+    map: MmapMut,
+    var: FbVarScreeninfo,
+    // Offset of the visible area in the mapping.
+    offset: usize,
+    pitch: usize,
+    format: PixelFormat,
+    swizzle: Swizzle,
+}
+
+impl Framebuffer {
+    /// Opens and maps a framebuffer device, taking its geometry and pixel layout from the
+    /// kernel.
+    pub fn open(path: &Path) -> anyhow::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(path)
+            .with_context(|| format!("open {}", path.display()))?;
+        let mut var = FbVarScreeninfo::default();
+        ioctl(&file, FBIOGET_VSCREENINFO, &mut var).context("FBIOGET_VSCREENINFO")?;
+        let mut fix = FbFixScreeninfo::default();
+        ioctl(&file, FBIOGET_FSCREENINFO, &mut fix).context("FBIOGET_FSCREENINFO")?;
+
+        let bytes_per_pixel = var.bits_per_pixel.div_ceil(8) as usize;
+        // Some drivers leave line_length unset.
+        let pitch = match fix.line_length {
+            0 => var.xres_virtual as usize * bytes_per_pixel,
+            line_length => line_length as usize,
+        };
+        // BGR framebuffers have red in the low bits.
+        let swizzle = match var.red.offset {
+            0 if var.blue.offset != 0 => Swizzle::SWAP_RB,
+            _ => Swizzle::NONE,
+        };
+        let format = match (var.bits_per_pixel, var.grayscale) {
+            (8, 0) if (var.red.length, var.green.length, var.blue.length) == (3, 3, 2) => {
+                PixelFormat::Rgb332
+            }
+            (8, 1) => PixelFormat::R8,
+            (16, 0) => PixelFormat::Rgb565,
+            (24, 0) => PixelFormat::Rgb888,
+            (32, 0) => PixelFormat::Xrgb8888,
+            (bpp, 0) => bail!("unsupported {bpp} bpp framebuffer"),
+            (bpp, _) => bail!("unsupported {bpp} bpp grayscale framebuffer"),
+        };
+
+        let offset = var.yoffset as usize * pitch + var.xoffset as usize * bytes_per_pixel;
+        let len = offset + var.yres as usize * pitch;
+        if len > fix.smem_len as usize {
+            bail!(
+                "visible area of {} bytes exceeds the {} byte framebuffer",
+                len,
+                fix.smem_len
+            );
+        }
+        let map =
+            unsafe { MmapOptions::new().len(len).map_mut(&file) }.context("map framebuffer")?;
+
+        Ok(Self {
+            file,
+            map,
+            var,
+            offset,
+            pitch,
+            format,
+            swizzle,
+        })
+    }
+
+    pub fn size(&self) -> (u32, u32) {
+        (self.var.xres, self.var.yres)
+    }
+
+    pub fn pitch(&self) -> usize {
+        self.pitch
+    }
+
+    pub fn format(&self) -> PixelFormat {
+        self.format
+    }
+
+    pub fn swizzle(&self) -> Swizzle {
+        self.swizzle
+    }
+
+    /// The visible rows of the framebuffer.
+    pub fn pixels(&mut self) -> &mut [u8] {
+        &mut self.map[self.offset..]
+    }
+
+    /// Fills the framebuffer with black.
+    pub fn clear(&mut self) {
+        self.pixels().fill(0);
+    }
+
+    /// The framebuffer's video mode, as offered to the host.
+    pub fn mode(&self) -> DisplayMode {
+        let var = &self.var;
+        let (hdisplay, vdisplay) = (var.xres, var.yres);
+        let hsync_start = hdisplay + var.right_margin;
+        let hsync_end = hsync_start + var.hsync_len;
+        let htotal = hsync_end + var.left_margin;
+        let vsync_start = vdisplay + var.lower_margin;
+        let vsync_end = vsync_start + var.vsync_len;
+        let vtotal = vsync_end + var.upper_margin;
+        // pixclock is in picoseconds, zero if the driver doesn't know (e.g. SPI panels).
+        let clock = match var.pixclock {
+            0 => htotal * vtotal * 60 / 1000,
+            pixclock => 1_000_000_000 / pixclock,
+        };
+        DisplayMode {
+            clock,
+            hdisplay: hdisplay as u16,
+            hsync_start: hsync_start as u16,
+            hsync_end: hsync_end as u16,
+            htotal: htotal as u16,
+            vdisplay: vdisplay as u16,
+            vsync_start: vsync_start as u16,
+            vsync_end: vsync_end as u16,
+            vtotal: vtotal as u16,
+            flags: mode_flags(var).bits(),
+        }
+    }
+
+    /// Powers the display down or back up. Not every driver supports it.
+    pub fn set_blank(&self, blank: bool) -> anyhow::Result<()> {
+        let level = match blank {
+            true => FB_BLANK_POWERDOWN,
+            false => FB_BLANK_UNBLANK,
+        };
+        let ret = unsafe { libc::ioctl(self.file.as_raw_fd(), FBIOBLANK as _, level) };
+        if ret < 0 {
+            return Err(std::io::Error::last_os_error()).context("FBIOBLANK");
+        }
+        Ok(())
+    }
+}
+
+// The sync polarity and scan of the framebuffer's only mode, which is therefore preferred.
+fn mode_flags(var: &FbVarScreeninfo) -> ModeFlags {
+    let mut flags = ModeFlags::PREFERRED;
+    flags |= match var.sync & FB_SYNC_HOR_HIGH_ACT {
+        0 => ModeFlags::NHSYNC,
+        _ => ModeFlags::PHSYNC,
+    };
+    flags |= match var.sync & FB_SYNC_VERT_HIGH_ACT {
+        0 => ModeFlags::NVSYNC,
+        _ => ModeFlags::PVSYNC,
+    };
+    match var.vmode & FB_VMODE_MASK {
+        FB_VMODE_INTERLACED => flags | ModeFlags::INTERLACE,
+        FB_VMODE_DOUBLE => flags | ModeFlags::DBLSCAN,
+        _ => flags,
+    }
+}
+
+fn ioctl<T>(file: &File, request: libc::c_ulong, arg: &mut T) -> std::io::Result<()> {
+    let ret = unsafe { libc::ioctl(file.as_raw_fd(), request as _, arg as *mut T) };
+    if ret < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+    Ok(())
+}
@@ -0,0 +1,234 @@
+use anyhow::Context;
+use clap::Parser;
+use gud_gadget::blit::{Filter, Scale, Transform};
+use gud_gadget::protocol::*;
+use gud_gadget::udc::{self, UdcEvent, UdcMonitor};
+use gud_gadget::wait::Waiter;
+use gud_gadget::{Event, Function};
+use std::os::fd::AsRawFd;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use tracing::warn;
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use usb_gadget::function::custom::{Custom, Interface};
+use usb_gadget::{default_udc, Class, Config, Gadget, Id, Strings};
+
+mod fbdev;
+
+use fbdev::Framebuffer;
+
+/// Expose a legacy fbdev framebuffer as a Generic USB Display gadget.
+#[derive(Debug, Parser)]
+struct Args {
+    /// Framebuffer device to draw to.
+    #[arg(default_value = "/dev/fb0")]
+    fb: PathBuf,
+    /// Rotate the output clockwise by this many degrees, for panels mounted sideways or upside
+    /// down. The host is offered the mode the way up it's seen.
+    #[arg(long, default_value_t = 0)]
+    rotate: u32,
+    /// Mirror the output horizontally, before rotating it.
+    #[arg(long)]
+    flip: bool,
+    /// Wait for a UDC to appear instead of failing when there's none yet, and bind the gadget
+    /// again when it goes away and comes back.
+    #[arg(long)]
+    wait_udc: bool,
+}
+
+fn main() -> anyhow::Result<()> {
+    let registry = tracing_subscriber::registry()
+        .with(fmt::layer())
+        .with(EnvFilter::from_default_env());
+    // Per-frame spans are at trace level, e.g. RUST_LOG=gud_gadget=trace shows them in Tracy.
+    #[cfg(feature = "tracy")]
+    let registry = registry.with(tracing_tracy::TracyLayer::default());
+    registry.init();
+
+    let args = Args::parse();
+    let transform = Transform {
+        flip: args.flip,
+        ..Transform::rotate(args.rotate)
+            .with_context(|| format!("unsupported rotation {}", args.rotate))?
+    };
+    let mut fb = Framebuffer::open(&args.fb)?;
+    let (width, height) = fb.size();
+    let mode = transform.apply_mode(&fb.mode());
+    println!(
+        "framebuffer {} is {}x{} {:?}",
+        args.fb.display(),
+        width,
+        height,
+        fb.format()
+    );
+    fb.clear();
+
+    let udc = match args.wait_udc {
+        true => udc::wait_for_udc(None)?,
+        false => default_udc().expect("no UDC found"),
+    };
+    usb_gadget::remove_all().expect("UDC init failed");
+
+    let (mut gud_data, gud_data_ep) = gud_gadget::PixelDataEndpoint::new();
+    gud_data.set_convert_to(Some(fb.format()));
+    gud_data.set_swizzle(fb.swizzle());
+    // Only the framebuffer's own mode is offered, but a host that picks something else anyway
+    // still ends up filling the screen.
+    gud_data.set_scale(Some(Scale {
+        width,
+        height,
+        filter: Filter::Bilinear,
+    }));
+    gud_data.set_transform(transform);
+    let (mut gud, gud_handle) = Custom::builder()
+        .with_interface(
+            Interface::new(Class::vendor_specific(Class::VENDOR_SPECIFIC, 0), "GUD")
+                .with_endpoint(gud_data_ep),
+        )
+        .build();
+
+    let mut reg = Gadget::new(
+        Class::interface_specific(),
+        Id::new(
+            gud_gadget::OPENMOKO_VENDOR_ID,
+            gud_gadget::OPENMOKO_GUD_PRODUCT_ID,
+        ),
+        Strings::new("The Internet", "Generic USB Display", ""),
+    )
+    .with_config(Config::new("gud").with_function(gud_handle))
+    .bind(&udc)
+    .expect("UDC binding failed");
+
+    let running = Arc::new(AtomicBool::new(true));
+    // The loop sleeps until the host sends a request, so Ctrl-C has to wake it.
+    let mut waiter = Waiter::new().context("create waiter")?;
+    waiter.watch(gud.fd().context("get ep0 fd")?);
+
+    let r = running.clone();
+    let waker = waiter.waker();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+        waker.wake();
+    })
+    .expect("cleanup handler registration failed");
+
+    let set_blank = |fb: &Framebuffer, blank: bool| {
+        if let Err(err) = fb.set_blank(blank) {
+            warn!("blanking framebuffer={} failed: {:#}", blank, err);
+        }
+    };
+
+    let mut function = Function::new();
+    // The host drops interlaced and doublescan modes unless the connector says it takes them.
+    let mut connector_flags = 0;
+    if mode.mode_flags().contains(ModeFlags::INTERLACE) {
+        connector_flags |= GUD_CONNECTOR_FLAGS_INTERLACE;
+    }
+    if mode.mode_flags().contains(ModeFlags::DBLSCAN) {
+        connector_flags |= GUD_CONNECTOR_FLAGS_DOUBLESCAN;
+    }
+    function.set_connector_flags(0, connector_flags);
+
+    // The gadget's bound and set up, the host can use it from here on.
+    #[cfg(feature = "systemd")]
+    let mut notifier = gud_gadget::systemd::Notifier::new();
+    #[cfg(feature = "systemd")]
+    notifier.ready();
+
+    let udc_monitor = match args.wait_udc {
+        true => UdcMonitor::open()
+            .inspect_err(|err| warn!("can't listen for UDCs, won't rebind: {:#}", err))
+            .ok(),
+        false => None,
+    };
+    if let Some(monitor) = &udc_monitor {
+        waiter.watch(monitor.as_raw_fd());
+    }
+    // Only the watchdog needs waking up for.
+    #[cfg(feature = "systemd")]
+    let timeout = notifier.interval();
+    #[cfg(not(feature = "systemd"))]
+    let timeout = None;
+
+    while running.load(Ordering::Relaxed) {
+        waiter.wait(timeout).context("wait for events")?;
+        #[cfg(feature = "systemd")]
+        notifier.tick();
+        if let Some(monitor) = &udc_monitor {
+            let events = monitor.events().unwrap_or_else(|err| {
+                warn!("read uevents failed: {:#}", err);
+                Vec::new()
+            });
+            for event in events {
+                match event {
+                    UdcEvent::Removed(name) if name == udc.name() => {
+                        warn!("UDC {} went away", name.to_string_lossy());
+                    }
+                    UdcEvent::Added(name) if name == udc.name() => {
+                        if let Err(err) = udc::rebind(&mut reg, &name) {
+                            warn!("rebinding the gadget failed: {:#}", err);
+                        }
+                    }
+                    _ => {}
+                }
+            }
+        }
+        let Some(event) = gud.try_event().expect("read GUD event") else {
+            continue;
+        };
+
+        let gud_event = match function.event(event) {
+            Ok(Some(gud_event)) => gud_event,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("GUD request failed: {:#}", err);
+                continue;
+            }
+        };
+
+        match gud_event {
+            Event::GetDescriptor(req) => {
+                let (width, height) = (mode.hdisplay.into(), mode.vdisplay.into());
+                req.send_descriptor(width, height, width, height)
+                    .expect("failed to send descriptor");
+            }
+            Event::GetPixelFormats(req) => {
+                // XRGB8888 gives the host better quality and compression.
+                let mut formats = vec![GUD_PIXEL_FORMAT_XRGB8888];
+                if fb.format() != PixelFormat::Xrgb8888 {
+                    formats.push(fb.format().into());
+                }
+                req.send_pixel_formats(&formats).unwrap()
+            }
+            Event::GetDisplayModes(req) => {
+                req.send_modes(std::slice::from_ref(&mode))
+                    .expect("failed to send modes");
+            }
+            Event::Buffer(info) => {
+                // The dispatcher only emits buffers once a state is committed.
+                let state = function.state().expect("no committed state");
+                let pitch = fb.pitch();
+                if let Err(err) = gud_data.recv_buffer_converted(info, state, fb.pixels(), pitch) {
+                    warn!("recv_buffer failed: {:#}", err);
+                }
+            }
+            // There's nothing to probe, the framebuffer's always there.
+            Event::DisplayEnable(true) | Event::ForceDetect { .. } => {}
+            Event::DisplayEnable(false) => fb.clear(),
+            Event::Connect | Event::Resume => set_blank(&fb, false),
+            Event::Suspend => set_blank(&fb, true),
+            Event::Reset | Event::Disconnect => {
+                if let Err(err) = gud_data.reset() {
+                    warn!("resetting data endpoint failed: {:#}", err);
+                }
+                fb.clear();
+                set_blank(&fb, true);
+            }
+        }
+    }
+
+    #[cfg(feature = "systemd")]
+    notifier.stopping();
+    Ok(())
+}
@@ -0,0 +1,87 @@
+//! The per-frame hot paths, on a full 720x1440 frame.
+//!
+//! `cargo bench -p gud-gadget --features simd` on the device compares the NEON kernels against
+//! the scalar ones.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use gud_gadget::blit::{blit, blit_convert, decompress, Swizzle};
+use gud_gadget::protocol::{PixelFormat, GUD_COMPRESSION_LZ4};
+use gud_gadget::SetBuffer;
+
+const WIDTH: u32 = 720;
+const HEIGHT: u32 = 1440;
+
+fn frame(bpp: u32) -> (SetBuffer, Vec<u8>) {
+    let length = WIDTH * HEIGHT * bpp;
+    let info = SetBuffer {
+        x: 0,
+        y: 0,
+        width: WIDTH,
+        height: HEIGHT,
+        length,
+        compression: 0,
+        compressed_length: 0,
+    };
+    // Something between noise and a flat color, like a desktop.
+    let buf = (0..length).map(|i| (i / 7 % 251) as u8).collect();
+    (info, buf)
+}
+
+fn copy(c: &mut Criterion) {
+    let (info, buf) = frame(2);
+    let mut fb = vec![0; buf.len()];
+    c.benchmark_group("copy")
+        .throughput(Throughput::Bytes(buf.len() as u64))
+        .bench_function("rgb565", |b| {
+            b.iter(|| blit(&info, &buf, &mut fb, WIDTH as usize * 2, 2).unwrap())
+        });
+}
+
+fn convert(c: &mut Criterion) {
+    let mut group = c.benchmark_group("convert");
+    for (name, format, swizzle) in [
+        ("rgb565_swap16", PixelFormat::Rgb565, Swizzle::SWAP16),
+        ("xrgb8888_to_rgb565", PixelFormat::Xrgb8888, Swizzle::NONE),
+        (
+            "xrgb8888_to_rgb565_swap16",
+            PixelFormat::Xrgb8888,
+            Swizzle::SWAP16,
+        ),
+    ] {
+        let (info, buf) = frame(format.bytes_per_pixel().unwrap() as u32);
+        let mut fb = vec![0; (WIDTH * HEIGHT * 2) as usize];
+        group.throughput(Throughput::Bytes(buf.len() as u64));
+        group.bench_function(BenchmarkId::from_parameter(name), |b| {
+            b.iter(|| {
+                let pitch = WIDTH as usize * 2;
+                blit_convert(
+                    &info,
+                    format,
+                    &buf,
+                    &mut fb,
+                    pitch,
+                    PixelFormat::Rgb565,
+                    swizzle,
+                )
+                .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+fn lz4(c: &mut Criterion) {
+    let (mut info, buf) = frame(4);
+    let compressed = lz4::block::compress(&buf, None, false).unwrap();
+    info.compression = GUD_COMPRESSION_LZ4;
+    info.compressed_length = compressed.len() as u32;
+    let mut dst = vec![0; buf.len()];
+    c.benchmark_group("decompress")
+        .throughput(Throughput::Bytes(buf.len() as u64))
+        .bench_function("xrgb8888", |b| {
+            b.iter(|| decompress(&info, &compressed, &mut dst).unwrap())
+        });
+}
+
+criterion_group!(benches, copy, convert, lz4);
+criterion_main!(benches);
@@ -0,0 +1,47 @@
+//! Replays a trace recorded with `gud-drm --trace` through the dispatcher and lists the requests
+//! it answers differently.
+//!
+//! `cargo run -p gud-gadget --features trace --example replay -- session.trace`, with
+//! `RUST_LOG=gud_gadget=debug` to follow along.
+
+use gud_gadget::trace::{self, Record};
+use gud_gadget::Function;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+fn main() -> anyhow::Result<ExitCode> {
+    tracing_subscriber::fmt::init();
+    let Some(path) = std::env::args_os().nth(1).map(PathBuf::from) else {
+        anyhow::bail!("usage: replay <trace>");
+    };
+    let records = trace::load(&path)?;
+    let payloads = records
+        .iter()
+        .filter(|record| matches!(record, Record::Payload { .. }))
+        .count();
+    println!(
+        "{} control transfers, {} payloads",
+        records.len() - payloads,
+        payloads
+    );
+
+    let mut function = Function::new();
+    trace::configure(&mut function, &records);
+    let mismatches = trace::replay(&mut function, &records);
+    for mismatch in &mismatches {
+        println!(
+            "#{} request {:#04x} value {} index {}: recorded {:?}, replayed {:?}",
+            mismatch.index,
+            mismatch.request.request,
+            mismatch.request.value,
+            mismatch.request.index,
+            mismatch.recorded,
+            mismatch.replayed
+        );
+    }
+    println!("{} mismatches", mismatches.len());
+    Ok(match mismatches.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
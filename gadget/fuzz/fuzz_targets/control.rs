@@ -0,0 +1,77 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use gud_gadget::protocol::{DisplayMode, GUD_PIXEL_FORMAT_RGB565};
+use gud_gadget::transport::mock::{MockReceiver, MockSender};
+use gud_gadget::transport::{ControlRequest, ControlTransfer};
+use gud_gadget::{Event, Function};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Arbitrary, Debug)]
+enum Transfer {
+    Get {
+        request: u8,
+        value: u16,
+        length: u16,
+    },
+    Set {
+        request: u8,
+        value: u16,
+        data: Vec<u8>,
+    },
+}
+
+fuzz_target!(|transfers: Vec<Transfer>| {
+    let mut function = Function::new();
+    let mode = DisplayMode {
+        hdisplay: 64,
+        vdisplay: 48,
+        ..Default::default()
+    };
+
+    for transfer in transfers {
+        let transfer = match transfer {
+            Transfer::Get {
+                request,
+                value,
+                length,
+            } => {
+                let (sender, _) = MockSender::new(ControlRequest {
+                    request,
+                    value,
+                    index: 0,
+                    length,
+                });
+                ControlTransfer::DeviceToHost(sender)
+            }
+            Transfer::Set {
+                request,
+                value,
+                data,
+            } => {
+                let (receiver, _) = MockReceiver::new(
+                    ControlRequest {
+                        request,
+                        value,
+                        ..Default::default()
+                    },
+                    &data,
+                );
+                ControlTransfer::HostToDevice(receiver)
+            }
+        };
+
+        let _ = match function.control(transfer) {
+            Ok(Some(Event::GetDescriptor(req))) => req.send_descriptor(64, 48, 64, 48),
+            Ok(Some(Event::GetPixelFormats(req))) => {
+                req.send_pixel_formats(&[GUD_PIXEL_FORMAT_RGB565])
+            }
+            Ok(Some(Event::GetDisplayModes(req))) => req.send_modes(std::slice::from_ref(&mode)),
+            Ok(Some(Event::Buffer(info))) => {
+                info.validate(function.mode().unwrap()).unwrap();
+                Ok(())
+            }
+            Ok(Some(_)) | Ok(None) | Err(_) => Ok(()),
+        };
+    }
+});
@@ -0,0 +1,49 @@
+#![no_main]
+
+use arbitrary::Arbitrary;
+use gud_gadget::blit;
+use gud_gadget::protocol::{DisplayMode, SetBuffer, WireFormat};
+use libfuzzer_sys::fuzz_target;
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    header: [u8; SetBuffer::LEN],
+    width: u8,
+    height: u8,
+    bpp: u8,
+    padding: u8,
+    payload: Vec<u8>,
+}
+
+fuzz_target!(|input: Input| {
+    let info = SetBuffer::from_bytes(&input.header).unwrap();
+    let (width, height) = (input.width as usize, input.height as usize);
+    let bpp = input.bpp as usize % 4 + 1;
+    let pitch = width * bpp + input.padding as usize;
+
+    let mode = DisplayMode {
+        hdisplay: width as u16,
+        vdisplay: height as u16,
+        ..Default::default()
+    };
+    if info.validate(&mode).is_err() {
+        return;
+    }
+
+    let mut fb = vec![0; pitch * height];
+    if info.validate_fb(fb.len(), pitch, bpp).is_err() {
+        return;
+    }
+
+    let mut decompressed;
+    let buf = if info.compression > 0 {
+        decompressed = vec![0; info.length as usize];
+        if blit::decompress(&info, &input.payload, &mut decompressed).is_err() {
+            return;
+        }
+        &decompressed
+    } else {
+        &input.payload
+    };
+    let _ = blit::blit(&info, buf, &mut fb, pitch, bpp);
+});
@@ -0,0 +1,10 @@
+#![no_main]
+
+use gud_gadget::protocol::{StateRequest, WireFormat};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(state) = StateRequest::from_bytes(data) {
+        assert_eq!(StateRequest::from_bytes(&state.to_bytes()).unwrap(), state);
+    }
+});
@@ -0,0 +1,684 @@
+//! Getting received pixel data into a framebuffer.
+//!
+//! These work on plain slices so they can be used (and fuzzed) independently of the endpoint.
+
+use std::ops::Range;
+
+use crate::protocol::PixelFormat;
+use crate::{DisplayMode, ProtocolError, SetBuffer};
+
+mod lut;
+#[cfg(all(feature = "simd", target_arch = "aarch64"))]
+mod simd;
+
+pub use lut::{correct, Lut};
+
+/// Decompresses an LZ4 block from `src` into `dst`, which must hold at least `info.length` bytes.
+pub fn decompress(info: &SetBuffer, src: &[u8], dst: &mut [u8]) -> Result<(), ProtocolError> {
+    let length = info.length as usize;
+    if length > i32::MAX as usize || dst.len() < length {
+        return Err(ProtocolError::LengthMismatch {
+            length,
+            expected: dst.len(),
+        });
+    }
+    let decompressed = lz4::block::decompress_to_buffer(src, Some(length as i32), dst)
+        .map_err(ProtocolError::Decompress)?;
+    if decompressed != length {
+        return Err(ProtocolError::LengthMismatch {
+            length: decompressed,
+            expected: length,
+        });
+    }
+    Ok(())
+}
+
+/// The bytes of `fb` the damage rect described by `info` covers, if they're all its own: the rect
+/// spans whole lines of a framebuffer whose lines have no padding, as they are in packed pixel
+/// data with lines of `line_len` bytes. The pixel data can then be written there as is, e.g.
+/// decompressed straight into it.
+pub fn contiguous_span(
+    info: &SetBuffer,
+    fb_len: usize,
+    fb_pitch: usize,
+    line_len: usize,
+) -> Option<Range<usize>> {
+    let length = info.length as usize;
+    if info.x != 0 || line_len == 0 || line_len != fb_pitch {
+        return None;
+    }
+    if Some(length) != line_len.checked_mul(info.height as usize) {
+        return None;
+    }
+    let start = (info.y as usize).checked_mul(fb_pitch)?;
+    let end = start.checked_add(length).filter(|&end| end <= fb_len)?;
+    Some(start..end)
+}
+
+/// Copies the damage rect described by `info` from the packed pixel data in `buf` into `fb`.
+pub fn blit(
+    info: &SetBuffer,
+    buf: &[u8],
+    fb: &mut [u8],
+    fb_pitch: usize,
+    bpp: usize,
+) -> Result<(), ProtocolError> {
+    info.validate_fb(fb.len(), fb_pitch, bpp)?;
+    if buf.len() < info.length as usize {
+        return Err(ProtocolError::LengthMismatch {
+            length: buf.len(),
+            expected: info.length as usize,
+        });
+    }
+
+    let line_len = info.width as usize * bpp;
+    let line_start = info.x as usize * bpp;
+    if line_len == 0 {
+        return Ok(());
+    }
+
+    let lines = buf.chunks_exact(line_len).take(info.height as usize);
+    for (y, line) in (info.y as usize..).zip(lines) {
+        let fb_start = y * fb_pitch + line_start;
+        fb[fb_start..fb_start + line_len].copy_from_slice(line);
+    }
+    Ok(())
+}
+
+/// Takes a damage rect one line at a time, for displays that aren't one linear mapping, such as
+/// tiled or bank-switched memory or a panel streamed over SPI.
+///
+/// Implemented for closures, so `|y, row| { ... }` can be passed wherever a sink is expected.
+pub trait RowSink {
+    /// Takes line `y` of the display, `row` being the damage rect's packed pixels on it, starting
+    /// at its `x`.
+    fn row(&mut self, y: usize, row: &[u8]);
+}
+
+impl<F: FnMut(usize, &[u8])> RowSink for F {
+    fn row(&mut self, y: usize, row: &[u8]) {
+        self(y, row)
+    }
+}
+
+/// Like [`blit`], but hands the damage rect's lines to `sink` top to bottom instead of copying
+/// them into a framebuffer.
+pub fn blit_rows(
+    info: &SetBuffer,
+    buf: &[u8],
+    bpp: usize,
+    sink: &mut impl RowSink,
+) -> Result<(), ProtocolError> {
+    info.validate_length(bpp)?;
+    if buf.len() < info.length as usize {
+        return Err(ProtocolError::LengthMismatch {
+            length: buf.len(),
+            expected: info.length as usize,
+        });
+    }
+
+    let line_len = info.width as usize * bpp;
+    if line_len == 0 {
+        return Ok(());
+    }
+
+    let lines = buf.chunks_exact(line_len).take(info.height as usize);
+    for (y, line) in (info.y as usize..).zip(lines) {
+        sink.row(y, line);
+    }
+    Ok(())
+}
+
+/// Channel reordering applied to pixels as they're written to the framebuffer, for panels that
+/// don't take the usual little-endian RGB layout.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Swizzle {
+    /// Writes 16-bit pixels big-endian. Only supported for RGB565.
+    pub swap16: bool,
+    /// Swaps the red and blue channels, i.e. writes BGR.
+    pub swap_rb: bool,
+}
+
+impl Swizzle {
+    pub const NONE: Swizzle = Swizzle {
+        swap16: false,
+        swap_rb: false,
+    };
+    pub const SWAP16: Swizzle = Swizzle {
+        swap16: true,
+        swap_rb: false,
+    };
+    pub const SWAP_RB: Swizzle = Swizzle {
+        swap16: false,
+        swap_rb: true,
+    };
+}
+
+/// Like [`blit`], converting the pixel data from the host's `format` to the framebuffer's
+/// `fb_format` and applying `swizzle` on the way.
+///
+/// Conversions are supported between R8, RGB332, RGB565, RGB888, XRGB8888 and ARGB8888, and from
+/// XRGB1111. Alpha is kept between ARGB8888 framebuffers, and written as opaque into XRGB8888
+/// ones. Colors are converted to R8 greyscale the way the kernel's GUD driver does it.
+pub fn blit_convert(
+    info: &SetBuffer,
+    format: PixelFormat,
+    buf: &[u8],
+    fb: &mut [u8],
+    fb_pitch: usize,
+    fb_format: PixelFormat,
+    swizzle: Swizzle,
+) -> Result<(), ProtocolError> {
+    let unsupported = || ProtocolError::UnsupportedConversion {
+        from: format.into(),
+        to: fb_format.into(),
+    };
+    let fb_bpp = fb_format.bytes_per_pixel().ok_or_else(unsupported)?;
+    if format == fb_format && swizzle == Swizzle::NONE {
+        return blit(info, buf, fb, fb_pitch, fb_bpp);
+    }
+
+    info.validate_format_length(format)?;
+    info.validate_fb_bounds(fb.len(), fb_pitch, fb_bpp)?;
+    if buf.len() < info.length as usize {
+        return Err(ProtocolError::LengthMismatch {
+            length: buf.len(),
+            expected: info.length as usize,
+        });
+    }
+
+    let line_len = format.line_len(info.width as usize);
+    let fb_line_len = info.width as usize * fb_bpp;
+    let fb_line_start = info.x as usize * fb_bpp;
+    if line_len == 0 {
+        return Ok(());
+    }
+
+    let lines = buf.chunks_exact(line_len).take(info.height as usize);
+    for (y, line) in (info.y as usize..).zip(lines) {
+        let fb_start = y * fb_pitch + fb_line_start;
+        convert_line(
+            format,
+            line,
+            fb_format,
+            swizzle,
+            &mut fb[fb_start..fb_start + fb_line_len],
+        )
+        .ok_or_else(unsupported)?;
+    }
+    Ok(())
+}
+
+/// How pixels are sampled when scaling.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Filter {
+    #[default]
+    Nearest,
+    Bilinear,
+}
+
+/// Scales frames to a framebuffer of a fixed size, whatever mode the host committed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Scale {
+    pub width: u32,
+    pub height: u32,
+    pub filter: Filter,
+}
+
+/// A fixed orientation applied on the way to the framebuffer, for panels that are mounted
+/// sideways or upside down. The host doesn't know about it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Transform {
+    /// Clockwise quarter turns, 0 to 3.
+    pub quarter_turns: u8,
+    /// Mirrors the frame horizontally, before it's rotated.
+    pub flip: bool,
+}
+
+impl Transform {
+    pub const IDENTITY: Transform = Transform {
+        quarter_turns: 0,
+        flip: false,
+    };
+
+    /// A clockwise rotation by `degrees`, if it's a multiple of 90.
+    pub fn rotate(degrees: u32) -> Option<Transform> {
+        degrees.is_multiple_of(90).then_some(Transform {
+            quarter_turns: (degrees / 90 % 4) as u8,
+            flip: false,
+        })
+    }
+
+    /// Whether width and height trade places.
+    pub fn swaps_axes(self) -> bool {
+        self.quarter_turns % 2 == 1
+    }
+
+    /// The size of a `width` x `height` frame once it's transformed.
+    pub fn apply_size(self, width: u32, height: u32) -> (u32, u32) {
+        match self.swaps_axes() {
+            true => (height, width),
+            false => (width, height),
+        }
+    }
+
+    /// Undoes the transform, taking the framebuffer back to the way the host sees it.
+    pub fn inverse(self) -> Transform {
+        match self.flip {
+            // A mirror image turned one way is the same as one turned back the other.
+            true => self,
+            false => Transform {
+                quarter_turns: (4 - self.quarter_turns % 4) % 4,
+                flip: false,
+            },
+        }
+    }
+
+    /// A mode of the panel as the host sees it, with the horizontal and vertical timings swapped
+    /// if it's turned sideways.
+    pub fn apply_mode(self, mode: &DisplayMode) -> DisplayMode {
+        match self.swaps_axes() {
+            true => DisplayMode {
+                hdisplay: mode.vdisplay,
+                hsync_start: mode.vsync_start,
+                hsync_end: mode.vsync_end,
+                htotal: mode.vtotal,
+                vdisplay: mode.hdisplay,
+                vsync_start: mode.hsync_start,
+                vsync_end: mode.hsync_end,
+                vtotal: mode.htotal,
+                ..mode.clone()
+            },
+            false => mode.clone(),
+        }
+    }
+
+    /// Where pixel `(x, y)` of a `width` x `height` frame ends up.
+    pub fn apply(self, x: u32, y: u32, width: u32, height: u32) -> (u32, u32) {
+        let x = match self.flip {
+            true => width - 1 - x,
+            false => x,
+        };
+        match self.quarter_turns % 4 {
+            0 => (x, y),
+            1 => (height - 1 - y, x),
+            2 => (width - 1 - x, height - 1 - y),
+            _ => (y, width - 1 - x),
+        }
+    }
+}
+
+/// Like [`blit_convert`], scaling the damage rect from the host's `mode` to the framebuffer size
+/// given by `scale`.
+///
+/// Only the damage rect is available, so bilinear filtering clamps to its edges rather than
+/// sampling the neighbouring pixels outside it.
+#[allow(clippy::too_many_arguments)]
+pub fn blit_scaled(
+    info: &SetBuffer,
+    mode: &DisplayMode,
+    format: PixelFormat,
+    buf: &[u8],
+    fb: &mut [u8],
+    fb_pitch: usize,
+    fb_format: PixelFormat,
+    swizzle: Swizzle,
+    scale: Scale,
+) -> Result<(), ProtocolError> {
+    let (src_width, src_height) = (mode.hdisplay as u32, mode.vdisplay as u32);
+    if (src_width, src_height) == (scale.width, scale.height) {
+        return blit_convert(info, format, buf, fb, fb_pitch, fb_format, swizzle);
+    }
+
+    let Some((scaled, pixels)) = scale_rect(info, mode, format, buf, fb_format, scale)? else {
+        return Ok(());
+    };
+    write_rect(&scaled, &pixels, fb, fb_pitch, fb_format, swizzle)
+}
+
+/// Like [`blit_scaled`], turning or flipping the frame with `transform` on the way. `scale` is
+/// the size of the framebuffer, so the host's mode is scaled to its transformed size.
+#[allow(clippy::too_many_arguments)]
+pub fn blit_transformed(
+    info: &SetBuffer,
+    mode: &DisplayMode,
+    format: PixelFormat,
+    buf: &[u8],
+    fb: &mut [u8],
+    fb_pitch: usize,
+    fb_format: PixelFormat,
+    swizzle: Swizzle,
+    scale: Scale,
+    transform: Transform,
+) -> Result<(), ProtocolError> {
+    if transform == Transform::IDENTITY {
+        return blit_scaled(
+            info, mode, format, buf, fb, fb_pitch, fb_format, swizzle, scale,
+        );
+    }
+
+    // Scale to the framebuffer as the host sees it, then move the pixels into place.
+    let (width, height) = transform.apply_size(scale.width, scale.height);
+    let upright = Scale {
+        width,
+        height,
+        ..scale
+    };
+    let Some((scaled, pixels)) = scale_rect(info, mode, format, buf, fb_format, upright)? else {
+        return Ok(());
+    };
+    let xs = scaled.x..scaled.x + scaled.width;
+    let ys = scaled.y..scaled.y + scaled.height;
+    let (x0, y0) = transform.apply(xs.start, ys.start, width, height);
+    let (x1, y1) = transform.apply(xs.end - 1, ys.end - 1, width, height);
+    let rect = SetBuffer {
+        x: x0.min(x1),
+        y: y0.min(y1),
+        width: x0.abs_diff(x1) + 1,
+        height: y0.abs_diff(y1) + 1,
+        ..*info
+    };
+
+    let mut transformed = vec![0; pixels.len()];
+    let lines = ys.zip(pixels.chunks_exact(xs.len() * 4));
+    for (y, line) in lines {
+        for (x, pixel) in xs.clone().zip(line.chunks_exact(4)) {
+            let (x, y) = transform.apply(x, y, width, height);
+            let i = ((y - rect.y) as usize * rect.width as usize + (x - rect.x) as usize) * 4;
+            transformed[i..i + 4].copy_from_slice(pixel);
+        }
+    }
+    write_rect(&rect, &transformed, fb, fb_pitch, fb_format, swizzle)
+}
+
+// Scales the damage rect from `mode` to `scale`, returning the framebuffer rect it covers along
+// with its ARGB8888 pixels there, or `None` if it covers nothing.
+fn scale_rect(
+    info: &SetBuffer,
+    mode: &DisplayMode,
+    format: PixelFormat,
+    buf: &[u8],
+    fb_format: PixelFormat,
+    scale: Scale,
+) -> Result<Option<(SetBuffer, Vec<u8>)>, ProtocolError> {
+    let (src_width, src_height) = (mode.hdisplay as u32, mode.vdisplay as u32);
+    let unsupported = || ProtocolError::UnsupportedConversion {
+        from: format.into(),
+        to: fb_format.into(),
+    };
+    fb_format.bytes_per_pixel().ok_or_else(unsupported)?;
+    info.validate(mode)?;
+    info.validate_format_length(format)?;
+    if buf.len() < info.length as usize {
+        return Err(ProtocolError::LengthMismatch {
+            length: buf.len(),
+            expected: info.length as usize,
+        });
+    }
+
+    if info.width == 0 || info.height == 0 || scale.width == 0 || scale.height == 0 {
+        return Ok(None);
+    }
+
+    let xs = scaled_range(info.x, info.width, src_width, scale.width);
+    let ys = scaled_range(info.y, info.height, src_height, scale.height);
+    if xs.is_empty() || ys.is_empty() {
+        return Ok(None);
+    }
+
+    // Decode the damage rect to ARGB8888 up front, so sampling only deals with one format.
+    let width = info.width as usize;
+    let mut rgb = vec![0; width * info.height as usize * 4];
+    let lines = buf
+        .chunks_exact(format.line_len(width))
+        .zip(rgb.chunks_exact_mut(width * 4));
+    for (src, dst) in lines {
+        convert_line(format, src, PixelFormat::Argb8888, Swizzle::NONE, dst)
+            .ok_or_else(unsupported)?;
+    }
+    let pixel = |x: usize, y: usize| -> [u8; 4] {
+        let i = (y * width + x) * 4;
+        rgb[i..i + 4].try_into().unwrap()
+    };
+
+    let line_len = xs.len() * 4;
+    let mut pixels = vec![0; line_len * ys.len()];
+    let lines = ys.clone().zip(pixels.chunks_exact_mut(line_len));
+    match scale.filter {
+        Filter::Nearest => {
+            let columns: Vec<usize> = xs
+                .clone()
+                .map(|x| (nearest(x, src_width, scale.width) - info.x) as usize)
+                .collect();
+            for (y, line) in lines {
+                let row = (nearest(y, src_height, scale.height) - info.y) as usize;
+                for (dst, &column) in line.chunks_exact_mut(4).zip(&columns) {
+                    dst.copy_from_slice(&pixel(column, row));
+                }
+            }
+        }
+        Filter::Bilinear => {
+            let columns: Vec<_> = xs
+                .clone()
+                .map(|x| bilinear(x, src_width, scale.width, info.x, info.width))
+                .collect();
+            for (y, line) in lines {
+                let (y0, y1, fy) = bilinear(y, src_height, scale.height, info.y, info.height);
+                for (dst, &(x0, x1, fx)) in line.chunks_exact_mut(4).zip(&columns) {
+                    let (a, b) = (pixel(x0, y0), pixel(x1, y0));
+                    let (c, d) = (pixel(x0, y1), pixel(x1, y1));
+                    for i in 0..4 {
+                        let top = a[i] as u32 * (256 - fx) + b[i] as u32 * fx;
+                        let bottom = c[i] as u32 * (256 - fx) + d[i] as u32 * fx;
+                        dst[i] = ((top * (256 - fy) + bottom * fy) >> 16) as u8;
+                    }
+                }
+            }
+        }
+    }
+    let scaled = SetBuffer {
+        x: xs.start,
+        y: ys.start,
+        width: xs.len() as u32,
+        height: ys.len() as u32,
+        ..*info
+    };
+    Ok(Some((scaled, pixels)))
+}
+
+// Converts ARGB8888 `pixels` covering `rect` into the framebuffer.
+fn write_rect(
+    rect: &SetBuffer,
+    pixels: &[u8],
+    fb: &mut [u8],
+    fb_pitch: usize,
+    fb_format: PixelFormat,
+    swizzle: Swizzle,
+) -> Result<(), ProtocolError> {
+    let unsupported = || ProtocolError::UnsupportedConversion {
+        from: PixelFormat::Argb8888.into(),
+        to: fb_format.into(),
+    };
+    let fb_bpp = fb_format.bytes_per_pixel().ok_or_else(unsupported)?;
+    rect.validate_fb_bounds(fb.len(), fb_pitch, fb_bpp)?;
+    let fb_line_start = rect.x as usize * fb_bpp;
+    let fb_line_len = rect.width as usize * fb_bpp;
+    let lines = pixels.chunks_exact(rect.width as usize * 4);
+    for (y, line) in (rect.y as usize..).zip(lines) {
+        let fb_start = y * fb_pitch + fb_line_start;
+        let fb_line = &mut fb[fb_start..fb_start + fb_line_len];
+        convert_line(PixelFormat::Argb8888, line, fb_format, swizzle, fb_line)
+            .ok_or_else(unsupported)?;
+    }
+    Ok(())
+}
+
+// The source coordinate sampled for the centre of destination pixel `dst`.
+fn nearest(dst: u32, src_len: u32, dst_len: u32) -> u32 {
+    ((2 * dst as u64 + 1) * src_len as u64 / (2 * dst_len as u64)) as u32
+}
+
+// The destination pixels whose centres sample a source pixel in `start..start + len`.
+fn scaled_range(start: u32, len: u32, src_len: u32, dst_len: u32) -> Range<u32> {
+    // The smallest destination pixel sampling at or after `src`.
+    let first = |src: u32| {
+        let mut dst = (src as u64 * dst_len as u64 / src_len as u64) as u32;
+        while dst > 0 && nearest(dst - 1, src_len, dst_len) >= src {
+            dst -= 1;
+        }
+        while dst < dst_len && nearest(dst, src_len, dst_len) < src {
+            dst += 1;
+        }
+        dst
+    };
+    first(start)..first(start + len)
+}
+
+// The two source pixels (relative to the damage rect at `start..start + len`) to interpolate
+// between for destination pixel `dst`, and the weight of the second in 1/256ths.
+fn bilinear(dst: u32, src_len: u32, dst_len: u32, start: u32, len: u32) -> (usize, usize, u32) {
+    // Centre of the destination pixel in source coordinates, in 1/256ths of a pixel.
+    let centre = (2 * dst as i64 + 1) * src_len as i64 * 128 / dst_len as i64 - 128;
+    let (lo, hi) = (start as i64 * 256, (start + len - 1) as i64 * 256);
+    let centre = centre.clamp(lo, hi) - lo;
+    let first = (centre >> 8) as usize;
+    let second = (first + 1).min(len as usize - 1);
+    (first, second, (centre & 0xff) as u32)
+}
+
+// Converts a line of pixels, or returns `None` if the conversion isn't supported.
+fn convert_line(
+    from: PixelFormat,
+    src: &[u8],
+    to: PixelFormat,
+    swizzle: Swizzle,
+    dst: &mut [u8],
+) -> Option<()> {
+    #[cfg(all(feature = "simd", target_arch = "aarch64"))]
+    let (src, dst) = {
+        let done = match (from, to) {
+            (PixelFormat::Xrgb8888 | PixelFormat::Argb8888, PixelFormat::Rgb565) => {
+                simd::xrgb8888_to_rgb565(src, dst, swizzle)
+            }
+            (PixelFormat::Rgb565, PixelFormat::Rgb565) if swizzle == Swizzle::SWAP16 => {
+                simd::swap16(src, dst)
+            }
+            _ => 0,
+        };
+        // Both write RGB565.
+        (
+            &src[done * from.bits_per_pixel() / 8..],
+            &mut dst[done * 2..],
+        )
+    };
+    match from {
+        PixelFormat::Xrgb1111 => {
+            // Unpacked to a pixel a byte first, there's no splitting a byte between pixels.
+            let unpacked: Vec<u8> = src.iter().flat_map(|&p| [p >> 4, p & 0xf]).collect();
+            convert_from(&unpacked, to, swizzle, dst, |[p]: [u8; 1]| {
+                let channel = |bit: u8| 0u8.wrapping_sub((p >> bit) & 1);
+                [channel(2), channel(1), channel(0), 0xff]
+            })
+        }
+        PixelFormat::R8 => convert_from(src, to, swizzle, dst, |[v]: [u8; 1]| [v, v, v, 0xff]),
+        PixelFormat::Rgb332 => convert_from(src, to, swizzle, dst, |[p]: [u8; 1]| {
+            let (r, g, b) = (p >> 5, (p >> 2) & 0x7, p & 0x3);
+            [
+                (r << 5) | (r << 2) | (r >> 1),
+                (g << 5) | (g << 2) | (g >> 1),
+                b * 0x55,
+                0xff,
+            ]
+        }),
+        PixelFormat::Rgb565 => convert_from(src, to, swizzle, dst, |[lo, hi]: [u8; 2]| {
+            let v = u16::from_le_bytes([lo, hi]);
+            let (r, g, b) = ((v >> 11) as u8, (v >> 5) as u8 & 0x3f, v as u8 & 0x1f);
+            [
+                (r << 3) | (r >> 2),
+                (g << 2) | (g >> 4),
+                (b << 3) | (b >> 2),
+                0xff,
+            ]
+        }),
+        PixelFormat::Rgb888 => {
+            convert_from(src, to, swizzle, dst, |[b, g, r]: [u8; 3]| [r, g, b, 0xff])
+        }
+        PixelFormat::Xrgb8888 => convert_from(src, to, swizzle, dst, |[b, g, r, _]: [u8; 4]| {
+            [r, g, b, 0xff]
+        }),
+        PixelFormat::Argb8888 => {
+            convert_from(src, to, swizzle, dst, |[b, g, r, a]: [u8; 4]| [r, g, b, a])
+        }
+        _ => None,
+    }
+}
+
+// Converts pixels of `N` bytes, decoded to RGBA by `decode`, into `to`.
+fn convert_from<const N: usize>(
+    src: &[u8],
+    to: PixelFormat,
+    swizzle: Swizzle,
+    dst: &mut [u8],
+    decode: impl Fn([u8; N]) -> [u8; 4],
+) -> Option<()> {
+    if swizzle.swap_rb {
+        let decode = |p| {
+            let [r, g, b, a] = decode(p);
+            [b, g, r, a]
+        };
+        convert_to(src, to, swizzle.swap16, dst, decode)
+    } else {
+        convert_to(src, to, swizzle.swap16, dst, decode)
+    }
+}
+
+fn convert_to<const N: usize>(
+    src: &[u8],
+    to: PixelFormat,
+    swap16: bool,
+    dst: &mut [u8],
+    decode: impl Fn([u8; N]) -> [u8; 4],
+) -> Option<()> {
+    let rgb565 = |p| {
+        let [r, g, b, _] = decode(p);
+        (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3
+    };
+    match (to, swap16) {
+        (PixelFormat::R8, false) => convert_pixels(src, dst, |p| {
+            let [r, g, b, _] = decode(p);
+            [((3 * r as u16 + 6 * g as u16 + b as u16) / 10) as u8]
+        }),
+        (PixelFormat::Rgb332, false) => convert_pixels(src, dst, |p| {
+            let [r, g, b, _] = decode(p);
+            [(r & 0xe0) | ((g >> 5) << 2) | (b >> 6)]
+        }),
+        (PixelFormat::Rgb565, false) => convert_pixels(src, dst, |p| rgb565(p).to_le_bytes()),
+        (PixelFormat::Rgb565, true) => convert_pixels(src, dst, |p| rgb565(p).to_be_bytes()),
+        (PixelFormat::Rgb888, false) => convert_pixels(src, dst, |p| {
+            let [r, g, b, _] = decode(p);
+            [b, g, r]
+        }),
+        (PixelFormat::Xrgb8888, false) => convert_pixels(src, dst, |p| {
+            let [r, g, b, _] = decode(p);
+            [b, g, r, 0xff]
+        }),
+        (PixelFormat::Argb8888, false) => convert_pixels(src, dst, |p| {
+            let [r, g, b, a] = decode(p);
+            [b, g, r, a]
+        }),
+        _ => return None,
+    }
+    Some(())
+}
+
+// Kept free of per-pixel branches so the compiler can vectorize it.
+fn convert_pixels<const N: usize, const M: usize>(
+    src: &[u8],
+    dst: &mut [u8],
+    convert: impl Fn([u8; N]) -> [u8; M],
+) {
+    for (src, dst) in src.chunks_exact(N).zip(dst.chunks_exact_mut(M)) {
+        let pixel = convert(src.try_into().unwrap());
+        dst.copy_from_slice(&pixel);
+    }
+}
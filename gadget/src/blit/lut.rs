@@ -0,0 +1,90 @@
+//! Color correction for panels whose response is off, applied to pixel data as it's received.
+
+use crate::protocol::PixelFormat;
+
+/// A lookup table per color channel, mapping each 8-bit value the host sends to the one to show.
+///
+/// RGB565 pixels, the most common on cheap panels, are looked up whole in a table built from the
+/// channel tables, so correcting them costs a load per pixel.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Lut {
+    red: [u8; 256],
+    green: [u8; 256],
+    blue: [u8; 256],
+    rgb565: Box<[u16]>,
+}
+
+impl Lut {
+    pub fn new(red: [u8; 256], green: [u8; 256], blue: [u8; 256]) -> Self {
+        let rgb565 = (0..=u16::MAX)
+            .map(|v| {
+                let (r, g, b) = ((v >> 11) as u8, (v >> 5) as u8 & 0x3f, v as u8 & 0x1f);
+                let r = red[((r << 3) | (r >> 2)) as usize];
+                let g = green[((g << 2) | (g >> 4)) as usize];
+                let b = blue[((b << 3) | (b >> 2)) as usize];
+                (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3
+            })
+            .collect();
+        Self {
+            red,
+            green,
+            blue,
+            rgb565,
+        }
+    }
+
+    /// The same table for all three channels.
+    pub fn uniform(table: [u8; 256]) -> Self {
+        Self::new(table, table, table)
+    }
+
+    /// Raises each channel to the power of `gamma`: above 1 darkens the midtones of a panel
+    /// that shows them washed out, below 1 brightens them.
+    pub fn gamma(gamma: f32) -> Self {
+        Self::uniform(std::array::from_fn(|v| {
+            ((v as f32 / 255.0).powf(gamma) * 255.0).round() as u8
+        }))
+    }
+
+    /// Corrects an RGB color.
+    pub fn apply(&self, [r, g, b]: [u8; 3]) -> [u8; 3] {
+        [
+            self.red[r as usize],
+            self.green[g as usize],
+            self.blue[b as usize],
+        ]
+    }
+}
+
+/// Corrects packed `pixels` in the GUD pixel `format` in place. R8 greyscale takes the green
+/// table, and the 1-bit formats are left alone.
+pub fn correct(format: PixelFormat, lut: &Lut, pixels: &mut [u8]) {
+    match format {
+        PixelFormat::R1 | PixelFormat::Xrgb1111 => {}
+        PixelFormat::R8 => pixels.iter_mut().for_each(|v| *v = lut.green[*v as usize]),
+        PixelFormat::Rgb332 => {
+            for p in pixels {
+                let (r, g, b) = (*p >> 5, (*p >> 2) & 0x7, *p & 0x3);
+                let [r, g, b] = lut.apply([
+                    (r << 5) | (r << 2) | (r >> 1),
+                    (g << 5) | (g << 2) | (g >> 1),
+                    b * 0x55,
+                ]);
+                *p = (r & 0xe0) | ((g >> 5) << 2) | (b >> 6);
+            }
+        }
+        PixelFormat::Rgb565 => {
+            for p in pixels.chunks_exact_mut(2) {
+                let v = u16::from_le_bytes([p[0], p[1]]);
+                p.copy_from_slice(&lut.rgb565[v as usize].to_le_bytes());
+            }
+        }
+        PixelFormat::Rgb888 | PixelFormat::Xrgb8888 | PixelFormat::Argb8888 => {
+            let bpp = format.bits_per_pixel() / 8;
+            for p in pixels.chunks_exact_mut(bpp) {
+                let [r, g, b] = lut.apply([p[2], p[1], p[0]]);
+                p[..3].copy_from_slice(&[b, g, r]);
+            }
+        }
+    }
+}
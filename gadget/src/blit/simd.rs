@@ -0,0 +1,58 @@
+//! NEON kernels for the conversions that dominate on Cortex-A53 class SoCs, where the compiler's
+//! auto-vectorization of the scalar loops falls short. Each handles whole blocks of 16 pixels and
+//! returns how many pixels it did, the scalar path finishes the line.
+
+use std::arch::aarch64::*;
+
+use super::Swizzle;
+
+const BLOCK: usize = 16;
+
+/// XRGB8888 (or ARGB8888) to RGB565.
+pub fn xrgb8888_to_rgb565(src: &[u8], dst: &mut [u8], swizzle: Swizzle) -> usize {
+    let pixels = (src.len() / 4).min(dst.len() / 2) / BLOCK * BLOCK;
+    for i in (0..pixels).step_by(BLOCK) {
+        // SAFETY: NEON is always there on aarch64, and the block is in bounds of both slices.
+        unsafe {
+            let bgrx = vld4q_u8(src.as_ptr().add(i * 4));
+            let (r, b) = match swizzle.swap_rb {
+                true => (bgrx.0, bgrx.2),
+                false => (bgrx.2, bgrx.0),
+            };
+            let g = bgrx.1;
+            let lo = pack565(vget_low_u8(r), vget_low_u8(g), vget_low_u8(b));
+            let hi = pack565(vget_high_u8(r), vget_high_u8(g), vget_high_u8(b));
+            let (lo, hi) = match swizzle.swap16 {
+                true => (vrev16q_u8(lo), vrev16q_u8(hi)),
+                false => (lo, hi),
+            };
+            let out = dst.as_mut_ptr().add(i * 2);
+            vst1q_u8(out, lo);
+            vst1q_u8(out.add(16), hi);
+        }
+    }
+    pixels
+}
+
+/// RGB565 to byte-swapped RGB565.
+pub fn swap16(src: &[u8], dst: &mut [u8]) -> usize {
+    let pixels = src.len().min(dst.len()) / 2 / BLOCK * BLOCK;
+    for i in (0..pixels).step_by(BLOCK) {
+        // SAFETY: as above.
+        unsafe {
+            let v = vld1q_u8_x2(src.as_ptr().add(i * 2));
+            let out = dst.as_mut_ptr().add(i * 2);
+            vst1q_u8(out, vrev16q_u8(v.0));
+            vst1q_u8(out.add(16), vrev16q_u8(v.1));
+        }
+    }
+    pixels
+}
+
+// Packs 8 pixels' channels into little-endian RGB565, keeping the top bits of each.
+#[inline(always)]
+unsafe fn pack565(r: uint8x8_t, g: uint8x8_t, b: uint8x8_t) -> uint8x16_t {
+    let rgb = vsriq_n_u16::<5>(vshll_n_u8::<8>(r), vshll_n_u8::<8>(g));
+    let rgb = vsriq_n_u16::<11>(rgb, vshll_n_u8::<8>(b));
+    vreinterpretq_u8_u16(rgb)
+}
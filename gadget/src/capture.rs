@@ -0,0 +1,198 @@
+//! Recording received frames to disk, for regression tests of the receive pipeline or as a
+//! lossless recorder of the host's output.
+//!
+//! Frames only carry a damage rect, so they're composited into a persistent [`Canvas`] first and
+//! every frame written out is the whole screen.
+
+use anyhow::Context;
+use std::io::Write;
+#[cfg(feature = "png")]
+use std::path::PathBuf;
+
+use crate::blit::{blit_convert, Swizzle};
+use crate::protocol::PixelFormat;
+use crate::{Frame, FrameSink, ProtocolError};
+
+/// The whole screen in XRGB8888, kept up to date from damage rects.
+#[derive(Debug, Clone)]
+pub struct Canvas {
+    width: u32,
+    height: u32,
+    pixels: Vec<u8>,
+}
+
+impl Canvas {
+    /// A black canvas.
+    pub fn new(width: u32, height: u32) -> Self {
+        Self {
+            width,
+            height,
+            pixels: vec![0; width as usize * height as usize * 4],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Bytes per row of [`pixels`](Self::pixels).
+    pub fn pitch(&self) -> usize {
+        self.width as usize * 4
+    }
+
+    /// XRGB8888 pixels, little-endian.
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    /// Copies the frame's damage rect onto the canvas, converting it to XRGB8888.
+    pub fn draw(&mut self, frame: &Frame) -> Result<(), ProtocolError> {
+        let format =
+            PixelFormat::from_u8(frame.format).ok_or(ProtocolError::UnsupportedConversion {
+                from: frame.format,
+                to: PixelFormat::Xrgb8888.into(),
+            })?;
+        let pitch = self.pitch();
+        blit_convert(
+            &frame.info,
+            format,
+            &frame.data,
+            &mut self.pixels,
+            pitch,
+            PixelFormat::Xrgb8888,
+            Swizzle::NONE,
+        )
+    }
+}
+
+/// Writes a YUV4MPEG2 stream with a frame of the whole canvas per received frame.
+///
+/// Chroma isn't subsampled (`C444`) and the range is full, so little is lost converting from RGB.
+/// Frames are written at a nominal rate since the host only sends when the screen changes.
+pub struct Y4mWriter<W: Write> {
+    out: W,
+    canvas: Canvas,
+    fps: u32,
+    header_written: bool,
+    // The Y, U and V planes of the frame being written.
+    planes: Vec<u8>,
+}
+
+impl<W: Write> Y4mWriter<W> {
+    pub fn new(out: W, width: u32, height: u32, fps: u32) -> Self {
+        Self {
+            out,
+            canvas: Canvas::new(width, height),
+            fps,
+            header_written: false,
+            planes: Vec::new(),
+        }
+    }
+
+    pub fn canvas(&self) -> &Canvas {
+        &self.canvas
+    }
+
+    /// Writes the canvas as a frame, without drawing anything new on it.
+    pub fn write_frame(&mut self) -> anyhow::Result<()> {
+        if !self.header_written {
+            writeln!(
+                self.out,
+                "YUV4MPEG2 W{} H{} F{}:1 Ip A1:1 C444 XCOLORRANGE=FULL",
+                self.canvas.width, self.canvas.height, self.fps
+            )
+            .context("write Y4M header")?;
+            self.header_written = true;
+        }
+
+        let len = self.canvas.pixels.len() / 4;
+        self.planes.resize(len * 3, 0);
+        let (y_plane, chroma) = self.planes.split_at_mut(len);
+        let (u_plane, v_plane) = chroma.split_at_mut(len);
+        for (i, pixel) in self.canvas.pixels.chunks_exact(4).enumerate() {
+            let [b, g, r] = [pixel[0], pixel[1], pixel[2]].map(i32::from);
+            // BT.601, full range.
+            y_plane[i] = ((77 * r + 150 * g + 29 * b + 128) >> 8) as u8;
+            u_plane[i] = (((-43 * r - 85 * g + 128 * b + 128) >> 8) + 128).clamp(0, 255) as u8;
+            v_plane[i] = (((128 * r - 107 * g - 21 * b + 128) >> 8) + 128).clamp(0, 255) as u8;
+        }
+        self.out.write_all(b"FRAME\n").context("write Y4M frame")?;
+        self.out
+            .write_all(&self.planes)
+            .context("write Y4M frame")?;
+        Ok(())
+    }
+
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+}
+
+impl<W: Write> FrameSink for Y4mWriter<W> {
+    fn frame(&mut self, frame: Frame) -> anyhow::Result<()> {
+        self.canvas.draw(&frame)?;
+        self.write_frame()
+    }
+}
+
+/// Writes the whole canvas to a numbered PNG per received frame, `frame-000000.png` onwards.
+#[cfg(feature = "png")]
+pub struct PngWriter {
+    dir: PathBuf,
+    canvas: Canvas,
+    index: u64,
+}
+
+#[cfg(feature = "png")]
+impl PngWriter {
+    /// Writes into `dir`, which must exist.
+    pub fn new(dir: impl Into<PathBuf>, width: u32, height: u32) -> Self {
+        Self {
+            dir: dir.into(),
+            canvas: Canvas::new(width, height),
+            index: 0,
+        }
+    }
+
+    pub fn canvas(&self) -> &Canvas {
+        &self.canvas
+    }
+
+    /// Writes the canvas to the next PNG, returning its path.
+    pub fn write_frame(&mut self) -> anyhow::Result<PathBuf> {
+        let path = self.dir.join(format!("frame-{:06}.png", self.index));
+        let file =
+            std::fs::File::create(&path).with_context(|| format!("create {}", path.display()))?;
+        let mut encoder = png::Encoder::new(
+            std::io::BufWriter::new(file),
+            self.canvas.width,
+            self.canvas.height,
+        );
+        encoder.set_color(png::ColorType::Rgb);
+        encoder.set_depth(png::BitDepth::Eight);
+        let mut writer = encoder.write_header().context("write PNG header")?;
+        let rgb: Vec<u8> = self
+            .canvas
+            .pixels
+            .chunks_exact(4)
+            .flat_map(|pixel| [pixel[2], pixel[1], pixel[0]])
+            .collect();
+        writer.write_image_data(&rgb).context("write PNG")?;
+        writer.finish().context("write PNG")?;
+        self.index += 1;
+        Ok(path)
+    }
+}
+
+#[cfg(feature = "png")]
+impl FrameSink for PngWriter {
+    fn frame(&mut self, frame: Frame) -> anyhow::Result<()> {
+        self.canvas.draw(&frame)?;
+        self.write_frame()?;
+        Ok(())
+    }
+}
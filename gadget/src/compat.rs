@@ -0,0 +1,43 @@
+//! The free-standing API this crate had before [`Function`], for code written against it.
+//!
+//! There the dispatcher kept no state of its own. Here each thread dispatches with a
+//! [`Function`] of its own instead, set up with [`with`] before the first event, so
+//! [`event`] answers the host the way a [`Function`] would: states are checked and committed,
+//! and [`Event::Buffer`] only comes once the host has read the buffer's status.
+//!
+//! New code should create a [`Function`] and call [`Function::event`]. That way there's no
+//! hidden state, and more than one function can be dispatched on a thread.
+
+use std::cell::RefCell;
+
+#[cfg(feature = "gadget")]
+use usb_gadget::function::custom::{self, CtrlSender};
+
+use crate::transport::{ControlReceiver, ControlSender, ControlTransfer};
+use crate::{Event, Function};
+
+thread_local! {
+    // One per thread, like the FunctionFS handles the events come from.
+    static FUNCTION: RefCell<Function> = RefCell::new(Function::new());
+}
+
+/// Runs `f` with the calling thread's function, e.g. to set its connectors or compression
+/// before the first event, or to read the committed [`Function::state`].
+///
+/// Panics if called from within `f`.
+pub fn with<T>(f: impl FnOnce(&mut Function) -> T) -> T {
+    FUNCTION.with(|function| f(&mut function.borrow_mut()))
+}
+
+/// Handles a FunctionFS event with the calling thread's function, see [`Function::event`].
+#[cfg(feature = "gadget")]
+pub fn event(event: custom::Event<'_>) -> anyhow::Result<Option<Event<CtrlSender<'_>>>> {
+    with(|function| function.event(event))
+}
+
+/// Handles a control transfer with the calling thread's function, see [`Function::control`].
+pub fn control<S: ControlSender, R: ControlReceiver>(
+    transfer: ControlTransfer<S, R>,
+) -> anyhow::Result<Option<Event<S>>> {
+    with(|function| function.control(transfer))
+}
@@ -0,0 +1,107 @@
+use std::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use std::sync::{Arc, Mutex};
+
+use crate::protocol::{ConnectorStatus, DisplayMode, GUD_CONNECTOR_STATUS_CHANGED};
+
+/// A connector's status, EDID and modes, shared with the [`Function`](crate::Function) that reports
+/// them.
+///
+/// Clones refer to the same connector, so a handle can be moved to another thread (e.g. one
+/// watching for hotplug) and flip the status between polls from the host.
+#[derive(Clone, Debug)]
+pub struct ConnectorHandle(Arc<Shared>);
+
+#[derive(Debug)]
+struct Shared {
+    status: AtomicU8,
+    // Set when the status changes, cleared once it's been reported to the host.
+    changed: AtomicBool,
+    edid: Mutex<Vec<u8>>,
+    // Modes reported without asking the application, once it's set some.
+    modes: Mutex<Option<Vec<DisplayMode>>>,
+}
+
+impl Default for ConnectorHandle {
+    fn default() -> Self {
+        Self::new(ConnectorStatus::Connected)
+    }
+}
+
+impl ConnectorHandle {
+    pub fn new(status: ConnectorStatus) -> Self {
+        Self(Arc::new(Shared {
+            status: AtomicU8::new(status.into()),
+            changed: AtomicBool::new(false),
+            edid: Mutex::new(Vec::new()),
+            modes: Mutex::new(None),
+        }))
+    }
+
+    pub fn status(&self) -> ConnectorStatus {
+        ConnectorStatus::from_u8(self.0.status.load(Ordering::Acquire)).unwrap_or_default()
+    }
+
+    /// Sets the status. If it differs from the current one, the host is told it changed the next
+    /// time it asks.
+    pub fn set_status(&self, status: ConnectorStatus) {
+        if self.0.status.swap(status.into(), Ordering::AcqRel) != u8::from(status) {
+            self.0.changed.store(true, Ordering::Release);
+        }
+    }
+
+    /// Flags the connector as changed even though its status didn't, so the host re-reads its
+    /// modes and EDID the next time it polls.
+    pub fn mark_changed(&self) {
+        self.0.changed.store(true, Ordering::Release);
+    }
+
+    /// The EDID of the attached display, empty if there's none.
+    pub fn edid(&self) -> Vec<u8> {
+        self.0.edid.lock().unwrap().clone()
+    }
+
+    /// Sets the EDID reported to the host, flagging a change if it differs.
+    pub fn set_edid(&self, edid: Vec<u8>) {
+        let mut current = self.0.edid.lock().unwrap();
+        if *current != edid {
+            *current = edid;
+            self.mark_changed();
+        }
+    }
+
+    /// The modes set with [`set_modes`](Self::set_modes), `None` if the application answers
+    /// [`Event::GetDisplayModes`](crate::Event::GetDisplayModes) itself.
+    pub fn modes(&self) -> Option<Vec<DisplayMode>> {
+        self.0.modes.lock().unwrap().clone()
+    }
+
+    /// Sets the modes reported to the host, e.g. after the panel's resolution changed, flagging
+    /// a change if they differ so the host re-probes them without the gadget being rebound.
+    /// From then on the host's requests for them are answered without an
+    /// [`Event::GetDisplayModes`](crate::Event::GetDisplayModes).
+    ///
+    /// The host only reads the display descriptor when it probes the device and drops modes past
+    /// the limits in it, so those have to cover any mode that's set later.
+    pub fn set_modes(&self, modes: Vec<DisplayMode>) {
+        let mut current = self.0.modes.lock().unwrap();
+        if current.as_ref() != Some(&modes) {
+            *current = Some(modes);
+            self.mark_changed();
+        }
+    }
+
+    /// Whether there's a change the host hasn't been told about yet.
+    pub fn is_changed(&self) -> bool {
+        self.0.changed.load(Ordering::Acquire)
+    }
+
+    /// The `GUD_REQ_GET_CONNECTOR_STATUS` response, flagging and then clearing a change.
+    pub(crate) fn report(&self) -> u8 {
+        let changed = self.0.changed.swap(false, Ordering::AcqRel);
+        let status = u8::from(self.status());
+        match changed {
+            true => status | GUD_CONNECTOR_STATUS_CHANGED,
+            false => status,
+        }
+    }
+}
@@ -0,0 +1,80 @@
+//! Checks for bringing up a new UDC, whose DMA may drop or corrupt parts of a transfer, and for
+//! testing host drivers against a slow device.
+
+use std::time::Duration;
+
+use crate::SetBuffer;
+
+/// Opt-in checks on every received frame, at some cost per frame. Set them with
+/// `PixelDataEndpoint::set_debug`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct DebugOptions {
+    /// Logs the [`checksum`] of each payload at debug level, and the `SET_BUFFER` header of any
+    /// that came up short or didn't decompress to exactly `length` bytes.
+    pub validate: bool,
+    /// Draws the outline of each damage rect, so what the host redraws can be seen on the panel.
+    pub outline: bool,
+}
+
+/// Makes the data endpoint slower than it is, so the host driver's frame dropping and timeouts can
+/// be tested reproducibly without slow hardware. Set it with `PixelDataEndpoint::set_throttle`.
+///
+/// Reads are held back before the next is queued, so the host sees its transfers stall like they
+/// would on a slow link.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Throttle {
+    /// Added to every read from the endpoint.
+    pub delay: Duration,
+    /// Bytes per second a payload is received at at most. `None` doesn't cap it.
+    pub bandwidth: Option<u64>,
+}
+
+impl Throttle {
+    /// Whether it slows anything down.
+    pub fn is_active(&self) -> bool {
+        !self.delay.is_zero() || self.bandwidth.is_some()
+    }
+
+    /// How long to hold back after a read, with `received` bytes of the payload in after
+    /// `elapsed`.
+    pub fn pause(&self, received: usize, elapsed: Duration) -> Duration {
+        let paced = match self.bandwidth {
+            Some(bandwidth) => {
+                let nanos = received as u128 * 1_000_000_000 / bandwidth.max(1) as u128;
+                Duration::from_nanos(nanos as u64).saturating_sub(elapsed)
+            }
+            None => Duration::ZERO,
+        };
+        self.delay + paced
+    }
+}
+
+/// CRC-32 of a payload as it goes over the wire. The user-space `gud-host` crate logs it for each
+/// payload it flushes, so with it as the host, a payload that differs on the device can be told
+/// from one that was sent that way. The kernel's driver computes none.
+pub fn checksum(payload: &[u8]) -> u32 {
+    crc32fast::hash(payload)
+}
+
+/// Draws a white outline around the damage rect into its packed pixel data in `buf`. Formats
+/// that pack several pixels into a byte are left alone.
+pub fn outline(info: &SetBuffer, buf: &mut [u8]) {
+    let (width, height) = (info.width as usize, info.height as usize);
+    let bpp = match width * height {
+        0 => return,
+        pixels => info.length as usize / pixels,
+    };
+    if bpp == 0 || width * height * bpp != info.length as usize {
+        return;
+    }
+
+    let line_len = width * bpp;
+    for (y, line) in buf.chunks_exact_mut(line_len).take(height).enumerate() {
+        if y == 0 || y == height - 1 {
+            line.fill(0xff);
+        } else {
+            line[..bpp].fill(0xff);
+            line[line_len - bpp..].fill(0xff);
+        }
+    }
+}
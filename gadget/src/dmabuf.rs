@@ -0,0 +1,118 @@
+//! Receiving frames straight into memory that's scanned out by the display controller.
+
+use std::io;
+use std::os::fd::{AsFd, AsRawFd, BorrowedFd, OwnedFd};
+use std::ptr::NonNull;
+
+/// A framebuffer written by the CPU and read by a device.
+///
+/// On SoCs without cache coherency between the CPU and the display controller, the access hooks
+/// are where caches get synchronized. Implement this for buffers that need something other than
+/// what [`DmaBuf`] does.
+pub trait ReceiveTarget {
+    fn buf_mut(&mut self) -> &mut [u8];
+
+    fn pitch(&self) -> usize;
+
+    /// Called before the CPU writes to the buffer.
+    fn begin_cpu_access(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once the CPU is done writing to the buffer, e.g. to flush caches.
+    fn end_cpu_access(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// From include/uapi/linux/dma-buf.h.
+const DMA_BUF_SYNC_WRITE: u64 = 2;
+const DMA_BUF_SYNC_START: u64 = 0;
+const DMA_BUF_SYNC_END: u64 = 4;
+// _IOW('b', 0, struct dma_buf_sync)
+const DMA_BUF_IOCTL_SYNC: libc::c_ulong = 0x40086200;
+
+/// A mapped DMA-BUF, e.g. a DRM buffer exported with PRIME.
+///
+/// CPU access is bracketed with `DMA_BUF_IOCTL_SYNC`, which has the kernel take care of cache
+/// maintenance.
+#[derive(Debug)]
+pub struct DmaBuf {
+    fd: OwnedFd,
+    map: NonNull<u8>,
+    len: usize,
+    pitch: usize,
+}
+
+// The mapping is owned by the DmaBuf and only accessed through &mut self.
+unsafe impl Send for DmaBuf {}
+
+impl DmaBuf {
+    /// Maps `len` bytes of the DMA-BUF `fd`, whose lines are `pitch` bytes apart.
+    pub fn import(fd: OwnedFd, len: usize, pitch: usize) -> io::Result<Self> {
+        let map = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED,
+                fd.as_raw_fd(),
+                0,
+            )
+        };
+        if map == libc::MAP_FAILED {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fd,
+            map: NonNull::new(map.cast()).expect("mmap returned null"),
+            len,
+            pitch,
+        })
+    }
+
+    fn sync(&self, flags: u64) -> io::Result<()> {
+        let flags = flags | DMA_BUF_SYNC_WRITE;
+        loop {
+            match unsafe { libc::ioctl(self.fd.as_raw_fd(), DMA_BUF_IOCTL_SYNC, &flags) } {
+                0 => return Ok(()),
+                _ => {
+                    let err = io::Error::last_os_error();
+                    if err.kind() != io::ErrorKind::Interrupted {
+                        return Err(err);
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl AsFd for DmaBuf {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.fd.as_fd()
+    }
+}
+
+impl ReceiveTarget for DmaBuf {
+    fn buf_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self.map.as_ptr(), self.len) }
+    }
+
+    fn pitch(&self) -> usize {
+        self.pitch
+    }
+
+    fn begin_cpu_access(&mut self) -> io::Result<()> {
+        self.sync(DMA_BUF_SYNC_START)
+    }
+
+    fn end_cpu_access(&mut self) -> io::Result<()> {
+        self.sync(DMA_BUF_SYNC_END)
+    }
+}
+
+impl Drop for DmaBuf {
+    fn drop(&mut self) {
+        unsafe { libc::munmap(self.map.as_ptr().cast(), self.len) };
+    }
+}
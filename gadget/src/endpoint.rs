@@ -0,0 +1,720 @@
+use anyhow::{bail, Context};
+use bytes::{Bytes, BytesMut};
+use std::collections::VecDeque;
+use std::os::fd::{AsRawFd, RawFd};
+use std::thread;
+use std::time::Instant;
+use tracing::{debug, trace_span, warn, Span};
+use usb_gadget::function::custom::{Endpoint, EndpointDirection, EndpointReceiver};
+
+use crate::blit::{Filter, Lut, RowSink, Scale, Swizzle, Transform};
+use crate::debug::{self, DebugOptions, Throttle};
+use crate::dmabuf::ReceiveTarget;
+use crate::protocol::{PixelFormat, StateRequest};
+use crate::{
+    blit, Coalesce, Damage, Frame, FrameLimiter, FrameQueue, FrameSink, ProtocolError, SetBuffer,
+    Stats,
+};
+
+// Bytes read at once by default: a few per frame keep the syscall count down, while still
+// fitting the host's transfers of small damage rects.
+const HIGH_SPEED_CHUNK: usize = 64 * 1024;
+const SUPER_SPEED_CHUNK: usize = 256 * 1024;
+// Reads queued on the endpoint at most, usb-gadget's default made explicit so a non-blocking
+// receive knows when another read would block.
+const QUEUE_LEN: u32 = 16;
+
+pub struct PixelDataEndpoint {
+    ep_rx: EndpointReceiver,
+    // A collection of the buffers we've allocated for submission to AIO to read from the endpoint.
+    ep_buf: Vec<BytesMut>,
+    // Sizes of the reads submitted to AIO that haven't completed yet, oldest first.
+    in_flight: VecDeque<usize>,
+    // Bytes per read, picked from the link speed if unset.
+    chunk_size: Option<usize>,
+    // The number and size of the buffers in `ep_buf` if it's a fixed pool.
+    pool: Option<(usize, usize)>,
+    // The full contents of a transmitted buffer are copied here.
+    buf: BytesMut,
+    // If compression is enabled, the received buffer is decompressed here.
+    compress_buf: BytesMut,
+    // Framebuffer format for recv_buffer_converted, if it differs from the host's.
+    convert_to: Option<PixelFormat>,
+    swizzle: Swizzle,
+    scale: Option<Scale>,
+    transform: Transform,
+    lut: Option<Lut>,
+    // Frames received with recv_frame_queued that haven't been taken yet.
+    queue: FrameQueue,
+    stats: Stats,
+    limiter: FrameLimiter,
+    // The payload being received by try_recv, and when it started.
+    receiving: Option<(SetBuffer, Instant)>,
+    debug: DebugOptions,
+    throttle: Throttle,
+    #[cfg(feature = "trace")]
+    tracer: Option<crate::trace::Tracer>,
+}
+
+impl PixelDataEndpoint {
+    pub fn new() -> (Self, Endpoint) {
+        let (ep_rx, ep_dir) = EndpointDirection::host_to_device();
+        let ep_dir = ep_dir.with_queue_len(QUEUE_LEN);
+
+        (
+            Self {
+                ep_rx,
+                ep_buf: Vec::new(),
+                in_flight: VecDeque::new(),
+                chunk_size: None,
+                pool: None,
+                buf: BytesMut::new(),
+                compress_buf: BytesMut::new(),
+                convert_to: None,
+                swizzle: Swizzle::NONE,
+                scale: None,
+                transform: Transform::IDENTITY,
+                lut: None,
+                queue: FrameQueue::default(),
+                stats: Stats::default(),
+                limiter: FrameLimiter::default(),
+                receiving: None,
+                debug: DebugOptions::default(),
+                throttle: Throttle::default(),
+                #[cfg(feature = "trace")]
+                tracer: None,
+            },
+            Endpoint::bulk(ep_dir),
+        )
+    }
+
+    /// Like [`new`](Self::new), for UDCs that run at SuperSpeed (e.g. DWC3). The endpoint's
+    /// companion descriptor lets the host burst `max_burst + 1` packets at a time, up to 16.
+    pub fn with_max_burst(max_burst: u8) -> (Self, Endpoint) {
+        let (endpoint, mut ep) = Self::new();
+        ep.max_burst_ss = max_burst.min(15);
+        (endpoint, ep)
+    }
+
+    /// Like [`new`](Self::new), but reads into a fixed pool of `count` buffers of `size` bytes
+    /// each, allocated up front, for devices that can't afford memory use to creep. Reads are at
+    /// most `size` bytes, and wait for a buffer to come back when all of them are queued.
+    /// Buffers lost to a [`reset`](Self::reset) are replaced.
+    ///
+    /// Panics unless there's at least one buffer of at least 1024 bytes, a SuperSpeed packet.
+    pub fn with_pool(count: usize, size: usize) -> (Self, Endpoint) {
+        assert!(count > 0 && size >= 1024, "pool too small");
+        let (mut endpoint, ep) = Self::new();
+        endpoint.pool = Some((count, size));
+        endpoint.fill_pool();
+        (endpoint, ep)
+    }
+
+    /// Sets how many bytes each read from the endpoint asks for, rounded up to whole packets.
+    /// Larger reads mean fewer syscalls per frame. `None` picks 64 KiB at high speed and 256 KiB
+    /// at SuperSpeed.
+    pub fn set_chunk_size(&mut self, chunk_size: Option<usize>) {
+        self.chunk_size = chunk_size;
+    }
+
+    /// Sets the pixel format of the framebuffer passed to
+    /// [`recv_buffer_converted`](Self::recv_buffer_converted), so the formats advertised to the
+    /// host don't need to match the panel's. `None` writes pixels in the host's format.
+    pub fn set_convert_to(&mut self, format: Option<PixelFormat>) {
+        self.convert_to = format;
+    }
+
+    /// Sets the channel order of the framebuffer passed to
+    /// [`recv_buffer_converted`](Self::recv_buffer_converted). It's applied while copying, so
+    /// there's no extra pass over the frame.
+    pub fn set_swizzle(&mut self, swizzle: Swizzle) {
+        self.swizzle = swizzle;
+    }
+
+    /// Scales frames passed to [`recv_buffer_converted`](Self::recv_buffer_converted) to a
+    /// framebuffer of the given size, so the host can be offered modes the panel doesn't support.
+    pub fn set_scale(&mut self, scale: Option<Scale>) {
+        self.scale = scale;
+    }
+
+    /// Turns or flips frames passed to [`recv_buffer_converted`](Self::recv_buffer_converted) on
+    /// their way to the framebuffer, for panels mounted sideways or upside down. The scale set
+    /// with [`set_scale`](Self::set_scale) is the framebuffer's size after the transform; without
+    /// one, frames keep the size of the host's mode.
+    pub fn set_transform(&mut self, transform: Transform) {
+        self.transform = transform;
+    }
+
+    /// Corrects the colors of frames received in a known format, i.e. with
+    /// [`recv_buffer_converted`](Self::recv_buffer_converted) and [`recv_frame`](Self::recv_frame)
+    /// and the methods built on them, for panels that need their gamma adjusted. It's applied to
+    /// the pixels in the host's format, before they're converted.
+    pub fn set_lut(&mut self, lut: Option<Lut>) {
+        self.lut = lut;
+    }
+
+    /// Sets what happens to frames queued with [`recv_frame_queued`](Self::recv_frame_queued)
+    /// that haven't been taken when a newer one arrives. Every frame is kept by default.
+    pub fn set_coalesce(&mut self, policy: Coalesce) {
+        self.queue.set_policy(policy);
+    }
+
+    /// Turns on checks on each received frame, for bringing up a new UDC. They're off by default.
+    pub fn set_debug(&mut self, debug: DebugOptions) {
+        self.debug = debug;
+    }
+
+    /// Slows down receiving, to test how the host copes with a slow device. Off by default.
+    pub fn set_throttle(&mut self, throttle: Throttle) {
+        self.throttle = throttle;
+    }
+
+    /// Records the header of each payload that's received in full to `tracer`, see
+    /// [`trace`](crate::trace).
+    #[cfg(feature = "trace")]
+    pub fn set_tracer(&mut self, tracer: Option<crate::trace::Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// Caps how often [`present`](Self::present) has frames presented at `fps` a second,
+    /// merging the damage of those received in between. Every frame is presented by default.
+    pub fn set_max_fps(&mut self, fps: Option<u32>) {
+        self.limiter = FrameLimiter::new(fps);
+    }
+
+    /// The damage of the frames received since the last presentation, merged, if it's due to
+    /// be presented now. Without a cap that's any frame that's been received; with one, it
+    /// waits until the interval since the last presentation has passed.
+    pub fn present(&mut self) -> Option<Damage> {
+        let (damage, frames) = self.limiter.take_due(Instant::now())?;
+        self.stats.presented += 1;
+        self.stats.merged += frames - 1;
+        Some(damage)
+    }
+
+    /// When the frames received since the last presentation are due to be presented, to wake
+    /// up for. `None` if there are none.
+    pub fn present_deadline(&self) -> Option<Instant> {
+        self.limiter.deadline()
+    }
+
+    /// What's been received so far.
+    pub fn stats(&self) -> Stats {
+        self.stats
+    }
+
+    pub fn recv_buffer(
+        &mut self,
+        info: SetBuffer,
+        fb: &mut [u8],
+        fb_pitch: usize,
+        bpp: usize,
+    ) -> anyhow::Result<()> {
+        let _frame = frame_span(&info).entered();
+        info.validate_fb(fb.len(), fb_pitch, bpp)?;
+        if self.recv_direct(&info, None, fb, fb_pitch, info.width as usize * bpp)? {
+            return Ok(());
+        }
+        let buf = self.recv_pixels(&info, None)?;
+        let result = trace_span!("blit").in_scope(|| blit::blit(&info, buf, fb, fb_pitch, bpp));
+        Ok(self.count(result)?)
+    }
+
+    /// Like [`recv_buffer`](Self::recv_buffer), but hands the pixel data to `sink` a line at a
+    /// time, for displays that can't be mapped as one flat framebuffer. See [`RowSink`].
+    pub fn recv_buffer_with(
+        &mut self,
+        info: SetBuffer,
+        bpp: usize,
+        sink: &mut impl RowSink,
+    ) -> anyhow::Result<()> {
+        let _frame = frame_span(&info).entered();
+        info.validate_length(bpp)?;
+        let buf = self.recv_pixels(&info, None)?;
+        let result = trace_span!("blit").in_scope(|| blit::blit_rows(&info, buf, bpp, sink));
+        Ok(self.count(result)?)
+    }
+
+    /// Like [`recv_buffer`](Self::recv_buffer), but converts pixel data sent in the format of the
+    /// committed `state` to the format set with [`set_convert_to`](Self::set_convert_to), in the
+    /// channel order set with [`set_swizzle`](Self::set_swizzle), scaled and transformed as set
+    /// with [`set_scale`](Self::set_scale) and [`set_transform`](Self::set_transform).
+    pub fn recv_buffer_converted(
+        &mut self,
+        info: SetBuffer,
+        state: &StateRequest,
+        fb: &mut [u8],
+        fb_pitch: usize,
+    ) -> anyhow::Result<()> {
+        let _frame = frame_span(&info).entered();
+        let format =
+            PixelFormat::from_u8(state.format).ok_or(ProtocolError::UnsupportedConversion {
+                from: state.format,
+                to: self.convert_to.map_or(state.format, u8::from),
+            })?;
+        // The lengths bound what's allocated to receive the payload, they mustn't exceed the mode.
+        info.validate(&state.mode)?;
+        info.validate_format_length(format)?;
+        let fb_format = self.convert_to.unwrap_or(format);
+        let (swizzle, transform) = (self.swizzle, self.transform);
+        let scale = self.scale.or_else(|| {
+            let (width, height) = (state.mode.hdisplay as u32, state.mode.vdisplay as u32);
+            let (width, height) = transform.apply_size(width, height);
+            (transform != Transform::IDENTITY).then_some(Scale {
+                width,
+                height,
+                filter: Filter::Nearest,
+            })
+        });
+        // Frames the framebuffer's size and format as they are can be decompressed into it.
+        let mode_size = (state.mode.hdisplay as u32, state.mode.vdisplay as u32);
+        let unscaled = scale.is_none_or(|scale| {
+            transform == Transform::IDENTITY && (scale.width, scale.height) == mode_size
+        });
+        if unscaled
+            && fb_format == format
+            && swizzle == Swizzle::NONE
+            && self.recv_direct(
+                &info,
+                Some(format),
+                fb,
+                fb_pitch,
+                format.line_len(info.width as usize),
+            )?
+        {
+            return Ok(());
+        }
+        let buf = self.recv_pixels(&info, Some(format))?;
+        let _blit = trace_span!("blit", scaled = scale.is_some()).entered();
+        let result = match scale {
+            Some(scale) => blit::blit_transformed(
+                &info,
+                &state.mode,
+                format,
+                buf,
+                fb,
+                fb_pitch,
+                fb_format,
+                swizzle,
+                scale,
+                transform,
+            ),
+            None => blit::blit_convert(&info, format, buf, fb, fb_pitch, fb_format, swizzle),
+        };
+        Ok(self.count(result)?)
+    }
+
+    /// Like [`recv_buffer_converted`](Self::recv_buffer_converted), but into a target such as a
+    /// [`DmaBuf`](crate::dmabuf::DmaBuf), with the CPU access bracketed by its cache hooks.
+    pub fn recv_buffer_into(
+        &mut self,
+        info: SetBuffer,
+        state: &StateRequest,
+        target: &mut impl ReceiveTarget,
+    ) -> anyhow::Result<()> {
+        let pitch = target.pitch();
+        target.begin_cpu_access().context("begin CPU access")?;
+        let result = self.recv_buffer_converted(info, state, target.buf_mut(), pitch);
+        target.end_cpu_access().context("end CPU access")?;
+        result
+    }
+
+    /// Receives the pixel data for `info` as an owned [`Frame`] in the given GUD pixel format.
+    /// The lengths are checked against the damage rect in `format` first, see
+    /// [`SetBuffer::validate_format`].
+    ///
+    /// Uncompressed data is handed over without copying, at the cost of a fresh receive buffer
+    /// for the next transfer.
+    pub fn recv_frame(&mut self, info: SetBuffer, format: u8) -> anyhow::Result<Frame> {
+        let _frame = frame_span(&info).entered();
+        info.validate_format(format)?;
+        self.recv(&info)?;
+        self.take_frame(info, format)
+    }
+
+    /// Like [`recv_frame`](Self::recv_frame), but doesn't block, for event loops that poll the
+    /// endpoint's descriptor (see [`AsRawFd`]) along with their own, such as a compositor's. It
+    /// takes what's arrived of the payload for `info` and returns the frame once all of it is in,
+    /// `None` until then. Call it again with the same `info` when the descriptor's readable.
+    ///
+    /// A different `info` starts over, so a payload that's given up on has to be cleared out with
+    /// [`reset`](Self::reset) first.
+    pub fn try_recv(&mut self, info: SetBuffer, format: u8) -> anyhow::Result<Option<Frame>> {
+        let _frame = frame_span(&info).entered();
+        if self.receiving.as_ref().map(|(receiving, _)| receiving) != Some(&info) {
+            info.validate_format(format)?;
+            self.start_recv(&info);
+        }
+        let done = self
+            .fill(&info, false)
+            .inspect_err(|_| self.receiving = None)?;
+        match done {
+            true => self.take_frame(info, format).map(Some),
+            false => Ok(None),
+        }
+    }
+
+    // Decompresses and finishes the payload received into `self.buf` as a frame.
+    fn take_frame(&mut self, info: SetBuffer, format: u8) -> anyhow::Result<Frame> {
+        let mut data = if info.compression > 0 {
+            let mut data = BytesMut::zeroed(info.length as usize);
+            let result = trace_span!("decompress")
+                .in_scope(|| blit::decompress(&info, &self.buf, &mut data));
+            self.check(&info, result)?;
+            data
+        } else {
+            self.buf.split()
+        };
+        let lut = self.lut.as_ref();
+        finish(
+            &info,
+            PixelFormat::from_u8(format),
+            lut,
+            self.debug,
+            &mut data,
+        );
+
+        Ok(Frame {
+            info,
+            format,
+            data: data.freeze(),
+        })
+    }
+
+    /// Like [`recv_frame`](Self::recv_frame), but passes the frame on to `sink`.
+    pub fn recv_frame_into(
+        &mut self,
+        info: SetBuffer,
+        format: u8,
+        sink: &mut impl FrameSink,
+    ) -> anyhow::Result<()> {
+        let frame = self.recv_frame(info, format)?;
+        sink.frame(frame)
+    }
+
+    /// Like [`recv_frame`](Self::recv_frame), but queues the frame to be taken with
+    /// [`next_frame`](Self::next_frame), coalescing it with the pending ones as set with
+    /// [`set_coalesce`](Self::set_coalesce). This lets the host's transfers be drained while the
+    /// panel is still busy presenting, without the backlog growing beyond the queue's
+    /// [capacity](FrameQueue::set_capacity).
+    pub fn recv_frame_queued(&mut self, info: SetBuffer, format: u8) -> anyhow::Result<()> {
+        let frame = self.recv_frame(info, format)?;
+        self.queue.push(frame);
+        Ok(())
+    }
+
+    /// The oldest frame queued by [`recv_frame_queued`](Self::recv_frame_queued).
+    pub fn next_frame(&mut self) -> Option<Frame> {
+        self.queue.pop()
+    }
+
+    /// Cancels transfers queued on the endpoint and drops partly received and queued frames and
+    /// damage that's not been presented, so the first frame after the host reset the bus starts
+    /// clean. Call it on [`Event::Reset`](crate::Event::Reset).
+    pub fn reset(&mut self) -> anyhow::Result<()> {
+        self.queue.clear();
+        self.limiter.clear();
+        self.rearm()
+    }
+
+    /// The frames queued by [`recv_frame_queued`](Self::recv_frame_queued).
+    pub fn queue(&mut self) -> &mut FrameQueue {
+        &mut self.queue
+    }
+
+    // The format and channel order frames are converted to, for the pipeline's decode stage.
+    pub(crate) fn conversion(&self) -> (Option<PixelFormat>, Swizzle) {
+        (self.convert_to, self.swizzle)
+    }
+
+    // Receives the payload for `info` as sent, leaving decompression to the caller.
+    pub(crate) fn recv_payload(&mut self, info: &SetBuffer) -> anyhow::Result<Bytes> {
+        let _frame = frame_span(info).entered();
+        self.recv(info)?;
+        Ok(self.buf.split().freeze())
+    }
+
+    // Receives the payload for `info`, returning the decompressed pixel data, color corrected if
+    // it's in a known `format`.
+    fn recv_pixels(
+        &mut self,
+        info: &SetBuffer,
+        format: Option<PixelFormat>,
+    ) -> anyhow::Result<&[u8]> {
+        self.recv(info)?;
+        let buf = match info.compression {
+            0 => &mut self.buf,
+            _ => {
+                let _decompress = trace_span!("decompress").entered();
+                if self.compress_buf.len() < info.length as usize {
+                    self.compress_buf.resize(info.length as usize, 0);
+                }
+                let result = blit::decompress(info, &self.buf, &mut self.compress_buf);
+                self.check(info, result)?;
+                &mut self.compress_buf
+            }
+        };
+        finish(info, format, self.lut.as_ref(), self.debug, buf);
+        Ok(buf)
+    }
+
+    // Receives compressed pixel data for damage spanning whole lines of `fb` by decompressing it
+    // straight into place, saving a pass over the frame. Returns false without receiving
+    // anything if it doesn't, or isn't compressed. A payload that fails to decompress may leave
+    // the rect partly drawn.
+    fn recv_direct(
+        &mut self,
+        info: &SetBuffer,
+        format: Option<PixelFormat>,
+        fb: &mut [u8],
+        fb_pitch: usize,
+        line_len: usize,
+    ) -> anyhow::Result<bool> {
+        let span = match blit::contiguous_span(info, fb.len(), fb_pitch, line_len) {
+            Some(span) if info.compression > 0 => span,
+            _ => return Ok(false),
+        };
+        self.recv(info)?;
+        let dst = &mut fb[span];
+        let result = trace_span!("decompress", direct = true)
+            .in_scope(|| blit::decompress(info, &self.buf, dst));
+        self.check(info, result)?;
+        finish(info, format, self.lut.as_ref(), self.debug, dst);
+        Ok(true)
+    }
+
+    // The color correction and debug options, for the pipeline's decode stage.
+    pub(crate) fn finishing(&self) -> (Option<Lut>, DebugOptions) {
+        (self.lut.clone(), self.debug)
+    }
+
+    // Cancels the transfers queued on the endpoint and forgets the partly received payload.
+    fn rearm(&mut self) -> anyhow::Result<()> {
+        self.buf.clear();
+        self.in_flight.clear();
+        self.receiving = None;
+        let result = self.ep_rx.cancel().context("cancel bulk transfers");
+        // The canceled reads took their buffers with them.
+        self.fill_pool();
+        result
+    }
+
+    // Tops the pool back up to its size, if there is one.
+    fn fill_pool(&mut self) {
+        if let Some((count, size)) = self.pool {
+            self.ep_buf.truncate(count);
+            while self.ep_buf.len() < count {
+                self.ep_buf.push(BytesMut::with_capacity(size));
+            }
+        }
+    }
+
+    // Reads the (possibly compressed) payload for `info` from the endpoint into `self.buf`.
+    fn recv(&mut self, info: &SetBuffer) -> anyhow::Result<()> {
+        self.start_recv(info);
+        self.fill(info, true)
+            .inspect_err(|_| self.receiving = None)?;
+        Ok(())
+    }
+
+    // Starts over receiving the payload for `info`.
+    fn start_recv(&mut self, info: &SetBuffer) {
+        let len = payload_len(info);
+        self.buf.clear();
+
+        // Ensure the buffer is large enough to fit all incoming data.
+        if self.buf.capacity() < len {
+            self.buf.reserve(len - self.buf.capacity());
+        }
+        self.receiving = Some((info.clone(), Instant::now()));
+    }
+
+    // Reads the rest of the payload for `info` into `self.buf`, returning whether it's all in.
+    // Unless it may `block`, it returns false once it'd have to wait for a read to complete.
+    fn fill(&mut self, info: &SetBuffer, block: bool) -> anyhow::Result<bool> {
+        let max_packet_size = self
+            .ep_rx
+            .max_packet_size()
+            .context("get max packet size")?;
+        let len = payload_len(info);
+        let started = self
+            .receiving
+            .as_ref()
+            .map_or_else(Instant::now, |(_, started)| *started);
+
+        // Read the incoming data fully into the buffer.
+        let _read = trace_span!("read", bytes = len).entered();
+        let chunk_size = match (self.pool, self.chunk_size) {
+            // Whole packets that fit the pool's buffers.
+            (Some((_, size)), _) => size / max_packet_size * max_packet_size,
+            (None, Some(chunk_size)) => chunk_size.max(1).next_multiple_of(max_packet_size),
+            // SuperSpeed bulk endpoints have 1024 byte packets, high speed ones 512.
+            (None, None) if max_packet_size >= 1024 => SUPER_SPEED_CHUNK,
+            (None, None) => HIGH_SPEED_CHUNK,
+        };
+        while self.buf.len() < len {
+            let requested = self.buf.len() + self.in_flight.iter().sum::<usize>();
+            let wanted = len.saturating_sub(requested);
+            // A pool that's all queued has to wait for a read to complete before asking for more.
+            // Without blocking, there has to be room in the endpoint's queue.
+            let submit = wanted > 0
+                && (self.pool.is_none() || !self.ep_buf.is_empty())
+                && (block || self.in_flight.len() < QUEUE_LEN as usize);
+            if !submit && self.in_flight.is_empty() {
+                self.stats.errors += 1;
+                bail!("receive buffer pool exhausted");
+            }
+            let result = match submit {
+                // Everything's been asked for, wait for what's still coming.
+                false if block => self.ep_rx.fetch(),
+                false => self.ep_rx.try_fetch(),
+                // Reads don't reach past the end of the payload: the host doesn't end a transfer
+                // that fills its last packet with a zero length packet, so a longer read would
+                // wait for the next frame.
+                true => {
+                    let size = wanted.min(chunk_size).next_multiple_of(max_packet_size);
+                    let buf = self.read_buf(size);
+                    self.in_flight.push_back(size);
+                    self.ep_rx.recv(buf)
+                }
+            };
+            let buf = match result {
+                Ok(buf) => buf,
+                Err(err) => {
+                    self.stats.errors += 1;
+                    // Most likely a reset, don't let what's still queued end up in the next frame.
+                    if let Err(err) = self.rearm() {
+                        warn!("re-arming bulk ep failed: {:#}", err);
+                    }
+                    return Err(err).context("read bulk ep");
+                }
+            };
+            let mut buf = match buf {
+                Some(buf) => buf,
+                // Nothing's arrived yet.
+                None if !submit && !block => return Ok(false),
+                // Nothing's queued any more, the payload came up short.
+                None if !submit => {
+                    self.in_flight.clear();
+                    self.fill_pool();
+                    break;
+                }
+                None => continue,
+            };
+            self.in_flight.pop_front();
+            self.buf.extend_from_slice(&buf);
+            buf.clear();
+            self.ep_buf.push(buf);
+            if self.throttle.is_active() {
+                thread::sleep(self.throttle.pause(self.buf.len(), started.elapsed()));
+            }
+        }
+        self.receiving = None;
+        if self.buf.len() != len {
+            self.stats.errors += 1;
+            if self.debug.validate {
+                warn!("payload of {} bytes for {:?}", self.buf.len(), info);
+            }
+            return Err(ProtocolError::LengthMismatch {
+                length: self.buf.len(),
+                expected: len,
+            }
+            .into());
+        }
+        if self.debug.validate {
+            debug!(
+                "received {:?} crc32={:#010x}",
+                info,
+                debug::checksum(&self.buf)
+            );
+        }
+        #[cfg(feature = "trace")]
+        if let Some(tracer) = &self.tracer {
+            tracer.payload(info, &self.buf);
+        }
+        self.stats.frames += 1;
+        self.limiter.add(info);
+        self.stats.bytes += len as u64;
+        self.stats.pixel_bytes += info.length as u64;
+        Ok(true)
+    }
+
+    // An empty buffer to read `size` bytes into. AIO reads as much as the buffer's capacity.
+    fn read_buf(&mut self, size: usize) -> BytesMut {
+        let mut buf = self.ep_buf.pop().unwrap_or_default();
+        buf.reserve(size);
+        if buf.capacity() > size {
+            // Dropping the tail leaves it to be reclaimed when the buffer's reused for more.
+            drop(buf.split_off(size));
+        }
+        buf
+    }
+
+    // Counts a frame that failed after it was received.
+    fn count<T, E>(&mut self, result: Result<T, E>) -> Result<T, E> {
+        if result.is_err() {
+            self.stats.errors += 1;
+        }
+        result
+    }
+
+    // Like `count`, logging which frame didn't decompress if asked to.
+    fn check<T>(
+        &mut self,
+        info: &SetBuffer,
+        result: Result<T, ProtocolError>,
+    ) -> Result<T, ProtocolError> {
+        if let Err(err) = &result {
+            if self.debug.validate {
+                warn!("{} for {:?}", err, info);
+            }
+        }
+        self.count(result)
+    }
+}
+
+impl AsRawFd for PixelDataEndpoint {
+    /// The bulk endpoint's descriptor, readable when a read has completed.
+    fn as_raw_fd(&self) -> RawFd {
+        self.ep_rx.as_raw_fd()
+    }
+}
+
+// Bytes sent over the wire for `info`.
+fn payload_len(info: &SetBuffer) -> usize {
+    let len = match info.compression {
+        0 => info.length,
+        _ => info.compressed_length,
+    };
+    len as usize
+}
+
+// What's done to pixel data once it's decompressed: correcting its colors, if it's in a known
+// `format`, and drawing debug outlines.
+pub(crate) fn finish(
+    info: &SetBuffer,
+    format: Option<PixelFormat>,
+    lut: Option<&Lut>,
+    debug: DebugOptions,
+    pixels: &mut [u8],
+) {
+    if let (Some(format), Some(lut)) = (format, lut) {
+        trace_span!("correct").in_scope(|| blit::correct(format, lut, pixels));
+    }
+    if debug.outline {
+        debug::outline(info, pixels);
+    }
+}
+
+// Spans everything done for one frame, so a profiler's timeline shows where each one's time went
+// and how that relates to its damage.
+fn frame_span(info: &SetBuffer) -> Span {
+    trace_span!(
+        "frame",
+        x = info.x,
+        y = info.y,
+        width = info.width,
+        height = info.height,
+        compressed = info.compression > 0,
+    )
+}
@@ -0,0 +1,77 @@
+use thiserror::Error;
+
+use crate::protocol::{GUD_STATUS_ERROR, GUD_STATUS_INVALID_PARAMETER, GUD_STATUS_PROTOCOL_ERROR};
+
+/// A request from the host that violates the GUD protocol or the current display state.
+///
+/// These are surfaced through `anyhow::Error`, use `downcast_ref::<ProtocolError>()` to inspect
+/// them.
+#[derive(Debug, Error)]
+pub enum ProtocolError {
+    #[error("malformed {0} request")]
+    Malformed(&'static str),
+    #[error("set buffer received before a mode was committed")]
+    NoMode,
+    #[error("no connector {0}")]
+    NoConnector(u16),
+    #[error("damage rect {width}x{height}+{x}+{y} exceeds {bound_width}x{bound_height}")]
+    RectOutOfBounds {
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        bound_width: u32,
+        bound_height: u32,
+    },
+    #[error("buffer length {length} doesn't match damage rect of {expected} bytes")]
+    LengthMismatch { length: usize, expected: usize },
+    #[error("damage rect needs {needed} bytes of framebuffer, only {available} available")]
+    FramebufferOverflow { needed: usize, available: usize },
+    #[error("unknown pixel format {0:#x}")]
+    UnknownFormat(u8),
+    #[error("pixel format {0:#x} wasn't offered to the host")]
+    FormatNotAdvertised(u8),
+    #[error("compression {0:#x} wasn't offered to the host")]
+    UnsupportedCompression(u8),
+    #[error(
+        "mode {width}x{height} is outside the offered {min_width}x{min_height} to \
+         {max_width}x{max_height}"
+    )]
+    ModeOutOfRange {
+        width: u32,
+        height: u32,
+        min_width: u32,
+        min_height: u32,
+        max_width: u32,
+        max_height: u32,
+    },
+    #[error("state checked before the {0} was sent to the host")]
+    NotAdvertised(&'static str),
+    #[error("can't convert pixel format {from:#x} to {to:#x}")]
+    UnsupportedConversion { from: u8, to: u8 },
+    #[error("{count} {what} don't fit in the response, the host takes {max}")]
+    TooMany {
+        what: &'static str,
+        count: usize,
+        max: usize,
+    },
+    #[error("protocol version {version} isn't supported, the newest is {max}")]
+    UnsupportedVersion { version: u8, max: u8 },
+    #[error("{what} isn't in protocol version {version}")]
+    NotInVersion { what: &'static str, version: u8 },
+    #[error("lz4 decompress failed")]
+    Decompress(#[source] std::io::Error),
+    #[error("the display is shutting down")]
+    ShutDown,
+}
+
+impl ProtocolError {
+    /// The GUD status reported to the host for this error.
+    pub fn status(&self) -> u8 {
+        match self {
+            ProtocolError::Malformed(_) | ProtocolError::Decompress(_) => GUD_STATUS_PROTOCOL_ERROR,
+            ProtocolError::ShutDown => GUD_STATUS_ERROR,
+            _ => GUD_STATUS_INVALID_PARAMETER,
+        }
+    }
+}
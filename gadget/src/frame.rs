@@ -0,0 +1,275 @@
+//! Owned frames, for consumers that don't have a mapped linear framebuffer to blit into.
+
+use bytes::{Bytes, BytesMut};
+use std::collections::VecDeque;
+use tracing::{trace, warn};
+
+use crate::blit::{self, Swizzle};
+use crate::protocol::PixelFormat;
+use crate::{ProtocolError, SetBuffer};
+
+/// The pixel data of a single damage rect, as sent by the host.
+#[derive(Clone, Debug)]
+pub struct Frame {
+    /// The damage rect. `length` is the size of the decompressed `data`.
+    pub info: SetBuffer,
+    /// GUD pixel format of `data`, one of the `GUD_PIXEL_FORMAT_*` constants.
+    pub format: u8,
+    /// Packed pixel data, `info.height` lines of `info.width` pixels each.
+    pub data: Bytes,
+}
+
+impl Frame {
+    /// Whether this frame's damage rect covers all of `other`'s, so drawing it hides `other`.
+    pub fn covers(&self, other: &Frame) -> bool {
+        let (a, b) = (&self.info, &other.info);
+        a.x <= b.x
+            && a.y <= b.y
+            && a.x + a.width >= b.x + b.width
+            && a.y + a.height >= b.y + b.height
+    }
+
+    /// Whether this frame's damage rect and `other`'s overlap.
+    pub fn intersects(&self, other: &Frame) -> bool {
+        let (a, b) = (&self.info, &other.info);
+        a.x < b.x + b.width && b.x < a.x + a.width && a.y < b.y + b.height && b.y < a.y + a.height
+    }
+
+    /// This frame with `newer` drawn over it, as one frame. `None` unless they're in the same
+    /// byte-aligned format and their damage rects together make up a rect, e.g. one covers the
+    /// other or they're consecutive strips of lines, since what's between them is unknown.
+    pub fn merged(&self, newer: &Frame) -> Option<Frame> {
+        let (a, b) = (&self.info, &newer.info);
+        let lined_up = self.covers(newer)
+            || newer.covers(self)
+            || (a.x == b.x && a.width == b.width && a.y <= b.y + b.height && b.y <= a.y + a.height)
+            || (a.y == b.y && a.height == b.height && a.x <= b.x + b.width && b.x <= a.x + a.width);
+        if self.format != newer.format || !lined_up {
+            return None;
+        }
+        let bpp = PixelFormat::from_u8(self.format)?.bytes_per_pixel()?;
+        let packed = |frame: &Frame| {
+            !frame.data.is_empty()
+                && frame.data.len() == frame.info.width as usize * frame.info.height as usize * bpp
+        };
+        if !packed(self) || !packed(newer) {
+            return None;
+        }
+
+        let (x, y) = (a.x.min(b.x), a.y.min(b.y));
+        let width = (a.x + a.width).max(b.x + b.width) - x;
+        let height = (a.y + a.height).max(b.y + b.height) - y;
+        let pitch = width as usize * bpp;
+        let mut data = BytesMut::zeroed(pitch * height as usize);
+        for frame in [self, newer] {
+            let line = frame.info.width as usize * bpp;
+            let offset = (frame.info.y - y) as usize * pitch + (frame.info.x - x) as usize * bpp;
+            for (row, src) in frame.data.chunks_exact(line).enumerate() {
+                let start = offset + row * pitch;
+                data[start..start + line].copy_from_slice(src);
+            }
+        }
+
+        Some(Frame {
+            info: SetBuffer {
+                x,
+                y,
+                width,
+                height,
+                length: data.len() as u32,
+                compression: 0,
+                compressed_length: 0,
+            },
+            format: self.format,
+            data: data.freeze(),
+        })
+    }
+
+    /// Converts the pixel data to `format`, applying `swizzle`, as
+    /// [`blit_convert`](blit::blit_convert) would. The converted frame's `info` describes
+    /// uncompressed data.
+    pub fn convert(&self, format: PixelFormat, swizzle: Swizzle) -> Result<Frame, ProtocolError> {
+        let unsupported = || ProtocolError::UnsupportedConversion {
+            from: self.format,
+            to: format.into(),
+        };
+        let from = PixelFormat::from_u8(self.format).ok_or_else(unsupported)?;
+        let bpp = format.bytes_per_pixel().ok_or_else(unsupported)?;
+
+        // Converted into a framebuffer that's just the damage rect.
+        let pitch = self.info.width as usize * bpp;
+        let mut data = BytesMut::zeroed(pitch * self.info.height as usize);
+        let rect = SetBuffer {
+            x: 0,
+            y: 0,
+            ..self.info.clone()
+        };
+        blit::blit_convert(&rect, from, &self.data, &mut data, pitch, format, swizzle)?;
+
+        Ok(Frame {
+            info: SetBuffer {
+                length: data.len() as u32,
+                compression: 0,
+                compressed_length: 0,
+                ..self.info.clone()
+            },
+            format: format.into(),
+            data: data.freeze(),
+        })
+    }
+}
+
+/// What a [`FrameQueue`] does with frames that are still pending when a newer one arrives.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Coalesce {
+    /// Every frame is presented, in order, as long as the queue has room.
+    #[default]
+    ProcessAll,
+    /// Pending frames that a newer one draws over completely are dropped, and a newer one that
+    /// lines up with a pending frame is drawn into it, see [`Frame::merged`]. A device that can't
+    /// keep up skips to the latest contents instead of falling further behind.
+    LatestWins,
+}
+
+/// How many frames a [`FrameQueue`] holds by default.
+pub const DEFAULT_QUEUE_CAPACITY: usize = 8;
+
+/// Frames received but not presented yet, for when the host sends them faster than the panel or
+/// CPU can present. Once it holds its capacity, the oldest frame is dropped for a newer one.
+#[derive(Debug)]
+pub struct FrameQueue {
+    policy: Coalesce,
+    capacity: usize,
+    frames: VecDeque<Frame>,
+}
+
+impl Default for FrameQueue {
+    fn default() -> Self {
+        Self::new(Coalesce::default())
+    }
+}
+
+impl FrameQueue {
+    pub fn new(policy: Coalesce) -> Self {
+        Self::with_capacity(policy, DEFAULT_QUEUE_CAPACITY)
+    }
+
+    /// A queue that holds at most `capacity` frames, at least 1.
+    pub fn with_capacity(policy: Coalesce, capacity: usize) -> Self {
+        Self {
+            policy,
+            capacity: capacity.max(1),
+            frames: VecDeque::new(),
+        }
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Changes the capacity, at least 1. Frames beyond it are dropped, oldest first.
+    pub fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity.max(1);
+        while self.frames.len() > self.capacity {
+            self.frames.pop_front();
+        }
+    }
+
+    pub fn policy(&self) -> Coalesce {
+        self.policy
+    }
+
+    /// Changes the policy. It applies to frames pushed from now on.
+    pub fn set_policy(&mut self, policy: Coalesce) {
+        self.policy = policy;
+    }
+
+    /// Queues a frame behind the pending ones. With [`Coalesce::LatestWins`], those it covers
+    /// are dropped and it's merged into a pending one it lines up with instead, if it can be.
+    /// Returns the oldest frame if it had to be dropped to stay within the capacity.
+    pub fn push(&mut self, frame: Frame) -> Option<Frame> {
+        if self.policy == Coalesce::LatestWins {
+            let before = self.frames.len();
+            self.frames.retain(|pending| !frame.covers(pending));
+            if self.frames.len() < before {
+                trace!("dropped {} covered frames", before - self.frames.len());
+            }
+            if self.merge(&frame) {
+                return None;
+            }
+        }
+        self.frames.push_back(frame);
+        if self.frames.len() > self.capacity {
+            warn!("frame queue full, dropping the oldest frame");
+            return self.frames.pop_front();
+        }
+        None
+    }
+
+    // Draws `frame` into the newest pending frame it can be merged with, as long as no frame
+    // after that one overlaps it, which would otherwise end up drawn over the newer contents.
+    fn merge(&mut self, frame: &Frame) -> bool {
+        for i in (0..self.frames.len()).rev() {
+            if let Some(merged) = self.frames[i].merged(frame) {
+                // Frames before it that the merged one covers aren't needed anymore.
+                let before = self.frames.len();
+                let mut index = 0;
+                self.frames.retain(|pending| {
+                    index += 1;
+                    index > i || !merged.covers(pending)
+                });
+                let dropped = before - self.frames.len();
+                trace!(
+                    "merged frame into a pending one, dropped {} covered",
+                    dropped
+                );
+                self.frames[i - dropped] = merged;
+                return true;
+            }
+            if self.frames[i].intersects(frame) {
+                break;
+            }
+        }
+        false
+    }
+
+    /// The oldest pending frame.
+    pub fn pop(&mut self) -> Option<Frame> {
+        self.frames.pop_front()
+    }
+
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Drops the pending frames, e.g. when the host goes away.
+    pub fn clear(&mut self) {
+        self.frames.clear();
+    }
+
+    /// Passes the pending frames on to `sink`, oldest first. A frame the sink fails on is
+    /// dropped and the error returned, the rest stay queued.
+    pub fn drain_into(&mut self, sink: &mut impl FrameSink) -> anyhow::Result<()> {
+        while let Some(frame) = self.pop() {
+            sink.frame(frame)?;
+        }
+        Ok(())
+    }
+}
+
+/// Receives frames as they come in.
+///
+/// Implemented for closures, so `|frame| { ... }` can be passed wherever a sink is expected.
+pub trait FrameSink {
+    fn frame(&mut self, frame: Frame) -> anyhow::Result<()>;
+}
+
+impl<F: FnMut(Frame) -> anyhow::Result<()>> FrameSink for F {
+    fn frame(&mut self, frame: Frame) -> anyhow::Result<()> {
+        self(frame)
+    }
+}
@@ -0,0 +1,906 @@
+use anyhow::Context;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+#[cfg(feature = "gadget")]
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+#[cfg(feature = "gadget")]
+use usb_gadget::function::custom::{self, CtrlReceiver, CtrlSender, Custom};
+#[cfg(feature = "gadget")]
+use usb_gadget::RegGadget;
+
+use crate::protocol::*;
+use crate::transport::{ControlReceiver, ControlRequest, ControlSender, ControlTransfer};
+use crate::{ConnectorHandle, ProtocolError};
+
+// A control transfer as it's handled, recorded if there's a tracer.
+#[cfg(feature = "trace")]
+type Traced<T> = crate::trace::Traced<T>;
+#[cfg(not(feature = "trace"))]
+type Traced<T> = T;
+
+/// A request the application has to answer. `S` is the [`ControlSender`] used to respond.
+#[derive(Debug)]
+pub enum Event<S> {
+    GetDescriptor(GetDescriptor<S>),
+    GetDisplayModes(GetDisplayModes<S>),
+    GetPixelFormats(GetPixelFormats<S>),
+    /// A frame for the connector of the committed [`Function::state`], whose payload follows on
+    /// the data endpoint.
+    ///
+    /// The descriptor has the host read the status after each request, and it only sends the
+    /// payload once it has. So this comes from the `GUD_REQ_GET_STATUS` following the
+    /// `GUD_REQ_SET_BUFFER`, after the status is sent, leaving the application free to block on
+    /// the data endpoint.
+    Buffer(SetBuffer),
+    /// The host switched the display on or off.
+    DisplayEnable(bool),
+    /// The host wants a connector's status probed now, rather than whenever the device gets to
+    /// it, e.g. because the user asked it to redetect displays. Its status, EDID and modes should
+    /// be refreshed before the host asks for them.
+    ForceDetect {
+        connector: usize,
+    },
+    /// The host configured the function, it'll start with a fresh state.
+    Connect,
+    /// The host reset the bus, re-enumerated the device or was unplugged, and may come back with
+    /// a [`Connect`](Event::Connect). The committed state is cleared, and transfers queued on
+    /// the data endpoint should be dropped with `PixelDataEndpoint::reset`.
+    Reset,
+    /// The function was unbound from the UDC, it gets no more requests until it's bound again.
+    /// The committed state is cleared.
+    Disconnect,
+    /// The bus was suspended, the host won't send frames until it resumes.
+    Suspend,
+    Resume,
+}
+
+#[derive(Debug)]
+pub struct GetDescriptor<S> {
+    sender: Traced<S>,
+    version: u8,
+    compression: u8,
+    advertised: Arc<Mutex<Advertised>>,
+}
+
+#[derive(Debug)]
+pub struct GetDisplayModes<S> {
+    sender: Traced<S>,
+    connector: usize,
+}
+
+#[derive(Debug)]
+pub struct GetPixelFormats<S> {
+    sender: Traced<S>,
+    advertised: Arc<Mutex<Advertised>>,
+}
+
+// What the application answered GET_DESCRIPTOR and GET_FORMATS with, the bounds of the states
+// the host may check: the frames received are sized by them.
+#[derive(Debug, Default)]
+struct Advertised {
+    // The smallest and largest modes, width by height.
+    sizes: Option<((u32, u32), (u32, u32))>,
+    formats: Vec<u8>,
+}
+
+impl<S: ControlSender> GetDescriptor<S> {
+    /// The request's setup packet, e.g. for its wValue and wIndex.
+    pub fn request(&self) -> ControlRequest {
+        self.sender.request()
+    }
+
+    /// The most bytes the host takes in response, its wLength.
+    pub fn max_len(&self) -> usize {
+        self.sender.len()
+    }
+
+    pub fn send_descriptor(
+        self,
+        min_width: u32,
+        min_height: u32,
+        max_width: u32,
+        max_height: u32,
+    ) -> anyhow::Result<()> {
+        let descriptor = DisplayDescriptor {
+            magic: GUD_DISPLAY_MAGIC,
+            version: self.version,
+            flags: GUD_DISPLAY_FLAG_STATUS_ON_SET,
+            compression: self.compression,
+            max_height,
+            max_width,
+            min_height,
+            min_width,
+            max_buffer_size: max_height * max_width * 4,
+        };
+
+        self.sender
+            .send(&descriptor.to_bytes())
+            .context("send display descriptor")?;
+        debug!("sent display descriptor {:?}", descriptor);
+        self.advertised.lock().unwrap().sizes =
+            Some(((min_width, min_height), (max_width, max_height)));
+        Ok(())
+    }
+}
+
+impl<S: ControlSender> GetDisplayModes<S> {
+    /// The request's setup packet, e.g. for its wValue and wIndex.
+    pub fn request(&self) -> ControlRequest {
+        self.sender.request()
+    }
+
+    /// The most bytes the host takes in response, its wLength.
+    pub fn max_len(&self) -> usize {
+        self.sender.len()
+    }
+
+    /// The index of the connector whose modes are requested.
+    pub fn connector(&self) -> usize {
+        self.connector
+    }
+
+    /// The most modes the host takes in response, limited by its transfer length and
+    /// [`GUD_CONNECTOR_MAX_NUM_MODES`]. Lists longer than this can be trimmed beforehand, e.g.
+    /// with [`ModeSet::retain`](crate::ModeSet::retain).
+    pub fn max_modes(&self) -> usize {
+        (self.sender.len() / DisplayMode::LEN).min(GUD_CONNECTOR_MAX_NUM_MODES)
+    }
+
+    /// Sends `modes`, failing with [`ProtocolError::TooMany`] if there are more than
+    /// [`max_modes`](Self::max_modes).
+    pub fn send_modes(self, modes: &[DisplayMode]) -> anyhow::Result<()> {
+        let max = self.max_modes();
+        if modes.len() > max {
+            return Err(ProtocolError::TooMany {
+                what: "display modes",
+                count: modes.len(),
+                max,
+            }
+            .into());
+        }
+
+        let mut buf = Vec::with_capacity(DisplayMode::LEN * modes.len());
+        for mode in modes {
+            mode.encode(&mut buf);
+        }
+
+        self.sender.send(&buf).context("send modes")?;
+
+        Ok(())
+    }
+
+    /// Like [`send_modes`](Self::send_modes), but sends as many of the first modes as fit
+    /// instead of failing, warning about the rest.
+    pub fn send_modes_truncated(self, modes: &[DisplayMode]) -> anyhow::Result<()> {
+        let max = self.max_modes();
+        if modes.len() > max {
+            warn!(
+                "dropping {} of {} display modes",
+                modes.len() - max,
+                modes.len()
+            );
+        }
+        self.send_modes(&modes[..modes.len().min(max)])
+    }
+}
+
+impl<S: ControlSender> GetPixelFormats<S> {
+    /// The request's setup packet, e.g. for its wValue and wIndex.
+    pub fn request(&self) -> ControlRequest {
+        self.sender.request()
+    }
+
+    /// The most bytes the host takes in response, its wLength.
+    pub fn max_len(&self) -> usize {
+        self.sender.len()
+    }
+
+    /// Sends the GUD pixel formats, failing with [`ProtocolError::TooMany`] if there are more
+    /// than the host takes.
+    pub fn send_pixel_formats(self, formats: &[u8]) -> anyhow::Result<()> {
+        let max = self.max_len().min(GUD_FORMATS_MAX_NUM);
+        if formats.len() > max {
+            return Err(ProtocolError::TooMany {
+                what: "pixel formats",
+                count: formats.len(),
+                max,
+            }
+            .into());
+        }
+        self.sender.send(formats).context("send pixel formats")?;
+        debug!("sent pixel formats: {:?}", formats);
+        self.advertised.lock().unwrap().formats = formats.to_vec();
+        Ok(())
+    }
+}
+
+/// Holds back acknowledging `SET_BUFFER` until the display can take another frame, so a host
+/// that renders faster than the panel refreshes is paced by it instead of racing ahead.
+pub trait FramePacer: std::fmt::Debug {
+    /// Blocks until the display of `connector` is ready for the next frame, e.g. until the
+    /// previous frame's page flip completes.
+    fn wait(&mut self, connector: usize) -> anyhow::Result<()>;
+}
+
+/// Dispatches GUD control requests, keeping track of the state negotiated with the host.
+#[derive(Debug)]
+pub struct Function {
+    // Status of the last control request, reported to the host on GUD_REQ_GET_STATUS.
+    status: u8,
+    // State from the last state check, applied when the host commits.
+    pending_state: Option<StateRequest>,
+    // A SET_BUFFER that's been accepted, handed on once the host's read its status.
+    buffer: Option<SetBuffer>,
+    // The last committed state.
+    state: Option<StateRequest>,
+    // The last committed state, kept when the host goes away.
+    last_state: Option<StateRequest>,
+    connectors: Vec<Connector>,
+    // Plane properties, reported on GUD_REQ_GET_PROPERTIES.
+    properties: Properties,
+    advertised: Arc<Mutex<Advertised>>,
+    compression: u8,
+    // The newest protocol version advertised, and the one the host selected, if it did.
+    version: u8,
+    selected_version: Option<u8>,
+    // Whether GUD_REQ_SET_VERSION is handled, it's halted like any unknown request otherwise.
+    negotiate_version: bool,
+    pacer: Option<Box<dyn FramePacer>>,
+    // Set by shutdown, the host can't commit a state from then on.
+    shut_down: bool,
+    // Whether the host's configured the function, so it's there to poll the connectors.
+    #[cfg(feature = "gadget")]
+    connected: bool,
+    #[cfg(feature = "trace")]
+    tracer: Option<crate::trace::Tracer>,
+}
+
+#[derive(Debug)]
+struct Connector {
+    descriptor: ConnectorDescriptor,
+    properties: Properties,
+    handle: ConnectorHandle,
+}
+
+impl Default for Connector {
+    fn default() -> Self {
+        Self {
+            descriptor: ConnectorDescriptor {
+                connector_type: GUD_CONNECTOR_TYPE_PANEL,
+                flags: 0,
+            },
+            properties: Properties::new(),
+            handle: ConnectorHandle::default(),
+        }
+    }
+}
+
+impl Default for Function {
+    fn default() -> Self {
+        Self {
+            status: GUD_STATUS_OK,
+            pending_state: None,
+            buffer: None,
+            state: None,
+            last_state: None,
+            connectors: vec![Connector::default()],
+            properties: Properties::new(),
+            advertised: Arc::default(),
+            compression: GUD_COMPRESSION_LZ4,
+            version: GUD_PROTOCOL_VERSION,
+            selected_version: None,
+            negotiate_version: false,
+            pacer: None,
+            shut_down: false,
+            #[cfg(feature = "gadget")]
+            connected: false,
+            #[cfg(feature = "trace")]
+            tracer: None,
+        }
+    }
+}
+
+impl Function {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds another connector, returning its index. The function starts out with one.
+    ///
+    /// The host gets a display per connector, it picks the one it's driving with the `connector`
+    /// of its state. Panics past [`GUD_CONNECTORS_MAX_NUM`] connectors.
+    pub fn add_connector(&mut self) -> usize {
+        assert!(
+            self.connectors.len() < GUD_CONNECTORS_MAX_NUM,
+            "too many connectors"
+        );
+        self.connectors.push(Connector::default());
+        self.connectors.len() - 1
+    }
+
+    /// The number of connectors reported to the host.
+    pub fn connector_count(&self) -> usize {
+        self.connectors.len()
+    }
+
+    /// Sets the `GUD_CONNECTOR_TYPE_*` reported for `connector`. Defaults to a panel.
+    ///
+    /// Panics if there's no such connector.
+    pub fn set_connector_type(&mut self, connector: usize, connector_type: u8) {
+        self.connectors[connector].descriptor.connector_type = connector_type;
+    }
+
+    /// Sets the `GUD_CONNECTOR_FLAGS_*` reported for `connector`.
+    ///
+    /// With `GUD_CONNECTOR_FLAGS_POLL_STATUS` the host polls the connector status and hot-adds or
+    /// removes the display as it changes, see [`ConnectorHandle::set_status`]. Panics if there's
+    /// no such connector.
+    pub fn set_connector_flags(&mut self, connector: usize, flags: u32) {
+        self.connectors[connector].descriptor.flags = flags;
+    }
+
+    /// Sets the properties reported for `connector`, e.g. its backlight. There are none by
+    /// default.
+    ///
+    /// Panics if there's no such connector.
+    pub fn set_connector_properties(&mut self, connector: usize, properties: Properties) {
+        self.connectors[connector].properties = properties;
+    }
+
+    /// Sets the status reported for `connector`. Connectors start out connected.
+    ///
+    /// Panics if there's no such connector.
+    pub fn set_connector_status(&mut self, connector: usize, status: ConnectorStatus) {
+        self.connectors[connector].handle.set_status(status);
+    }
+
+    /// Sets the modes reported for `connector`, see [`ConnectorHandle::set_modes`].
+    ///
+    /// Panics if there's no such connector.
+    pub fn set_connector_modes(&mut self, connector: usize, modes: Vec<DisplayMode>) {
+        self.connectors[connector].handle.set_modes(modes);
+    }
+
+    /// The status reported for `connector`, if it exists.
+    pub fn connector_status(&self, connector: usize) -> Option<ConnectorStatus> {
+        self.connectors
+            .get(connector)
+            .map(|connector| connector.handle.status())
+    }
+
+    /// A handle to update the status of `connector` from elsewhere, if it exists.
+    pub fn connector(&self, connector: usize) -> Option<ConnectorHandle> {
+        self.connectors
+            .get(connector)
+            .map(|connector| connector.handle.clone())
+    }
+
+    /// Sets the plane properties reported to the host, e.g. the supported rotations. There are
+    /// none by default.
+    pub fn set_properties(&mut self, properties: Properties) {
+        self.properties = properties;
+    }
+
+    /// Sets the `GUD_COMPRESSION_*` flags advertised to the host, 0 disables compression.
+    /// Defaults to LZ4.
+    pub fn set_compression(&mut self, compression: u8) {
+        self.compression = compression;
+    }
+
+    /// Sets the protocol version advertised in the display descriptor, the newest the host may
+    /// select with [`GUD_REQ_SET_VERSION`]. Defaults to [`GUD_PROTOCOL_VERSION`].
+    ///
+    /// Panics on 0, which the kernel's driver rejects.
+    pub fn set_version(&mut self, version: u8) {
+        assert!(version > 0, "protocol version 0");
+        self.version = version;
+    }
+
+    /// Handles [`GUD_REQ_SET_VERSION`], this crate's extension for a host to select an older
+    /// protocol version. Off by default, the request is halted like any other the kernel's
+    /// protocol doesn't have.
+    pub fn set_version_negotiation(&mut self, enabled: bool) {
+        self.negotiate_version = enabled;
+    }
+
+    /// The protocol version spoken with the host: the one it selected, or the advertised one if
+    /// it didn't select any.
+    pub fn version(&self) -> u8 {
+        self.selected_version.unwrap_or(self.version)
+    }
+
+    /// Paces `SET_BUFFER` acknowledgements with `pacer`, `None` acknowledges them right away.
+    pub fn set_frame_pacer(&mut self, pacer: Option<Box<dyn FramePacer>>) {
+        self.pacer = pacer;
+    }
+
+    /// Records the control transfers with the host to `tracer`, see [`trace`](crate::trace).
+    #[cfg(feature = "trace")]
+    pub fn set_tracer(&mut self, tracer: Option<crate::trace::Tracer>) {
+        self.tracer = tracer;
+    }
+
+    /// The currently committed state, if any.
+    pub fn state(&self) -> Option<&StateRequest> {
+        self.state.as_ref()
+    }
+
+    /// The currently committed display mode, if any.
+    pub fn mode(&self) -> Option<&DisplayMode> {
+        self.state.as_ref().map(|state| &state.mode)
+    }
+
+    /// The GUD pixel format of the currently committed state, if any.
+    pub fn format(&self) -> Option<u8> {
+        self.state.as_ref().map(|state| state.format)
+    }
+
+    /// Parks the display ahead of the application stopping, e.g. on Ctrl-C: every connector is
+    /// reported disconnected, the committed state is dropped so further frames are refused, and
+    /// the host can't commit a new one. The host only learns of it once it polls the connectors,
+    /// see [`is_shutdown_reported`](Self::is_shutdown_reported), so keep handling requests until
+    /// then before unbinding the gadget, or use [`stop`](Self::stop) which does all that.
+    ///
+    /// The last state is kept, to be saved with [`save_state`](Self::save_state).
+    pub fn shutdown(&mut self) {
+        debug!("shutting down");
+        for connector in &self.connectors {
+            connector.handle.set_status(ConnectorStatus::Disconnected);
+        }
+        self.pending_state = None;
+        self.buffer = None;
+        self.state = None;
+        self.shut_down = true;
+    }
+
+    /// Whether [`shutdown`](Self::shutdown) has been called.
+    pub fn is_shut_down(&self) -> bool {
+        self.shut_down
+    }
+
+    /// Whether the host's read the status of every connector it polls, those with
+    /// `GUD_CONNECTOR_FLAGS_POLL_STATUS`, since [`shutdown`](Self::shutdown), so it's dropped
+    /// the displays. With none polled, the host only finds out when the gadget's unbound, so
+    /// this is true as soon as it's shut down.
+    pub fn is_shutdown_reported(&self) -> bool {
+        self.shut_down
+            && self
+                .connectors
+                .iter()
+                .filter(|connector| {
+                    connector.descriptor.flags & GUD_CONNECTOR_FLAGS_POLL_STATUS != 0
+                })
+                .all(|connector| !connector.handle.is_changed())
+    }
+
+    /// [`shutdown`](Self::shutdown)s and keeps handling the requests on `custom` until the host
+    /// has read that the connectors are disconnected, or `timeout` has passed, then unbinds
+    /// `gadget`. That way the host drops the displays rather than keep frozen ones around, and
+    /// the UDC isn't left half set up. Requests that need the application are refused.
+    ///
+    /// The host polls connectors with `GUD_CONNECTOR_FLAGS_POLL_STATUS` every 10 seconds or so,
+    /// the timeout should allow for that. It's not waited for if the host isn't connected or no
+    /// connector has the flag, since then the host isn't polling at all.
+    #[cfg(feature = "gadget")]
+    pub fn stop(
+        &mut self,
+        custom: &mut Custom,
+        gadget: &mut RegGadget,
+        timeout: Duration,
+    ) -> anyhow::Result<()> {
+        self.shutdown();
+        let deadline = Instant::now() + timeout;
+        while self.connected && !self.is_shutdown_reported() {
+            let Some(remaining) = deadline.checked_duration_since(Instant::now()) else {
+                warn!("host didn't poll the connectors before unbinding");
+                break;
+            };
+            let Some(event) = custom
+                .event_timeout(remaining)
+                .context("read FunctionFS event")?
+            else {
+                continue;
+            };
+            if let Err(err) = self.event(event) {
+                debug!("GUD request while shutting down failed: {:#}", err);
+            }
+        }
+        debug!("unbinding gadget");
+        gadget.bind(None).context("unbind gadget")
+    }
+
+    /// The last state the host committed. Unlike [`state`](Self::state), it's kept when the host
+    /// resets or goes away, so the display can be set up the way it was as soon as the host is
+    /// back, without waiting for it to negotiate again.
+    pub fn last_state(&self) -> Option<&StateRequest> {
+        self.last_state.as_ref()
+    }
+
+    /// Sets the state reported by [`last_state`](Self::last_state) until the host commits one,
+    /// e.g. one kept from before the application restarted.
+    pub fn restore_state(&mut self, state: StateRequest) {
+        self.last_state = Some(state);
+    }
+
+    /// Writes the [`last_state`](Self::last_state) to `path`, replacing the file in one go so
+    /// it's never left half written. Does nothing if there's no state yet.
+    pub fn save_state(&self, path: &Path) -> anyhow::Result<()> {
+        let Some(state) = &self.last_state else {
+            return Ok(());
+        };
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, state.to_bytes()).with_context(|| format!("write {}", tmp.display()))?;
+        fs::rename(&tmp, path).with_context(|| format!("rename to {}", path.display()))
+    }
+
+    /// Restores the state saved to `path` with [`save_state`](Self::save_state), returning
+    /// whether there was one.
+    pub fn load_state(&mut self, path: &Path) -> anyhow::Result<bool> {
+        let buf = match fs::read(path) {
+            Ok(buf) => buf,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err).with_context(|| format!("read {}", path.display())),
+        };
+        let state =
+            StateRequest::from_bytes(&buf).with_context(|| format!("parse {}", path.display()))?;
+        self.restore_state(state);
+        Ok(true)
+    }
+
+    /// Handles the next FunctionFS event waiting on `custom`, without blocking. `None` if there
+    /// isn't one or it needed no handling by the caller. For event loops that poll the
+    /// function's ep0 descriptor (`Custom::fd`) along with their own.
+    #[cfg(feature = "gadget")]
+    pub fn try_event<'a>(
+        &mut self,
+        custom: &'a mut Custom,
+    ) -> anyhow::Result<Option<Event<CtrlSender<'a>>>> {
+        match custom.try_event().context("read FunctionFS event")? {
+            Some(event) => self.event(event),
+            None => Ok(None),
+        }
+    }
+
+    /// Handles a FunctionFS event.
+    #[cfg(feature = "gadget")]
+    pub fn event<'a>(
+        &mut self,
+        event: custom::Event<'a>,
+    ) -> anyhow::Result<Option<Event<CtrlSender<'a>>>> {
+        let transfer: ControlTransfer<CtrlSender<'a>, CtrlReceiver<'a>> = match event {
+            custom::Event::SetupDeviceToHost(req) => ControlTransfer::DeviceToHost(req),
+            custom::Event::SetupHostToDevice(req) => ControlTransfer::HostToDevice(req),
+            custom::Event::Bind => return Ok(None),
+            custom::Event::Enable => {
+                debug!("host connected");
+                self.connected = true;
+                return Ok(Some(Event::Connect));
+            }
+            custom::Event::Disable => {
+                debug!("host reset");
+                self.connected = false;
+                self.pending_state = None;
+                self.buffer = None;
+                self.state = None;
+                self.selected_version = None;
+                return Ok(Some(Event::Reset));
+            }
+            custom::Event::Unbind => {
+                debug!("function unbound");
+                self.connected = false;
+                self.pending_state = None;
+                self.buffer = None;
+                self.state = None;
+                self.selected_version = None;
+                return Ok(Some(Event::Disconnect));
+            }
+            custom::Event::Suspend => return Ok(Some(Event::Suspend)),
+            custom::Event::Resume => return Ok(Some(Event::Resume)),
+            event => {
+                warn!("unhandled event {:?}", event);
+                return Ok(None);
+            }
+        };
+        self.control(transfer)
+    }
+
+    /// Handles a control transfer from the host.
+    pub fn control<S: ControlSender, R: ControlReceiver>(
+        &mut self,
+        transfer: ControlTransfer<S, R>,
+    ) -> anyhow::Result<Option<Event<S>>> {
+        let result = self.dispatch(transfer);
+        if let Err(err) = &result {
+            if let Some(err) = err.downcast_ref::<ProtocolError>() {
+                self.status = err.status();
+            }
+        }
+        result
+    }
+
+    #[cfg(feature = "trace")]
+    fn trace_sender<S: ControlSender>(&self, sender: S) -> Traced<S> {
+        Traced::sender(sender, self.tracer.clone())
+    }
+
+    #[cfg(not(feature = "trace"))]
+    fn trace_sender<S>(&self, sender: S) -> Traced<S> {
+        sender
+    }
+
+    #[cfg(feature = "trace")]
+    fn trace_receiver<R: ControlReceiver>(&self, receiver: R) -> Traced<R> {
+        Traced::receiver(receiver, self.tracer.clone())
+    }
+
+    #[cfg(not(feature = "trace"))]
+    fn trace_receiver<R>(&self, receiver: R) -> Traced<R> {
+        receiver
+    }
+
+    fn dispatch<S: ControlSender, R: ControlReceiver>(
+        &mut self,
+        transfer: ControlTransfer<S, R>,
+    ) -> anyhow::Result<Option<Event<S>>> {
+        match transfer {
+            ControlTransfer::DeviceToHost(req) => {
+                let req = self.trace_sender(req);
+                let ctrl_req = req.request();
+                if ctrl_req.request != GUD_REQ_GET_STATUS {
+                    self.status = GUD_STATUS_OK;
+                    self.drop_buffer();
+                }
+                match ctrl_req.request {
+                    GUD_REQ_GET_STATUS => {
+                        let buffer = self.buffer.take();
+                        req.send(&[self.status]).context("send status")?;
+                        debug!("sent status {}", self.status);
+                        // The host sends the payload now that it's seen the buffer's accepted.
+                        if let Some(buffer) = buffer {
+                            return Ok(Some(Event::Buffer(buffer)));
+                        }
+                    }
+                    GUD_REQ_GET_DESCRIPTOR => {
+                        return Ok(Some(Event::GetDescriptor(GetDescriptor {
+                            sender: req,
+                            version: self.version,
+                            compression: self.compression(),
+                            advertised: self.advertised.clone(),
+                        })));
+                    }
+                    GUD_REQ_GET_FORMATS => {
+                        return Ok(Some(Event::GetPixelFormats(GetPixelFormats {
+                            sender: req,
+                            advertised: self.advertised.clone(),
+                        })));
+                    }
+                    GUD_REQ_GET_PROPERTIES => {
+                        let properties = self.negotiated(&self.properties);
+                        send_properties(req, properties).context("send properties")?;
+                        debug!("sent {} properties", properties.len());
+                    }
+                    GUD_REQ_GET_CONNECTORS => {
+                        let mut buf =
+                            Vec::with_capacity(ConnectorDescriptor::LEN * self.connectors.len());
+                        for connector in &self.connectors {
+                            connector.descriptor.encode(&mut buf);
+                        }
+                        req.send(&buf).context("send connectors")?;
+                        debug!("sent {} connectors", self.connectors.len());
+                    }
+                    GUD_REQ_GET_CONNECTOR_PROPERTIES => {
+                        let properties =
+                            self.negotiated(&self.find_connector(ctrl_req.value)?.properties);
+                        send_properties(req, properties).context("send connector properties")?;
+                        debug!("sent {} connector properties", properties.len());
+                    }
+                    GUD_REQ_GET_CONNECTOR_MODES => {
+                        let modes = self.find_connector(ctrl_req.value)?.handle.modes();
+                        let req = GetDisplayModes {
+                            sender: req,
+                            connector: ctrl_req.value.into(),
+                        };
+                        match modes {
+                            Some(modes) => {
+                                req.send_modes_truncated(&modes)?;
+                                debug!("sent {} modes", modes.len());
+                            }
+                            None => return Ok(Some(Event::GetDisplayModes(req))),
+                        }
+                    }
+                    GUD_REQ_GET_CONNECTOR_EDID => {
+                        let edid = self.find_connector(ctrl_req.value)?.handle.edid();
+                        match edid.is_empty() {
+                            true => req.send(&[0]),
+                            false => {
+                                let len = edid.len().min(GUD_CONNECTOR_MAX_EDID_LEN);
+                                req.send(&edid[..len])
+                            }
+                        }
+                        .context("send EDID")?;
+                        debug!("sent EDID of {} bytes", edid.len());
+                    }
+                    GUD_REQ_GET_CONNECTOR_STATUS => {
+                        let status = self.find_connector(ctrl_req.value)?.handle.report();
+                        req.send(&[status]).context("send connector status")?;
+                        debug!("sent connector status {:#x}", status);
+                    }
+                    v => {
+                        warn!("unhandled SetupDeviceToHost request {:x}", v);
+                        self.status = GUD_STATUS_REQUEST_NOT_SUPPORTED;
+                        req.halt().context("halt unhandled request")?;
+                    }
+                }
+            }
+            ControlTransfer::HostToDevice(req) => {
+                let req = self.trace_receiver(req);
+                let ctrl_req = req.request();
+                self.status = GUD_STATUS_OK;
+                self.drop_buffer();
+                match ctrl_req.request {
+                    GUD_REQ_SET_VERSION if self.negotiate_version => {
+                        let req = req.recv_all().context("recv set version")?;
+                        let [version] = req[..] else {
+                            return Err(ProtocolError::Malformed("version").into());
+                        };
+                        if version == 0 || version > self.version {
+                            return Err(ProtocolError::UnsupportedVersion {
+                                version,
+                                max: self.version,
+                            }
+                            .into());
+                        }
+                        debug!("host selected protocol version {}", version);
+                        self.selected_version = Some(version);
+                    }
+                    GUD_REQ_SET_CONNECTOR_FORCE_DETECT => {
+                        debug!("force detect on connector {}", ctrl_req.value);
+                        req.recv_all().context("recv set connector force detect")?;
+                        self.find_connector(ctrl_req.value)?;
+                        return Ok(Some(Event::ForceDetect {
+                            connector: ctrl_req.value.into(),
+                        }));
+                    }
+                    GUD_REQ_SET_STATE_CHECK => {
+                        let req = req.recv_all().context("recv set state check")?;
+                        let state = StateRequest::from_bytes(&req)?;
+                        debug!("received state check: {:?}", state);
+                        if self.shut_down {
+                            return Err(ProtocolError::ShutDown.into());
+                        }
+                        self.find_connector(state.connector.into())?;
+                        self.check_advertised(&state)?;
+                        self.pending_state = Some(state);
+                    }
+                    GUD_REQ_SET_CONTROLLER_ENABLE => {
+                        let req = req.recv_all().context("recv set controller enable")?;
+                        debug!("received controller enable: {:?}", req);
+                    }
+                    GUD_REQ_SET_DISPLAY_ENABLE => {
+                        let req = req.recv_all().context("recv set display enable")?;
+                        debug!("received display enable: {:?}", req);
+                        let [enable] = req[..] else {
+                            return Err(ProtocolError::Malformed("display enable").into());
+                        };
+                        return Ok(Some(Event::DisplayEnable(enable != 0)));
+                    }
+                    GUD_REQ_SET_STATE_COMMIT => {
+                        req.recv_all().context("recv set state commit")?;
+                        debug!("received state commit");
+                        if let Some(state) = self.pending_state.take() {
+                            self.last_state = Some(state.clone());
+                            self.state = Some(state);
+                        }
+                    }
+                    GUD_REQ_SET_BUFFER => {
+                        if let (Some(pacer), Some(state)) = (&mut self.pacer, &self.state) {
+                            // The host would rather have a late frame than none at all.
+                            if let Err(err) = pacer.wait(state.connector.into()) {
+                                warn!("frame pacing failed: {:#}", err);
+                            }
+                        }
+                        let req = req.recv_all().context("recv set buffer")?;
+                        let v = SetBuffer::from_bytes(&req)?;
+                        debug!("received set buffer: {:?}", v);
+                        if v.compression != 0 && self.version() < GUD_VERSION_COMPRESSION {
+                            return Err(ProtocolError::NotInVersion {
+                                what: "compression",
+                                version: self.version(),
+                            }
+                            .into());
+                        }
+                        if v.compression & !self.compression() != 0 {
+                            return Err(ProtocolError::UnsupportedCompression(v.compression).into());
+                        }
+                        let state = self.state.as_ref().ok_or(ProtocolError::NoMode)?;
+                        // Checked here rather than when the payload comes in, so the host gets
+                        // an error status instead of sending a payload that'd be refused.
+                        v.validate(&state.mode)?;
+                        v.validate_format(state.format)?;
+                        self.buffer = Some(v);
+                    }
+                    v => {
+                        warn!("unhandled set request {:x}", v);
+                        self.status = GUD_STATUS_REQUEST_NOT_SUPPORTED;
+                        req.halt().context("halt unhandled request")?;
+                    }
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    // Refuses a state with a mode or format the host wasn't offered, since the buffers frames
+    // are received into are sized by them.
+    fn check_advertised(&self, state: &StateRequest) -> Result<(), ProtocolError> {
+        let advertised = self.advertised.lock().unwrap();
+        let ((min_width, min_height), (max_width, max_height)) = advertised
+            .sizes
+            .ok_or(ProtocolError::NotAdvertised("display descriptor"))?;
+        let (width, height) = (state.mode.hdisplay.into(), state.mode.vdisplay.into());
+        if !(min_width..=max_width).contains(&width) || !(min_height..=max_height).contains(&height)
+        {
+            return Err(ProtocolError::ModeOutOfRange {
+                width,
+                height,
+                min_width,
+                min_height,
+                max_width,
+                max_height,
+            });
+        }
+        if !advertised.formats.contains(&state.format) {
+            return Err(ProtocolError::FormatNotAdvertised(state.format));
+        }
+        Ok(())
+    }
+
+    // Forgets an accepted SET_BUFFER the host went on without reading the status of.
+    fn drop_buffer(&mut self) {
+        if let Some(buffer) = self.buffer.take() {
+            warn!("host didn't read the status of {:?}, dropping it", buffer);
+        }
+    }
+
+    // The compression advertised, if the protocol version spoken has it.
+    fn compression(&self) -> u8 {
+        match self.version() >= GUD_VERSION_COMPRESSION {
+            true => self.compression,
+            false => 0,
+        }
+    }
+
+    // `properties`, or none if the protocol version spoken doesn't have them.
+    fn negotiated<'a>(&self, properties: &'a Properties) -> &'a Properties {
+        static NONE: Properties = Properties::new();
+        match self.version() >= GUD_VERSION_PROPERTIES {
+            true => properties,
+            false => &NONE,
+        }
+    }
+
+    // The connector a request's wValue refers to.
+    fn find_connector(&self, index: u16) -> Result<&Connector, ProtocolError> {
+        self.connectors
+            .get(usize::from(index))
+            .ok_or(ProtocolError::NoConnector(index))
+    }
+}
+
+// Sends `properties`, refusing to truncate them: the host would misread a partial list.
+fn send_properties(sender: impl ControlSender, properties: &Properties) -> anyhow::Result<()> {
+    let buf = properties.to_bytes();
+    if buf.len() > sender.len() {
+        return Err(ProtocolError::TooMany {
+            what: "properties",
+            count: properties.len(),
+            max: sender.len() / Property::LEN,
+        }
+        .into());
+    }
+    sender.send(&buf)?;
+    Ok(())
+}
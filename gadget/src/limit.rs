@@ -0,0 +1,103 @@
+//! Capping how often frames are presented.
+//!
+//! Hosts send a frame for every damage rect, and some send them far faster than slow panels
+//! refresh, e.g. SPI panels at 30Hz or less. Presenting each one costs a page flip or a transfer
+//! to the panel. A [`FrameLimiter`] merges the damage of the frames received within its interval
+//! instead, so they're presented at once. The host's frames are still received as they come, so
+//! it's never kept waiting.
+
+use std::time::{Duration, Instant};
+
+use crate::SetBuffer;
+
+/// A damage rect, or the bounding box of several.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Damage {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+impl Damage {
+    /// The damage rect of `info`.
+    pub fn of(info: &SetBuffer) -> Self {
+        Self {
+            x: info.x,
+            y: info.y,
+            width: info.width,
+            height: info.height,
+        }
+    }
+
+    /// The smallest rect covering both.
+    pub fn union(self, other: Self) -> Self {
+        let (x, y) = (self.x.min(other.x), self.y.min(other.y));
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Self {
+            x,
+            y,
+            width: right - x,
+            height: bottom - y,
+        }
+    }
+}
+
+/// Presents at most a given number of frames a second, merging the damage of those received in
+/// between.
+#[derive(Clone, Debug, Default)]
+pub struct FrameLimiter {
+    interval: Duration,
+    // When damage was last presented.
+    presented: Option<Instant>,
+    // The damage received since, and how many frames it's from.
+    pending: Option<(Damage, u64)>,
+}
+
+impl FrameLimiter {
+    /// Presents at most `fps` frames a second, or every frame as it arrives with `None`.
+    pub fn new(fps: Option<u32>) -> Self {
+        Self {
+            interval: fps.map_or(Duration::ZERO, |fps| Duration::from_secs(1) / fps.max(1)),
+            ..Default::default()
+        }
+    }
+
+    /// Adds the damage of a received frame.
+    pub fn add(&mut self, info: &SetBuffer) {
+        let damage = Damage::of(info);
+        self.pending = Some(match self.pending {
+            Some((pending, frames)) => (pending.union(damage), frames + 1),
+            None => (damage, 1),
+        });
+    }
+
+    /// When the pending damage is due to be presented, `None` if there's none.
+    pub fn deadline(&self) -> Option<Instant> {
+        self.pending?;
+        Some(match self.presented {
+            Some(presented) => presented + self.interval,
+            None => Instant::now(),
+        })
+    }
+
+    /// Takes the pending damage if it's due to be presented at `now`, with the number of frames
+    /// merged into it.
+    pub fn take_due(&mut self, now: Instant) -> Option<(Damage, u64)> {
+        if self
+            .presented
+            .is_some_and(|presented| now < presented + self.interval)
+        {
+            return None;
+        }
+        let pending = self.pending.take()?;
+        self.presented = Some(now);
+        Some(pending)
+    }
+
+    /// Drops the pending damage, e.g. when the host goes away.
+    pub fn clear(&mut self) {
+        self.pending = None;
+    }
+}
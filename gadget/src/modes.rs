@@ -0,0 +1,113 @@
+use std::cmp::Reverse;
+
+use crate::protocol::{DisplayMode, ModeFlags, GUD_DISPLAY_MODE_FLAG_PREFERRED};
+
+/// A connector's modes, kept in the order the host should see them.
+///
+/// The host takes the first mode flagged as preferred as the default, falling back to the first
+/// one. Modes are kept without duplicates, the preferred one first, then the rest largest and
+/// fastest first, like DRM sorts a connector's modes.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ModeSet {
+    modes: Vec<DisplayMode>,
+}
+
+impl ModeSet {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a mode, returning whether it's new. A mode with the same timings as one that's
+    /// already there only adds its preferred flag to it.
+    pub fn insert(&mut self, mode: DisplayMode) -> bool {
+        let added = match self.modes.iter_mut().find(|m| same_timings(m, &mode)) {
+            Some(existing) => {
+                let preferred = mode.mode_flags() & ModeFlags::PREFERRED;
+                existing.set_mode_flags(existing.mode_flags() | preferred);
+                false
+            }
+            None => {
+                self.modes.push(mode);
+                true
+            }
+        };
+        self.sort();
+        added
+    }
+
+    /// Leaves out the modes `keep` returns false for, e.g. ones the panel or the conversion path
+    /// can't keep up with.
+    pub fn retain(&mut self, keep: impl FnMut(&DisplayMode) -> bool) {
+        self.modes.retain(keep);
+    }
+
+    /// Makes the first mode `is_preferred` picks the only preferred one. If there's no such mode,
+    /// nothing changes and false is returned.
+    pub fn set_preferred(&mut self, mut is_preferred: impl FnMut(&DisplayMode) -> bool) -> bool {
+        let Some(index) = self.modes.iter().position(&mut is_preferred) else {
+            return false;
+        };
+        for (i, mode) in self.modes.iter_mut().enumerate() {
+            mode.set_mode_flags(mode.mode_flags().difference(ModeFlags::PREFERRED));
+            if i == index {
+                mode.set_mode_flags(mode.mode_flags() | ModeFlags::PREFERRED);
+            }
+        }
+        self.sort();
+        true
+    }
+
+    /// The mode the host picks by default, if there are any.
+    pub fn preferred(&self) -> Option<&DisplayMode> {
+        self.modes.first()
+    }
+
+    /// The modes in the order they're sent to the host.
+    pub fn modes(&self) -> &[DisplayMode] {
+        &self.modes
+    }
+
+    pub fn len(&self) -> usize {
+        self.modes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.modes.is_empty()
+    }
+
+    fn sort(&mut self) {
+        // Stable, so modes that compare equal keep the order they were added in.
+        self.modes.sort_by_key(|mode| {
+            (
+                !mode.mode_flags().contains(ModeFlags::PREFERRED),
+                Reverse(mode.hdisplay as u32 * mode.vdisplay as u32),
+                Reverse(mode.vrefresh()),
+                Reverse(mode.clock),
+            )
+        });
+    }
+}
+
+impl Extend<DisplayMode> for ModeSet {
+    fn extend<I: IntoIterator<Item = DisplayMode>>(&mut self, modes: I) {
+        for mode in modes {
+            self.insert(mode);
+        }
+    }
+}
+
+impl FromIterator<DisplayMode> for ModeSet {
+    fn from_iter<I: IntoIterator<Item = DisplayMode>>(modes: I) -> Self {
+        let mut set = Self::new();
+        set.extend(modes);
+        set
+    }
+}
+
+fn same_timings(a: &DisplayMode, b: &DisplayMode) -> bool {
+    let timings = |mode: &DisplayMode| DisplayMode {
+        flags: mode.flags & !GUD_DISPLAY_MODE_FLAG_PREFERRED,
+        ..mode.clone()
+    };
+    timings(a) == timings(b)
+}
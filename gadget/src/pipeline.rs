@@ -0,0 +1,219 @@
+//! Receiving, decoding and presenting frames on separate threads.
+//!
+//! [`PixelDataEndpoint`]'s `recv_*` methods do everything for a frame in turn on the calling
+//! thread, so the bulk endpoint sits idle while a frame is decompressed and drawn. A [`Pipeline`]
+//! instead has one thread drain the endpoint and another decompress and convert what it read,
+//! handing finished frames to the presenter over a bounded channel. On multi-core SoCs the USB
+//! transfer of a frame then overlaps with decoding the one before and presenting the one before
+//! that.
+
+use anyhow::Context;
+use bytes::{Bytes, BytesMut};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use tracing::{trace_span, warn};
+
+use crate::blit::{self, Lut, Swizzle};
+use crate::debug::DebugOptions;
+use crate::endpoint;
+use crate::protocol::PixelFormat;
+use crate::{Frame, PixelDataEndpoint, ProtocolError, SetBuffer, Stats};
+
+enum Request {
+    Buffer(SetBuffer, u8),
+    Reset,
+}
+
+type Payload = anyhow::Result<(SetBuffer, u8, Bytes)>;
+
+/// A [`PixelDataEndpoint`] read on a thread of its own, with frames decoded on another.
+///
+/// Each [`Event::Buffer`](crate::Event::Buffer) is passed on with [`submit`](Self::submit), and
+/// the frames come out of [`frames`](Self::frames) in the same order, decompressed and converted
+/// to the format set on the endpoint with
+/// [`set_convert_to`](PixelDataEndpoint::set_convert_to) and
+/// [`set_swizzle`](PixelDataEndpoint::set_swizzle), and color corrected as set with
+/// [`set_lut`](PixelDataEndpoint::set_lut). Scaling and transforms are left to the
+/// presenter. When the presenter falls behind by more frames than the pipeline's depth, the
+/// endpoint stops being read, which holds up the host until it catches up.
+pub struct Pipeline {
+    requests: Option<Sender<Request>>,
+    frames: Receiver<anyhow::Result<Frame>>,
+    stats: Arc<Mutex<Stats>>,
+    decode_errors: Arc<AtomicU64>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl Pipeline {
+    /// Starts the receive and decode threads, with up to `depth` frames waiting between each
+    /// stage.
+    pub fn spawn(mut endpoint: PixelDataEndpoint, depth: usize) -> anyhow::Result<Self> {
+        let (requests, request_rx) = mpsc::channel();
+        let (payloads, payload_rx) = mpsc::sync_channel::<Payload>(depth);
+        let (frames, frame_rx) = mpsc::sync_channel(depth);
+        let stats = Arc::new(Mutex::new(endpoint.stats()));
+        let decode_errors = Arc::new(AtomicU64::new(0));
+        let (convert_to, swizzle) = endpoint.conversion();
+        let (lut, debug) = endpoint.finishing();
+
+        let receive_stats = stats.clone();
+        let receive = thread::Builder::new()
+            .name("gud-receive".into())
+            .spawn(move || {
+                for request in request_rx {
+                    match request {
+                        Request::Buffer(info, format) => {
+                            let payload = endpoint
+                                .recv_payload(&info)
+                                .map(|payload| (info, format, payload));
+                            *receive_stats.lock().unwrap() = endpoint.stats();
+                            if payloads.send(payload).is_err() {
+                                break;
+                            }
+                        }
+                        Request::Reset => {
+                            if let Err(err) = endpoint.reset() {
+                                warn!("resetting data endpoint failed: {:#}", err);
+                            }
+                        }
+                    }
+                }
+            })
+            .context("spawn receive thread")?;
+
+        let errors = decode_errors.clone();
+        let decode = thread::Builder::new()
+            .name("gud-decode".into())
+            .spawn(move || {
+                for payload in payload_rx {
+                    let frame = payload.and_then(|(info, format, payload)| {
+                        let frame = decode(
+                            info,
+                            format,
+                            payload,
+                            convert_to,
+                            swizzle,
+                            lut.as_ref(),
+                            debug,
+                        );
+                        if frame.is_err() {
+                            errors.fetch_add(1, Ordering::Relaxed);
+                        }
+                        Ok(frame?)
+                    });
+                    if frames.send(frame).is_err() {
+                        break;
+                    }
+                }
+            })
+            .context("spawn decode thread")?;
+
+        Ok(Self {
+            requests: Some(requests),
+            frames: frame_rx,
+            stats,
+            decode_errors,
+            threads: vec![receive, decode],
+        })
+    }
+
+    /// Has the payload for `info` received, in the host's GUD pixel `format`. It doesn't wait for
+    /// the transfer, so it can be called from the event loop.
+    ///
+    /// The lengths are checked here, see [`SetBuffer::validate_format`], so a buffer the threads
+    /// would have to allocate too much for is refused to the caller rather than queued.
+    pub fn submit(&self, info: SetBuffer, format: u8) -> Result<(), ProtocolError> {
+        info.validate_format(format)?;
+        self.send(Request::Buffer(info, format));
+        Ok(())
+    }
+
+    /// Like [`PixelDataEndpoint::reset`], once the frames submitted before have been received.
+    /// Frames already on their way to the presenter are still delivered.
+    pub fn reset(&self) {
+        self.send(Request::Reset);
+    }
+
+    /// Finished frames, or why one couldn't be received or decoded, in the order they were
+    /// submitted.
+    pub fn frames(&self) -> &Receiver<anyhow::Result<Frame>> {
+        &self.frames
+    }
+
+    /// What's been received so far, counting frames that failed to decode as errors.
+    pub fn stats(&self) -> Stats {
+        let stats = *self.stats.lock().unwrap();
+        Stats {
+            errors: stats.errors + self.decode_errors.load(Ordering::Relaxed),
+            ..stats
+        }
+    }
+
+    fn send(&self, request: Request) {
+        let requests = self.requests.as_ref().expect("pipeline shut down");
+        // The receive thread only exits once the pipeline's dropped.
+        requests.send(request).ok();
+    }
+}
+
+impl Drop for Pipeline {
+    /// Stops the threads once the submitted transfers are done. Frames still in the
+    /// pipeline are dropped.
+    fn drop(&mut self) {
+        drop(self.requests.take());
+        // The decode thread may be waiting for room to hand over a frame.
+        while self.frames.recv().is_ok() {}
+        for thread in self.threads.drain(..) {
+            thread.join().ok();
+        }
+    }
+}
+
+fn decode(
+    info: SetBuffer,
+    format: u8,
+    payload: Bytes,
+    convert_to: Option<PixelFormat>,
+    swizzle: Swizzle,
+    lut: Option<&Lut>,
+    debug: DebugOptions,
+) -> Result<Frame, ProtocolError> {
+    let _decode = trace_span!("decode", bytes = info.length).entered();
+    let data = if info.compression > 0 {
+        let mut data = BytesMut::zeroed(info.length as usize);
+        let result =
+            trace_span!("decompress").in_scope(|| blit::decompress(&info, &payload, &mut data));
+        if let (Err(err), true) = (&result, debug.validate) {
+            warn!("{} for {:?}", err, info);
+        }
+        result?;
+        data.freeze()
+    } else {
+        payload
+    };
+    let data = if debug.outline || lut.is_some() {
+        // Copied, the payload may be shared.
+        let mut data = BytesMut::from(&data[..]);
+        endpoint::finish(&info, PixelFormat::from_u8(format), lut, debug, &mut data);
+        data.freeze()
+    } else {
+        data
+    };
+    let frame = Frame { info, format, data };
+
+    let to = match convert_to {
+        Some(to) => to,
+        None if swizzle == Swizzle::NONE => return Ok(frame),
+        // Only swizzled, in the host's format.
+        None => PixelFormat::from_u8(format).ok_or(ProtocolError::UnsupportedConversion {
+            from: format,
+            to: format,
+        })?,
+    };
+    if u8::from(to) == format && swizzle == Swizzle::NONE {
+        return Ok(frame);
+    }
+    trace_span!("convert").in_scope(|| frame.convert(to, swizzle))
+}
@@ -0,0 +1,739 @@
+//! GUD protocol definitions, mirroring the kernel's `include/drm/gud.h`.
+//!
+//! All structures are packed and little-endian. They're encoded and decoded field by field rather
+//! than relying on the in-memory layout of the Rust types.
+
+use bytes::{Buf, BufMut};
+
+use crate::ProtocolError;
+
+pub const GUD_DISPLAY_MAGIC: u32 = 0x1d50614d;
+
+/// The newest protocol version, advertised in the display descriptor by default. Like the
+/// versions below, it's not in the kernel's gud.h but this crate's, see [`GUD_REQ_SET_VERSION`].
+///
+/// The kernel's driver doesn't negotiate: it takes any version but 0 and speaks the whole
+/// protocol, which is this version. Version 1 is the bare protocol, without the features
+/// versioned below.
+pub const GUD_PROTOCOL_VERSION: u8 = 3;
+/// The first protocol version with LZ4 compressed buffers. A host that selects an older one gets
+/// no compression advertised and can't send compressed buffers.
+pub const GUD_VERSION_COMPRESSION: u8 = 2;
+/// The first protocol version with plane and connector properties. A host that selects an older
+/// one is sent none.
+pub const GUD_VERSION_PROPERTIES: u8 = 3;
+
+/// Host reads status after every SET request.
+pub const GUD_DISPLAY_FLAG_STATUS_ON_SET: u32 = 0x01;
+/// Host always sends the full framebuffer, not just the damaged region.
+pub const GUD_DISPLAY_FLAG_FULL_UPDATE: u32 = 0x02;
+
+pub const GUD_COMPRESSION_LZ4: u8 = 0x01;
+
+pub const GUD_DISPLAY_MODE_FLAG_PHSYNC: u32 = 1 << 0;
+pub const GUD_DISPLAY_MODE_FLAG_NHSYNC: u32 = 1 << 1;
+pub const GUD_DISPLAY_MODE_FLAG_PVSYNC: u32 = 1 << 2;
+pub const GUD_DISPLAY_MODE_FLAG_NVSYNC: u32 = 1 << 3;
+pub const GUD_DISPLAY_MODE_FLAG_INTERLACE: u32 = 1 << 4;
+pub const GUD_DISPLAY_MODE_FLAG_DBLSCAN: u32 = 1 << 5;
+pub const GUD_DISPLAY_MODE_FLAG_CSYNC: u32 = 1 << 6;
+pub const GUD_DISPLAY_MODE_FLAG_PCSYNC: u32 = 1 << 7;
+pub const GUD_DISPLAY_MODE_FLAG_NCSYNC: u32 = 1 << 8;
+pub const GUD_DISPLAY_MODE_FLAG_PREFERRED: u32 = 1 << 10;
+
+pub const GUD_CONNECTOR_TYPE_PANEL: u8 = 0;
+pub const GUD_CONNECTOR_TYPE_VGA: u8 = 1;
+pub const GUD_CONNECTOR_TYPE_COMPOSITE: u8 = 2;
+pub const GUD_CONNECTOR_TYPE_SVIDEO: u8 = 3;
+pub const GUD_CONNECTOR_TYPE_COMPONENT: u8 = 4;
+pub const GUD_CONNECTOR_TYPE_DVI: u8 = 5;
+pub const GUD_CONNECTOR_TYPE_DISPLAYPORT: u8 = 6;
+pub const GUD_CONNECTOR_TYPE_HDMI: u8 = 7;
+
+/// Host polls the connector status.
+pub const GUD_CONNECTOR_FLAGS_POLL_STATUS: u32 = 1 << 0;
+pub const GUD_CONNECTOR_FLAGS_INTERLACE: u32 = 1 << 1;
+pub const GUD_CONNECTOR_FLAGS_DOUBLESCAN: u32 = 1 << 2;
+
+// Connector properties.
+pub const GUD_PROPERTY_TV_LEFT_MARGIN: u16 = 1;
+pub const GUD_PROPERTY_TV_RIGHT_MARGIN: u16 = 2;
+pub const GUD_PROPERTY_TV_TOP_MARGIN: u16 = 3;
+pub const GUD_PROPERTY_TV_BOTTOM_MARGIN: u16 = 4;
+pub const GUD_PROPERTY_TV_MODE: u16 = 5;
+pub const GUD_PROPERTY_TV_BRIGHTNESS: u16 = 6;
+pub const GUD_PROPERTY_TV_CONTRAST: u16 = 7;
+pub const GUD_PROPERTY_TV_FLICKER_REDUCTION: u16 = 8;
+pub const GUD_PROPERTY_TV_OVERSCAN: u16 = 9;
+pub const GUD_PROPERTY_TV_SATURATION: u16 = 10;
+pub const GUD_PROPERTY_TV_HUE: u16 = 11;
+/// Backlight brightness in the range 0-100.
+pub const GUD_PROPERTY_BACKLIGHT_BRIGHTNESS: u16 = 12;
+
+// Plane properties.
+pub const GUD_PROPERTY_ROTATION: u16 = 50;
+pub const GUD_ROTATION_0: u64 = 1 << 0;
+pub const GUD_ROTATION_90: u64 = 1 << 1;
+pub const GUD_ROTATION_180: u64 = 1 << 2;
+pub const GUD_ROTATION_270: u64 = 1 << 3;
+pub const GUD_ROTATION_REFLECT_X: u64 = 1 << 4;
+pub const GUD_ROTATION_REFLECT_Y: u64 = 1 << 5;
+pub const GUD_ROTATION_MASK: u64 = GUD_ROTATION_0
+    | GUD_ROTATION_90
+    | GUD_ROTATION_180
+    | GUD_ROTATION_270
+    | GUD_ROTATION_REFLECT_X
+    | GUD_ROTATION_REFLECT_Y;
+
+pub const GUD_REQ_GET_STATUS: u8 = 0x00;
+pub const GUD_STATUS_OK: u8 = 0x00;
+pub const GUD_STATUS_BUSY: u8 = 0x01;
+pub const GUD_STATUS_REQUEST_NOT_SUPPORTED: u8 = 0x02;
+pub const GUD_STATUS_PROTOCOL_ERROR: u8 = 0x03;
+pub const GUD_STATUS_INVALID_PARAMETER: u8 = 0x04;
+pub const GUD_STATUS_ERROR: u8 = 0x05;
+
+pub const GUD_REQ_GET_DESCRIPTOR: u8 = 0x01;
+
+pub const GUD_REQ_GET_FORMATS: u8 = 0x40;
+pub const GUD_FORMATS_MAX_NUM: usize = 32;
+/// 1-bit monochrome.
+pub const GUD_PIXEL_FORMAT_R1: u8 = 0x01;
+/// 8-bit greyscale.
+pub const GUD_PIXEL_FORMAT_R8: u8 = 0x08;
+pub const GUD_PIXEL_FORMAT_XRGB1111: u8 = 0x20;
+pub const GUD_PIXEL_FORMAT_RGB332: u8 = 0x30;
+pub const GUD_PIXEL_FORMAT_RGB565: u8 = 0x40;
+pub const GUD_PIXEL_FORMAT_RGB888: u8 = 0x50;
+pub const GUD_PIXEL_FORMAT_XRGB8888: u8 = 0x80;
+pub const GUD_PIXEL_FORMAT_ARGB8888: u8 = 0x81;
+
+/// The GUD pixel formats as an enum, for code that needs to know their layout.
+///
+/// Multi-byte formats are little-endian like their DRM fourcc counterparts, e.g. XRGB8888 is
+/// stored as B, G, R, X.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum PixelFormat {
+    R1 = GUD_PIXEL_FORMAT_R1,
+    R8 = GUD_PIXEL_FORMAT_R8,
+    Xrgb1111 = GUD_PIXEL_FORMAT_XRGB1111,
+    Rgb332 = GUD_PIXEL_FORMAT_RGB332,
+    Rgb565 = GUD_PIXEL_FORMAT_RGB565,
+    Rgb888 = GUD_PIXEL_FORMAT_RGB888,
+    Xrgb8888 = GUD_PIXEL_FORMAT_XRGB8888,
+    Argb8888 = GUD_PIXEL_FORMAT_ARGB8888,
+}
+
+impl PixelFormat {
+    pub fn from_u8(format: u8) -> Option<Self> {
+        Some(match format {
+            GUD_PIXEL_FORMAT_R1 => Self::R1,
+            GUD_PIXEL_FORMAT_R8 => Self::R8,
+            GUD_PIXEL_FORMAT_XRGB1111 => Self::Xrgb1111,
+            GUD_PIXEL_FORMAT_RGB332 => Self::Rgb332,
+            GUD_PIXEL_FORMAT_RGB565 => Self::Rgb565,
+            GUD_PIXEL_FORMAT_RGB888 => Self::Rgb888,
+            GUD_PIXEL_FORMAT_XRGB8888 => Self::Xrgb8888,
+            GUD_PIXEL_FORMAT_ARGB8888 => Self::Argb8888,
+            _ => return None,
+        })
+    }
+
+    pub fn bits_per_pixel(self) -> usize {
+        match self {
+            Self::R1 => 1,
+            Self::Xrgb1111 => 4,
+            Self::R8 | Self::Rgb332 => 8,
+            Self::Rgb565 => 16,
+            Self::Rgb888 => 24,
+            Self::Xrgb8888 | Self::Argb8888 => 32,
+        }
+    }
+
+    /// Bytes per pixel, or `None` for formats that pack several pixels into a byte.
+    pub fn bytes_per_pixel(self) -> Option<usize> {
+        match self.bits_per_pixel() {
+            bits if bits % 8 == 0 => Some(bits / 8),
+            _ => None,
+        }
+    }
+
+    /// Bytes taken by a line of `width` pixels. Lines of formats that pack several pixels into a
+    /// byte start on a byte boundary, the first pixel in the most significant bits.
+    pub fn line_len(self, width: usize) -> usize {
+        (width * self.bits_per_pixel()).div_ceil(8)
+    }
+}
+
+impl From<PixelFormat> for u8 {
+    fn from(format: PixelFormat) -> u8 {
+        format as u8
+    }
+}
+
+pub const GUD_REQ_GET_PROPERTIES: u8 = 0x41;
+pub const GUD_PROPERTIES_MAX_NUM: usize = 32;
+
+// Connector requests carry the connector index in wValue.
+pub const GUD_REQ_GET_CONNECTORS: u8 = 0x50;
+pub const GUD_CONNECTORS_MAX_NUM: usize = 32;
+
+pub const GUD_REQ_GET_CONNECTOR_PROPERTIES: u8 = 0x51;
+pub const GUD_CONNECTOR_PROPERTIES_MAX_NUM: usize = 32;
+
+pub const GUD_REQ_GET_CONNECTOR_TV_MODE_VALUES: u8 = 0x52;
+pub const GUD_CONNECTOR_TV_MODE_NAME_LEN: usize = 16;
+pub const GUD_CONNECTOR_TV_MODE_MAX_NUM: usize = 16;
+
+pub const GUD_REQ_SET_CONNECTOR_FORCE_DETECT: u8 = 0x53;
+
+pub const GUD_REQ_GET_CONNECTOR_STATUS: u8 = 0x54;
+pub const GUD_CONNECTOR_STATUS_DISCONNECTED: u8 = 0x00;
+pub const GUD_CONNECTOR_STATUS_CONNECTED: u8 = 0x01;
+pub const GUD_CONNECTOR_STATUS_UNKNOWN: u8 = 0x02;
+pub const GUD_CONNECTOR_STATUS_CONNECTED_MASK: u8 = 0x03;
+pub const GUD_CONNECTOR_STATUS_CHANGED: u8 = 1 << 7;
+
+/// Whether a display is attached to a connector, as reported by `GUD_REQ_GET_CONNECTOR_STATUS`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+#[repr(u8)]
+pub enum ConnectorStatus {
+    Disconnected = GUD_CONNECTOR_STATUS_DISCONNECTED,
+    #[default]
+    Connected = GUD_CONNECTOR_STATUS_CONNECTED,
+    Unknown = GUD_CONNECTOR_STATUS_UNKNOWN,
+}
+
+impl ConnectorStatus {
+    pub fn from_u8(status: u8) -> Option<Self> {
+        Some(match status {
+            GUD_CONNECTOR_STATUS_DISCONNECTED => Self::Disconnected,
+            GUD_CONNECTOR_STATUS_CONNECTED => Self::Connected,
+            GUD_CONNECTOR_STATUS_UNKNOWN => Self::Unknown,
+            _ => return None,
+        })
+    }
+}
+
+impl From<ConnectorStatus> for u8 {
+    fn from(status: ConnectorStatus) -> u8 {
+        status as u8
+    }
+}
+
+pub const GUD_REQ_GET_CONNECTOR_MODES: u8 = 0x55;
+pub const GUD_CONNECTOR_MAX_NUM_MODES: usize = 128;
+
+pub const GUD_REQ_GET_CONNECTOR_EDID: u8 = 0x56;
+pub const GUD_CONNECTOR_MAX_EDID_LEN: usize = 2048;
+
+pub const GUD_REQ_SET_BUFFER: u8 = 0x60;
+pub const GUD_REQ_SET_STATE_CHECK: u8 = 0x61;
+pub const GUD_REQ_SET_STATE_COMMIT: u8 = 0x62;
+pub const GUD_REQ_SET_CONTROLLER_ENABLE: u8 = 0x63;
+pub const GUD_REQ_SET_DISPLAY_ENABLE: u8 = 0x64;
+
+/// Not part of the kernel's protocol (`include/drm/gud.h`), but an extension of this crate, like
+/// [`GUD_PROTOCOL_VERSION`], [`GUD_VERSION_COMPRESSION`] and [`GUD_VERSION_PROPERTIES`]. The
+/// stock kernel host never sends it: only a host written against this crate, such as `gud-host`,
+/// does. It's kept in the vendor range above the kernel's requests.
+///
+/// The host selects the protocol version it speaks, one byte between 1 and the descriptor's. A
+/// host that doesn't is taken to speak the descriptor's version. Only handled once enabled with
+/// [`Function::set_version_negotiation`](crate::Function::set_version_negotiation).
+pub const GUD_REQ_SET_VERSION: u8 = 0xf0;
+
+/// A GUD protocol structure with a fixed little-endian wire layout.
+pub trait WireFormat: Sized {
+    /// Encoded size in bytes. For variable length structures this is the size of the fixed part.
+    const LEN: usize;
+
+    fn encode<B: BufMut>(&self, buf: &mut B);
+
+    /// Decodes the structure from `buf`, which must hold at least [`WireFormat::LEN`] bytes.
+    fn decode<B: Buf>(buf: &mut B) -> Self;
+
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Self::LEN);
+        self.encode(&mut buf);
+        buf
+    }
+
+    fn from_bytes(mut buf: &[u8]) -> Result<Self, ProtocolError> {
+        if buf.len() < Self::LEN {
+            return Err(ProtocolError::Malformed(std::any::type_name::<Self>()));
+        }
+        Ok(Self::decode(&mut buf))
+    }
+}
+
+/// `struct gud_display_descriptor_req`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DisplayDescriptor {
+    pub magic: u32,
+    pub version: u8,
+    pub flags: u32,
+    pub compression: u8,
+    pub max_buffer_size: u32,
+    pub min_width: u32,
+    pub max_width: u32,
+    pub min_height: u32,
+    pub max_height: u32,
+}
+
+impl WireFormat for DisplayDescriptor {
+    const LEN: usize = 30;
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u32_le(self.magic);
+        buf.put_u8(self.version);
+        buf.put_u32_le(self.flags);
+        buf.put_u8(self.compression);
+        buf.put_u32_le(self.max_buffer_size);
+        buf.put_u32_le(self.min_width);
+        buf.put_u32_le(self.max_width);
+        buf.put_u32_le(self.min_height);
+        buf.put_u32_le(self.max_height);
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Self {
+        Self {
+            magic: buf.get_u32_le(),
+            version: buf.get_u8(),
+            flags: buf.get_u32_le(),
+            compression: buf.get_u8(),
+            max_buffer_size: buf.get_u32_le(),
+            min_width: buf.get_u32_le(),
+            max_width: buf.get_u32_le(),
+            min_height: buf.get_u32_le(),
+            max_height: buf.get_u32_le(),
+        }
+    }
+}
+
+/// `struct gud_display_mode_req`
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DisplayMode {
+    pub clock: u32,
+    pub hdisplay: u16,
+    pub hsync_start: u16,
+    pub hsync_end: u16,
+    pub htotal: u16,
+    pub vdisplay: u16,
+    pub vsync_start: u16,
+    pub vsync_end: u16,
+    pub vtotal: u16,
+    pub flags: u32,
+}
+
+bitflags::bitflags! {
+    /// The `GUD_DISPLAY_MODE_FLAG_*` bits of a [`DisplayMode`]. Apart from `PREFERRED`, they have
+    /// the same values as DRM's `DRM_MODE_FLAG_*`.
+    #[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash)]
+    pub struct ModeFlags: u32 {
+        const PHSYNC = GUD_DISPLAY_MODE_FLAG_PHSYNC;
+        const NHSYNC = GUD_DISPLAY_MODE_FLAG_NHSYNC;
+        const PVSYNC = GUD_DISPLAY_MODE_FLAG_PVSYNC;
+        const NVSYNC = GUD_DISPLAY_MODE_FLAG_NVSYNC;
+        const INTERLACE = GUD_DISPLAY_MODE_FLAG_INTERLACE;
+        const DBLSCAN = GUD_DISPLAY_MODE_FLAG_DBLSCAN;
+        const CSYNC = GUD_DISPLAY_MODE_FLAG_CSYNC;
+        const PCSYNC = GUD_DISPLAY_MODE_FLAG_PCSYNC;
+        const NCSYNC = GUD_DISPLAY_MODE_FLAG_NCSYNC;
+        /// The mode the display works best in. DRM keeps this in the mode's type instead.
+        const PREFERRED = GUD_DISPLAY_MODE_FLAG_PREFERRED;
+    }
+}
+
+impl DisplayMode {
+    /// The mode's `flags`, without bits GUD doesn't define.
+    pub fn mode_flags(&self) -> ModeFlags {
+        ModeFlags::from_bits_truncate(self.flags)
+    }
+
+    pub fn set_mode_flags(&mut self, flags: ModeFlags) {
+        self.flags = flags.bits();
+    }
+
+    /// The refresh rate in Hz, rounded the way DRM does it, or 0 if the mode has no totals.
+    pub fn vrefresh(&self) -> u32 {
+        let (mut num, mut den) = (
+            self.clock as u64 * 1000,
+            self.htotal as u64 * self.vtotal as u64,
+        );
+        let flags = self.mode_flags();
+        if flags.contains(ModeFlags::INTERLACE) {
+            num *= 2;
+        }
+        if flags.contains(ModeFlags::DBLSCAN) {
+            den *= 2;
+        }
+        match den {
+            0 => 0,
+            den => ((num + den / 2) / den) as u32,
+        }
+    }
+}
+
+#[cfg(feature = "drm")]
+impl From<drm::control::Mode> for DisplayMode {
+    /// Takes the timings, sync polarity and scan flags of a DRM mode, and whether it's preferred.
+    fn from(mode: drm::control::Mode) -> Self {
+        let (hdisplay, vdisplay) = mode.size();
+        let (hsync_start, hsync_end, htotal) = mode.hsync();
+        let (vsync_start, vsync_end, vtotal) = mode.vsync();
+        // DRM's bit for PREFERRED is a deprecated broadcast flag.
+        let mut flags = ModeFlags::from_bits_truncate(mode.flags().bits()) - ModeFlags::PREFERRED;
+        if mode
+            .mode_type()
+            .contains(drm::control::ModeTypeFlags::PREFERRED)
+        {
+            flags |= ModeFlags::PREFERRED;
+        }
+        Self {
+            clock: mode.clock(),
+            hdisplay,
+            hsync_start,
+            hsync_end,
+            htotal,
+            vdisplay,
+            vsync_start,
+            vsync_end,
+            vtotal,
+            flags: flags.bits(),
+        }
+    }
+}
+
+impl WireFormat for DisplayMode {
+    const LEN: usize = 24;
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u32_le(self.clock);
+        buf.put_u16_le(self.hdisplay);
+        buf.put_u16_le(self.hsync_start);
+        buf.put_u16_le(self.hsync_end);
+        buf.put_u16_le(self.htotal);
+        buf.put_u16_le(self.vdisplay);
+        buf.put_u16_le(self.vsync_start);
+        buf.put_u16_le(self.vsync_end);
+        buf.put_u16_le(self.vtotal);
+        buf.put_u32_le(self.flags);
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Self {
+        Self {
+            clock: buf.get_u32_le(),
+            hdisplay: buf.get_u16_le(),
+            hsync_start: buf.get_u16_le(),
+            hsync_end: buf.get_u16_le(),
+            htotal: buf.get_u16_le(),
+            vdisplay: buf.get_u16_le(),
+            vsync_start: buf.get_u16_le(),
+            vsync_end: buf.get_u16_le(),
+            vtotal: buf.get_u16_le(),
+            flags: buf.get_u32_le(),
+        }
+    }
+}
+
+/// `struct gud_connector_descriptor_req`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ConnectorDescriptor {
+    pub connector_type: u8,
+    pub flags: u32,
+}
+
+impl WireFormat for ConnectorDescriptor {
+    const LEN: usize = 5;
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u8(self.connector_type);
+        buf.put_u32_le(self.flags);
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Self {
+        Self {
+            connector_type: buf.get_u8(),
+            flags: buf.get_u32_le(),
+        }
+    }
+}
+
+/// `struct gud_property_req`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Property {
+    pub prop: u16,
+    pub val: u64,
+}
+
+impl WireFormat for Property {
+    const LEN: usize = 10;
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u16_le(self.prop);
+        buf.put_u64_le(self.val);
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Self {
+        Self {
+            prop: buf.get_u16_le(),
+            val: buf.get_u64_le(),
+        }
+    }
+}
+
+impl Property {
+    /// The plane rotations the display supports, a mask of `GUD_ROTATION_*` bits.
+    /// `GUD_ROTATION_0` is always included, the host requires it.
+    pub fn rotation(rotations: u64) -> Self {
+        Self {
+            prop: GUD_PROPERTY_ROTATION,
+            val: (rotations & GUD_ROTATION_MASK) | GUD_ROTATION_0,
+        }
+    }
+
+    /// A connector's backlight, with its brightness at startup in the range 0-100.
+    pub fn backlight_brightness(brightness: u8) -> Self {
+        Self {
+            prop: GUD_PROPERTY_BACKLIGHT_BRIGHTNESS,
+            val: brightness.min(100).into(),
+        }
+    }
+}
+
+/// The properties advertised with `GUD_REQ_GET_PROPERTIES` or
+/// `GUD_REQ_GET_CONNECTOR_PROPERTIES`, each at most once.
+///
+/// What a property's value means depends on the property, e.g. the supported rotations or an
+/// initial brightness. The host sends back the values it picks in each [`StateRequest`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Properties(Vec<Property>);
+
+impl Properties {
+    pub const fn new() -> Self {
+        Self(Vec::new())
+    }
+
+    /// Adds `property`, replacing one that's already there with the same `prop`.
+    ///
+    /// Panics past [`GUD_PROPERTIES_MAX_NUM`] properties.
+    pub fn with(mut self, property: Property) -> Self {
+        self.set(property);
+        self
+    }
+
+    /// Like [`with`](Self::with), in place.
+    pub fn set(&mut self, property: Property) {
+        match self.0.iter_mut().find(|p| p.prop == property.prop) {
+            Some(existing) => *existing = property,
+            None => {
+                assert!(self.0.len() < GUD_PROPERTIES_MAX_NUM, "too many properties");
+                self.0.push(property);
+            }
+        }
+    }
+
+    /// The value of `prop`, if it's there.
+    pub fn get(&self, prop: u16) -> Option<u64> {
+        self.0.iter().find(|p| p.prop == prop).map(|p| p.val)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &Property> {
+        self.0.iter()
+    }
+
+    /// The properties as sent to the host, one after the other. Empty if there are none.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buf = Vec::with_capacity(Property::LEN * self.0.len());
+        for property in &self.0 {
+            property.encode(&mut buf);
+        }
+        buf
+    }
+}
+
+/// `struct gud_set_buffer_req`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SetBuffer {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+    pub length: u32,
+    pub compression: u8,
+    pub compressed_length: u32,
+}
+
+impl WireFormat for SetBuffer {
+    const LEN: usize = 25;
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        buf.put_u32_le(self.x);
+        buf.put_u32_le(self.y);
+        buf.put_u32_le(self.width);
+        buf.put_u32_le(self.height);
+        buf.put_u32_le(self.length);
+        buf.put_u8(self.compression);
+        buf.put_u32_le(self.compressed_length);
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Self {
+        Self {
+            x: buf.get_u32_le(),
+            y: buf.get_u32_le(),
+            width: buf.get_u32_le(),
+            height: buf.get_u32_le(),
+            length: buf.get_u32_le(),
+            compression: buf.get_u8(),
+            compressed_length: buf.get_u32_le(),
+        }
+    }
+}
+
+impl SetBuffer {
+    /// Checks that the damage rect lies within the given mode.
+    pub fn validate(&self, mode: &DisplayMode) -> Result<(), ProtocolError> {
+        let out_of_bounds = || ProtocolError::RectOutOfBounds {
+            x: self.x,
+            y: self.y,
+            width: self.width,
+            height: self.height,
+            bound_width: mode.hdisplay as u32,
+            bound_height: mode.vdisplay as u32,
+        };
+        let end_x = self.x.checked_add(self.width).ok_or_else(out_of_bounds)?;
+        let end_y = self.y.checked_add(self.height).ok_or_else(out_of_bounds)?;
+        if end_x > mode.hdisplay as u32 || end_y > mode.vdisplay as u32 {
+            return Err(out_of_bounds());
+        }
+        Ok(())
+    }
+
+    /// Checks that the damage rect fits in a framebuffer of `fb_len` bytes with the given pitch and
+    /// bytes per pixel, and that the buffer lengths are consistent with it.
+    pub fn validate_fb(
+        &self,
+        fb_len: usize,
+        fb_pitch: usize,
+        bpp: usize,
+    ) -> Result<(), ProtocolError> {
+        self.validate_length(bpp)?;
+        self.validate_fb_bounds(fb_len, fb_pitch, bpp)
+    }
+
+    /// Checks that the buffer lengths are consistent with the damage rect at `bpp` bytes per
+    /// pixel.
+    pub fn validate_length(&self, bpp: usize) -> Result<(), ProtocolError> {
+        self.validate_line_length((self.width as usize).saturating_mul(bpp))
+    }
+
+    /// Like [`validate_length`](Self::validate_length), for pixel data in `format`, which may pack
+    /// several pixels into a byte.
+    pub fn validate_format_length(&self, format: PixelFormat) -> Result<(), ProtocolError> {
+        let width = self.width as usize;
+        let bits = width.saturating_mul(format.bits_per_pixel());
+        self.validate_line_length(bits.div_ceil(8))
+    }
+
+    /// Checks that `format` is a GUD pixel format and the lengths match the damage rect in it,
+    /// returning the format. The lengths are the host's to pick, so this has to pass before
+    /// anything's allocated for the payload.
+    pub fn validate_format(&self, format: u8) -> Result<PixelFormat, ProtocolError> {
+        let format = PixelFormat::from_u8(format).ok_or(ProtocolError::UnknownFormat(format))?;
+        self.validate_format_length(format)?;
+        Ok(format)
+    }
+
+    fn validate_line_length(&self, line_len: usize) -> Result<(), ProtocolError> {
+        let expected = line_len.saturating_mul(self.height as usize);
+        if self.length as usize != expected {
+            return Err(ProtocolError::LengthMismatch {
+                length: self.length as usize,
+                expected,
+            });
+        }
+        if self.compression > 0 && self.compressed_length > self.length {
+            return Err(ProtocolError::LengthMismatch {
+                length: self.compressed_length as usize,
+                expected,
+            });
+        }
+        Ok(())
+    }
+
+    /// Checks that the damage rect fits in a framebuffer of `fb_len` bytes with the given pitch and
+    /// bytes per pixel.
+    pub fn validate_fb_bounds(
+        &self,
+        fb_len: usize,
+        fb_pitch: usize,
+        bpp: usize,
+    ) -> Result<(), ProtocolError> {
+        let (x, y) = (self.x as usize, self.y as usize);
+        let (width, height) = (self.width as usize, self.height as usize);
+
+        let line_end = x.saturating_add(width).saturating_mul(bpp);
+        let needed = match height {
+            0 => 0,
+            _ => y
+                .saturating_add(height - 1)
+                .saturating_mul(fb_pitch)
+                .saturating_add(line_end),
+        };
+        if line_end > fb_pitch || needed > fb_len {
+            return Err(ProtocolError::FramebufferOverflow {
+                needed,
+                available: fb_len,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// `struct gud_state_req`, followed by a variable number of properties.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct StateRequest {
+    pub mode: DisplayMode,
+    pub format: u8,
+    pub connector: u8,
+    pub properties: Vec<Property>,
+}
+
+impl WireFormat for StateRequest {
+    const LEN: usize = DisplayMode::LEN + 2;
+
+    fn encode<B: BufMut>(&self, buf: &mut B) {
+        self.mode.encode(buf);
+        buf.put_u8(self.format);
+        buf.put_u8(self.connector);
+        for property in &self.properties {
+            property.encode(buf);
+        }
+    }
+
+    fn decode<B: Buf>(buf: &mut B) -> Self {
+        let mode = DisplayMode::decode(buf);
+        let format = buf.get_u8();
+        let connector = buf.get_u8();
+        let mut properties = Vec::with_capacity(buf.remaining() / Property::LEN);
+        while buf.remaining() >= Property::LEN {
+            properties.push(Property::decode(buf));
+        }
+        Self {
+            mode,
+            format,
+            connector,
+            properties,
+        }
+    }
+}
@@ -0,0 +1,73 @@
+//! Counters of what's been received, for monitoring.
+
+use std::io::{self, Write};
+
+/// What a [`PixelDataEndpoint`](crate::PixelDataEndpoint) has received since it was created.
+/// Rates like frames per second and bandwidth follow from the difference between two samples.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Frames whose pixel data was received in full.
+    pub frames: u64,
+    /// Bytes read from the endpoint, compressed or not.
+    pub bytes: u64,
+    /// Bytes of pixel data those came to after decompression.
+    pub pixel_bytes: u64,
+    /// Frames that failed to be received, decompressed or converted.
+    pub errors: u64,
+    /// Times received frames were presented, each once without a frame rate cap.
+    pub presented: u64,
+    /// Frames whose damage was merged into a later one's presentation by a frame rate cap.
+    pub merged: u64,
+}
+
+impl Stats {
+    /// How many bytes of pixel data each byte on the wire came to, 1 without compression.
+    pub fn compression_ratio(&self) -> f64 {
+        match self.bytes {
+            0 => 1.0,
+            bytes => self.pixel_bytes as f64 / bytes as f64,
+        }
+    }
+
+    /// Writes the counters in the Prometheus text exposition format, as `gud_*` metrics.
+    pub fn write_prometheus(&self, out: &mut impl Write) -> io::Result<()> {
+        for (name, help, value) in [
+            ("gud_frames_total", "Frames received.", self.frames),
+            (
+                "gud_received_bytes_total",
+                "Bytes read from the USB endpoint.",
+                self.bytes,
+            ),
+            (
+                "gud_pixel_bytes_total",
+                "Bytes of pixel data after decompression.",
+                self.pixel_bytes,
+            ),
+            (
+                "gud_errors_total",
+                "Frames that failed to be received or converted.",
+                self.errors,
+            ),
+            (
+                "gud_presented_total",
+                "Times received frames were presented.",
+                self.presented,
+            ),
+            (
+                "gud_merged_frames_total",
+                "Frames merged into another's presentation by the frame rate cap.",
+                self.merged,
+            ),
+        ] {
+            writeln!(out, "# HELP {} {}", name, help)?;
+            writeln!(out, "# TYPE {} counter", name)?;
+            writeln!(out, "{} {}", name, value)?;
+        }
+        writeln!(
+            out,
+            "# HELP gud_compression_ratio Pixel data bytes per byte received."
+        )?;
+        writeln!(out, "# TYPE gud_compression_ratio gauge")?;
+        writeln!(out, "gud_compression_ratio {}", self.compression_ratio())
+    }
+}
@@ -0,0 +1,66 @@
+//! Keeping systemd informed when a gadget daemon runs as a service: `READY=1` once the gadget is
+//! bound, and watchdog keep-alives from the event loop so a daemon stuck in a FunctionFS read is
+//! restarted. Without a `NOTIFY_SOCKET`, e.g. when run by hand, it does nothing.
+
+use sd_notify::NotifyState;
+use std::time::{Duration, Instant};
+use tracing::{debug, warn};
+
+pub struct Notifier {
+    // Half the service's WatchdogSec, if it has one.
+    watchdog: Option<Duration>,
+    petted: Instant,
+}
+
+impl Default for Notifier {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Notifier {
+    pub fn new() -> Self {
+        let mut usec = 0;
+        let watchdog =
+            sd_notify::watchdog_enabled(false, &mut usec).then(|| Duration::from_micros(usec / 2));
+        if let Some(interval) = watchdog {
+            debug!("petting the systemd watchdog every {:?}", interval);
+        }
+        Self {
+            watchdog,
+            petted: Instant::now(),
+        }
+    }
+
+    /// Tells systemd the service is up. Call it once the gadget is bound to the UDC.
+    pub fn ready(&self) {
+        notify(&[NotifyState::Ready]);
+    }
+
+    /// Pets the watchdog if it's due. Call it on every pass of the event loop, which has to come
+    /// round more often than the watchdog interval.
+    pub fn tick(&mut self) {
+        if let Some(interval) = self.watchdog {
+            if self.petted.elapsed() >= interval {
+                notify(&[NotifyState::Watchdog]);
+                self.petted = Instant::now();
+            }
+        }
+    }
+
+    /// How often [`tick`](Self::tick) has to be called, if the service has a watchdog.
+    pub fn interval(&self) -> Option<Duration> {
+        self.watchdog
+    }
+
+    /// Tells systemd the service is shutting down.
+    pub fn stopping(&self) {
+        notify(&[NotifyState::Stopping]);
+    }
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(err) = sd_notify::notify(false, state) {
+        warn!("notifying systemd failed: {:#}", err);
+    }
+}
@@ -0,0 +1,240 @@
+//! A HID multitouch function to go alongside GUD, so touches on the device's panel reach the host
+//! like those of a USB touchscreen monitor.
+//!
+//! Add the function built by [`hid_builder`] to the same configuration as the GUD function, then
+//! [`TouchReporter::open`] it once the gadget is bound. With the `touch` feature, [`Touchscreen`]
+//! reads contacts from the panel's evdev device.
+//!
+//! Positions are normalized to `0..=`[`LOGICAL_MAX`] across the panel. The host maps the
+//! digitizer onto the display it belongs to, and since frames are scaled to fill the panel this
+//! lines up with whichever mode is committed.
+
+use std::fs::File;
+use std::io::{self, Write};
+use usb_gadget::function::hid::{Hid, HidBuilder};
+
+/// Contacts reported at once.
+pub const MAX_CONTACTS: usize = 5;
+/// The largest coordinate, at the right and bottom edge of the panel.
+pub const LOGICAL_MAX: u16 = 0x7fff;
+
+const REPORT_ID: u8 = 1;
+// Tip switch and padding, contact identifier, X and Y.
+const CONTACT_LEN: usize = 6;
+/// Bytes in a report: the report ID, the contacts, and the contact count.
+pub const REPORT_LEN: usize = 1 + MAX_CONTACTS * CONTACT_LEN + 1;
+
+/// A finger on the panel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Contact {
+    /// Stays the same while the finger is down.
+    pub id: u8,
+    pub x: u16,
+    pub y: u16,
+}
+
+/// The HID report descriptor of a touch screen with [`MAX_CONTACTS`] contacts.
+pub fn report_descriptor() -> Vec<u8> {
+    let mut desc = vec![
+        0x05, 0x0d, // Usage Page (Digitizer)
+        0x09, 0x04, // Usage (Touch Screen)
+        0xa1, 0x01, // Collection (Application)
+        0x85, REPORT_ID, // Report ID
+    ];
+    let [max_lo, max_hi] = LOGICAL_MAX.to_le_bytes();
+    for _ in 0..MAX_CONTACTS {
+        desc.extend_from_slice(&[
+            0x09, 0x22, // Usage (Finger)
+            0xa1, 0x02, // Collection (Logical)
+            0x09, 0x42, // Usage (Tip Switch)
+            0x15, 0x00, // Logical Minimum (0)
+            0x25, 0x01, // Logical Maximum (1)
+            0x75, 0x01, // Report Size (1)
+            0x95, 0x01, // Report Count (1)
+            0x81, 0x02, // Input (Data, Variable, Absolute)
+            0x75, 0x07, // Report Size (7)
+            0x81, 0x03, // Input (Constant)
+            0x09, 0x51, // Usage (Contact Identifier)
+            0x25, 0x7f, // Logical Maximum (127)
+            0x75, 0x08, // Report Size (8)
+            0x81, 0x02, // Input (Data, Variable, Absolute)
+            0x05, 0x01, // Usage Page (Generic Desktop)
+            0x26, max_lo, max_hi, // Logical Maximum (LOGICAL_MAX)
+            0x75, 0x10, // Report Size (16)
+            0x09, 0x30, // Usage (X)
+            0x81, 0x02, // Input (Data, Variable, Absolute)
+            0x09, 0x31, // Usage (Y)
+            0x81, 0x02, // Input (Data, Variable, Absolute)
+            0x05, 0x0d, // Usage Page (Digitizer)
+            0xc0, // End Collection
+        ]);
+    }
+    desc.extend_from_slice(&[
+        0x09, 0x54, // Usage (Contact Count)
+        0x25, 0x7f, // Logical Maximum (127)
+        0x75, 0x08, // Report Size (8)
+        0x95, 0x01, // Report Count (1)
+        0x81, 0x02, // Input (Data, Variable, Absolute)
+        0xc0, // End Collection
+    ]);
+    desc
+}
+
+/// Encodes the contacts currently down. Only the first [`MAX_CONTACTS`] are reported, an empty
+/// slice lifts every finger.
+pub fn report(contacts: &[Contact]) -> [u8; REPORT_LEN] {
+    let mut report = [0; REPORT_LEN];
+    report[0] = REPORT_ID;
+    let contacts = &contacts[..contacts.len().min(MAX_CONTACTS)];
+    for (contact, buf) in contacts
+        .iter()
+        .zip(report[1..].chunks_exact_mut(CONTACT_LEN))
+    {
+        buf[0] = 1;
+        buf[1] = contact.id;
+        buf[2..4].copy_from_slice(&contact.x.min(LOGICAL_MAX).to_le_bytes());
+        buf[4..6].copy_from_slice(&contact.y.min(LOGICAL_MAX).to_le_bytes());
+    }
+    report[REPORT_LEN - 1] = contacts.len() as u8;
+    report
+}
+
+/// A HID function for the touch screen, to add to the configuration next to GUD.
+pub fn hid_builder() -> HidBuilder {
+    let mut builder = Hid::builder();
+    builder.sub_class = 0;
+    builder.protocol = 0;
+    builder.report_desc = report_descriptor();
+    builder.report_len = REPORT_LEN as u8;
+    builder.no_out_endpoint = true;
+    builder
+}
+
+/// Sends touch reports to the host through the HID function's `/dev/hidgN` device.
+pub struct TouchReporter {
+    hidg: File,
+}
+
+impl TouchReporter {
+    /// Opens the device node of a bound HID function.
+    pub fn open(hid: &Hid) -> io::Result<Self> {
+        let (major, minor) = hid.device()?;
+        let uevent = std::fs::read_to_string(format!("/sys/dev/char/{major}:{minor}/uevent"))?;
+        let name = uevent
+            .lines()
+            .find_map(|line| line.strip_prefix("DEVNAME="))
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no hidg device name"))?;
+        let hidg = File::options().write(true).open(format!("/dev/{name}"))?;
+        Ok(Self { hidg })
+    }
+
+    /// Reports the contacts currently down. Blocks while the host isn't polling.
+    pub fn send(&mut self, contacts: &[Contact]) -> io::Result<()> {
+        self.hidg.write_all(&report(contacts))
+    }
+}
+
+#[cfg(feature = "touch")]
+pub use evdev_touchscreen::Touchscreen;
+
+#[cfg(feature = "touch")]
+mod evdev_touchscreen {
+    use evdev::raw_stream::RawDevice;
+    use evdev::{AbsoluteAxisType, InputEventKind, Synchronization};
+    use std::io;
+    use std::ops::RangeInclusive;
+    use std::path::Path;
+
+    use super::{Contact, LOGICAL_MAX, MAX_CONTACTS};
+
+    /// Reads contacts from a multitouch (protocol B) evdev device.
+    pub struct Touchscreen {
+        device: RawDevice,
+        x: RangeInclusive<i32>,
+        y: RangeInclusive<i32>,
+        slot: usize,
+        // The raw position of the finger in each slot, if there's one down.
+        slots: [Option<(i32, i32)>; MAX_CONTACTS],
+    }
+
+    impl Touchscreen {
+        pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+            let device = RawDevice::open(path)?;
+            let abs = device.get_abs_state()?;
+            let range = |axis: AbsoluteAxisType| {
+                let info = abs[axis.0 as usize];
+                info.minimum..=info.maximum
+            };
+            Ok(Self {
+                x: range(AbsoluteAxisType::ABS_MT_POSITION_X),
+                y: range(AbsoluteAxisType::ABS_MT_POSITION_Y),
+                device,
+                slot: 0,
+                slots: [None; MAX_CONTACTS],
+            })
+        }
+
+        /// Blocks until the contacts change, returning the ones that are down. A contact's id is
+        /// its slot. Slots past [`MAX_CONTACTS`] are ignored.
+        pub fn next_contacts(&mut self) -> io::Result<Vec<Contact>> {
+            loop {
+                let mut synced = false;
+                for event in self.device.fetch_events()? {
+                    let value = event.value();
+                    let slot = self.slots.get_mut(self.slot);
+                    match event.kind() {
+                        InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_SLOT) => {
+                            self.slot = value.max(0) as usize;
+                        }
+                        InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_TRACKING_ID) => {
+                            if let Some(slot) = slot {
+                                *slot = match value {
+                                    -1 => None,
+                                    _ => Some(slot.unwrap_or_default()),
+                                };
+                            }
+                        }
+                        InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_POSITION_X) => {
+                            if let Some(Some((x, _))) = slot {
+                                *x = value;
+                            }
+                        }
+                        InputEventKind::AbsAxis(AbsoluteAxisType::ABS_MT_POSITION_Y) => {
+                            if let Some(Some((_, y))) = slot {
+                                *y = value;
+                            }
+                        }
+                        InputEventKind::Synchronization(Synchronization::SYN_REPORT) => {
+                            synced = true;
+                        }
+                        _ => {}
+                    }
+                }
+                if synced {
+                    return Ok(self.contacts());
+                }
+            }
+        }
+
+        fn contacts(&self) -> Vec<Contact> {
+            self.slots
+                .iter()
+                .enumerate()
+                .filter_map(|(slot, position)| {
+                    let (x, y) = (*position)?;
+                    Some(Contact {
+                        id: slot as u8,
+                        x: normalize(x, &self.x),
+                        y: normalize(y, &self.y),
+                    })
+                })
+                .collect()
+        }
+    }
+
+    fn normalize(value: i32, range: &RangeInclusive<i32>) -> u16 {
+        let (min, max) = (*range.start() as i64, *range.end() as i64);
+        let span = (max - min).max(1);
+        ((value as i64 - min).clamp(0, span) * LOGICAL_MAX as i64 / span) as u16
+    }
+}
@@ -0,0 +1,526 @@
+//! Recording sessions with a host, and replaying them without one.
+//!
+//! A [`Tracer`] set on a [`Function`] (and a `PixelDataEndpoint`) writes each control transfer,
+//! with the data that went either way, and the header of each frame's payload to a compact
+//! binary log. [`replay`] feeds such a log back through the dispatcher with
+//! [`mock`](crate::transport::mock) transfers and reports where its replies differ from the
+//! recorded ones, so a host that trips the device up can be debugged from a log its user sends
+//! in rather than on their hardware.
+//!
+//! The log starts with `GUDTRACE` and a version byte. Each record is a tag byte followed by:
+//! - control transfers (tag 0 device-to-host, 1 host-to-device): the setup packet's request,
+//!   value, index and length, then 0 if it was left unanswered, 2 if it was halted, or 1 and the
+//!   data with a little-endian `u16` length;
+//! - payloads (tag 2): the `SET_BUFFER` header, then 1 and its little-endian crc32 or 0 if
+//!   checksums are off.
+
+use crate::protocol::*;
+use crate::transport::mock::{MockReceiver, MockSender, Outcome};
+use crate::transport::{ControlReceiver, ControlRequest, ControlSender, ControlTransfer};
+use crate::{debug, Event, Function};
+use anyhow::Context;
+use bytes::{Buf, BufMut};
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use tracing::warn;
+
+const MAGIC: &[u8] = b"GUDTRACE";
+const VERSION: u8 = 1;
+
+const TAG_DEVICE_TO_HOST: u8 = 0;
+const TAG_HOST_TO_DEVICE: u8 = 1;
+const TAG_PAYLOAD: u8 = 2;
+
+/// Which way a control transfer's data went.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    DeviceToHost,
+    HostToDevice,
+}
+
+/// An entry of a trace.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Record {
+    /// A control transfer. [`Outcome::Pending`] means the device never answered it.
+    Control {
+        direction: Direction,
+        request: ControlRequest,
+        outcome: Outcome,
+    },
+    /// The payload of a frame was received, with its crc32 if the tracer takes checksums.
+    Payload {
+        info: SetBuffer,
+        checksum: Option<u32>,
+    },
+}
+
+impl Record {
+    fn encode(&self, buf: &mut Vec<u8>) {
+        match self {
+            Record::Control {
+                direction,
+                request,
+                outcome,
+            } => {
+                buf.put_u8(match direction {
+                    Direction::DeviceToHost => TAG_DEVICE_TO_HOST,
+                    Direction::HostToDevice => TAG_HOST_TO_DEVICE,
+                });
+                buf.put_u8(request.request);
+                buf.put_u16_le(request.value);
+                buf.put_u16_le(request.index);
+                buf.put_u16_le(request.length);
+                match outcome {
+                    Outcome::Pending => buf.put_u8(0),
+                    Outcome::Data(data) => {
+                        // Control transfers can't carry more than wLength, a u16.
+                        let data = &data[..data.len().min(u16::MAX as usize)];
+                        buf.put_u8(1);
+                        buf.put_u16_le(data.len() as u16);
+                        buf.put_slice(data);
+                    }
+                    Outcome::Halted => buf.put_u8(2),
+                }
+            }
+            Record::Payload { info, checksum } => {
+                buf.put_u8(TAG_PAYLOAD);
+                info.encode(buf);
+                match checksum {
+                    Some(checksum) => {
+                        buf.put_u8(1);
+                        buf.put_u32_le(*checksum);
+                    }
+                    None => buf.put_u8(0),
+                }
+            }
+        }
+    }
+
+    // Decodes the next record, `None` if `buf` ends before it does.
+    fn decode(buf: &mut &[u8]) -> anyhow::Result<Option<Self>> {
+        fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+            let (head, tail) = buf.split_at_checked(len)?;
+            *buf = tail;
+            Some(head)
+        }
+
+        let Some(&[tag]) = take(buf, 1) else {
+            return Ok(None);
+        };
+        let record = match tag {
+            TAG_DEVICE_TO_HOST | TAG_HOST_TO_DEVICE => {
+                let Some(mut setup) = take(buf, 8) else {
+                    return Ok(None);
+                };
+                let request = ControlRequest {
+                    request: setup.get_u8(),
+                    value: setup.get_u16_le(),
+                    index: setup.get_u16_le(),
+                    length: setup.get_u16_le(),
+                };
+                let outcome = match setup.get_u8() {
+                    0 => Outcome::Pending,
+                    1 => {
+                        let Some(mut len) = take(buf, 2) else {
+                            return Ok(None);
+                        };
+                        let Some(data) = take(buf, len.get_u16_le() as usize) else {
+                            return Ok(None);
+                        };
+                        Outcome::Data(data.to_vec())
+                    }
+                    2 => Outcome::Halted,
+                    v => anyhow::bail!("unknown control outcome {}", v),
+                };
+                Record::Control {
+                    direction: match tag {
+                        TAG_DEVICE_TO_HOST => Direction::DeviceToHost,
+                        _ => Direction::HostToDevice,
+                    },
+                    request,
+                    outcome,
+                }
+            }
+            TAG_PAYLOAD => {
+                let Some(mut header) = take(buf, SetBuffer::LEN + 1) else {
+                    return Ok(None);
+                };
+                let info = SetBuffer::decode(&mut header);
+                let checksum = match header.get_u8() {
+                    0 => None,
+                    _ => match take(buf, 4) {
+                        Some(mut checksum) => Some(checksum.get_u32_le()),
+                        None => return Ok(None),
+                    },
+                };
+                Record::Payload { info, checksum }
+            }
+            v => anyhow::bail!("unknown record tag {}", v),
+        };
+        Ok(Some(record))
+    }
+}
+
+/// Writes a trace, shared by everything that records to it.
+#[derive(Clone)]
+pub struct Tracer(Arc<Mutex<Log>>);
+
+struct Log {
+    out: Box<dyn Write + Send>,
+    checksums: bool,
+    failed: bool,
+}
+
+impl std::fmt::Debug for Tracer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Tracer").finish_non_exhaustive()
+    }
+}
+
+impl Tracer {
+    /// Starts a trace in `out`. With `checksums`, payloads are recorded with their crc32, which
+    /// costs a pass over each frame.
+    pub fn new(out: impl Write + Send + 'static, checksums: bool) -> io::Result<Self> {
+        let mut out: Box<dyn Write + Send> = Box::new(out);
+        out.write_all(MAGIC)?;
+        out.write_all(&[VERSION])?;
+        out.flush()?;
+        Ok(Self(Arc::new(Mutex::new(Log {
+            out,
+            checksums,
+            failed: false,
+        }))))
+    }
+
+    /// Starts a trace in a new file at `path`, replacing one that's there.
+    pub fn create(path: &Path, checksums: bool) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), checksums)
+    }
+
+    /// Appends `record`. Each is flushed, so the trace of a session that crashed is complete up
+    /// to the crash. A trace that can't be written is warned about once and not stopped for.
+    pub fn record(&self, record: &Record) {
+        let mut buf = Vec::new();
+        record.encode(&mut buf);
+        let mut log = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        if let Err(err) = log.out.write_all(&buf).and_then(|_| log.out.flush()) {
+            if !log.failed {
+                warn!("writing trace failed: {}", err);
+                log.failed = true;
+            }
+        }
+    }
+
+    /// Records the received `payload` for `info`.
+    pub fn payload(&self, info: &SetBuffer, payload: &[u8]) {
+        let checksums = self
+            .0
+            .lock()
+            .unwrap_or_else(|err| err.into_inner())
+            .checksums;
+        self.record(&Record::Payload {
+            info: info.clone(),
+            checksum: checksums.then(|| debug::checksum(payload)),
+        });
+    }
+}
+
+/// Decodes the records of a trace. One cut short, e.g. by a crash or a full disk, ends at the
+/// last complete record.
+pub fn decode(mut buf: &[u8]) -> anyhow::Result<Vec<Record>> {
+    let Some(rest) = buf.strip_prefix(MAGIC) else {
+        anyhow::bail!("not a GUD trace");
+    };
+    let Some((&version, rest)) = rest.split_first() else {
+        anyhow::bail!("not a GUD trace");
+    };
+    if version != VERSION {
+        anyhow::bail!("unsupported trace version {}", version);
+    }
+    buf = rest;
+    let mut records = Vec::new();
+    while !buf.is_empty() {
+        match Record::decode(&mut buf).with_context(|| format!("record {}", records.len()))? {
+            Some(record) => records.push(record),
+            None => {
+                warn!("trace cut short after {} records", records.len());
+                break;
+            }
+        }
+    }
+    Ok(records)
+}
+
+/// Reads the trace at `path`.
+pub fn load(path: &Path) -> anyhow::Result<Vec<Record>> {
+    let buf = std::fs::read(path).with_context(|| format!("read {}", path.display()))?;
+    decode(&buf)
+}
+
+/// A control transfer that's recorded once it's answered, or dropped without an answer.
+#[derive(Debug)]
+pub(crate) struct Traced<T> {
+    // Taken when it's answered.
+    inner: Option<T>,
+    direction: Direction,
+    request: ControlRequest,
+    tracer: Option<Tracer>,
+}
+
+impl<T> Traced<T> {
+    fn new(
+        inner: T,
+        direction: Direction,
+        request: ControlRequest,
+        tracer: Option<Tracer>,
+    ) -> Self {
+        Self {
+            inner: Some(inner),
+            direction,
+            request,
+            tracer,
+        }
+    }
+
+    fn answer<U>(
+        mut self,
+        answer: impl FnOnce(T) -> io::Result<U>,
+        outcome: Outcome,
+    ) -> io::Result<U> {
+        let inner = self.inner.take().expect("answered twice");
+        let result = answer(inner);
+        if let (Ok(_), Some(tracer)) = (&result, &self.tracer) {
+            tracer.record(&Record::Control {
+                direction: self.direction,
+                request: self.request,
+                outcome,
+            });
+        }
+        result
+    }
+}
+
+impl<S: ControlSender> Traced<S> {
+    pub(crate) fn sender(sender: S, tracer: Option<Tracer>) -> Self {
+        let request = sender.request();
+        Self::new(sender, Direction::DeviceToHost, request, tracer)
+    }
+}
+
+impl<R: ControlReceiver> Traced<R> {
+    pub(crate) fn receiver(receiver: R, tracer: Option<Tracer>) -> Self {
+        let request = receiver.request();
+        Self::new(receiver, Direction::HostToDevice, request, tracer)
+    }
+}
+
+impl<T> Drop for Traced<T> {
+    fn drop(&mut self) {
+        if let (Some(_), Some(tracer)) = (&self.inner, &self.tracer) {
+            tracer.record(&Record::Control {
+                direction: self.direction,
+                request: self.request,
+                outcome: Outcome::Pending,
+            });
+        }
+    }
+}
+
+impl<S: ControlSender> ControlSender for Traced<S> {
+    fn request(&self) -> ControlRequest {
+        self.request
+    }
+
+    fn len(&self) -> usize {
+        self.inner.as_ref().map_or(0, |inner| inner.len())
+    }
+
+    fn send(self, data: &[u8]) -> io::Result<usize> {
+        let len = data.len().min(self.len());
+        let outcome = Outcome::Data(data[..len].to_vec());
+        self.answer(|inner| inner.send(data), outcome)
+    }
+
+    fn halt(self) -> io::Result<()> {
+        self.answer(|inner| inner.halt(), Outcome::Halted)
+    }
+}
+
+impl<R: ControlReceiver> ControlReceiver for Traced<R> {
+    fn request(&self) -> ControlRequest {
+        self.request
+    }
+
+    fn recv_all(mut self) -> io::Result<Vec<u8>> {
+        let inner = self.inner.take().expect("answered twice");
+        let data = inner.recv_all()?;
+        if let Some(tracer) = &self.tracer {
+            tracer.record(&Record::Control {
+                direction: self.direction,
+                request: self.request,
+                outcome: Outcome::Data(data.clone()),
+            });
+        }
+        Ok(data)
+    }
+
+    fn halt(self) -> io::Result<()> {
+        self.answer(|inner| inner.halt(), Outcome::Halted)
+    }
+}
+
+/// A replayed control transfer the dispatcher answered differently than in the recording.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Mismatch {
+    /// The transfer's index among the records.
+    pub index: usize,
+    pub request: ControlRequest,
+    pub recorded: Outcome,
+    pub replayed: Outcome,
+}
+
+/// Sets up `function` like the device that recorded `records` was, as far as its replies tell:
+/// its compression, connectors, EDIDs and plane and connector properties, and whether it took
+/// [`GUD_REQ_SET_VERSION`]. Replaying a trace with a function set up differently shows up as
+/// mismatches on those requests.
+pub fn configure(function: &mut Function, records: &[Record]) {
+    for record in records {
+        if let Record::Control {
+            request,
+            outcome: Outcome::Data(_),
+            ..
+        } = record
+        {
+            if request.request == GUD_REQ_SET_VERSION {
+                function.set_version_negotiation(true);
+            }
+        }
+        let Record::Control {
+            direction: Direction::DeviceToHost,
+            request,
+            outcome: Outcome::Data(data),
+        } = record
+        else {
+            continue;
+        };
+        let connector = request.value as usize;
+        match request.request {
+            GUD_REQ_GET_DESCRIPTOR => {
+                if let Ok(descriptor) = DisplayDescriptor::from_bytes(data) {
+                    function.set_compression(descriptor.compression);
+                    if descriptor.version > 0 {
+                        function.set_version(descriptor.version);
+                    }
+                }
+            }
+            GUD_REQ_GET_CONNECTORS => {
+                for (i, descriptor) in data.chunks_exact(ConnectorDescriptor::LEN).enumerate() {
+                    if i >= GUD_CONNECTORS_MAX_NUM {
+                        break;
+                    }
+                    while function.connector_count() <= i {
+                        function.add_connector();
+                    }
+                    let descriptor = ConnectorDescriptor::decode(&mut &descriptor[..]);
+                    function.set_connector_type(i, descriptor.connector_type);
+                    function.set_connector_flags(i, descriptor.flags);
+                }
+            }
+            GUD_REQ_GET_PROPERTIES => function.set_properties(properties(data)),
+            GUD_REQ_GET_CONNECTOR_PROPERTIES if connector < function.connector_count() => {
+                function.set_connector_properties(connector, properties(data));
+            }
+            GUD_REQ_GET_CONNECTOR_EDID if data[..] != [0] => {
+                if let Some(handle) = function.connector(connector) {
+                    handle.set_edid(data.clone());
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn properties(data: &[u8]) -> Properties {
+    data.chunks_exact(Property::LEN)
+        .take(GUD_PROPERTIES_MAX_NUM)
+        .fold(Properties::new(), |properties, property| {
+            properties.with(Property::decode(&mut &property[..]))
+        })
+}
+
+/// Feeds the control transfers of `records` through `function` in order, returning those it
+/// answered differently. Requests the application answers, for the descriptor, pixel formats
+/// and modes, are answered with what was recorded; frames aren't received, so payloads are
+/// skipped.
+pub fn replay(function: &mut Function, records: &[Record]) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    for (index, record) in records.iter().enumerate() {
+        let Record::Control {
+            direction,
+            request,
+            outcome: recorded,
+        } = record
+        else {
+            continue;
+        };
+        let (transfer, outcome) = match direction {
+            Direction::DeviceToHost => {
+                let (sender, outcome) = MockSender::new(*request);
+                (ControlTransfer::DeviceToHost(sender), outcome)
+            }
+            Direction::HostToDevice => {
+                let data = match recorded {
+                    Outcome::Data(data) => &data[..],
+                    _ => &[],
+                };
+                let (receiver, outcome) = MockReceiver::new(*request, data);
+                (ControlTransfer::HostToDevice(receiver), outcome)
+            }
+        };
+        let result = function.control(transfer).and_then(|event| match event {
+            Some(event) => answer(event, recorded),
+            None => Ok(()),
+        });
+        if let Err(err) = result {
+            warn!("replayed request {:#x} failed: {:#}", request.request, err);
+        }
+        let replayed = outcome.get();
+        if replayed != *recorded {
+            mismatches.push(Mismatch {
+                index,
+                request: *request,
+                recorded: recorded.clone(),
+                replayed,
+            });
+        }
+    }
+    mismatches
+}
+
+fn answer(event: Event<MockSender>, recorded: &Outcome) -> anyhow::Result<()> {
+    let Outcome::Data(data) = recorded else {
+        return Ok(());
+    };
+    match event {
+        Event::GetDescriptor(req) => {
+            let descriptor = DisplayDescriptor::from_bytes(data)?;
+            req.send_descriptor(
+                descriptor.min_width,
+                descriptor.min_height,
+                descriptor.max_width,
+                descriptor.max_height,
+            )
+        }
+        Event::GetPixelFormats(req) => req.send_pixel_formats(data),
+        Event::GetDisplayModes(req) => {
+            let modes: Vec<_> = data
+                .chunks_exact(DisplayMode::LEN)
+                .map(|mode| DisplayMode::decode(&mut &mode[..]))
+                .collect();
+            req.send_modes(&modes)
+        }
+        _ => Ok(()),
+    }
+}
@@ -0,0 +1,216 @@
+//! The control transfer primitives [`Function`](crate::Function) dispatches on.
+//!
+//! FunctionFS provides these through `usb_gadget`'s `CtrlSender` and `CtrlReceiver`. The
+//! [`mock`] implementations work from memory, so the dispatcher can be driven without a UDC.
+
+use std::io;
+
+/// The setup packet of a control transfer.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ControlRequest {
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    pub length: u16,
+}
+
+/// The data stage of a device-to-host control transfer.
+pub trait ControlSender {
+    fn request(&self) -> ControlRequest;
+
+    /// Maximum number of bytes the host accepts.
+    fn len(&self) -> usize {
+        self.request().length as usize
+    }
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn send(self, data: &[u8]) -> io::Result<usize>;
+
+    /// Stalls the request.
+    fn halt(self) -> io::Result<()>;
+}
+
+/// The data stage of a host-to-device control transfer.
+pub trait ControlReceiver {
+    fn request(&self) -> ControlRequest;
+
+    fn recv_all(self) -> io::Result<Vec<u8>>;
+
+    /// Stalls the request.
+    fn halt(self) -> io::Result<()>;
+}
+
+/// A control transfer in either direction.
+#[derive(Debug)]
+pub enum ControlTransfer<S, R> {
+    DeviceToHost(S),
+    HostToDevice(R),
+}
+
+#[cfg(feature = "gadget")]
+mod ffs {
+    use super::*;
+    use usb_gadget::function::custom::{CtrlReceiver, CtrlReq, CtrlSender};
+
+    fn request(req: &CtrlReq) -> ControlRequest {
+        ControlRequest {
+            request: req.request,
+            value: req.value,
+            index: req.index,
+            length: req.length,
+        }
+    }
+
+    impl ControlSender for CtrlSender<'_> {
+        fn request(&self) -> ControlRequest {
+            request(self.ctrl_req())
+        }
+
+        fn len(&self) -> usize {
+            CtrlSender::len(self)
+        }
+
+        fn send(self, data: &[u8]) -> io::Result<usize> {
+            CtrlSender::send(self, data)
+        }
+
+        fn halt(self) -> io::Result<()> {
+            CtrlSender::halt(self)
+        }
+    }
+
+    impl ControlReceiver for CtrlReceiver<'_> {
+        fn request(&self) -> ControlRequest {
+            request(self.ctrl_req())
+        }
+
+        fn recv_all(self) -> io::Result<Vec<u8>> {
+            CtrlReceiver::recv_all(self)
+        }
+
+        fn halt(self) -> io::Result<()> {
+            CtrlReceiver::halt(self)
+        }
+    }
+}
+
+/// In-memory control transfers for driving the dispatcher in tests.
+pub mod mock {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// What the device did with a mocked control transfer.
+    #[derive(Clone, Debug, Default, PartialEq, Eq)]
+    pub enum Outcome {
+        /// The transfer wasn't completed yet.
+        #[default]
+        Pending,
+        /// Data was sent to the host, or received from it.
+        Data(Vec<u8>),
+        Halted,
+    }
+
+    /// Handle to inspect the outcome of a mocked transfer after it's been consumed.
+    #[derive(Clone, Debug, Default)]
+    pub struct MockOutcome(Rc<RefCell<Outcome>>);
+
+    impl MockOutcome {
+        pub fn get(&self) -> Outcome {
+            self.0.borrow().clone()
+        }
+
+        /// The data sent to the host, panicking if there was none.
+        pub fn data(&self) -> Vec<u8> {
+            match self.get() {
+                Outcome::Data(data) => data,
+                outcome => panic!("expected data, got {:?}", outcome),
+            }
+        }
+
+        fn set(&self, outcome: Outcome) {
+            *self.0.borrow_mut() = outcome;
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct MockSender {
+        request: ControlRequest,
+        outcome: MockOutcome,
+    }
+
+    impl MockSender {
+        pub fn new(request: ControlRequest) -> (Self, MockOutcome) {
+            let outcome = MockOutcome::default();
+            (
+                Self {
+                    request,
+                    outcome: outcome.clone(),
+                },
+                outcome,
+            )
+        }
+    }
+
+    impl ControlSender for MockSender {
+        fn request(&self) -> ControlRequest {
+            self.request
+        }
+
+        fn send(self, data: &[u8]) -> io::Result<usize> {
+            let len = data.len().min(self.len());
+            self.outcome.set(Outcome::Data(data[..len].to_vec()));
+            Ok(len)
+        }
+
+        fn halt(self) -> io::Result<()> {
+            self.outcome.set(Outcome::Halted);
+            Ok(())
+        }
+    }
+
+    #[derive(Debug)]
+    pub struct MockReceiver {
+        request: ControlRequest,
+        data: Vec<u8>,
+        outcome: MockOutcome,
+    }
+
+    impl MockReceiver {
+        /// A host-to-device transfer carrying `data`. The request's length is set to match.
+        pub fn new(mut request: ControlRequest, data: &[u8]) -> (Self, MockOutcome) {
+            request.length = data.len() as u16;
+            let outcome = MockOutcome::default();
+            (
+                Self {
+                    request,
+                    data: data.to_vec(),
+                    outcome: outcome.clone(),
+                },
+                outcome,
+            )
+        }
+    }
+
+    impl ControlReceiver for MockReceiver {
+        fn request(&self) -> ControlRequest {
+            self.request
+        }
+
+        fn recv_all(self) -> io::Result<Vec<u8>> {
+            self.outcome.set(Outcome::Data(self.data.clone()));
+            Ok(self.data)
+        }
+
+        fn halt(self) -> io::Result<()> {
+            self.outcome.set(Outcome::Halted);
+            Ok(())
+        }
+    }
+
+    /// A mocked control transfer as accepted by [`Function::control`](crate::Function::control).
+    pub type MockTransfer = ControlTransfer<MockSender, MockReceiver>;
+}
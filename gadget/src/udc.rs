@@ -0,0 +1,183 @@
+//! Following USB device controllers as they come and go.
+//!
+//! At boot the gadget daemon may well start before the UDC driver has probed, and some UDCs go
+//! away and come back, e.g. when their power domain is cycled or their module reloaded. The
+//! kernel announces both on its uevent netlink socket, which [`UdcMonitor`] listens on.
+
+use anyhow::Context;
+use std::ffi::{OsStr, OsString};
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::time::{Duration, Instant};
+use tracing::{debug, info};
+use usb_gadget::{RegGadget, Udc};
+
+// The multicast group the kernel sends uevents to (udev rebroadcasts on others).
+const KERNEL_UEVENT_GROUP: u32 = 1;
+
+/// A UDC showing up or going away, by name (e.g. `fe980000.usb`).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum UdcEvent {
+    Added(OsString),
+    Removed(OsString),
+}
+
+impl UdcEvent {
+    /// Picks the UDC events out of raw uevents, a header followed by NUL separated `KEY=value`
+    /// pairs.
+    pub fn parse(uevent: &[u8]) -> Option<Self> {
+        let (mut action, mut udc, mut name) = (None, false, None);
+        for field in uevent.split(|&b| b == 0) {
+            if let Some(value) = field.strip_prefix(b"ACTION=") {
+                action = Some(value);
+            } else if field == b"SUBSYSTEM=udc" {
+                udc = true;
+            } else if let Some(path) = field.strip_prefix(b"DEVPATH=") {
+                name = path.rsplit(|&b| b == b'/').next();
+            }
+        }
+        if !udc {
+            return None;
+        }
+        let name = OsStr::from_bytes(name.filter(|name| !name.is_empty())?).to_owned();
+        match action? {
+            b"add" => Some(Self::Added(name)),
+            b"remove" => Some(Self::Removed(name)),
+            _ => None,
+        }
+    }
+}
+
+/// Listens for UDCs being added and removed.
+pub struct UdcMonitor(OwnedFd);
+
+impl UdcMonitor {
+    pub fn open() -> io::Result<Self> {
+        let fd = unsafe {
+            libc::socket(
+                libc::AF_NETLINK,
+                libc::SOCK_DGRAM | libc::SOCK_CLOEXEC | libc::SOCK_NONBLOCK,
+                libc::NETLINK_KOBJECT_UEVENT,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+        let mut addr: libc::sockaddr_nl = unsafe { std::mem::zeroed() };
+        addr.nl_family = libc::AF_NETLINK as libc::sa_family_t;
+        addr.nl_groups = KERNEL_UEVENT_GROUP;
+        let ret = unsafe {
+            libc::bind(
+                fd.as_raw_fd(),
+                &addr as *const libc::sockaddr_nl as *const libc::sockaddr,
+                std::mem::size_of::<libc::sockaddr_nl>() as libc::socklen_t,
+            )
+        };
+        if ret < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(fd))
+    }
+
+    /// Drains the pending uevents without blocking, returning the UDC events among them.
+    pub fn events(&self) -> io::Result<Vec<UdcEvent>> {
+        let mut buf = [0u8; 8192];
+        let mut events = Vec::new();
+        loop {
+            let len = unsafe {
+                libc::recv(
+                    self.0.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                    0,
+                )
+            };
+            if len < 0 {
+                let err = io::Error::last_os_error();
+                return match err.kind() {
+                    io::ErrorKind::WouldBlock => Ok(events),
+                    _ => Err(err),
+                };
+            }
+            events.extend(UdcEvent::parse(&buf[..len as usize]));
+        }
+    }
+
+    /// Waits up to `timeout` for a uevent to arrive, returning whether one did.
+    pub fn wait(&self, timeout: Duration) -> io::Result<bool> {
+        let mut fd = libc::pollfd {
+            fd: self.0.as_raw_fd(),
+            events: libc::POLLIN,
+            revents: 0,
+        };
+        let timeout = timeout.as_millis().min(i32::MAX as u128) as libc::c_int;
+        match unsafe { libc::poll(&mut fd, 1, timeout) } {
+            ret if ret < 0 => {
+                let err = io::Error::last_os_error();
+                match err.kind() {
+                    io::ErrorKind::Interrupted => Ok(false),
+                    _ => Err(err),
+                }
+            }
+            ret => Ok(ret > 0),
+        }
+    }
+}
+
+impl AsRawFd for UdcMonitor {
+    fn as_raw_fd(&self) -> RawFd {
+        self.0.as_raw_fd()
+    }
+}
+
+/// The UDC called `name`, if it's there.
+pub fn find_udc(name: &OsStr) -> io::Result<Option<Udc>> {
+    Ok(usb_gadget::udcs()?
+        .into_iter()
+        .find(|udc| udc.name() == name))
+}
+
+/// Binds `reg` to the UDC called `name` again once it's come back, returning whether it had to.
+/// Kernels that rebind the gadget by themselves leave nothing to do.
+pub fn rebind(reg: &mut RegGadget, name: &OsStr) -> anyhow::Result<bool> {
+    if reg.udc().context("get bound UDC")?.as_deref() == Some(name) {
+        return Ok(false);
+    }
+    let udc = find_udc(name)
+        .context("list UDCs")?
+        .with_context(|| format!("UDC {} is gone", name.to_string_lossy()))?;
+    reg.bind(Some(&udc)).context("bind gadget")?;
+    Ok(true)
+}
+
+/// Like [`usb_gadget::default_udc`], but waits for a UDC to appear if there's none yet, for up
+/// to `timeout` or forever.
+pub fn wait_for_udc(timeout: Option<Duration>) -> anyhow::Result<Udc> {
+    // Listening first, so a UDC that's added right after looking isn't missed.
+    let monitor = UdcMonitor::open().context("listen for UDCs")?;
+    let started = Instant::now();
+    let mut logged = false;
+    loop {
+        if let Ok(udc) = usb_gadget::default_udc() {
+            debug!("found UDC {}", udc.name().to_string_lossy());
+            return Ok(udc);
+        }
+        if !logged {
+            info!("waiting for a UDC to appear");
+            logged = true;
+        }
+        let remaining = match timeout {
+            Some(timeout) => match timeout.checked_sub(started.elapsed()) {
+                Some(remaining) if !remaining.is_zero() => remaining,
+                _ => anyhow::bail!("no UDC appeared within {:?}", timeout),
+            },
+            None => Duration::from_secs(60),
+        };
+        if monitor.wait(remaining).context("wait for UDCs")? {
+            monitor.events().context("read uevents")?;
+        }
+    }
+}
@@ -0,0 +1,102 @@
+//! Sleeping until there's something to do.
+//!
+//! Looping on `Custom::event_timeout` wakes the CPU up every few milliseconds even while no host
+//! is attached, which costs a phone's battery. A [`Waiter`] instead blocks in `poll(2)` on the
+//! FunctionFS ep0 descriptor (`Custom::fd`) and whatever else the event loop listens to, such as
+//! uevent sockets, until one of them is readable or a [`Waker`] is woken, e.g. from a signal
+//! handler.
+
+use std::io;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Blocks until one of the descriptors it watches is readable.
+pub struct Waiter {
+    fds: Vec<RawFd>,
+    wake: Arc<OwnedFd>,
+}
+
+/// Wakes a [`Waiter`] from another thread. Wakes while it isn't waiting end its next wait right
+/// away, so none are lost.
+#[derive(Clone)]
+pub struct Waker(Arc<OwnedFd>);
+
+impl Waiter {
+    pub fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self {
+            fds: Vec::new(),
+            wake: Arc::new(unsafe { OwnedFd::from_raw_fd(fd) }),
+        })
+    }
+
+    /// Ends waits when `fd` is readable. It has to stay open as long as the waiter's used.
+    pub fn watch(&mut self, fd: RawFd) {
+        self.fds.push(fd);
+    }
+
+    pub fn waker(&self) -> Waker {
+        Waker(self.wake.clone())
+    }
+
+    /// Waits until a watched descriptor is readable or the waiter is woken, for at most
+    /// `timeout` if there is one. Returns false if it timed out or was interrupted by a signal.
+    pub fn wait(&self, timeout: Option<Duration>) -> io::Result<bool> {
+        let mut fds: Vec<_> = self
+            .fds
+            .iter()
+            .chain([&self.wake.as_raw_fd()])
+            .map(|&fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        let timeout = match timeout {
+            // Rounded up, so a wait doesn't end just before it's due.
+            Some(timeout) => timeout
+                .as_nanos()
+                .div_ceil(1_000_000)
+                .min(libc::c_int::MAX as u128) as libc::c_int,
+            None => -1,
+        };
+        let ready = unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) };
+        if ready < 0 {
+            let err = io::Error::last_os_error();
+            return match err.kind() {
+                io::ErrorKind::Interrupted => Ok(false),
+                _ => Err(err),
+            };
+        }
+        if fds.last().is_some_and(|wake| wake.revents != 0) {
+            // Resets the eventfd's count.
+            let mut count = 0u64;
+            unsafe {
+                libc::read(
+                    self.wake.as_raw_fd(),
+                    &mut count as *mut u64 as *mut libc::c_void,
+                    8,
+                )
+            };
+        }
+        Ok(ready > 0)
+    }
+}
+
+impl Waker {
+    pub fn wake(&self) {
+        let count = 1u64;
+        // Only fails if the count would overflow, in which case it's woken anyway.
+        unsafe {
+            libc::write(
+                self.0.as_raw_fd(),
+                &count as *const u64 as *const libc::c_void,
+                8,
+            )
+        };
+    }
+}
@@ -0,0 +1,655 @@
+//! Pixel format conversion, scaling and transforms.
+
+use gud_gadget::blit::{
+    blit_convert, blit_rows, blit_scaled, blit_transformed, contiguous_span, correct, Filter, Lut,
+    Scale, Swizzle, Transform,
+};
+use gud_gadget::protocol::{PixelFormat, GUD_PIXEL_FORMAT_RGB565, GUD_PIXEL_FORMAT_XRGB8888};
+use gud_gadget::{DisplayMode, Frame, ProtocolError, SetBuffer};
+
+fn set_buffer(x: u32, y: u32, width: u32, height: u32, bpp: u32) -> SetBuffer {
+    SetBuffer {
+        x,
+        y,
+        width,
+        height,
+        length: width * height * bpp,
+        compression: 0,
+        compressed_length: 0,
+    }
+}
+
+#[test]
+fn validate_format_rejects_oversized_length() {
+    let info = set_buffer(0, 0, 2, 2, 2);
+    assert_eq!(
+        info.validate_format(GUD_PIXEL_FORMAT_RGB565).unwrap(),
+        PixelFormat::Rgb565
+    );
+
+    // A compressed 2x2 rect claiming to decompress to 4 GiB is refused before it's allocated.
+    let oversized = SetBuffer {
+        length: u32::MAX,
+        compression: 1,
+        compressed_length: 16,
+        ..info
+    };
+    assert!(matches!(
+        oversized.validate_format(GUD_PIXEL_FORMAT_RGB565),
+        Err(ProtocolError::LengthMismatch {
+            length: 0xffff_ffff,
+            expected: 8,
+        })
+    ));
+    let oversized = SetBuffer {
+        compressed_length: u32::MAX,
+        ..oversized
+    };
+    assert!(oversized.validate_format(GUD_PIXEL_FORMAT_RGB565).is_err());
+    assert!(matches!(
+        info.validate_format(0xee),
+        Err(ProtocolError::UnknownFormat(0xee))
+    ));
+}
+
+#[test]
+fn xrgb8888_to_rgb565() {
+    let info = set_buffer(1, 0, 2, 1, 4);
+    // Pure red and white, stored B, G, R, X.
+    let buf = [0x00, 0x00, 0xff, 0x00, 0xff, 0xff, 0xff, 0x00];
+    let mut fb = [0; 6];
+    blit_convert(
+        &info,
+        PixelFormat::Xrgb8888,
+        &buf,
+        &mut fb,
+        6,
+        PixelFormat::Rgb565,
+        Swizzle::NONE,
+    )
+    .unwrap();
+    assert_eq!(fb, [0, 0, 0x00, 0xf8, 0xff, 0xff]);
+}
+
+#[test]
+fn rgb565_to_xrgb8888() {
+    let info = set_buffer(0, 1, 1, 1, 2);
+    // Pure green.
+    let buf = 0x07e0u16.to_le_bytes();
+    let mut fb = [0; 8];
+    blit_convert(
+        &info,
+        PixelFormat::Rgb565,
+        &buf,
+        &mut fb,
+        4,
+        PixelFormat::Xrgb8888,
+        Swizzle::NONE,
+    )
+    .unwrap();
+    assert_eq!(fb, [0, 0, 0, 0, 0x00, 0xff, 0x00, 0xff]);
+}
+
+#[test]
+fn rgb888_to_xrgb8888() {
+    let info = set_buffer(0, 0, 2, 1, 3);
+    let buf = [0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+    let mut fb = [0; 8];
+    blit_convert(
+        &info,
+        PixelFormat::Rgb888,
+        &buf,
+        &mut fb,
+        8,
+        PixelFormat::Xrgb8888,
+        Swizzle::NONE,
+    )
+    .unwrap();
+    assert_eq!(fb, [0x01, 0x02, 0x03, 0xff, 0x04, 0x05, 0x06, 0xff]);
+}
+
+#[test]
+fn same_format_is_copied() {
+    let info = set_buffer(0, 0, 1, 2, 2);
+    let buf = [0x12, 0x34, 0x56, 0x78];
+    let mut fb = [0; 4];
+    blit_convert(
+        &info,
+        PixelFormat::Rgb565,
+        &buf,
+        &mut fb,
+        2,
+        PixelFormat::Rgb565,
+        Swizzle::NONE,
+    )
+    .unwrap();
+    assert_eq!(fb, buf);
+}
+
+#[test]
+fn length_is_checked_against_host_format() {
+    // Sized for the framebuffer's 2 bytes per pixel rather than the host's 4.
+    let info = set_buffer(0, 0, 2, 2, 2);
+    let mut fb = [0; 8];
+    let err = blit_convert(
+        &info,
+        PixelFormat::Xrgb8888,
+        &[0; 8],
+        &mut fb,
+        4,
+        PixelFormat::Rgb565,
+        Swizzle::NONE,
+    )
+    .unwrap_err();
+    assert!(matches!(err, ProtocolError::LengthMismatch { .. }));
+}
+
+#[test]
+fn unsupported_conversion() {
+    let mut info = set_buffer(0, 0, 8, 1, 1);
+    info.length = 1;
+    let mut fb = [0; 16];
+    let err = blit_convert(
+        &info,
+        PixelFormat::R1,
+        &[0; 1],
+        &mut fb,
+        16,
+        PixelFormat::Rgb565,
+        Swizzle::NONE,
+    )
+    .unwrap_err();
+    assert!(matches!(
+        err,
+        ProtocolError::UnsupportedConversion {
+            from: 0x01,
+            to: 0x40
+        }
+    ));
+}
+
+#[test]
+fn swap16() {
+    let info = set_buffer(0, 0, 1, 1, 4);
+    // Pure red.
+    let buf = [0x00, 0x00, 0xff, 0x00];
+    let mut fb = [0; 2];
+    blit_convert(
+        &info,
+        PixelFormat::Xrgb8888,
+        &buf,
+        &mut fb,
+        2,
+        PixelFormat::Rgb565,
+        Swizzle::SWAP16,
+    )
+    .unwrap();
+    assert_eq!(fb, [0xf8, 0x00]);
+}
+
+#[test]
+fn swap_rb_in_same_format() {
+    let info = set_buffer(0, 0, 1, 1, 2);
+    // Pure red, written as pure blue.
+    let buf = 0xf800u16.to_le_bytes();
+    let mut fb = [0; 2];
+    blit_convert(
+        &info,
+        PixelFormat::Rgb565,
+        &buf,
+        &mut fb,
+        2,
+        PixelFormat::Rgb565,
+        Swizzle::SWAP_RB,
+    )
+    .unwrap();
+    assert_eq!(fb, 0x001fu16.to_le_bytes());
+}
+
+#[test]
+fn long_lines_to_rgb565() {
+    // Long enough for whole blocks of the SIMD kernels and a remainder.
+    let width = 37;
+    let info = set_buffer(0, 0, width, 1, 4);
+    let buf: Vec<u8> = (0..width * 4).map(|i| (i * 29 % 256) as u8).collect();
+    for swizzle in [
+        Swizzle::NONE,
+        Swizzle::SWAP16,
+        Swizzle::SWAP_RB,
+        Swizzle {
+            swap16: true,
+            swap_rb: true,
+        },
+    ] {
+        let mut fb = vec![0; width as usize * 2];
+        let pitch = fb.len();
+        let (from, to) = (PixelFormat::Xrgb8888, PixelFormat::Rgb565);
+        blit_convert(&info, from, &buf, &mut fb, pitch, to, swizzle).unwrap();
+        let expected: Vec<u8> = buf
+            .chunks_exact(4)
+            .flat_map(|p| {
+                let (r, g, b) = match swizzle.swap_rb {
+                    true => (p[0], p[1], p[2]),
+                    false => (p[2], p[1], p[0]),
+                };
+                let v = (r as u16 >> 3) << 11 | (g as u16 >> 2) << 5 | b as u16 >> 3;
+                match swizzle.swap16 {
+                    true => v.to_be_bytes(),
+                    false => v.to_le_bytes(),
+                }
+            })
+            .collect();
+        assert_eq!(fb, expected, "{:?}", swizzle);
+
+        // And back to RGB565 with the bytes swapped again.
+        if swizzle == Swizzle::SWAP16 {
+            let info = set_buffer(0, 0, width, 1, 2);
+            let mut swapped = vec![0; fb.len()];
+            blit_convert(&info, to, &fb, &mut swapped, pitch, to, swizzle).unwrap();
+            let expected: Vec<u8> = fb.chunks_exact(2).flat_map(|p| [p[1], p[0]]).collect();
+            assert_eq!(swapped, expected);
+        }
+    }
+}
+
+#[test]
+fn swap16_needs_16_bit_framebuffer() {
+    let info = set_buffer(0, 0, 1, 1, 4);
+    let mut fb = [0; 4];
+    let swizzle = Swizzle::SWAP16;
+    assert!(blit_convert(
+        &info,
+        PixelFormat::Xrgb8888,
+        &[0; 4],
+        &mut fb,
+        4,
+        PixelFormat::Xrgb8888,
+        swizzle
+    )
+    .is_err());
+}
+
+fn mode(width: u16, height: u16) -> DisplayMode {
+    DisplayMode {
+        hdisplay: width,
+        vdisplay: height,
+        ..Default::default()
+    }
+}
+
+fn scale(width: u32, height: u32, filter: Filter) -> Scale {
+    Scale {
+        width,
+        height,
+        filter,
+    }
+}
+
+#[test]
+fn nearest_downscale() {
+    // A 4x2 red ramp, scaled to 2x1.
+    let info = set_buffer(0, 0, 4, 2, 4);
+    let buf: Vec<u8> = (0..8).flat_map(|i| [i * 10, 0, 0, 0]).collect();
+    let mut fb = [0; 8];
+    blit_scaled(
+        &info,
+        &mode(4, 2),
+        PixelFormat::Xrgb8888,
+        &buf,
+        &mut fb,
+        8,
+        PixelFormat::Xrgb8888,
+        Swizzle::NONE,
+        scale(2, 1, Filter::Nearest),
+    )
+    .unwrap();
+    // Centres of the destination pixels land on source (1, 1) and (3, 1).
+    assert_eq!(fb, [50, 0, 0, 0xff, 70, 0, 0, 0xff]);
+}
+
+#[test]
+fn nearest_upscale_damage_rect() {
+    // A single pixel damaged at (1, 0) of a 2x1 mode, scaled to 4x2.
+    let info = set_buffer(1, 0, 1, 1, 2);
+    let buf = 0xffffu16.to_le_bytes();
+    let mut fb = [0; 16];
+    blit_scaled(
+        &info,
+        &mode(2, 1),
+        PixelFormat::Rgb565,
+        &buf,
+        &mut fb,
+        8,
+        PixelFormat::Rgb565,
+        Swizzle::NONE,
+        scale(4, 2, Filter::Nearest),
+    )
+    .unwrap();
+    assert_eq!(fb, [0, 0, 0, 0, 0xff, 0xff, 0xff, 0xff].repeat(2)[..]);
+}
+
+#[test]
+fn bilinear_downscale() {
+    let info = set_buffer(0, 0, 2, 1, 4);
+    let buf = [0, 0, 0, 0, 200, 100, 50, 0];
+    let mut fb = [0; 4];
+    blit_scaled(
+        &info,
+        &mode(2, 1),
+        PixelFormat::Xrgb8888,
+        &buf,
+        &mut fb,
+        4,
+        PixelFormat::Xrgb8888,
+        Swizzle::NONE,
+        scale(1, 1, Filter::Bilinear),
+    )
+    .unwrap();
+    assert_eq!(fb, [100, 50, 25, 0xff]);
+}
+
+#[test]
+fn scaled_rect_out_of_mode() {
+    let info = set_buffer(1, 0, 2, 1, 2);
+    let mut fb = [0; 16];
+    let err = blit_scaled(
+        &info,
+        &mode(2, 1),
+        PixelFormat::Rgb565,
+        &[0; 4],
+        &mut fb,
+        8,
+        PixelFormat::Rgb565,
+        Swizzle::NONE,
+        scale(4, 2, Filter::Nearest),
+    )
+    .unwrap_err();
+    assert!(matches!(err, ProtocolError::RectOutOfBounds { .. }));
+}
+
+#[test]
+fn rotation_in_quarter_turns() {
+    assert_eq!(Transform::rotate(0), Some(Transform::IDENTITY));
+    assert_eq!(Transform::rotate(270).unwrap().quarter_turns, 3);
+    assert_eq!(Transform::rotate(450).unwrap().quarter_turns, 1);
+    assert_eq!(Transform::rotate(45), None);
+    assert_eq!(Transform::rotate(90).unwrap().apply_size(3, 2), (2, 3));
+}
+
+#[test]
+fn inverse_transforms() {
+    for quarter_turns in 0..4 {
+        for flip in [false, true] {
+            let transform = Transform {
+                quarter_turns,
+                flip,
+            };
+            let (x, y) = transform.apply(1, 0, 3, 2);
+            let (width, height) = transform.apply_size(3, 2);
+            assert_eq!(
+                transform.inverse().apply(x, y, width, height),
+                (1, 0),
+                "{transform:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn rotate_90() {
+    // A 3x2 mode onto a panel mounted sideways, 2x3.
+    let info = set_buffer(0, 0, 3, 2, 4);
+    let buf: Vec<u8> = (0..6).flat_map(|i| [i, 0, 0, 0]).collect();
+    let mut fb = [0; 24];
+    blit_transformed(
+        &info,
+        &mode(3, 2),
+        PixelFormat::Xrgb8888,
+        &buf,
+        &mut fb,
+        8,
+        PixelFormat::Xrgb8888,
+        Swizzle::NONE,
+        scale(2, 3, Filter::Nearest),
+        Transform::rotate(90).unwrap(),
+    )
+    .unwrap();
+    // The left column ends up along the top, read from the bottom.
+    let blue: Vec<u8> = fb.chunks_exact(4).map(|pixel| pixel[0]).collect();
+    assert_eq!(blue, [3, 0, 4, 1, 5, 2]);
+}
+
+#[test]
+fn flip_damage_rect() {
+    // Mirrored and turned upside down, the top left pixel lands bottom left.
+    let info = set_buffer(0, 0, 1, 1, 4);
+    let buf = [0xff; 4];
+    let mut fb = [0; 16];
+    blit_transformed(
+        &info,
+        &mode(2, 2),
+        PixelFormat::Xrgb8888,
+        &buf,
+        &mut fb,
+        8,
+        PixelFormat::Xrgb8888,
+        Swizzle::NONE,
+        scale(2, 2, Filter::Nearest),
+        Transform {
+            quarter_turns: 2,
+            flip: true,
+        },
+    )
+    .unwrap();
+    assert_eq!(fb[..8], [0; 8]);
+    assert_eq!(fb[8..12], [0xff; 4]);
+    assert_eq!(fb[12..], [0; 4]);
+}
+
+#[test]
+fn rows_to_sink() {
+    let info = set_buffer(3, 5, 2, 2, 2);
+    let buf = [1, 2, 3, 4, 5, 6, 7, 8];
+    let mut rows = Vec::new();
+    blit_rows(&info, &buf, 2, &mut |y, row: &[u8]| {
+        rows.push((y, row.to_vec()))
+    })
+    .unwrap();
+    assert_eq!(rows, [(5, vec![1, 2, 3, 4]), (6, vec![5, 6, 7, 8])]);
+}
+
+#[test]
+fn rows_short_buffer() {
+    let info = set_buffer(0, 0, 2, 2, 2);
+    let err = blit_rows(&info, &[0; 6], 2, &mut |_, _: &[u8]| {}).unwrap_err();
+    assert!(matches!(
+        err,
+        ProtocolError::LengthMismatch {
+            length: 6,
+            expected: 8
+        }
+    ));
+}
+
+#[test]
+fn convert_frame() {
+    let frame = Frame {
+        info: set_buffer(7, 3, 2, 1, 4),
+        format: GUD_PIXEL_FORMAT_XRGB8888,
+        // Pure red and white, stored B, G, R, X.
+        data: vec![0x00, 0x00, 0xff, 0x00, 0xff, 0xff, 0xff, 0x00].into(),
+    };
+    let converted = frame.convert(PixelFormat::Rgb565, Swizzle::NONE).unwrap();
+    assert_eq!(converted.format, GUD_PIXEL_FORMAT_RGB565);
+    assert_eq!(converted.info, set_buffer(7, 3, 2, 1, 2));
+    assert_eq!(&converted.data[..], [0x00, 0xf8, 0xff, 0xff]);
+}
+
+#[test]
+fn xrgb1111_to_xrgb8888() {
+    let mut info = set_buffer(0, 0, 3, 1, 1);
+    // Lines start on a byte boundary, the first pixel in the high nibble.
+    info.length = 2;
+    // Red, cyan and white.
+    let buf = [0x43, 0x70];
+    let mut fb = [0; 12];
+    blit_convert(
+        &info,
+        PixelFormat::Xrgb1111,
+        &buf,
+        &mut fb,
+        12,
+        PixelFormat::Xrgb8888,
+        Swizzle::NONE,
+    )
+    .unwrap();
+    assert_eq!(
+        fb,
+        [0x00, 0x00, 0xff, 0xff, 0xff, 0xff, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff]
+    );
+}
+
+#[test]
+fn xrgb1111_length() {
+    let mut info = set_buffer(0, 0, 3, 2, 1);
+    let mut fb = [0; 24];
+    let convert = |info: &SetBuffer, fb: &mut [u8]| {
+        blit_convert(
+            info,
+            PixelFormat::Xrgb1111,
+            &[0; 6],
+            fb,
+            12,
+            PixelFormat::Xrgb8888,
+            Swizzle::NONE,
+        )
+    };
+    assert!(matches!(
+        convert(&info, &mut fb),
+        Err(ProtocolError::LengthMismatch {
+            length: 6,
+            expected: 4
+        })
+    ));
+    info.length = 4;
+    convert(&info, &mut fb).unwrap();
+}
+
+#[test]
+fn argb8888_keeps_alpha() {
+    let info = set_buffer(0, 0, 1, 1, 4);
+    // Half transparent red, stored B, G, R, A.
+    let buf = [0x00, 0x00, 0xff, 0x80];
+    let convert = |fb_format| {
+        let mut fb = [0; 4];
+        blit_convert(
+            &info,
+            PixelFormat::Argb8888,
+            &buf,
+            &mut fb,
+            4,
+            fb_format,
+            Swizzle::SWAP_RB,
+        )
+        .unwrap();
+        fb
+    };
+    assert_eq!(convert(PixelFormat::Argb8888), [0xff, 0x00, 0x00, 0x80]);
+    assert_eq!(convert(PixelFormat::Xrgb8888), [0xff, 0x00, 0x00, 0xff]);
+}
+
+#[test]
+fn r8_and_rgb332_to_xrgb8888() {
+    let info = set_buffer(0, 0, 2, 1, 1);
+    let convert = |format, buf: [u8; 2]| {
+        let mut fb = [0; 8];
+        blit_convert(
+            &info,
+            format,
+            &buf,
+            &mut fb,
+            8,
+            PixelFormat::Xrgb8888,
+            Swizzle::NONE,
+        )
+        .unwrap();
+        fb
+    };
+    assert_eq!(
+        convert(PixelFormat::R8, [0x00, 0x80]),
+        [0x00, 0x00, 0x00, 0xff, 0x80, 0x80, 0x80, 0xff]
+    );
+    // Pure red and pure blue.
+    assert_eq!(
+        convert(PixelFormat::Rgb332, [0xe0, 0x03]),
+        [0x00, 0x00, 0xff, 0xff, 0xff, 0x00, 0x00, 0xff]
+    );
+}
+
+#[test]
+fn xrgb8888_to_r8_and_rgb332() {
+    let info = set_buffer(0, 0, 2, 1, 4);
+    // White and pure green, stored B, G, R, X.
+    let buf = [0xff, 0xff, 0xff, 0x00, 0x00, 0xff, 0x00, 0x00];
+    let convert = |fb_format| {
+        let mut fb = [0; 2];
+        blit_convert(
+            &info,
+            PixelFormat::Xrgb8888,
+            &buf,
+            &mut fb,
+            2,
+            fb_format,
+            Swizzle::NONE,
+        )
+        .unwrap();
+        fb
+    };
+    assert_eq!(convert(PixelFormat::R8), [0xff, 0x99]);
+    assert_eq!(convert(PixelFormat::Rgb332), [0xff, 0x1c]);
+}
+
+#[test]
+fn full_width_damage_is_contiguous() {
+    // Lines 2 and 3 of a 4x4 XRGB8888 framebuffer.
+    let info = set_buffer(0, 2, 4, 2, 4);
+    assert_eq!(contiguous_span(&info, 64, 16, 16), Some(32..64));
+    // Padded lines, or a rect that doesn't start at the left edge.
+    assert_eq!(contiguous_span(&info, 80, 20, 16), None);
+    assert_eq!(
+        contiguous_span(&set_buffer(1, 2, 3, 2, 4), 64, 16, 12),
+        None
+    );
+    // Past the end of the framebuffer.
+    assert_eq!(
+        contiguous_span(&set_buffer(0, 3, 4, 2, 4), 64, 16, 16),
+        None
+    );
+}
+
+#[test]
+fn lut_corrects_channels() {
+    let mut red = [0; 256];
+    red[0x80] = 0x40;
+    red[0x84] = 0x40;
+    let lut = Lut::new(red, std::array::from_fn(|v| v as u8), [0xff; 256]);
+
+    let mut pixels = [0x00, 0x10, 0x80, 0x7f, 0x00, 0x10, 0x80, 0x7f];
+    correct(PixelFormat::Xrgb8888, &lut, &mut pixels);
+    assert_eq!(pixels, [0xff, 0x10, 0x40, 0x7f, 0xff, 0x10, 0x40, 0x7f]);
+
+    // Red 0x84, green 0x86 and blue 0 once expanded to 8 bits.
+    let mut pixels = 0x8420u16.to_le_bytes();
+    correct(PixelFormat::Rgb565, &lut, &mut pixels);
+    assert_eq!(u16::from_le_bytes(pixels), 0x443f);
+}
+
+#[test]
+fn gamma_lut() {
+    let mut pixels: Vec<u8> = (0..=255).collect();
+    correct(PixelFormat::R8, &Lut::gamma(1.0), &mut pixels);
+    assert!(pixels.iter().enumerate().all(|(i, &v)| v as usize == i));
+
+    correct(PixelFormat::R8, &Lut::gamma(2.0), &mut pixels);
+    assert_eq!((pixels[0], pixels[128], pixels[255]), (0, 64, 255));
+}
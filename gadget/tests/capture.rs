@@ -0,0 +1,95 @@
+//! Compositing frames into a canvas and writing them out.
+
+use bytes::Bytes;
+use gud_gadget::capture::{Canvas, Y4mWriter};
+use gud_gadget::protocol::*;
+use gud_gadget::{Frame, FrameSink, SetBuffer};
+
+fn frame(x: u32, y: u32, width: u32, height: u32, format: u8, data: &[u8]) -> Frame {
+    Frame {
+        info: SetBuffer {
+            x,
+            y,
+            width,
+            height,
+            length: data.len() as u32,
+            compression: 0,
+            compressed_length: 0,
+        },
+        format,
+        data: Bytes::copy_from_slice(data),
+    }
+}
+
+// The B, G, R bytes of the canvas pixel at `(x, y)`.
+fn bgr(canvas: &Canvas, x: usize, y: usize) -> [u8; 3] {
+    let i = y * canvas.pitch() + x * 4;
+    canvas.pixels()[i..i + 3].try_into().unwrap()
+}
+
+#[test]
+fn canvas_keeps_undamaged_pixels() {
+    let mut canvas = Canvas::new(2, 2);
+    canvas
+        .draw(&frame(0, 0, 2, 2, GUD_PIXEL_FORMAT_XRGB8888, &[0xff; 16]))
+        .unwrap();
+    // Pure red in RGB565, little-endian.
+    canvas
+        .draw(&frame(1, 1, 1, 1, GUD_PIXEL_FORMAT_RGB565, &[0x00, 0xf8]))
+        .unwrap();
+
+    assert_eq!(bgr(&canvas, 0, 0), [0xff, 0xff, 0xff]);
+    assert_eq!(bgr(&canvas, 1, 0), [0xff, 0xff, 0xff]);
+    assert_eq!(bgr(&canvas, 0, 1), [0xff, 0xff, 0xff]);
+    assert_eq!(bgr(&canvas, 1, 1), [0x00, 0x00, 0xff]);
+}
+
+#[test]
+fn canvas_rejects_rects_out_of_bounds() {
+    let mut canvas = Canvas::new(2, 2);
+    assert!(canvas
+        .draw(&frame(1, 0, 2, 1, GUD_PIXEL_FORMAT_XRGB8888, &[0; 8]))
+        .is_err());
+}
+
+#[test]
+fn y4m_writes_whole_frames() {
+    let mut writer = Y4mWriter::new(Vec::new(), 2, 1, 30);
+    writer
+        .frame(frame(0, 0, 1, 1, GUD_PIXEL_FORMAT_XRGB8888, &[0xff; 4]))
+        .unwrap();
+    writer
+        .frame(frame(1, 0, 1, 1, GUD_PIXEL_FORMAT_XRGB8888, &[0xff; 4]))
+        .unwrap();
+    let out = writer.into_inner();
+
+    let header = b"YUV4MPEG2 W2 H1 F30:1 Ip A1:1 C444 XCOLORRANGE=FULL\n";
+    assert!(out.starts_with(header));
+    let frames = &out[header.len()..];
+    // FRAME, then the Y, U and V planes at full resolution.
+    let frame_len = 6 + 2 * 3;
+    assert_eq!(frames.len(), 2 * frame_len);
+    // White then black, neutral chroma.
+    assert_eq!(&frames[..frame_len], b"FRAME\n\xff\x00\x80\x80\x80\x80");
+    assert_eq!(&frames[frame_len..], b"FRAME\n\xff\xff\x80\x80\x80\x80");
+}
+
+#[cfg(feature = "png")]
+#[test]
+fn png_writes_numbered_files() {
+    use gud_gadget::capture::PngWriter;
+
+    let dir = std::env::temp_dir().join(format!("gud-capture-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let mut writer = PngWriter::new(&dir, 2, 2);
+    writer
+        .frame(frame(0, 0, 1, 1, GUD_PIXEL_FORMAT_XRGB8888, &[0xff; 4]))
+        .unwrap();
+    writer.write_frame().unwrap();
+
+    for name in ["frame-000000.png", "frame-000001.png"] {
+        let data = std::fs::read(dir.join(name)).unwrap();
+        assert!(data.starts_with(b"\x89PNG\r\n\x1a\n"));
+    }
+    std::fs::remove_dir_all(&dir).unwrap();
+}
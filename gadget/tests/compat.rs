@@ -0,0 +1,106 @@
+//! The free-standing dispatch API, backed by a function per thread.
+
+use gud_gadget::compat;
+use gud_gadget::protocol::*;
+use gud_gadget::transport::mock::{MockOutcome, MockReceiver, MockSender, MockTransfer};
+use gud_gadget::transport::{ControlRequest, ControlTransfer};
+use gud_gadget::{DisplayMode, Event, SetBuffer};
+use std::thread;
+
+fn get(request: u8, length: u16) -> (MockTransfer, MockOutcome) {
+    let (sender, outcome) = MockSender::new(ControlRequest {
+        request,
+        length,
+        ..Default::default()
+    });
+    (ControlTransfer::DeviceToHost(sender), outcome)
+}
+
+fn set(request: u8, data: &[u8]) -> (MockTransfer, MockOutcome) {
+    let (receiver, outcome) = MockReceiver::new(
+        ControlRequest {
+            request,
+            ..Default::default()
+        },
+        data,
+    );
+    (ControlTransfer::HostToDevice(receiver), outcome)
+}
+
+fn descriptor() -> DisplayDescriptor {
+    let (transfer, outcome) = get(GUD_REQ_GET_DESCRIPTOR, DisplayDescriptor::LEN as u16);
+    let Some(Event::GetDescriptor(req)) = compat::control(transfer).unwrap() else {
+        panic!("expected GetDescriptor");
+    };
+    req.send_descriptor(1, 1, 64, 32).unwrap();
+    DisplayDescriptor::from_bytes(&outcome.data()).unwrap()
+}
+
+fn mode() -> DisplayMode {
+    DisplayMode {
+        clock: 1000,
+        hdisplay: 64,
+        hsync_start: 64,
+        hsync_end: 64,
+        htotal: 64,
+        vdisplay: 32,
+        vsync_start: 32,
+        vsync_end: 32,
+        vtotal: 32,
+        flags: 0,
+    }
+}
+
+#[test]
+fn configured_with_function() {
+    assert_eq!(descriptor().compression, GUD_COMPRESSION_LZ4);
+    compat::with(|function| function.set_compression(0));
+    assert_eq!(descriptor().compression, 0);
+
+    // Every thread has a function of its own.
+    let compression = thread::spawn(|| descriptor().compression).join().unwrap();
+    assert_eq!(compression, GUD_COMPRESSION_LZ4);
+}
+
+#[test]
+fn state_kept_across_calls() {
+    descriptor();
+    let (transfer, _) = get(GUD_REQ_GET_FORMATS, GUD_FORMATS_MAX_NUM as u16);
+    let Some(Event::GetPixelFormats(req)) = compat::control(transfer).unwrap() else {
+        panic!("expected GetPixelFormats");
+    };
+    req.send_pixel_formats(&[GUD_PIXEL_FORMAT_RGB565]).unwrap();
+
+    let state = StateRequest {
+        mode: mode(),
+        format: GUD_PIXEL_FORMAT_RGB565,
+        connector: 0,
+        properties: vec![],
+    };
+    let (transfer, _) = set(GUD_REQ_SET_STATE_CHECK, &state.to_bytes());
+    assert!(compat::control(transfer).unwrap().is_none());
+    let (transfer, _) = set(GUD_REQ_SET_STATE_COMMIT, &[]);
+    assert!(compat::control(transfer).unwrap().is_none());
+    assert_eq!(
+        compat::with(|function| function.mode().cloned()),
+        Some(mode())
+    );
+
+    let info = SetBuffer {
+        x: 0,
+        y: 0,
+        width: 8,
+        height: 8,
+        length: 8 * 8 * 2,
+        compression: 0,
+        compressed_length: 0,
+    };
+    let (transfer, _) = set(GUD_REQ_SET_BUFFER, &info.to_bytes());
+    assert!(compat::control(transfer).unwrap().is_none());
+    let (transfer, outcome) = get(GUD_REQ_GET_STATUS, 1);
+    let Some(Event::Buffer(buffer)) = compat::control(transfer).unwrap() else {
+        panic!("expected Buffer");
+    };
+    assert_eq!(outcome.data(), [GUD_STATUS_OK]);
+    assert_eq!(buffer, info);
+}
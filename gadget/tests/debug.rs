@@ -0,0 +1,82 @@
+//! Frame validation, damage outlines and throttling.
+
+use gud_gadget::debug;
+use gud_gadget::SetBuffer;
+use std::time::Duration;
+
+fn set_buffer(width: u32, height: u32, bpp: u32) -> SetBuffer {
+    SetBuffer {
+        x: 0,
+        y: 0,
+        width,
+        height,
+        length: width * height * bpp,
+        compression: 0,
+        compressed_length: 0,
+    }
+}
+
+#[test]
+fn checksum_matches_crc32() {
+    assert_eq!(debug::checksum(b"123456789"), 0xcbf4_3926);
+    assert_eq!(debug::checksum(&[]), 0);
+}
+
+#[test]
+fn outline_rect() {
+    let info = set_buffer(4, 3, 2);
+    let mut buf = vec![0; info.length as usize];
+    debug::outline(&info, &mut buf);
+
+    #[rustfmt::skip]
+    let expected = [
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+        0xff, 0xff, 0x00, 0x00, 0x00, 0x00, 0xff, 0xff,
+        0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+    ];
+    assert_eq!(buf, expected);
+}
+
+#[test]
+fn outline_skips_packed_formats() {
+    // R1, 8 pixels to a byte.
+    let info = SetBuffer {
+        length: 2,
+        ..set_buffer(8, 2, 0)
+    };
+    let mut buf = vec![0; 2];
+    debug::outline(&info, &mut buf);
+    assert_eq!(buf, [0, 0]);
+}
+
+#[test]
+fn throttle_delay() {
+    let throttle = debug::Throttle {
+        delay: Duration::from_millis(5),
+        bandwidth: None,
+    };
+    assert!(throttle.is_active());
+    assert_eq!(
+        throttle.pause(1 << 20, Duration::ZERO),
+        Duration::from_millis(5)
+    );
+    assert!(!debug::Throttle::default().is_active());
+}
+
+#[test]
+fn throttle_bandwidth() {
+    let throttle = debug::Throttle {
+        delay: Duration::ZERO,
+        bandwidth: Some(1_000_000),
+    };
+    // 100 KB in at 1 MB/s takes 100ms.
+    assert_eq!(
+        throttle.pause(100_000, Duration::from_millis(40)),
+        Duration::from_millis(60)
+    );
+    // Slower than the cap already.
+    assert_eq!(
+        throttle.pause(100_000, Duration::from_millis(150)),
+        Duration::ZERO
+    );
+}
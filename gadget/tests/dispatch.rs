@@ -0,0 +1,1160 @@
+//! Drives `Function` with mocked control transfers, covering every request code.
+
+use gud_gadget::protocol::*;
+use gud_gadget::transport::mock::{MockOutcome, MockReceiver, MockSender, MockTransfer, Outcome};
+use gud_gadget::transport::{ControlRequest, ControlTransfer};
+use gud_gadget::{blit, Event, FramePacer, Function, ProtocolError};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+fn get(request: u8, length: u16) -> (MockTransfer, MockOutcome) {
+    let (sender, outcome) = MockSender::new(ControlRequest {
+        request,
+        length,
+        ..Default::default()
+    });
+    (ControlTransfer::DeviceToHost(sender), outcome)
+}
+
+fn set(request: u8, data: &[u8]) -> (MockTransfer, MockOutcome) {
+    let (receiver, outcome) = MockReceiver::new(
+        ControlRequest {
+            request,
+            ..Default::default()
+        },
+        data,
+    );
+    (ControlTransfer::HostToDevice(receiver), outcome)
+}
+
+fn status(function: &mut Function) -> u8 {
+    let (transfer, outcome) = get(GUD_REQ_GET_STATUS, 1);
+    assert!(function.control(transfer).unwrap().is_none());
+    outcome.data()[0]
+}
+
+fn connector_status(function: &mut Function) -> u8 {
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_STATUS, 1);
+    assert!(function.control(transfer).unwrap().is_none());
+    outcome.data()[0]
+}
+
+fn mode(width: u16, height: u16) -> DisplayMode {
+    DisplayMode {
+        clock: 1000,
+        hdisplay: width,
+        hsync_start: width,
+        hsync_end: width,
+        htotal: width,
+        vdisplay: height,
+        vsync_start: height,
+        vsync_end: height,
+        vtotal: height,
+        flags: 0,
+    }
+}
+
+// Answers the descriptor and formats requests like a host's probe would have them, which bounds
+// the states it can check.
+fn advertise(function: &mut Function) {
+    let (transfer, _) = get(GUD_REQ_GET_DESCRIPTOR, DisplayDescriptor::LEN as u16);
+    let Some(Event::GetDescriptor(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetDescriptor");
+    };
+    req.send_descriptor(1, 1, 1920, 1080).unwrap();
+    let (transfer, _) = get(GUD_REQ_GET_FORMATS, GUD_FORMATS_MAX_NUM as u16);
+    let Some(Event::GetPixelFormats(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetPixelFormats");
+    };
+    req.send_pixel_formats(&[GUD_PIXEL_FORMAT_RGB565, GUD_PIXEL_FORMAT_XRGB8888])
+        .unwrap();
+}
+
+fn commit(function: &mut Function, mode: DisplayMode) {
+    advertise(function);
+    let state = StateRequest {
+        mode,
+        format: GUD_PIXEL_FORMAT_RGB565,
+        connector: 0,
+        properties: vec![],
+    };
+    let (transfer, _) = set(GUD_REQ_SET_STATE_CHECK, &state.to_bytes());
+    function.control(transfer).unwrap();
+    let (transfer, _) = set(GUD_REQ_SET_STATE_COMMIT, &[]);
+    function.control(transfer).unwrap();
+}
+
+fn set_buffer(x: u32, y: u32, width: u32, height: u32) -> SetBuffer {
+    SetBuffer {
+        x,
+        y,
+        width,
+        height,
+        length: width * height * 2,
+        compression: 0,
+        compressed_length: 0,
+    }
+}
+
+#[test]
+fn get_status_defaults_to_ok() {
+    let mut function = Function::new();
+    assert_eq!(status(&mut function), GUD_STATUS_OK);
+}
+
+#[test]
+fn get_descriptor() {
+    let mut function = Function::new();
+    let (transfer, outcome) = get(GUD_REQ_GET_DESCRIPTOR, DisplayDescriptor::LEN as u16);
+    let Some(Event::GetDescriptor(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetDescriptor");
+    };
+    req.send_descriptor(320, 240, 1920, 1080).unwrap();
+
+    let descriptor = DisplayDescriptor::from_bytes(&outcome.data()).unwrap();
+    assert_eq!(descriptor.magic, GUD_DISPLAY_MAGIC);
+    assert_eq!(descriptor.min_width, 320);
+    assert_eq!(descriptor.min_height, 240);
+    assert_eq!(descriptor.max_width, 1920);
+    assert_eq!(descriptor.max_height, 1080);
+    assert_ne!(descriptor.flags & GUD_DISPLAY_FLAG_STATUS_ON_SET, 0);
+    assert_eq!(descriptor.compression, GUD_COMPRESSION_LZ4);
+}
+
+#[test]
+fn get_descriptor_without_compression() {
+    let mut function = Function::new();
+    function.set_compression(0);
+    let (transfer, outcome) = get(GUD_REQ_GET_DESCRIPTOR, DisplayDescriptor::LEN as u16);
+    let Some(Event::GetDescriptor(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetDescriptor");
+    };
+    req.send_descriptor(320, 240, 1920, 1080).unwrap();
+
+    let descriptor = DisplayDescriptor::from_bytes(&outcome.data()).unwrap();
+    assert_eq!(descriptor.compression, 0);
+}
+
+fn descriptor(function: &mut Function) -> DisplayDescriptor {
+    let (transfer, outcome) = get(GUD_REQ_GET_DESCRIPTOR, DisplayDescriptor::LEN as u16);
+    let Some(Event::GetDescriptor(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetDescriptor");
+    };
+    req.send_descriptor(320, 240, 1920, 1080).unwrap();
+    DisplayDescriptor::from_bytes(&outcome.data()).unwrap()
+}
+
+#[test]
+fn get_descriptor_version() {
+    let mut function = Function::new();
+    assert_eq!(descriptor(&mut function).version, GUD_PROTOCOL_VERSION);
+    function.set_version(2);
+    assert_eq!(descriptor(&mut function).version, 2);
+    assert_eq!(function.version(), 2);
+}
+
+#[test]
+fn set_version() {
+    let mut function = Function::new();
+    function.set_version_negotiation(true);
+    let (transfer, outcome) = set(GUD_REQ_SET_VERSION, &[2]);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(outcome.data(), [2]);
+    assert_eq!(status(&mut function), GUD_STATUS_OK);
+    assert_eq!(function.version(), 2);
+    // The descriptor still has the newest, for a host that probes again.
+    assert_eq!(descriptor(&mut function).version, GUD_PROTOCOL_VERSION);
+}
+
+#[test]
+fn set_version_halted_without_negotiation() {
+    let mut function = Function::new();
+    let (transfer, outcome) = set(GUD_REQ_SET_VERSION, &[1]);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(outcome.get(), Outcome::Halted);
+    assert_eq!(function.version(), GUD_PROTOCOL_VERSION);
+}
+
+#[test]
+fn set_version_out_of_range() {
+    let mut function = Function::new();
+    function.set_version_negotiation(true);
+    for version in [0, GUD_PROTOCOL_VERSION + 1] {
+        let (transfer, _) = set(GUD_REQ_SET_VERSION, &[version]);
+        let err = function.control(transfer).unwrap_err();
+        let Some(&ProtocolError::UnsupportedVersion { version: v, max }) =
+            err.downcast_ref::<ProtocolError>()
+        else {
+            panic!("expected UnsupportedVersion, got {err}");
+        };
+        assert_eq!((v, max), (version, GUD_PROTOCOL_VERSION));
+        assert_eq!(status(&mut function), GUD_STATUS_INVALID_PARAMETER);
+        assert_eq!(function.version(), GUD_PROTOCOL_VERSION);
+    }
+
+    let (transfer, _) = set(GUD_REQ_SET_VERSION, &[1, 0]);
+    let err = function.control(transfer).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::Malformed(_))
+    ));
+}
+
+fn versioned_function(version: u8) -> Function {
+    let mut function = Function::new();
+    function.set_version_negotiation(true);
+    function.set_properties(Properties::new().with(Property::rotation(GUD_ROTATION_90)));
+    function.set_connector_properties(
+        0,
+        Properties::new().with(Property::backlight_brightness(50)),
+    );
+    let (transfer, _) = set(GUD_REQ_SET_VERSION, &[version]);
+    function.control(transfer).unwrap();
+    assert_eq!(function.version(), version);
+    function
+}
+
+fn properties_len(function: &mut Function, request: u8) -> usize {
+    let (transfer, outcome) = get(request, 320);
+    function.control(transfer).unwrap();
+    outcome.data().len()
+}
+
+fn compressed_buffer(function: &mut Function) -> anyhow::Result<Option<Event<MockSender>>> {
+    commit(function, mode(64, 32));
+    let info = SetBuffer {
+        compression: GUD_COMPRESSION_LZ4,
+        compressed_length: 100,
+        ..set_buffer(0, 0, 64, 32)
+    };
+    let (transfer, _) = set(GUD_REQ_SET_BUFFER, &info.to_bytes());
+    function.control(transfer)
+}
+
+#[test]
+fn version_1_has_no_compression_or_properties() {
+    let mut function = versioned_function(1);
+    assert_eq!(descriptor(&mut function).compression, 0);
+    assert_eq!(properties_len(&mut function, GUD_REQ_GET_PROPERTIES), 0);
+    assert_eq!(
+        properties_len(&mut function, GUD_REQ_GET_CONNECTOR_PROPERTIES),
+        0
+    );
+    let err = compressed_buffer(&mut function).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::NotInVersion { version: 1, .. })
+    ));
+}
+
+#[test]
+fn version_2_has_compression_but_no_properties() {
+    let mut function = versioned_function(GUD_VERSION_COMPRESSION);
+    assert_eq!(descriptor(&mut function).compression, GUD_COMPRESSION_LZ4);
+    assert_eq!(properties_len(&mut function, GUD_REQ_GET_PROPERTIES), 0);
+    assert_eq!(
+        properties_len(&mut function, GUD_REQ_GET_CONNECTOR_PROPERTIES),
+        0
+    );
+    assert!(compressed_buffer(&mut function).unwrap().is_none());
+}
+
+#[test]
+fn newest_version_has_compression_and_properties() {
+    let mut function = versioned_function(GUD_VERSION_PROPERTIES);
+    assert_eq!(descriptor(&mut function).compression, GUD_COMPRESSION_LZ4);
+    assert_eq!(
+        properties_len(&mut function, GUD_REQ_GET_PROPERTIES),
+        Property::LEN
+    );
+    assert_eq!(
+        properties_len(&mut function, GUD_REQ_GET_CONNECTOR_PROPERTIES),
+        Property::LEN
+    );
+}
+
+#[test]
+fn get_formats() {
+    let mut function = Function::new();
+    let (transfer, outcome) = get(GUD_REQ_GET_FORMATS, GUD_FORMATS_MAX_NUM as u16);
+    let Some(Event::GetPixelFormats(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetPixelFormats");
+    };
+    req.send_pixel_formats(&[GUD_PIXEL_FORMAT_RGB565, GUD_PIXEL_FORMAT_XRGB8888])
+        .unwrap();
+    assert_eq!(
+        outcome.data(),
+        [GUD_PIXEL_FORMAT_RGB565, GUD_PIXEL_FORMAT_XRGB8888]
+    );
+}
+
+#[test]
+fn get_properties() {
+    let mut function = Function::new();
+    let (transfer, outcome) = get(GUD_REQ_GET_PROPERTIES, 320);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(outcome.data().len() % Property::LEN, 0);
+}
+
+#[test]
+fn get_properties_none_by_default() {
+    let mut function = Function::new();
+    let (transfer, outcome) = get(GUD_REQ_GET_PROPERTIES, 320);
+    function.control(transfer).unwrap();
+    assert!(outcome.data().is_empty());
+}
+
+#[test]
+fn get_properties_registered() {
+    let mut function = Function::new();
+    function.set_properties(
+        Properties::new().with(Property::rotation(GUD_ROTATION_90 | GUD_ROTATION_270)),
+    );
+    let (transfer, outcome) = get(GUD_REQ_GET_PROPERTIES, 320);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(
+        Property::from_bytes(&outcome.data()).unwrap(),
+        Property {
+            prop: GUD_PROPERTY_ROTATION,
+            val: GUD_ROTATION_0 | GUD_ROTATION_90 | GUD_ROTATION_270,
+        }
+    );
+    assert_eq!(outcome.data().len(), Property::LEN);
+}
+
+#[test]
+fn get_connectors() {
+    let mut function = Function::new();
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTORS, 160);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(
+        ConnectorDescriptor::from_bytes(&outcome.data()).unwrap(),
+        ConnectorDescriptor {
+            connector_type: GUD_CONNECTOR_TYPE_PANEL,
+            flags: 0,
+        }
+    );
+}
+
+#[test]
+fn get_connectors_with_type() {
+    let mut function = Function::new();
+    function.set_connector_type(0, GUD_CONNECTOR_TYPE_HDMI);
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTORS, 160);
+    assert!(function.control(transfer).unwrap().is_none());
+    let connector = ConnectorDescriptor::from_bytes(&outcome.data()).unwrap();
+    assert_eq!(connector.connector_type, GUD_CONNECTOR_TYPE_HDMI);
+}
+
+#[test]
+fn get_connectors_with_poll_status() {
+    let mut function = Function::new();
+    function.set_connector_flags(0, GUD_CONNECTOR_FLAGS_POLL_STATUS);
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTORS, 160);
+    assert!(function.control(transfer).unwrap().is_none());
+    let connector = ConnectorDescriptor::from_bytes(&outcome.data()).unwrap();
+    assert_eq!(connector.flags, GUD_CONNECTOR_FLAGS_POLL_STATUS);
+}
+
+#[test]
+fn get_connectors_multiple() {
+    let mut function = Function::new();
+    let hdmi = function.add_connector();
+    assert_eq!(hdmi, 1);
+    assert_eq!(function.connector_count(), 2);
+    function.set_connector_type(hdmi, GUD_CONNECTOR_TYPE_HDMI);
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTORS, 160);
+    assert!(function.control(transfer).unwrap().is_none());
+
+    let data = outcome.data();
+    assert_eq!(data.len(), 2 * ConnectorDescriptor::LEN);
+    let panel = ConnectorDescriptor::from_bytes(&data).unwrap();
+    assert_eq!(panel.connector_type, GUD_CONNECTOR_TYPE_PANEL);
+    let hdmi = ConnectorDescriptor::from_bytes(&data[ConnectorDescriptor::LEN..]).unwrap();
+    assert_eq!(hdmi.connector_type, GUD_CONNECTOR_TYPE_HDMI);
+}
+
+#[test]
+fn get_connector_properties() {
+    let mut function = Function::new();
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_PROPERTIES, 320);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(outcome.data().len() % Property::LEN, 0);
+}
+
+#[test]
+fn get_connector_properties_registered() {
+    let mut function = Function::new();
+    let properties = Properties::new()
+        .with(Property::backlight_brightness(80))
+        .with(Property {
+            prop: GUD_PROPERTY_TV_BRIGHTNESS,
+            val: 50,
+        });
+    function.set_connector_properties(0, properties.clone());
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_PROPERTIES, 320);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(outcome.data(), properties.to_bytes());
+    assert_eq!(outcome.data().len(), 2 * Property::LEN);
+}
+
+#[test]
+fn get_connector_status() {
+    let mut function = Function::new();
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_STATUS, 1);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(
+        outcome.data()[0] & GUD_CONNECTOR_STATUS_CONNECTED_MASK,
+        GUD_CONNECTOR_STATUS_CONNECTED
+    );
+}
+
+#[test]
+fn get_connector_status_set_by_application() {
+    let mut function = Function::new();
+    for status in [
+        ConnectorStatus::Disconnected,
+        ConnectorStatus::Unknown,
+        ConnectorStatus::Connected,
+    ] {
+        function.set_connector_status(0, status);
+        let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_STATUS, 1);
+        assert!(function.control(transfer).unwrap().is_none());
+        assert_eq!(
+            outcome.data()[0] & GUD_CONNECTOR_STATUS_CONNECTED_MASK,
+            u8::from(status)
+        );
+    }
+}
+
+#[test]
+fn get_connector_status_flags_changes_once() {
+    let mut function = Function::new();
+    let poll = |function: &mut Function| {
+        let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_STATUS, 1);
+        assert!(function.control(transfer).unwrap().is_none());
+        outcome.data()[0]
+    };
+    assert_eq!(poll(&mut function), GUD_CONNECTOR_STATUS_CONNECTED);
+
+    // Setting the same status isn't a change.
+    function.set_connector_status(0, ConnectorStatus::Connected);
+    assert_eq!(poll(&mut function), GUD_CONNECTOR_STATUS_CONNECTED);
+
+    let handle = function.connector(0).unwrap();
+    std::thread::spawn(move || handle.set_status(ConnectorStatus::Disconnected))
+        .join()
+        .unwrap();
+    assert_eq!(
+        poll(&mut function),
+        GUD_CONNECTOR_STATUS_DISCONNECTED | GUD_CONNECTOR_STATUS_CHANGED
+    );
+    assert_eq!(poll(&mut function), GUD_CONNECTOR_STATUS_DISCONNECTED);
+    assert_eq!(
+        function.connector_status(0),
+        Some(ConnectorStatus::Disconnected)
+    );
+}
+
+#[test]
+fn get_connector_status_of_missing_connector() {
+    let mut function = Function::new();
+    let (sender, outcome) = MockSender::new(ControlRequest {
+        request: GUD_REQ_GET_CONNECTOR_STATUS,
+        value: 1,
+        length: 1,
+        ..Default::default()
+    });
+    let transfer: MockTransfer = ControlTransfer::DeviceToHost(sender);
+    let err = function.control(transfer).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::NoConnector(1))
+    ));
+    assert!(!matches!(outcome.get(), Outcome::Data(_)));
+    assert_eq!(status(&mut function), GUD_STATUS_INVALID_PARAMETER);
+}
+
+#[test]
+fn get_connector_modes() {
+    let mut function = Function::new();
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_MODES, 128 * 24);
+    let Some(Event::GetDisplayModes(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetDisplayModes");
+    };
+    let modes = [mode(1920, 1080), mode(1280, 720)];
+    req.send_modes(&modes).unwrap();
+
+    let data = outcome.data();
+    assert_eq!(data.len(), 2 * DisplayMode::LEN);
+    assert_eq!(DisplayMode::from_bytes(&data).unwrap(), modes[0]);
+    assert_eq!(
+        DisplayMode::from_bytes(&data[DisplayMode::LEN..]).unwrap(),
+        modes[1]
+    );
+}
+
+#[test]
+fn get_connector_modes_overflow() {
+    let mut function = Function::new();
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_MODES, 3 * 24 - 1);
+    let Some(Event::GetDisplayModes(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetDisplayModes");
+    };
+    assert_eq!(req.max_modes(), 2);
+    let modes = [mode(1920, 1080), mode(1280, 720), mode(640, 480)];
+    let err = req.send_modes(&modes).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::TooMany {
+            count: 3,
+            max: 2,
+            ..
+        })
+    ));
+    assert!(!matches!(outcome.get(), Outcome::Data(_)));
+}
+
+#[test]
+fn get_connector_modes_truncated() {
+    let mut function = Function::new();
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_MODES, 2 * 24);
+    let Some(Event::GetDisplayModes(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetDisplayModes");
+    };
+    let modes = [mode(1920, 1080), mode(1280, 720), mode(640, 480)];
+    req.send_modes_truncated(&modes).unwrap();
+
+    let data = outcome.data();
+    assert_eq!(data.len(), 2 * DisplayMode::LEN);
+    assert_eq!(
+        DisplayMode::from_bytes(&data[DisplayMode::LEN..]).unwrap(),
+        modes[1]
+    );
+}
+
+#[test]
+fn get_formats_too_many() {
+    let mut function = Function::new();
+    let (transfer, outcome) = get(GUD_REQ_GET_FORMATS, 1);
+    let Some(Event::GetPixelFormats(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetPixelFormats");
+    };
+    assert_eq!(req.max_len(), 1);
+    let err = req
+        .send_pixel_formats(&[GUD_PIXEL_FORMAT_RGB565, GUD_PIXEL_FORMAT_XRGB8888])
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::TooMany {
+            count: 2,
+            max: 1,
+            ..
+        })
+    ));
+    assert!(!matches!(outcome.get(), Outcome::Data(_)));
+}
+
+#[test]
+fn request_accessors() {
+    let mut function = Function::new();
+    function.add_connector();
+    let request = ControlRequest {
+        request: GUD_REQ_GET_CONNECTOR_MODES,
+        value: 1,
+        index: 2,
+        length: 10 * 24,
+    };
+    let (sender, _) = MockSender::new(request);
+    let transfer: MockTransfer = ControlTransfer::DeviceToHost(sender);
+    let Some(Event::GetDisplayModes(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetDisplayModes");
+    };
+    assert_eq!(req.request(), request);
+    assert_eq!(req.connector(), 1);
+    assert_eq!(req.max_len(), 240);
+    assert_eq!(req.max_modes(), 10);
+}
+
+#[test]
+fn get_connector_modes_of_second_connector() {
+    let mut function = Function::new();
+    function.add_connector();
+    let (sender, _) = MockSender::new(ControlRequest {
+        request: GUD_REQ_GET_CONNECTOR_MODES,
+        value: 1,
+        length: 128 * 24,
+        ..Default::default()
+    });
+    let Some(Event::GetDisplayModes(req)) = function
+        .control(MockTransfer::DeviceToHost(sender))
+        .unwrap()
+    else {
+        panic!("expected GetDisplayModes");
+    };
+    assert_eq!(req.connector(), 1);
+}
+
+#[test]
+fn get_connector_modes_set_at_runtime() {
+    let mut function = Function::new();
+    let handle = function.connector(0).unwrap();
+    handle.set_modes(vec![mode(800, 600)]);
+
+    // The change is flagged once so the host re-probes, then answered without the application.
+    assert_eq!(
+        connector_status(&mut function),
+        GUD_CONNECTOR_STATUS_CONNECTED | GUD_CONNECTOR_STATUS_CHANGED
+    );
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_MODES, 128 * 24);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(
+        DisplayMode::from_bytes(&outcome.data()).unwrap(),
+        mode(800, 600)
+    );
+
+    // Setting the same modes again isn't a change.
+    handle.set_modes(vec![mode(800, 600)]);
+    assert_eq!(
+        connector_status(&mut function),
+        GUD_CONNECTOR_STATUS_CONNECTED
+    );
+}
+
+#[test]
+fn get_connector_edid() {
+    let mut function = Function::new();
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_EDID, 2048);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_ne!(outcome.get(), Outcome::Pending);
+}
+
+#[test]
+fn get_connector_edid_set_by_application() {
+    let mut function = Function::new();
+    let edid: Vec<u8> = (0..=255).collect();
+    let handle = function.connector(0).unwrap();
+    handle.set_edid(edid.clone());
+
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_EDID, 2048);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(outcome.data(), edid);
+
+    // A new EDID is flagged like a status change, so the host re-reads it.
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_STATUS, 1);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(
+        outcome.data()[0],
+        GUD_CONNECTOR_STATUS_CONNECTED | GUD_CONNECTOR_STATUS_CHANGED
+    );
+}
+
+#[test]
+fn set_connector_force_detect() {
+    let mut function = Function::new();
+    let (transfer, outcome) = set(GUD_REQ_SET_CONNECTOR_FORCE_DETECT, &[]);
+    assert!(matches!(
+        function.control(transfer).unwrap(),
+        Some(Event::ForceDetect { connector: 0 })
+    ));
+    assert_eq!(outcome.get(), Outcome::Data(vec![]));
+    assert_eq!(status(&mut function), GUD_STATUS_OK);
+}
+
+#[test]
+fn set_connector_force_detect_of_second_connector() {
+    let mut function = Function::new();
+    let request = ControlRequest {
+        request: GUD_REQ_SET_CONNECTOR_FORCE_DETECT,
+        value: 1,
+        ..Default::default()
+    };
+    let (receiver, _) = MockReceiver::new(request, &[]);
+    let transfer: MockTransfer = ControlTransfer::HostToDevice(receiver);
+    function.control(transfer).unwrap_err();
+    assert_eq!(status(&mut function), GUD_STATUS_INVALID_PARAMETER);
+
+    function.add_connector();
+    let (receiver, _) = MockReceiver::new(request, &[]);
+    let transfer: MockTransfer = ControlTransfer::HostToDevice(receiver);
+    assert!(matches!(
+        function.control(transfer).unwrap(),
+        Some(Event::ForceDetect { connector: 1 })
+    ));
+}
+
+#[test]
+fn set_controller_enable() {
+    let mut function = Function::new();
+    let (transfer, outcome) = set(GUD_REQ_SET_CONTROLLER_ENABLE, &[1]);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(outcome.get(), Outcome::Data(vec![1]));
+    assert_eq!(status(&mut function), GUD_STATUS_OK);
+}
+
+#[test]
+fn set_display_enable() {
+    let mut function = Function::new();
+    for enable in [true, false] {
+        let (transfer, outcome) = set(GUD_REQ_SET_DISPLAY_ENABLE, &[enable as u8]);
+        let Some(Event::DisplayEnable(enabled)) = function.control(transfer).unwrap() else {
+            panic!("expected DisplayEnable");
+        };
+        assert_eq!(enabled, enable);
+        assert_eq!(outcome.get(), Outcome::Data(vec![enable as u8]));
+        assert_eq!(status(&mut function), GUD_STATUS_OK);
+    }
+
+    let (transfer, _) = set(GUD_REQ_SET_DISPLAY_ENABLE, &[]);
+    function.control(transfer).unwrap_err();
+    assert_eq!(status(&mut function), GUD_STATUS_PROTOCOL_ERROR);
+}
+
+#[test]
+fn set_state_check_and_commit() {
+    let mut function = Function::new();
+    advertise(&mut function);
+    assert!(function.mode().is_none());
+
+    let state = StateRequest {
+        mode: mode(1920, 1080),
+        format: GUD_PIXEL_FORMAT_RGB565,
+        connector: 0,
+        properties: vec![],
+    };
+    let (transfer, _) = set(GUD_REQ_SET_STATE_CHECK, &state.to_bytes());
+    function.control(transfer).unwrap();
+    assert!(function.mode().is_none());
+    assert_eq!(status(&mut function), GUD_STATUS_OK);
+
+    let (transfer, _) = set(GUD_REQ_SET_STATE_COMMIT, &[]);
+    function.control(transfer).unwrap();
+    assert_eq!(function.mode(), Some(&state.mode));
+    assert_eq!(function.format(), Some(GUD_PIXEL_FORMAT_RGB565));
+    assert_eq!(status(&mut function), GUD_STATUS_OK);
+}
+
+#[test]
+fn last_state_saved_and_restored() {
+    let mut function = Function::new();
+    advertise(&mut function);
+    assert!(function.last_state().is_none());
+    let state = StateRequest {
+        mode: mode(1280, 720),
+        format: GUD_PIXEL_FORMAT_XRGB8888,
+        connector: 0,
+        properties: vec![Property::backlight_brightness(40)],
+    };
+    let (transfer, _) = set(GUD_REQ_SET_STATE_CHECK, &state.to_bytes());
+    function.control(transfer).unwrap();
+    let (transfer, _) = set(GUD_REQ_SET_STATE_COMMIT, &[]);
+    function.control(transfer).unwrap();
+    assert_eq!(function.last_state(), Some(&state));
+
+    let path = std::env::temp_dir().join(format!("gud-state-{}", std::process::id()));
+    function.save_state(&path).unwrap();
+    let mut restored = Function::new();
+    assert!(restored.load_state(&path).unwrap());
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(restored.last_state(), Some(&state));
+    // Only the host commits states.
+    assert!(restored.state().is_none());
+    assert!(!restored.load_state(&path).unwrap());
+}
+
+#[test]
+fn shutdown_disconnects_and_refuses_state() {
+    let mut function = Function::new();
+    commit(&mut function, mode(64, 32));
+    assert!(!function.is_shut_down());
+    function.shutdown();
+    assert!(function.is_shut_down());
+    assert_eq!(
+        connector_status(&mut function),
+        GUD_CONNECTOR_STATUS_DISCONNECTED | GUD_CONNECTOR_STATUS_CHANGED
+    );
+    assert!(function.state().is_none());
+    assert_eq!(function.last_state().unwrap().mode, mode(64, 32));
+
+    // Frames in flight are refused.
+    let (transfer, _) = set(GUD_REQ_SET_BUFFER, &set_buffer(0, 0, 64, 32).to_bytes());
+    let err = function.control(transfer).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::NoMode)
+    ));
+
+    // And so is bringing the display back.
+    let state = StateRequest {
+        mode: mode(64, 32),
+        format: GUD_PIXEL_FORMAT_RGB565,
+        connector: 0,
+        properties: vec![],
+    };
+    let (transfer, _) = set(GUD_REQ_SET_STATE_CHECK, &state.to_bytes());
+    let err = function.control(transfer).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::ShutDown)
+    ));
+    assert_eq!(status(&mut function), GUD_STATUS_ERROR);
+    let (transfer, _) = set(GUD_REQ_SET_STATE_COMMIT, &[]);
+    function.control(transfer).unwrap();
+    assert!(function.state().is_none());
+}
+
+#[test]
+fn shutdown_reported_once_connectors_polled() {
+    let mut function = Function::new();
+    function.add_connector();
+    function.set_connector_flags(0, GUD_CONNECTOR_FLAGS_POLL_STATUS);
+    function.set_connector_flags(1, GUD_CONNECTOR_FLAGS_POLL_STATUS);
+    // Already disconnected and polled, so there's nothing to tell the host about it.
+    function.set_connector_status(1, ConnectorStatus::Disconnected);
+    let (sender, _) = MockSender::new(ControlRequest {
+        request: GUD_REQ_GET_CONNECTOR_STATUS,
+        value: 1,
+        length: 1,
+        ..Default::default()
+    });
+    let transfer: MockTransfer = ControlTransfer::DeviceToHost(sender);
+    function.control(transfer).unwrap();
+    assert!(!function.connector(1).unwrap().is_changed());
+    assert!(!function.is_shutdown_reported());
+
+    function.shutdown();
+    assert!(!function.is_shutdown_reported());
+    assert_eq!(
+        connector_status(&mut function),
+        GUD_CONNECTOR_STATUS_DISCONNECTED | GUD_CONNECTOR_STATUS_CHANGED
+    );
+    assert!(function.is_shutdown_reported());
+}
+
+#[test]
+fn shutdown_reported_right_away_without_polled_connectors() {
+    let mut function = Function::new();
+    function.add_connector();
+    function.set_connector_flags(1, GUD_CONNECTOR_FLAGS_POLL_STATUS);
+    function.shutdown();
+    // Connector 0 is never polled, so only connector 1's status is waited for.
+    let (sender, _) = MockSender::new(ControlRequest {
+        request: GUD_REQ_GET_CONNECTOR_STATUS,
+        value: 1,
+        length: 1,
+        ..Default::default()
+    });
+    let transfer: MockTransfer = ControlTransfer::DeviceToHost(sender);
+    function.control(transfer).unwrap();
+    assert!(function.is_shutdown_reported());
+
+    // With the default flags no connector is, and there's nothing to wait for.
+    let mut function = Function::new();
+    assert!(!function.is_shutdown_reported());
+    function.shutdown();
+    assert!(function.is_shutdown_reported());
+}
+
+#[test]
+fn set_state_check_malformed() {
+    let mut function = Function::new();
+    let (transfer, _) = set(GUD_REQ_SET_STATE_CHECK, &[0; 3]);
+    let err = function.control(transfer).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::Malformed(_))
+    ));
+    assert_eq!(status(&mut function), GUD_STATUS_PROTOCOL_ERROR);
+}
+
+#[test]
+fn set_state_check_for_second_connector() {
+    let mut function = Function::new();
+    advertise(&mut function);
+    let state = StateRequest {
+        mode: mode(1920, 1080),
+        format: GUD_PIXEL_FORMAT_RGB565,
+        connector: 1,
+        properties: vec![],
+    };
+    let (transfer, _) = set(GUD_REQ_SET_STATE_CHECK, &state.to_bytes());
+    let err = function.control(transfer).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::NoConnector(1))
+    ));
+    assert_eq!(status(&mut function), GUD_STATUS_INVALID_PARAMETER);
+
+    function.add_connector();
+    let (transfer, _) = set(GUD_REQ_SET_STATE_CHECK, &state.to_bytes());
+    function.control(transfer).unwrap();
+    let (transfer, _) = set(GUD_REQ_SET_STATE_COMMIT, &[]);
+    function.control(transfer).unwrap();
+    assert_eq!(function.state().map(|state| state.connector), Some(1));
+}
+
+#[test]
+fn set_buffer_within_mode() {
+    let mut function = Function::new();
+    commit(&mut function, mode(64, 48));
+
+    let buffer = set_buffer(8, 8, 16, 16);
+    let (transfer, _) = set(GUD_REQ_SET_BUFFER, &buffer.to_bytes());
+    assert!(function.control(transfer).unwrap().is_none());
+    let (transfer, outcome) = get(GUD_REQ_GET_STATUS, 1);
+    let Some(Event::Buffer(info)) = function.control(transfer).unwrap() else {
+        panic!("expected Buffer");
+    };
+    assert_eq!(info, buffer);
+    assert_eq!(outcome.data(), [GUD_STATUS_OK]);
+}
+
+// The host reads the status of SET_BUFFER before it sends the payload, so the buffer has to
+// wait for that rather than have the application block on a payload that isn't coming.
+#[test]
+fn set_buffer_handed_on_after_status() {
+    let mut function = Function::new();
+    commit(&mut function, mode(4, 2));
+    let info = set_buffer(1, 0, 2, 2);
+    let payload: Vec<u8> = (1..=8).collect();
+
+    let (transfer, _) = set(GUD_REQ_SET_BUFFER, &info.to_bytes());
+    assert!(function.control(transfer).unwrap().is_none());
+
+    // The host only goes on to the bulk transfer once it's read an OK status.
+    let (transfer, outcome) = get(GUD_REQ_GET_STATUS, 1);
+    let event = function.control(transfer).unwrap();
+    assert_eq!(outcome.data(), [GUD_STATUS_OK]);
+    let Some(Event::Buffer(info)) = event else {
+        panic!("expected Buffer, got {:?}", event);
+    };
+    let mut fb = [0; 4 * 2 * 2];
+    blit::blit(&info, &payload, &mut fb, 4 * 2, 2).unwrap();
+    assert_eq!(fb, [0, 0, 1, 2, 3, 4, 0, 0, 0, 0, 5, 6, 7, 8, 0, 0]);
+
+    // Each buffer is handed on once.
+    assert!(function
+        .control(get(GUD_REQ_GET_STATUS, 1).0)
+        .unwrap()
+        .is_none());
+}
+
+#[test]
+fn set_buffer_rejected_is_not_handed_on() {
+    let mut function = Function::new();
+    commit(&mut function, mode(4, 2));
+    let (transfer, _) = set(GUD_REQ_SET_BUFFER, &set_buffer(0, 0, 8, 8).to_bytes());
+    assert!(function.control(transfer).is_err());
+    let (transfer, outcome) = get(GUD_REQ_GET_STATUS, 1);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(outcome.data(), [GUD_STATUS_INVALID_PARAMETER]);
+}
+
+#[test]
+fn set_buffer_dropped_without_status() {
+    let mut function = Function::new();
+    commit(&mut function, mode(4, 2));
+    let (transfer, _) = set(GUD_REQ_SET_BUFFER, &set_buffer(0, 0, 2, 2).to_bytes());
+    assert!(function.control(transfer).unwrap().is_none());
+    let (transfer, _) = set(GUD_REQ_SET_DISPLAY_ENABLE, &[1]);
+    function.control(transfer).unwrap();
+    assert!(function
+        .control(get(GUD_REQ_GET_STATUS, 1).0)
+        .unwrap()
+        .is_none());
+}
+
+// Records the connectors it's asked to wait for.
+#[derive(Debug, Default)]
+struct RecordingPacer(Rc<RefCell<Vec<usize>>>);
+
+impl FramePacer for RecordingPacer {
+    fn wait(&mut self, connector: usize) -> anyhow::Result<()> {
+        self.0.borrow_mut().push(connector);
+        Ok(())
+    }
+}
+
+#[test]
+fn set_buffer_waits_for_pacer() {
+    let waits = Rc::new(RefCell::new(Vec::new()));
+    let mut function = Function::new();
+    advertise(&mut function);
+    function.add_connector();
+    function.set_frame_pacer(Some(Box::new(RecordingPacer(waits.clone()))));
+    let state = StateRequest {
+        mode: mode(64, 48),
+        format: GUD_PIXEL_FORMAT_RGB565,
+        connector: 1,
+        properties: vec![],
+    };
+    let (transfer, _) = set(GUD_REQ_SET_STATE_CHECK, &state.to_bytes());
+    function.control(transfer).unwrap();
+    let (transfer, _) = set(GUD_REQ_SET_STATE_COMMIT, &[]);
+    function.control(transfer).unwrap();
+
+    let (transfer, _) = set(GUD_REQ_SET_BUFFER, &set_buffer(0, 0, 8, 8).to_bytes());
+    function.control(transfer).unwrap();
+    assert_eq!(*waits.borrow(), [1]);
+    let (transfer, _) = get(GUD_REQ_GET_STATUS, 1);
+    assert!(matches!(
+        function.control(transfer).unwrap(),
+        Some(Event::Buffer(_))
+    ));
+}
+
+#[test]
+fn set_buffer_out_of_bounds() {
+    let mut function = Function::new();
+    commit(&mut function, mode(64, 48));
+
+    for buffer in [
+        set_buffer(60, 0, 16, 16),
+        set_buffer(0, 40, 16, 16),
+        set_buffer(u32::MAX, 0, 2, 1),
+    ] {
+        let (transfer, _) = set(GUD_REQ_SET_BUFFER, &buffer.to_bytes());
+        let err = function.control(transfer).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::RectOutOfBounds { .. })
+        ));
+        assert_eq!(status(&mut function), GUD_STATUS_INVALID_PARAMETER);
+    }
+}
+
+#[test]
+fn set_buffer_lengths_checked_against_format() {
+    let mut function = Function::new();
+    commit(&mut function, mode(64, 48));
+
+    let short = SetBuffer {
+        length: 8 * 8 * 4,
+        ..set_buffer(0, 0, 8, 8)
+    };
+    let inflated = SetBuffer {
+        compression: GUD_COMPRESSION_LZ4,
+        compressed_length: 8 * 8 * 2 + 1,
+        ..set_buffer(0, 0, 8, 8)
+    };
+    for buffer in [short, inflated] {
+        let (transfer, _) = set(GUD_REQ_SET_BUFFER, &buffer.to_bytes());
+        let err = function.control(transfer).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::LengthMismatch { .. })
+        ));
+        // The host sees the error before it sends a payload, and none is expected.
+        assert_eq!(status(&mut function), GUD_STATUS_INVALID_PARAMETER);
+    }
+}
+
+#[test]
+fn set_buffer_compression_not_offered() {
+    let mut function = Function::new();
+    function.set_compression(0);
+    commit(&mut function, mode(64, 48));
+    let buffer = SetBuffer {
+        compression: GUD_COMPRESSION_LZ4,
+        compressed_length: 10,
+        ..set_buffer(0, 0, 8, 8)
+    };
+    let (transfer, _) = set(GUD_REQ_SET_BUFFER, &buffer.to_bytes());
+    let err = function.control(transfer).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::UnsupportedCompression(GUD_COMPRESSION_LZ4))
+    ));
+    assert_eq!(status(&mut function), GUD_STATUS_INVALID_PARAMETER);
+}
+
+#[test]
+fn set_state_check_within_advertised() {
+    let check = |function: &mut Function, mode, format| {
+        let state = StateRequest {
+            mode,
+            format,
+            connector: 0,
+            properties: vec![],
+        };
+        let (transfer, _) = set(GUD_REQ_SET_STATE_CHECK, &state.to_bytes());
+        function.control(transfer)
+    };
+
+    let mut function = Function::new();
+    let err = check(&mut function, mode(64, 48), GUD_PIXEL_FORMAT_RGB565).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::NotAdvertised(_))
+    ));
+
+    advertise(&mut function);
+    for mode in [mode(0, 48), mode(1921, 1080), mode(u16::MAX, u16::MAX)] {
+        let err = check(&mut function, mode, GUD_PIXEL_FORMAT_RGB565).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ProtocolError>(),
+            Some(ProtocolError::ModeOutOfRange { .. })
+        ));
+        assert_eq!(status(&mut function), GUD_STATUS_INVALID_PARAMETER);
+    }
+    let err = check(&mut function, mode(64, 48), GUD_PIXEL_FORMAT_R1).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::FormatNotAdvertised(GUD_PIXEL_FORMAT_R1))
+    ));
+
+    check(&mut function, mode(1920, 1080), GUD_PIXEL_FORMAT_XRGB8888).unwrap();
+    let (transfer, _) = set(GUD_REQ_SET_STATE_COMMIT, &[]);
+    function.control(transfer).unwrap();
+    assert_eq!(function.format(), Some(GUD_PIXEL_FORMAT_XRGB8888));
+}
+
+#[test]
+fn set_buffer_without_mode() {
+    let mut function = Function::new();
+    let (transfer, _) = set(GUD_REQ_SET_BUFFER, &set_buffer(0, 0, 1, 1).to_bytes());
+    let err = function.control(transfer).unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<ProtocolError>(),
+        Some(ProtocolError::NoMode)
+    ));
+    assert_eq!(status(&mut function), GUD_STATUS_INVALID_PARAMETER);
+}
+
+#[test]
+fn set_buffer_malformed() {
+    let mut function = Function::new();
+    commit(&mut function, mode(64, 48));
+    let (transfer, _) = set(GUD_REQ_SET_BUFFER, &[0; SetBuffer::LEN - 1]);
+    assert!(function.control(transfer).is_err());
+    assert_eq!(status(&mut function), GUD_STATUS_PROTOCOL_ERROR);
+}
+
+#[test]
+fn status_is_reset_by_next_request() {
+    let mut function = Function::new();
+    let (transfer, _) = set(GUD_REQ_SET_BUFFER, &[]);
+    assert!(function.control(transfer).is_err());
+    assert_eq!(status(&mut function), GUD_STATUS_PROTOCOL_ERROR);
+    // Reading the status doesn't clear it.
+    assert_eq!(status(&mut function), GUD_STATUS_PROTOCOL_ERROR);
+
+    let (transfer, _) = set(GUD_REQ_SET_DISPLAY_ENABLE, &[1]);
+    function.control(transfer).unwrap();
+    assert_eq!(status(&mut function), GUD_STATUS_OK);
+}
+
+#[test]
+fn unhandled_requests_are_halted() {
+    let mut function = Function::new();
+
+    let (transfer, outcome) = get(GUD_REQ_GET_CONNECTOR_TV_MODE_VALUES, 256);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(outcome.get(), Outcome::Halted);
+    assert_eq!(status(&mut function), GUD_STATUS_REQUEST_NOT_SUPPORTED);
+
+    let (transfer, outcome) = set(0x7f, &[]);
+    assert!(function.control(transfer).unwrap().is_none());
+    assert_eq!(outcome.get(), Outcome::Halted);
+    assert_eq!(status(&mut function), GUD_STATUS_REQUEST_NOT_SUPPORTED);
+}
@@ -0,0 +1,55 @@
+//! Taking display modes from the drm crate.
+#![cfg(feature = "drm")]
+
+use drm::control::Mode;
+use drm_ffi::drm_mode_modeinfo;
+use gud_gadget::protocol::ModeFlags;
+use gud_gadget::DisplayMode;
+
+// 1080i from CEA-861, as the kernel lists it for an HDMI sink.
+fn mode_1080i(type_: u32) -> Mode {
+    Mode::from(drm_mode_modeinfo {
+        clock: 74250,
+        hdisplay: 1920,
+        hsync_start: 2008,
+        hsync_end: 2052,
+        htotal: 2200,
+        vdisplay: 1080,
+        vsync_start: 1084,
+        vsync_end: 1094,
+        vtotal: 1125,
+        vrefresh: 60,
+        flags: drm_ffi::DRM_MODE_FLAG_PHSYNC
+            | drm_ffi::DRM_MODE_FLAG_PVSYNC
+            | drm_ffi::DRM_MODE_FLAG_INTERLACE,
+        type_,
+        ..Default::default()
+    })
+}
+
+#[test]
+fn from_drm_mode() {
+    let mode = DisplayMode::from(mode_1080i(drm_ffi::DRM_MODE_TYPE_DRIVER));
+    assert_eq!(
+        (
+            mode.clock,
+            mode.hdisplay,
+            mode.htotal,
+            mode.vdisplay,
+            mode.vtotal
+        ),
+        (74250, 1920, 2200, 1080, 1125)
+    );
+    assert_eq!(
+        mode.mode_flags(),
+        ModeFlags::PHSYNC | ModeFlags::PVSYNC | ModeFlags::INTERLACE
+    );
+}
+
+#[test]
+fn preferred_comes_from_the_mode_type() {
+    let mode = DisplayMode::from(mode_1080i(
+        drm_ffi::DRM_MODE_TYPE_DRIVER | drm_ffi::DRM_MODE_TYPE_PREFERRED,
+    ));
+    assert!(mode.mode_flags().contains(ModeFlags::PREFERRED));
+}
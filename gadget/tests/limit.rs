@@ -0,0 +1,73 @@
+//! Capping the presentation rate and merging the damage of frames received in between.
+
+use gud_gadget::{Damage, FrameLimiter, SetBuffer};
+use std::time::{Duration, Instant};
+
+fn info(x: u32, y: u32, width: u32, height: u32) -> SetBuffer {
+    SetBuffer {
+        x,
+        y,
+        width,
+        height,
+        length: width * height * 2,
+        compression: 0,
+        compressed_length: 0,
+    }
+}
+
+fn damage(x: u32, y: u32, width: u32, height: u32) -> Damage {
+    Damage {
+        x,
+        y,
+        width,
+        height,
+    }
+}
+
+#[test]
+fn damage_union_is_bounding_box() {
+    let union = damage(10, 20, 5, 5).union(damage(0, 30, 4, 10));
+    assert_eq!(union, damage(0, 20, 15, 20));
+    assert_eq!(union.union(damage(1, 21, 1, 1)), union);
+}
+
+#[test]
+fn uncapped_presents_every_frame() {
+    let mut limiter = FrameLimiter::new(None);
+    let now = Instant::now();
+    assert_eq!(limiter.deadline(), None);
+    assert_eq!(limiter.take_due(now), None);
+
+    limiter.add(&info(0, 0, 8, 8));
+    assert_eq!(limiter.take_due(now), Some((damage(0, 0, 8, 8), 1)));
+    limiter.add(&info(8, 0, 8, 8));
+    assert_eq!(limiter.take_due(now), Some((damage(8, 0, 8, 8), 1)));
+}
+
+#[test]
+fn capped_merges_frames_within_interval() {
+    let mut limiter = FrameLimiter::new(Some(10));
+    let start = Instant::now();
+    limiter.add(&info(0, 0, 8, 8));
+    // Nothing's been presented yet, so the first frame goes out right away.
+    assert_eq!(limiter.take_due(start), Some((damage(0, 0, 8, 8), 1)));
+
+    limiter.add(&info(0, 16, 8, 8));
+    limiter.add(&info(32, 0, 8, 4));
+    assert_eq!(limiter.deadline(), Some(start + Duration::from_millis(100)));
+    assert_eq!(limiter.take_due(start + Duration::from_millis(50)), None);
+    assert_eq!(
+        limiter.take_due(start + Duration::from_millis(100)),
+        Some((damage(0, 0, 40, 24), 2))
+    );
+    assert_eq!(limiter.deadline(), None);
+}
+
+#[test]
+fn clear_drops_pending_damage() {
+    let mut limiter = FrameLimiter::new(Some(30));
+    limiter.add(&info(0, 0, 8, 8));
+    limiter.clear();
+    assert_eq!(limiter.deadline(), None);
+    assert_eq!(limiter.take_due(Instant::now()), None);
+}
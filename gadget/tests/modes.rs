@@ -0,0 +1,86 @@
+//! Ordering and deduplicating the modes offered to the host.
+
+use gud_gadget::protocol::ModeFlags;
+use gud_gadget::{DisplayMode, ModeSet};
+
+fn mode(width: u16, height: u16, refresh: u32) -> DisplayMode {
+    let (htotal, vtotal) = (width + 100, height + 10);
+    DisplayMode {
+        clock: htotal as u32 * vtotal as u32 * refresh / 1000,
+        hdisplay: width,
+        hsync_start: width + 20,
+        hsync_end: width + 40,
+        htotal,
+        vdisplay: height,
+        vsync_start: height + 2,
+        vsync_end: height + 4,
+        vtotal,
+        flags: 0,
+    }
+}
+
+fn preferred(mut mode: DisplayMode) -> DisplayMode {
+    mode.set_mode_flags(mode.mode_flags() | ModeFlags::PREFERRED);
+    mode
+}
+
+fn sizes(modes: &ModeSet) -> Vec<(u16, u16, u32)> {
+    modes
+        .modes()
+        .iter()
+        .map(|mode| (mode.hdisplay, mode.vdisplay, mode.vrefresh()))
+        .collect()
+}
+
+#[test]
+fn preferred_first_then_largest() {
+    let modes: ModeSet = [
+        mode(640, 480, 60),
+        mode(1920, 1080, 30),
+        preferred(mode(1280, 720, 60)),
+        mode(1920, 1080, 60),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(
+        sizes(&modes),
+        [
+            (1280, 720, 60),
+            (1920, 1080, 60),
+            (1920, 1080, 30),
+            (640, 480, 60)
+        ]
+    );
+    assert_eq!(modes.preferred().unwrap().hdisplay, 1280);
+}
+
+#[test]
+fn duplicates_merged() {
+    let mut modes = ModeSet::new();
+    assert!(modes.insert(mode(800, 600, 60)));
+    assert!(modes.insert(mode(1024, 768, 60)));
+    assert!(!modes.insert(preferred(mode(800, 600, 60))));
+    assert_eq!(sizes(&modes), [(800, 600, 60), (1024, 768, 60)]);
+    assert!(modes.modes()[0].mode_flags().contains(ModeFlags::PREFERRED));
+}
+
+#[test]
+fn retain_and_set_preferred() {
+    let mut modes: ModeSet = [
+        preferred(mode(3840, 2160, 30)),
+        mode(1920, 1080, 60),
+        mode(1280, 720, 60),
+    ]
+    .into_iter()
+    .collect();
+    modes.retain(|mode| mode.hdisplay <= 1920);
+    assert!(!modes.set_preferred(|mode| mode.hdisplay == 3840));
+    assert!(modes.set_preferred(|mode| mode.hdisplay == 1280));
+    assert_eq!(sizes(&modes), [(1280, 720, 60), (1920, 1080, 60)]);
+    let preferred = modes
+        .modes()
+        .iter()
+        .filter(|mode| mode.mode_flags().contains(ModeFlags::PREFERRED))
+        .count();
+    assert_eq!(preferred, 1);
+}
@@ -0,0 +1,192 @@
+//! Round-trip tests of the GUD wire structures against byte fixtures laid out as in
+//! `include/drm/gud.h`.
+
+use gud_gadget::protocol::{
+    ConnectorDescriptor, DisplayDescriptor, DisplayMode, ModeFlags, Properties, Property,
+    SetBuffer, StateRequest, WireFormat, GUD_DISPLAY_MODE_FLAG_INTERLACE,
+    GUD_DISPLAY_MODE_FLAG_NHSYNC, GUD_DISPLAY_MODE_FLAG_PREFERRED, GUD_DISPLAY_MODE_FLAG_PVSYNC,
+    GUD_PROPERTY_BACKLIGHT_BRIGHTNESS, GUD_PROPERTY_ROTATION, GUD_PROPERTY_TV_HUE, GUD_ROTATION_0,
+    GUD_ROTATION_180,
+};
+
+// 1920x1080@60 (CEA-861 VIC 16), +hsync +vsync.
+const MODE_1080P: [u8; 24] = [
+    0x14, 0x44, 0x02, 0x00, // clock
+    0x80, 0x07, // hdisplay
+    0xd8, 0x07, // hsync_start
+    0x04, 0x08, // hsync_end
+    0x98, 0x08, // htotal
+    0x38, 0x04, // vdisplay
+    0x3c, 0x04, // vsync_start
+    0x41, 0x04, // vsync_end
+    0x65, 0x04, // vtotal
+    0x05, 0x00, 0x00, 0x00, // flags
+];
+
+fn mode_1080p() -> DisplayMode {
+    DisplayMode {
+        clock: 148500,
+        hdisplay: 1920,
+        hsync_start: 2008,
+        hsync_end: 2052,
+        htotal: 2200,
+        vdisplay: 1080,
+        vsync_start: 1084,
+        vsync_end: 1089,
+        vtotal: 1125,
+        flags: 0x05,
+    }
+}
+
+fn assert_round_trip<T: WireFormat + PartialEq + std::fmt::Debug>(value: T, fixture: &[u8]) {
+    assert_eq!(value.to_bytes(), fixture);
+    assert_eq!(T::from_bytes(fixture).unwrap(), value);
+}
+
+#[test]
+fn display_descriptor() {
+    let fixture = [
+        0x4d, 0x61, 0x50, 0x1d, // magic
+        0x01, // version
+        0x01, 0x00, 0x00, 0x00, // flags
+        0x01, // compression
+        0x00, 0x90, 0x7e, 0x00, // max_buffer_size
+        0x80, 0x02, 0x00, 0x00, // min_width
+        0x80, 0x07, 0x00, 0x00, // max_width
+        0xe0, 0x01, 0x00, 0x00, // min_height
+        0x38, 0x04, 0x00, 0x00, // max_height
+    ];
+    assert_eq!(fixture.len(), DisplayDescriptor::LEN);
+    assert_round_trip(
+        DisplayDescriptor {
+            magic: 0x1d50614d,
+            version: 1,
+            flags: 0x01,
+            compression: 0x01,
+            max_buffer_size: 1920 * 1080 * 4,
+            min_width: 640,
+            max_width: 1920,
+            min_height: 480,
+            max_height: 1080,
+        },
+        &fixture,
+    );
+}
+
+#[test]
+fn display_mode() {
+    assert_eq!(MODE_1080P.len(), DisplayMode::LEN);
+    assert_round_trip(mode_1080p(), &MODE_1080P);
+}
+
+#[test]
+fn connector_descriptor() {
+    let fixture = [0x07, 0x01, 0x00, 0x00, 0x00];
+    assert_eq!(fixture.len(), ConnectorDescriptor::LEN);
+    assert_round_trip(
+        ConnectorDescriptor {
+            connector_type: 7,
+            flags: 0x01,
+        },
+        &fixture,
+    );
+}
+
+#[test]
+fn set_buffer() {
+    let fixture = [
+        0x10, 0x00, 0x00, 0x00, // x
+        0x20, 0x00, 0x00, 0x00, // y
+        0x40, 0x00, 0x00, 0x00, // width
+        0x30, 0x00, 0x00, 0x00, // height
+        0x00, 0x18, 0x00, 0x00, // length
+        0x01, // compression
+        0xd2, 0x04, 0x00, 0x00, // compressed_length
+    ];
+    assert_eq!(fixture.len(), SetBuffer::LEN);
+    assert_round_trip(
+        SetBuffer {
+            x: 16,
+            y: 32,
+            width: 64,
+            height: 48,
+            length: 64 * 48 * 2,
+            compression: 1,
+            compressed_length: 1234,
+        },
+        &fixture,
+    );
+}
+
+#[test]
+fn state_request() {
+    let mut fixture = MODE_1080P.to_vec();
+    fixture.extend_from_slice(&[0x40, 0x00]); // format, connector
+    fixture.extend_from_slice(&[0x32, 0x00, 0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    fixture.extend_from_slice(&[0x0c, 0x00, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00]);
+    assert_round_trip(
+        StateRequest {
+            mode: mode_1080p(),
+            format: 0x40,
+            connector: 0,
+            properties: vec![
+                Property { prop: 50, val: 1 },
+                Property { prop: 12, val: 100 },
+            ],
+        },
+        &fixture,
+    );
+}
+
+#[test]
+fn state_request_without_properties() {
+    let mut fixture = MODE_1080P.to_vec();
+    fixture.extend_from_slice(&[0x80, 0x00]);
+    assert_eq!(fixture.len(), StateRequest::LEN);
+    assert_round_trip(
+        StateRequest {
+            mode: mode_1080p(),
+            format: 0x80,
+            connector: 0,
+            properties: vec![],
+        },
+        &fixture,
+    );
+}
+
+#[test]
+fn truncated() {
+    assert!(DisplayMode::from_bytes(&MODE_1080P[..DisplayMode::LEN - 1]).is_err());
+    assert!(SetBuffer::from_bytes(&[0; SetBuffer::LEN - 1]).is_err());
+    assert!(StateRequest::from_bytes(&MODE_1080P).is_err());
+}
+
+#[test]
+fn properties_replace_by_id() {
+    let properties = Properties::new()
+        .with(Property::backlight_brightness(20))
+        .with(Property::rotation(GUD_ROTATION_180))
+        .with(Property::backlight_brightness(120));
+    assert_eq!(properties.len(), 2);
+    assert_eq!(properties.get(GUD_PROPERTY_BACKLIGHT_BRIGHTNESS), Some(100));
+    assert_eq!(
+        properties.get(GUD_PROPERTY_ROTATION),
+        Some(GUD_ROTATION_0 | GUD_ROTATION_180)
+    );
+    assert_eq!(properties.get(GUD_PROPERTY_TV_HUE), None);
+    assert!(Properties::new().to_bytes().is_empty());
+}
+
+#[test]
+fn mode_flags() {
+    let mut mode = DisplayMode {
+        flags: GUD_DISPLAY_MODE_FLAG_NHSYNC | GUD_DISPLAY_MODE_FLAG_INTERLACE | 1 << 31,
+        ..Default::default()
+    };
+    assert_eq!(mode.mode_flags(), ModeFlags::NHSYNC | ModeFlags::INTERLACE);
+    mode.set_mode_flags(ModeFlags::PVSYNC | ModeFlags::PREFERRED);
+    assert_eq!(
+        mode.flags,
+        GUD_DISPLAY_MODE_FLAG_PVSYNC | GUD_DISPLAY_MODE_FLAG_PREFERRED
+    );
+}
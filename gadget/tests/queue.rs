@@ -0,0 +1,148 @@
+//! Queueing and coalescing frames that can't be presented right away.
+
+use bytes::Bytes;
+use gud_gadget::protocol::GUD_PIXEL_FORMAT_XRGB8888;
+use gud_gadget::{Coalesce, Frame, FrameQueue, SetBuffer};
+
+fn frame(x: u32, y: u32, width: u32, height: u32) -> Frame {
+    filled(x, y, width, height, 0)
+}
+
+// A frame whose pixels are all `value`.
+fn filled(x: u32, y: u32, width: u32, height: u32, value: u8) -> Frame {
+    let length = width * height * 4;
+    Frame {
+        info: SetBuffer {
+            x,
+            y,
+            width,
+            height,
+            length,
+            compression: 0,
+            compressed_length: 0,
+        },
+        format: GUD_PIXEL_FORMAT_XRGB8888,
+        data: Bytes::from(vec![value; length as usize]),
+    }
+}
+
+fn rects(queue: &mut FrameQueue) -> Vec<(u32, u32, u32, u32)> {
+    std::iter::from_fn(|| queue.pop())
+        .map(|frame| {
+            (
+                frame.info.x,
+                frame.info.y,
+                frame.info.width,
+                frame.info.height,
+            )
+        })
+        .collect()
+}
+
+#[test]
+fn process_all_keeps_every_frame() {
+    let mut queue = FrameQueue::new(Coalesce::ProcessAll);
+    queue.push(frame(0, 0, 8, 8));
+    queue.push(frame(0, 0, 8, 8));
+    assert_eq!(queue.len(), 2);
+    assert_eq!(rects(&mut queue), [(0, 0, 8, 8), (0, 0, 8, 8)]);
+}
+
+#[test]
+fn latest_wins_drops_covered_frames() {
+    let mut queue = FrameQueue::new(Coalesce::LatestWins);
+    queue.push(frame(2, 2, 2, 2));
+    // Only partly covered by what follows, so it's still needed.
+    queue.push(frame(6, 0, 4, 4));
+    queue.push(frame(0, 0, 8, 8));
+    assert_eq!(rects(&mut queue), [(6, 0, 4, 4), (0, 0, 8, 8)]);
+    assert!(queue.is_empty());
+}
+
+#[test]
+fn latest_wins_merges_strips() {
+    let mut queue = FrameQueue::new(Coalesce::LatestWins);
+    queue.push(filled(0, 0, 4, 2, 1));
+    queue.push(filled(0, 2, 4, 1, 2));
+    let frame = queue.pop().unwrap();
+    assert!(queue.is_empty());
+    assert_eq!(
+        (
+            frame.info.x,
+            frame.info.y,
+            frame.info.width,
+            frame.info.height
+        ),
+        (0, 0, 4, 3)
+    );
+    assert_eq!(frame.info.length as usize, frame.data.len());
+    let lines: Vec<u8> = frame.data.chunks_exact(16).map(|line| line[0]).collect();
+    assert_eq!(lines, [1, 1, 2]);
+}
+
+#[test]
+fn latest_wins_merges_into_covering_frame() {
+    let mut queue = FrameQueue::new(Coalesce::LatestWins);
+    queue.push(filled(0, 0, 4, 4, 1));
+    // Doesn't overlap the damage merged below, so it can stay behind it.
+    queue.push(filled(8, 0, 2, 2, 3));
+    queue.push(filled(1, 1, 2, 2, 2));
+    assert_eq!(queue.len(), 2);
+    let frame = queue.pop().unwrap();
+    assert_eq!(
+        (
+            frame.info.x,
+            frame.info.y,
+            frame.info.width,
+            frame.info.height
+        ),
+        (0, 0, 4, 4)
+    );
+    let pixels: Vec<u8> = frame.data.chunks_exact(4).map(|pixel| pixel[0]).collect();
+    assert_eq!(pixels, [1, 1, 1, 1, 1, 2, 2, 1, 1, 2, 2, 1, 1, 1, 1, 1]);
+    assert_eq!(rects(&mut queue), [(8, 0, 2, 2)]);
+}
+
+#[test]
+fn latest_wins_keeps_overlapped_frames_in_order() {
+    let mut queue = FrameQueue::new(Coalesce::LatestWins);
+    queue.push(frame(0, 0, 8, 8));
+    queue.push(frame(6, 6, 4, 4));
+    // Merging into the first frame would draw it under the second.
+    queue.push(frame(2, 2, 5, 5));
+    assert_eq!(
+        rects(&mut queue),
+        [(0, 0, 8, 8), (6, 6, 4, 4), (2, 2, 5, 5)]
+    );
+}
+
+#[test]
+fn full_queue_drops_oldest() {
+    let mut queue = FrameQueue::with_capacity(Coalesce::ProcessAll, 2);
+    assert!(queue.push(frame(0, 0, 1, 1)).is_none());
+    assert!(queue.push(frame(1, 0, 1, 1)).is_none());
+    let dropped = queue.push(frame(2, 0, 1, 1)).unwrap();
+    assert_eq!(dropped.info.x, 0);
+    assert_eq!(rects(&mut queue), [(1, 0, 1, 1), (2, 0, 1, 1)]);
+
+    queue.push(frame(0, 0, 1, 1));
+    queue.push(frame(1, 0, 1, 1));
+    queue.set_capacity(1);
+    assert_eq!(rects(&mut queue), [(1, 0, 1, 1)]);
+}
+
+#[test]
+fn drain_into_sink() {
+    let mut queue = FrameQueue::new(Coalesce::LatestWins);
+    queue.push(frame(0, 0, 1, 1));
+    queue.push(frame(2, 0, 1, 1));
+    let mut seen = Vec::new();
+    queue
+        .drain_into(&mut |frame: Frame| {
+            seen.push(frame.info.x);
+            Ok(())
+        })
+        .unwrap();
+    assert_eq!(seen, [0, 2]);
+    assert!(queue.is_empty());
+}
@@ -0,0 +1,46 @@
+//! Exporting the receive counters.
+
+use gud_gadget::Stats;
+
+#[test]
+fn compression_ratio() {
+    assert_eq!(Stats::default().compression_ratio(), 1.0);
+    let stats = Stats {
+        frames: 2,
+        bytes: 1000,
+        pixel_bytes: 4000,
+        ..Default::default()
+    };
+    assert_eq!(stats.compression_ratio(), 4.0);
+}
+
+#[test]
+fn prometheus_text_format() {
+    let stats = Stats {
+        frames: 3,
+        bytes: 100,
+        pixel_bytes: 250,
+        errors: 1,
+        presented: 2,
+        merged: 1,
+    };
+    let mut out = Vec::new();
+    stats.write_prometheus(&mut out).unwrap();
+    let text = String::from_utf8(out).unwrap();
+
+    let samples: Vec<&str> = text.lines().filter(|line| !line.starts_with('#')).collect();
+    assert_eq!(
+        samples,
+        [
+            "gud_frames_total 3",
+            "gud_received_bytes_total 100",
+            "gud_pixel_bytes_total 250",
+            "gud_errors_total 1",
+            "gud_presented_total 2",
+            "gud_merged_frames_total 1",
+            "gud_compression_ratio 2.5",
+        ]
+    );
+    assert!(text.contains("# TYPE gud_frames_total counter\n"));
+    assert!(text.contains("# TYPE gud_compression_ratio gauge\n"));
+}
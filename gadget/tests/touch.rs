@@ -0,0 +1,61 @@
+//! HID touch reports.
+#![cfg(feature = "gadget")]
+
+use gud_gadget::touch::{
+    report, report_descriptor, Contact, LOGICAL_MAX, MAX_CONTACTS, REPORT_LEN,
+};
+
+#[test]
+fn report_encodes_contacts() {
+    let report = report(&[
+        Contact { id: 0, x: 1, y: 2 },
+        Contact {
+            id: 3,
+            x: LOGICAL_MAX,
+            y: 0x1234,
+        },
+    ]);
+    assert_eq!(report[0], 1);
+    assert_eq!(report[1..7], [1, 0, 1, 0, 2, 0]);
+    assert_eq!(report[7..13], [1, 3, 0xff, 0x7f, 0x34, 0x12]);
+    // The remaining contacts are lifted.
+    assert!(report[13..REPORT_LEN - 1].iter().all(|&b| b == 0));
+    assert_eq!(report[REPORT_LEN - 1], 2);
+}
+
+#[test]
+fn report_drops_excess_contacts() {
+    let contacts = vec![Contact { id: 0, x: 0, y: 0 }; MAX_CONTACTS + 2];
+    assert_eq!(report(&contacts)[REPORT_LEN - 1], MAX_CONTACTS as u8);
+}
+
+#[test]
+fn report_descriptor_matches_report_len() {
+    // Sum the input bits, tracking Report Size and Report Count as they're set.
+    let desc = report_descriptor();
+    let (mut size, mut count, mut bits, mut depth) = (0u32, 0u32, 0u32, 0i32);
+    let mut i = 0;
+    while i < desc.len() {
+        let prefix = desc[i];
+        let len = match prefix & 0x03 {
+            3 => 4,
+            n => n as usize,
+        };
+        let data = desc[i + 1..i + 1 + len]
+            .iter()
+            .rev()
+            .fold(0u32, |acc, &b| acc << 8 | b as u32);
+        match prefix & 0xfc {
+            0x74 => size = data,
+            0x94 => count = data,
+            0x80 => bits += size * count,
+            0xa0 => depth += 1,
+            0xc0 => depth -= 1,
+            _ => {}
+        }
+        i += 1 + len;
+    }
+    assert_eq!(depth, 0);
+    // Everything but the report ID.
+    assert_eq!(bits as usize, (REPORT_LEN - 1) * 8);
+}
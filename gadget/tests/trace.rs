@@ -0,0 +1,260 @@
+//! Recording control transfers and replaying them through the dispatcher.
+#![cfg(feature = "trace")]
+
+use gud_gadget::protocol::*;
+use gud_gadget::trace::{self, Direction, Record, Tracer};
+use gud_gadget::transport::mock::{MockOutcome, MockReceiver, MockSender, MockTransfer, Outcome};
+use gud_gadget::transport::{ControlRequest, ControlTransfer};
+use gud_gadget::{Event, Function};
+use std::path::PathBuf;
+
+fn get(request: u8, length: u16) -> (MockTransfer, MockOutcome) {
+    let (sender, outcome) = MockSender::new(ControlRequest {
+        request,
+        length,
+        ..Default::default()
+    });
+    (ControlTransfer::DeviceToHost(sender), outcome)
+}
+
+fn set(request: u8, data: &[u8]) -> (MockTransfer, MockOutcome) {
+    let (receiver, outcome) = MockReceiver::new(
+        ControlRequest {
+            request,
+            ..Default::default()
+        },
+        data,
+    );
+    (ControlTransfer::HostToDevice(receiver), outcome)
+}
+
+fn trace_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("gud-trace-{}-{}", name, std::process::id()))
+}
+
+fn mode() -> DisplayMode {
+    DisplayMode {
+        clock: 1000,
+        hdisplay: 64,
+        hsync_start: 64,
+        hsync_end: 64,
+        htotal: 64,
+        vdisplay: 32,
+        vsync_start: 32,
+        vsync_end: 32,
+        vtotal: 32,
+        flags: 0,
+    }
+}
+
+// A short session: the host probes the display, sets a mode and sends a frame.
+fn session(function: &mut Function) {
+    let (transfer, _) = get(GUD_REQ_GET_DESCRIPTOR, DisplayDescriptor::LEN as u16);
+    let Some(Event::GetDescriptor(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetDescriptor");
+    };
+    req.send_descriptor(32, 16, 64, 32).unwrap();
+
+    let (transfer, _) = get(GUD_REQ_GET_FORMATS, GUD_FORMATS_MAX_NUM as u16);
+    let Some(Event::GetPixelFormats(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetPixelFormats");
+    };
+    req.send_pixel_formats(&[GUD_PIXEL_FORMAT_RGB565]).unwrap();
+
+    let (transfer, _) = get(GUD_REQ_GET_CONNECTORS, 64);
+    function.control(transfer).unwrap();
+
+    let (transfer, _) = get(GUD_REQ_GET_CONNECTOR_MODES, 240);
+    let Some(Event::GetDisplayModes(req)) = function.control(transfer).unwrap() else {
+        panic!("expected GetDisplayModes");
+    };
+    req.send_modes(&[mode()]).unwrap();
+
+    let state = StateRequest {
+        mode: mode(),
+        format: GUD_PIXEL_FORMAT_RGB565,
+        connector: 0,
+        properties: vec![],
+    };
+    let (transfer, _) = set(GUD_REQ_SET_STATE_CHECK, &state.to_bytes());
+    function.control(transfer).unwrap();
+    let (transfer, _) = set(GUD_REQ_SET_STATE_COMMIT, &[]);
+    function.control(transfer).unwrap();
+
+    let info = SetBuffer {
+        x: 0,
+        y: 0,
+        width: 64,
+        height: 32,
+        length: 64 * 32 * 2,
+        compression: 0,
+        compressed_length: 0,
+    };
+    let (transfer, _) = set(GUD_REQ_SET_BUFFER, &info.to_bytes());
+    function.control(transfer).unwrap();
+
+    // Not a GUD request, so it's halted.
+    let (transfer, _) = get(0x7f, 1);
+    function.control(transfer).unwrap();
+    let (transfer, _) = get(GUD_REQ_GET_STATUS, 1);
+    function.control(transfer).unwrap();
+}
+
+#[test]
+fn records_control_transfers() {
+    let path = trace_path("record");
+    let tracer = Tracer::create(&path, false).unwrap();
+    let mut function = Function::new();
+    function.set_tracer(Some(tracer));
+    session(&mut function);
+    drop(function);
+
+    let records = trace::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(records.len(), 9);
+    let Record::Control {
+        direction: Direction::DeviceToHost,
+        request,
+        outcome: Outcome::Data(data),
+    } = &records[0]
+    else {
+        panic!("expected the descriptor, got {:?}", records[0]);
+    };
+    assert_eq!(request.request, GUD_REQ_GET_DESCRIPTOR);
+    let descriptor = DisplayDescriptor::from_bytes(data).unwrap();
+    assert_eq!((descriptor.max_width, descriptor.max_height), (64, 32));
+
+    let Record::Control {
+        direction: Direction::HostToDevice,
+        request,
+        outcome: Outcome::Data(data),
+    } = &records[6]
+    else {
+        panic!("expected the buffer, got {:?}", records[6]);
+    };
+    assert_eq!(request.request, GUD_REQ_SET_BUFFER);
+    assert_eq!(SetBuffer::from_bytes(data).unwrap().width, 64);
+
+    assert!(matches!(
+        records[7],
+        Record::Control {
+            outcome: Outcome::Halted,
+            ..
+        }
+    ));
+    assert_eq!(
+        records[8],
+        Record::Control {
+            direction: Direction::DeviceToHost,
+            request: ControlRequest {
+                request: GUD_REQ_GET_STATUS,
+                length: 1,
+                ..Default::default()
+            },
+            outcome: Outcome::Data(vec![GUD_STATUS_REQUEST_NOT_SUPPORTED]),
+        }
+    );
+}
+
+#[test]
+fn replay_matches_recording() {
+    let path = trace_path("replay");
+    let tracer = Tracer::create(&path, false).unwrap();
+    let mut function = Function::new();
+    function.set_connector_type(0, GUD_CONNECTOR_TYPE_DISPLAYPORT);
+    function.set_compression(0);
+    function.set_tracer(Some(tracer));
+    session(&mut function);
+    drop(function);
+    let records = trace::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    // Set up differently, the descriptor and connectors give it away.
+    let mismatches = trace::replay(&mut Function::new(), &records);
+    assert_eq!(
+        mismatches
+            .iter()
+            .map(|mismatch| mismatch.request.request)
+            .collect::<Vec<_>>(),
+        [GUD_REQ_GET_DESCRIPTOR, GUD_REQ_GET_CONNECTORS]
+    );
+
+    let mut function = Function::new();
+    trace::configure(&mut function, &records);
+    assert_eq!(trace::replay(&mut function, &records), []);
+    assert_eq!(function.state().unwrap().mode, mode());
+}
+
+#[test]
+fn replay_reports_changed_replies() {
+    let mut function = Function::new();
+    let records = [
+        Record::Control {
+            direction: Direction::HostToDevice,
+            request: ControlRequest {
+                request: GUD_REQ_SET_STATE_CHECK,
+                length: 1,
+                ..Default::default()
+            },
+            outcome: Outcome::Data(vec![0]),
+        },
+        // The malformed state check failed, but it was recorded as if it hadn't.
+        Record::Control {
+            direction: Direction::DeviceToHost,
+            request: ControlRequest {
+                request: GUD_REQ_GET_STATUS,
+                length: 1,
+                ..Default::default()
+            },
+            outcome: Outcome::Data(vec![GUD_STATUS_OK]),
+        },
+    ];
+    let mismatches = trace::replay(&mut function, &records);
+    assert_eq!(mismatches.len(), 1);
+    assert_eq!(mismatches[0].index, 1);
+    assert_eq!(mismatches[0].recorded, Outcome::Data(vec![GUD_STATUS_OK]));
+    assert_ne!(mismatches[0].replayed, mismatches[0].recorded);
+}
+
+#[test]
+fn payload_checksums() {
+    let path = trace_path("payload");
+    let tracer = Tracer::create(&path, true).unwrap();
+    let info = SetBuffer {
+        x: 1,
+        y: 2,
+        width: 3,
+        height: 4,
+        length: 9,
+        compression: 0,
+        compressed_length: 0,
+    };
+    tracer.payload(&info, b"123456789");
+    drop(tracer);
+
+    let records = trace::load(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+    assert_eq!(
+        records,
+        [Record::Payload {
+            info,
+            checksum: Some(0xcbf4_3926),
+        }]
+    );
+}
+
+#[test]
+fn decode_stops_at_cut_short_record() {
+    let path = trace_path("cut");
+    let tracer = Tracer::create(&path, false).unwrap();
+    let mut function = Function::new();
+    function.set_tracer(Some(tracer));
+    session(&mut function);
+    drop(function);
+    let mut buf = std::fs::read(&path).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    buf.pop();
+    assert_eq!(trace::decode(&buf).unwrap().len(), 8);
+    assert!(trace::decode(b"GUDTRACX\x01").is_err());
+}
@@ -0,0 +1,35 @@
+//! Picking UDCs coming and going out of uevents.
+#![cfg(feature = "gadget")]
+
+use gud_gadget::udc::UdcEvent;
+
+fn uevent(action: &str, devpath: &str, subsystem: &str) -> Vec<u8> {
+    format!(
+        "{action}@{devpath}\0ACTION={action}\0DEVPATH={devpath}\0SUBSYSTEM={subsystem}\0SEQNUM=1\0"
+    )
+    .into_bytes()
+}
+
+#[test]
+fn udc_added_and_removed() {
+    let devpath = "/devices/platform/soc/fe980000.usb/udc/fe980000.usb";
+    assert_eq!(
+        UdcEvent::parse(&uevent("add", devpath, "udc")),
+        Some(UdcEvent::Added("fe980000.usb".into()))
+    );
+    assert_eq!(
+        UdcEvent::parse(&uevent("remove", devpath, "udc")),
+        Some(UdcEvent::Removed("fe980000.usb".into()))
+    );
+}
+
+#[test]
+fn other_uevents_ignored() {
+    let devpath = "/devices/platform/soc/fe980000.usb/udc/fe980000.usb";
+    assert_eq!(UdcEvent::parse(&uevent("change", devpath, "udc")), None);
+    assert_eq!(
+        UdcEvent::parse(&uevent("add", "/devices/virtual/drm/card0", "drm")),
+        None
+    );
+    assert_eq!(UdcEvent::parse(b"libudev\0garbage"), None);
+}
@@ -0,0 +1,45 @@
+//! Sleeping until there's something to do.
+#![cfg(feature = "gadget")]
+
+use gud_gadget::wait::Waiter;
+use std::io::Write;
+use std::os::fd::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::thread;
+use std::time::{Duration, Instant};
+
+#[test]
+fn times_out() {
+    let waiter = Waiter::new().unwrap();
+    let started = Instant::now();
+    assert!(!waiter.wait(Some(Duration::from_millis(20))).unwrap());
+    assert!(started.elapsed() >= Duration::from_millis(20));
+}
+
+#[test]
+fn woken_from_another_thread() {
+    let waiter = Waiter::new().unwrap();
+    let waker = waiter.waker();
+    let wake = thread::spawn(move || {
+        thread::sleep(Duration::from_millis(10));
+        waker.wake();
+    });
+    assert!(waiter.wait(None).unwrap());
+    wake.join().unwrap();
+    // The wake's used up.
+    assert!(!waiter.wait(Some(Duration::ZERO)).unwrap());
+
+    // Wakes before waiting aren't lost.
+    waiter.waker().wake();
+    assert!(waiter.wait(Some(Duration::from_secs(5))).unwrap());
+}
+
+#[test]
+fn watched_fd_readable() {
+    let (mut tx, rx) = UnixStream::pair().unwrap();
+    let mut waiter = Waiter::new().unwrap();
+    waiter.watch(rx.as_raw_fd());
+    assert!(!waiter.wait(Some(Duration::ZERO)).unwrap());
+    tx.write_all(b"x").unwrap();
+    assert!(waiter.wait(Some(Duration::from_secs(5))).unwrap());
+}
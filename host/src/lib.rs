@@ -0,0 +1,262 @@
+//! The host half of the GUD protocol, implemented in user space on top of libusb.
+//!
+//! This is mostly useful for exercising a gadget without the kernel's `gud` driver, e.g. in
+//! loopback tests or from machines that don't run Linux.
+
+use anyhow::{bail, Context};
+use gud_gadget::debug::checksum;
+use gud_gadget::protocol::*;
+use gud_gadget::{OPENMOKO_GUD_PRODUCT_ID, OPENMOKO_VENDOR_ID};
+use rusb::{Direction, GlobalContext, Recipient, RequestType, TransferType};
+use std::time::Duration;
+use tracing::debug;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// An opened GUD display.
+pub struct Display {
+    handle: rusb::DeviceHandle<GlobalContext>,
+    interface: u8,
+    endpoint: u8,
+    descriptor: DisplayDescriptor,
+}
+
+impl Display {
+    /// Opens the first attached GUD display.
+    pub fn open() -> anyhow::Result<Self> {
+        let device = rusb::devices()
+            .context("list USB devices")?
+            .iter()
+            .find(|device| {
+                device.device_descriptor().is_ok_and(|desc| {
+                    desc.vendor_id() == OPENMOKO_VENDOR_ID
+                        && desc.product_id() == OPENMOKO_GUD_PRODUCT_ID
+                })
+            })
+            .context("no GUD display found")?;
+        Self::from_device(device)
+    }
+
+    pub fn from_device(device: rusb::Device<GlobalContext>) -> anyhow::Result<Self> {
+        let config = device
+            .active_config_descriptor()
+            .context("get config descriptor")?;
+        let (interface, endpoint) = config
+            .interfaces()
+            .flat_map(|interface| interface.descriptors())
+            .filter(|desc| desc.class_code() == 0xff)
+            .find_map(|desc| {
+                desc.endpoint_descriptors()
+                    .find(|ep| {
+                        ep.direction() == Direction::Out && ep.transfer_type() == TransferType::Bulk
+                    })
+                    .map(|ep| (desc.interface_number(), ep.address()))
+            })
+            .context("no GUD interface found")?;
+
+        let handle = device.open().context("open device")?;
+        // Not supported on every platform, in which case there's no kernel driver to detach.
+        let _ = handle.set_auto_detach_kernel_driver(true);
+        handle
+            .claim_interface(interface)
+            .context("claim interface")?;
+
+        let mut display = Self {
+            handle,
+            interface,
+            endpoint,
+            descriptor: DisplayDescriptor::default(),
+        };
+
+        let mut buf = [0; DisplayDescriptor::LEN];
+        display.get(GUD_REQ_GET_DESCRIPTOR, 0, &mut buf)?;
+        let descriptor = DisplayDescriptor::from_bytes(&buf)?;
+        if descriptor.magic != GUD_DISPLAY_MAGIC {
+            bail!("bad display descriptor magic {:x}", descriptor.magic);
+        }
+        debug!("got display descriptor {:?}", descriptor);
+        display.descriptor = descriptor;
+
+        Ok(display)
+    }
+
+    pub fn descriptor(&self) -> &DisplayDescriptor {
+        &self.descriptor
+    }
+
+    /// Status of the last request.
+    pub fn status(&self) -> anyhow::Result<u8> {
+        let mut buf = [0];
+        self.read_control(GUD_REQ_GET_STATUS, 0, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn formats(&self) -> anyhow::Result<Vec<u8>> {
+        let mut buf = [0; GUD_FORMATS_MAX_NUM];
+        let len = self.get(GUD_REQ_GET_FORMATS, 0, &mut buf)?;
+        Ok(buf[..len].to_vec())
+    }
+
+    pub fn properties(&self) -> anyhow::Result<Vec<Property>> {
+        let mut buf = [0; Property::LEN * GUD_PROPERTIES_MAX_NUM];
+        let len = self.get(GUD_REQ_GET_PROPERTIES, 0, &mut buf)?;
+        Ok(decode_all(&buf[..len]))
+    }
+
+    pub fn connectors(&self) -> anyhow::Result<Vec<ConnectorDescriptor>> {
+        let mut buf = [0; ConnectorDescriptor::LEN * GUD_CONNECTORS_MAX_NUM];
+        let len = self.get(GUD_REQ_GET_CONNECTORS, 0, &mut buf)?;
+        Ok(decode_all(&buf[..len]))
+    }
+
+    pub fn connector_properties(&self, connector: u16) -> anyhow::Result<Vec<Property>> {
+        let mut buf = [0; Property::LEN * GUD_CONNECTOR_PROPERTIES_MAX_NUM];
+        let len = self.get(GUD_REQ_GET_CONNECTOR_PROPERTIES, connector, &mut buf)?;
+        Ok(decode_all(&buf[..len]))
+    }
+
+    pub fn connector_status(&self, connector: u16) -> anyhow::Result<u8> {
+        let mut buf = [0];
+        self.get(GUD_REQ_GET_CONNECTOR_STATUS, connector, &mut buf)?;
+        Ok(buf[0])
+    }
+
+    pub fn modes(&self, connector: u16) -> anyhow::Result<Vec<DisplayMode>> {
+        let mut buf = vec![0; DisplayMode::LEN * GUD_CONNECTOR_MAX_NUM_MODES];
+        let len = self.get(GUD_REQ_GET_CONNECTOR_MODES, connector, &mut buf)?;
+        Ok(decode_all(&buf[..len]))
+    }
+
+    pub fn edid(&self, connector: u16) -> anyhow::Result<Vec<u8>> {
+        let mut buf = vec![0; GUD_CONNECTOR_MAX_EDID_LEN];
+        let len = self.get(GUD_REQ_GET_CONNECTOR_EDID, connector, &mut buf)?;
+        buf.truncate(len);
+        Ok(buf)
+    }
+
+    pub fn check_state(&self, state: &StateRequest) -> anyhow::Result<()> {
+        self.set(GUD_REQ_SET_STATE_CHECK, 0, &state.to_bytes())
+    }
+
+    pub fn commit_state(&self) -> anyhow::Result<()> {
+        self.set(GUD_REQ_SET_STATE_COMMIT, 0, &[])
+    }
+
+    pub fn set_controller_enable(&self, enable: bool) -> anyhow::Result<()> {
+        self.set(GUD_REQ_SET_CONTROLLER_ENABLE, 0, &[enable as u8])
+    }
+
+    pub fn set_display_enable(&self, enable: bool) -> anyhow::Result<()> {
+        self.set(GUD_REQ_SET_DISPLAY_ENABLE, 0, &[enable as u8])
+    }
+
+    /// Selects the protocol version to speak, from 1 to the descriptor's. `GUD_REQ_SET_VERSION`
+    /// isn't part of the kernel's protocol: displays that don't know it, or haven't enabled it,
+    /// fail this and speak the descriptor's version.
+    pub fn select_version(&self, version: u8) -> anyhow::Result<()> {
+        self.set(GUD_REQ_SET_VERSION, 0, &[version])
+    }
+
+    /// Sends the pixel data for a damaged rect, LZ4 compressed if the display supports it.
+    pub fn flush(
+        &self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        data: &[u8],
+    ) -> anyhow::Result<()> {
+        if data.len() > self.descriptor.max_buffer_size as usize {
+            bail!(
+                "buffer of {} bytes exceeds max buffer size {}",
+                data.len(),
+                self.descriptor.max_buffer_size
+            );
+        }
+
+        let compressed = if self.descriptor.compression & GUD_COMPRESSION_LZ4 != 0 {
+            lz4::block::compress(data, None, false)
+                .ok()
+                .filter(|compressed| compressed.len() < data.len())
+        } else {
+            None
+        };
+
+        let req = SetBuffer {
+            x,
+            y,
+            width,
+            height,
+            length: data.len() as u32,
+            compression: compressed.as_ref().map_or(0, |_| GUD_COMPRESSION_LZ4),
+            compressed_length: compressed.as_ref().map_or(0, |c| c.len() as u32),
+        };
+        self.set(GUD_REQ_SET_BUFFER, 0, &req.to_bytes())?;
+
+        let mut payload = compressed.as_deref().unwrap_or(data);
+        let crc = checksum(payload);
+        while !payload.is_empty() {
+            let sent = self
+                .handle
+                .write_bulk(self.endpoint, payload, TIMEOUT)
+                .context("write bulk")?;
+            payload = &payload[sent..];
+        }
+        debug!("flushed {:?} crc32={:#010x}", req, crc);
+        Ok(())
+    }
+
+    fn read_control(&self, request: u8, value: u16, buf: &mut [u8]) -> rusb::Result<usize> {
+        let request_type =
+            rusb::request_type(Direction::In, RequestType::Vendor, Recipient::Interface);
+        self.handle.read_control(
+            request_type,
+            request,
+            value,
+            self.interface as u16,
+            buf,
+            TIMEOUT,
+        )
+    }
+
+    fn get(&self, request: u8, value: u16, buf: &mut [u8]) -> anyhow::Result<usize> {
+        match self.read_control(request, value, buf) {
+            Ok(len) => Ok(len),
+            Err(rusb::Error::Pipe) => bail!(
+                "request {:x} stalled with status {}",
+                request,
+                self.status()?
+            ),
+            Err(err) => Err(err).with_context(|| format!("request {:x}", request)),
+        }
+    }
+
+    fn set(&self, request: u8, value: u16, data: &[u8]) -> anyhow::Result<()> {
+        let request_type =
+            rusb::request_type(Direction::Out, RequestType::Vendor, Recipient::Interface);
+        let result = self.handle.write_control(
+            request_type,
+            request,
+            value,
+            self.interface as u16,
+            data,
+            TIMEOUT,
+        );
+        match result {
+            Ok(_) if self.descriptor.flags & GUD_DISPLAY_FLAG_STATUS_ON_SET == 0 => Ok(()),
+            Ok(_) | Err(rusb::Error::Pipe) => match self.status()? {
+                GUD_STATUS_OK => Ok(()),
+                status => bail!("request {:x} failed with status {}", request, status),
+            },
+            Err(err) => Err(err).with_context(|| format!("request {:x}", request)),
+        }
+    }
+}
+
+fn decode_all<T: WireFormat>(mut buf: &[u8]) -> Vec<T> {
+    let mut items = Vec::with_capacity(buf.len() / T::LEN);
+    while buf.len() >= T::LEN {
+        items.push(T::decode(&mut buf));
+    }
+    items
+}
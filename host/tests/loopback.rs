@@ -0,0 +1,117 @@
+//! End-to-end test of the host crate against the gadget over a loopback UDC.
+//!
+//! Needs root and a UDC that's wired back to this machine, e.g. `modprobe dummy_hcd`, then run
+//! with `cargo test -p gud-host -- --ignored`.
+
+use gud_gadget::protocol::{StateRequest, GUD_PIXEL_FORMAT_RGB565};
+use gud_gadget::{DisplayMode, Event, Function, PixelDataEndpoint, OPENMOKO_GUD_ID};
+use gud_host::Display;
+use std::thread;
+use std::time::{Duration, Instant};
+use usb_gadget::function::custom::{Custom, Interface};
+use usb_gadget::{default_udc, Class, Config, Gadget, Strings};
+
+const WIDTH: u16 = 64;
+const HEIGHT: u16 = 48;
+
+#[test]
+#[ignore]
+fn loopback() {
+    usb_gadget::remove_all().expect("remove gadgets");
+    let udc = default_udc().expect("no UDC found");
+
+    let (mut data, data_ep) = PixelDataEndpoint::new();
+    let (mut custom, handle) = Custom::builder()
+        .with_interface(
+            Interface::new(Class::vendor_specific(Class::VENDOR_SPECIFIC, 0), "GUD")
+                .with_endpoint(data_ep),
+        )
+        .build();
+    let reg = Gadget::new(
+        Class::interface_specific(),
+        OPENMOKO_GUD_ID,
+        Strings::new("gud-host", "loopback", ""),
+    )
+    .with_config(Config::new("gud").with_function(handle))
+    .bind(&udc)
+    .expect("bind gadget");
+
+    let mode = DisplayMode {
+        clock: 1000,
+        hdisplay: WIDTH,
+        hsync_start: WIDTH,
+        hsync_end: WIDTH,
+        htotal: WIDTH,
+        vdisplay: HEIGHT,
+        vsync_start: HEIGHT,
+        vsync_end: HEIGHT,
+        vtotal: HEIGHT,
+        flags: 0,
+    };
+
+    let gadget_mode = mode.clone();
+    let gadget = thread::spawn(move || {
+        let (width, height) = (WIDTH as u32, HEIGHT as u32);
+        let pitch = WIDTH as usize * 2;
+        let mut fb = vec![0u8; pitch * HEIGHT as usize];
+        let mut function = Function::new();
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while Instant::now() < deadline {
+            let Some(event) = custom
+                .event_timeout(Duration::from_millis(100))
+                .expect("read GUD event")
+            else {
+                continue;
+            };
+            match function.event(event).expect("GUD request") {
+                Some(Event::GetDescriptor(req)) => {
+                    req.send_descriptor(width, height, width, height).unwrap()
+                }
+                Some(Event::GetPixelFormats(req)) => {
+                    req.send_pixel_formats(&[GUD_PIXEL_FORMAT_RGB565]).unwrap()
+                }
+                Some(Event::GetDisplayModes(req)) => {
+                    req.send_modes(std::slice::from_ref(&gadget_mode)).unwrap()
+                }
+                Some(Event::Buffer(info)) => {
+                    data.recv_buffer(info, &mut fb, pitch, 2).unwrap();
+                    return fb;
+                }
+                _ => {}
+            }
+        }
+        panic!("no buffer received");
+    });
+
+    let deadline = Instant::now() + Duration::from_secs(5);
+    let display = loop {
+        match Display::open() {
+            Ok(display) => break display,
+            Err(_) if Instant::now() < deadline => thread::sleep(Duration::from_millis(100)),
+            Err(err) => panic!("open display: {:#}", err),
+        }
+    };
+
+    assert_eq!(display.formats().unwrap(), [GUD_PIXEL_FORMAT_RGB565]);
+    assert_eq!(display.modes(0).unwrap(), std::slice::from_ref(&mode));
+
+    display
+        .check_state(&StateRequest {
+            mode,
+            format: GUD_PIXEL_FORMAT_RGB565,
+            connector: 0,
+            properties: vec![],
+        })
+        .unwrap();
+    display.commit_state().unwrap();
+
+    let pattern: Vec<u8> = (0..WIDTH as usize * HEIGHT as usize * 2)
+        .map(|i| (i % 251) as u8)
+        .collect();
+    display
+        .flush(0, 0, WIDTH as u32, HEIGHT as u32, &pattern)
+        .unwrap();
+
+    assert_eq!(gadget.join().unwrap(), pattern);
+    reg.remove().expect("remove gadget");
+}
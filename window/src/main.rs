@@ -0,0 +1,250 @@
+//! A "fake USB monitor": the GUD display is shown in a desktop window instead of on a panel, so
+//! host compositors can be tested against `dummy_hcd` or a devboard without display hardware.
+
+use anyhow::Context;
+use clap::Parser;
+use gud_gadget::protocol::*;
+use gud_gadget::{DisplayMode, Event, Function};
+use minifb::{Key, KeyRepeat, ScaleMode, Window, WindowOptions};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+use tracing_subscriber::{fmt, prelude::*, EnvFilter};
+use usb_gadget::function::custom::{Custom, Interface};
+use usb_gadget::{default_udc, Class, Config, Gadget, Id, Strings};
+
+/// Show a Generic USB Display gadget in a window.
+///
+/// Press D to unplug and replug the display.
+#[derive(Debug, Parser)]
+struct Args {
+    /// A mode offered to the host, as `WIDTHxHEIGHT`. Repeat it to offer several, the window
+    /// opens at the first.
+    #[arg(long, default_values = ["1280x720", "1920x1080", "800x600"])]
+    mode: Vec<Size>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Size {
+    width: u16,
+    height: u16,
+}
+
+impl FromStr for Size {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (width, height) = s.split_once('x').context("expected WIDTHxHEIGHT")?;
+        Ok(Size {
+            width: width.parse().context("invalid width")?,
+            height: height.parse().context("invalid height")?,
+        })
+    }
+}
+
+impl Size {
+    /// A 60Hz mode with made up blanking, there's no real signal to time.
+    fn mode(self) -> DisplayMode {
+        let (width, height) = (self.width, self.height);
+        let (htotal, vtotal) = (width + 160, height + 30);
+        DisplayMode {
+            clock: htotal as u32 * vtotal as u32 * 60 / 1000,
+            hdisplay: width,
+            hsync_start: width + 48,
+            hsync_end: width + 80,
+            htotal,
+            vdisplay: height,
+            vsync_start: height + 3,
+            vsync_end: height + 8,
+            vtotal,
+            flags: 0,
+        }
+    }
+}
+
+/// What's shown in the window, in minifb's 0RGB.
+struct Screen {
+    width: usize,
+    height: usize,
+    // The host's frames in XRGB8888, kept across partial updates.
+    fb: Vec<u8>,
+    pixels: Vec<u32>,
+}
+
+impl Screen {
+    fn new(width: usize, height: usize) -> Self {
+        Self {
+            width,
+            height,
+            fb: vec![0; width * height * 4],
+            pixels: vec![0; width * height],
+        }
+    }
+
+    /// Resizes to the host's mode, dropping the contents if it changed.
+    fn resize(&mut self, mode: &DisplayMode) {
+        let (width, height) = (mode.hdisplay.into(), mode.vdisplay.into());
+        if (width, height) != (self.width, self.height) {
+            *self = Self::new(width, height);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.fb.fill(0);
+        self.pixels.fill(0);
+    }
+
+    fn present(&mut self, window: &mut Window) -> anyhow::Result<()> {
+        for (pixel, bytes) in self.pixels.iter_mut().zip(self.fb.chunks_exact(4)) {
+            *pixel = u32::from_le_bytes(bytes.try_into().unwrap()) & 0xff_ffff;
+        }
+        window
+            .update_with_buffer(&self.pixels, self.width, self.height)
+            .context("update window")
+    }
+}
+
+fn main() -> anyhow::Result<()> {
+    tracing_subscriber::registry()
+        .with(fmt::layer())
+        .with(EnvFilter::from_default_env())
+        .init();
+
+    let args = Args::parse();
+    let modes: Vec<DisplayMode> = args.mode.iter().map(|size| size.mode()).collect();
+    let first = args.mode[0];
+    let mut window = Window::new(
+        "GUD",
+        first.width.into(),
+        first.height.into(),
+        WindowOptions {
+            resize: true,
+            scale_mode: ScaleMode::AspectRatioStretch,
+            ..Default::default()
+        },
+    )
+    .context("open window")?;
+    let mut screen = Screen::new(first.width.into(), first.height.into());
+    screen.present(&mut window)?;
+
+    let udc = default_udc().expect("no UDC found");
+    usb_gadget::remove_all().expect("UDC init failed");
+
+    let (mut gud_data, gud_data_ep) = gud_gadget::PixelDataEndpoint::new();
+    gud_data.set_convert_to(Some(PixelFormat::Xrgb8888));
+    let (mut gud, gud_handle) = Custom::builder()
+        .with_interface(
+            Interface::new(Class::vendor_specific(Class::VENDOR_SPECIFIC, 0), "GUD")
+                .with_endpoint(gud_data_ep),
+        )
+        .build();
+
+    let _reg = Gadget::new(
+        Class::interface_specific(),
+        Id::new(
+            gud_gadget::OPENMOKO_VENDOR_ID,
+            gud_gadget::OPENMOKO_GUD_PRODUCT_ID,
+        ),
+        Strings::new("The Internet", "Generic USB Display", ""),
+    )
+    .with_config(Config::new("gud").with_function(gud_handle))
+    .bind(&udc)
+    .expect("UDC binding failed");
+
+    let running = Arc::new(AtomicBool::new(true));
+
+    let r = running.clone();
+    ctrlc::set_handler(move || {
+        r.store(false, Ordering::SeqCst);
+    })
+    .expect("cleanup handler registration failed");
+
+    let mut function = Function::new();
+    function.set_connector_type(0, GUD_CONNECTOR_TYPE_HDMI);
+    // So the host notices the display being unplugged with D.
+    function.set_connector_flags(0, GUD_CONNECTOR_FLAGS_POLL_STATUS);
+    let connector = function.connector(0).unwrap();
+
+    while running.load(Ordering::Relaxed) && window.is_open() {
+        if window.is_key_pressed(Key::D, KeyRepeat::No) {
+            let status = match connector.status() {
+                ConnectorStatus::Connected => ConnectorStatus::Disconnected,
+                _ => ConnectorStatus::Connected,
+            };
+            info!("display {:?}", status);
+            connector.set_status(status);
+        }
+
+        // Short enough to keep the window responsive.
+        let event = gud
+            .event_timeout(Duration::from_millis(16))
+            .expect("read GUD event");
+        let Some(event) = event else {
+            window.update();
+            continue;
+        };
+
+        let gud_event = match function.event(event) {
+            Ok(Some(gud_event)) => gud_event,
+            Ok(None) => continue,
+            Err(err) => {
+                warn!("GUD request failed: {:#}", err);
+                continue;
+            }
+        };
+
+        match gud_event {
+            Event::GetDescriptor(req) => {
+                let widths = || modes.iter().map(|mode| mode.hdisplay as u32);
+                let heights = || modes.iter().map(|mode| mode.vdisplay as u32);
+                req.send_descriptor(
+                    widths().min().unwrap(),
+                    heights().min().unwrap(),
+                    widths().max().unwrap(),
+                    heights().max().unwrap(),
+                )
+                .expect("failed to send descriptor");
+            }
+            Event::GetPixelFormats(req) => req
+                .send_pixel_formats(&[GUD_PIXEL_FORMAT_XRGB8888, GUD_PIXEL_FORMAT_RGB565])
+                .unwrap(),
+            Event::GetDisplayModes(req) => {
+                // Nothing to show while it's unplugged.
+                let modes = match connector.status() {
+                    ConnectorStatus::Disconnected => &[][..],
+                    _ => &modes[..],
+                };
+                req.send_modes(modes).expect("failed to send modes");
+            }
+            Event::Buffer(info) => {
+                // The dispatcher only emits buffers once a state is committed.
+                let state = function.state().expect("no committed state");
+                screen.resize(&state.mode);
+                let pitch = screen.width * 4;
+                let result = gud_data
+                    .recv_buffer_converted(info, state, &mut screen.fb, pitch)
+                    .and_then(|()| screen.present(&mut window));
+                if let Err(err) = result {
+                    warn!("recv_buffer failed: {:#}", err);
+                }
+            }
+            Event::DisplayEnable(true) => {}
+            Event::Reset | Event::Disconnect => {
+                if let Err(err) = gud_data.reset() {
+                    warn!("resetting data endpoint failed: {:#}", err);
+                }
+                screen.clear();
+                screen.present(&mut window)?;
+            }
+            Event::DisplayEnable(false) => {
+                screen.clear();
+                screen.present(&mut window)?;
+            }
+            Event::Connect | Event::Suspend | Event::Resume | Event::ForceDetect { .. } => {}
+        }
+    }
+
+    Ok(())
+}